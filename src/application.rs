@@ -1,31 +1,52 @@
-pub enum MouseButton {
-    Left,
-    Right,
-    Middle,
-}
-
-pub enum MouseButton {
-    Left,
-    Right,
-    Middle,
-}
+use crate::buffer::Buffer;
+use crate::pane::Pane;
 
-pub enum MouseAction {
-    Up,
+// What the left button did. Modes don't see the other buttons or the wheel.
+pub enum MouseEvent {
     Down,
-    Motion { x1: i32, y1: i32, x2: i32, y2: i32 },
+    Drag,
 }
 
-pub struct MouseEvent {
-    pub button: MouseButton,
-        Left,
-        Right,
-        Middle,
-    },
-    pub action: MouseAction,
+// An input mode sits between the editor and a buffer pane. It sees every key and
+// piece of text first, and anything it doesn't use goes through the pane's own
+// bindings as usual.
+pub trait Mode {
+    fn name(&self) -> &'static str;
+    // Shown in the pane bar
+    fn status(&self) -> String;
+    // Return true if the key was used
+    fn handle_key(&mut self, pane: &mut Pane, buffer: &mut Buffer, kstr: &str) -> bool;
+    // Return true if the text was used
+    fn handle_text(&mut self, pane: &mut Pane, buffer: &mut Buffer, text: &str) -> bool;
+    fn handle_mouse(&mut self, _pane: &mut Pane, _buffer: &mut Buffer, _event: &MouseEvent) -> bool {
+        false
+    }
+    // Editor commands the mode wants run, by name, like "pane.split" for a key that
+    // needs more than the current pane
+    fn take_commands(&mut self) -> Vec<&'static str> {
+        Vec::new()
+    }
+    // Called every frame, for timeouts
+    fn tick(&mut self) {}
 }
 
-pub trait Mode {
-    fn handle_key(kstr: &str) -> bool,
-    fn handle_mouse(event: MouseEvent
+// Plain editing with the pane's bindings
+pub struct DefaultMode;
+
+impl Mode for DefaultMode {
+    fn name(&self) -> &'static str {
+        "default"
+    }
+
+    fn status(&self) -> String {
+        String::new()
+    }
+
+    fn handle_key(&mut self, _pane: &mut Pane, _buffer: &mut Buffer, _kstr: &str) -> bool {
+        false
+    }
+
+    fn handle_text(&mut self, _pane: &mut Pane, _buffer: &mut Buffer, _text: &str) -> bool {
+        false
+    }
 }
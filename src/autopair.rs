@@ -0,0 +1,224 @@
+use crate::buffer::Buffer;
+use crate::change::Position;
+use crate::highlight::SyntaxContext;
+
+pub struct AutoPairs {
+    pub enabled: bool,
+    pub pairs: Vec<(char, char)>,
+    // Closing characters we inserted ourselves and where they are now. Typing one of
+    // these right in front of it steps over it instead of inserting another.
+    pending: Vec<(Position, char)>,
+    // The buffer version the positions are up to date with
+    version: u64,
+}
+
+impl AutoPairs {
+    pub fn new() -> Self {
+        Self {
+            enabled: true,
+            pairs: Self::default_pairs(),
+            pending: Vec::new(),
+            version: 0,
+        }
+    }
+
+    // For buffers in a language without a definition
+    pub fn default_pairs() -> Vec<(char, char)> {
+        vec![('(', ')'), ('[', ']'), ('{', '}'), ('"', '"'), ('\'', '\'')]
+    }
+
+    fn closer_for(&self, c: char) -> Option<char> {
+        self.pairs.iter().find(|(open, _)| *open == c).map(|(_, close)| *close)
+    }
+
+    // Move the closers along with the edits made since we last looked, forgetting the
+    // ones that were deleted, and any the cursor has left behind
+    fn track_changes(&mut self, buffer: &Buffer) {
+        match buffer.changes_since(self.version) {
+            Some(changes) => {
+                for change in &changes {
+                    let old = change.old_range;
+                    self.pending.retain(|(p, _)| *p < old.start || old.end <= *p);
+                    for (p, _) in &mut self.pending {
+                        *p = change.adjust(*p, true);
+                    }
+                }
+            }
+            None => self.pending.clear(),
+        }
+        self.version = buffer.version();
+        let cursor = Position::new(buffer.cursor_x, buffer.cursor_y);
+        self.pending.retain(|(p, _)| p.y == cursor.y && cursor.x <= p.x);
+    }
+
+    // Handle a typed character. Returns false if it should just be inserted normally.
+    pub fn type_char(&mut self, buffer: &mut Buffer, c: char, context: SyntaxContext) -> bool {
+        if !self.enabled {
+            return false;
+        }
+        let (x1, y1, x2, y2) = buffer.get_selection();
+        let has_selection = x1 != x2 || y1 != y2;
+        self.track_changes(buffer);
+
+        let line = buffer.line_graphemes(y1);
+        let next = line.get(x1).and_then(|g| g.chars().next());
+        let prev = if x1 > 0 { line[x1 - 1].chars().next() } else { None };
+
+        // Step over a closer we inserted
+        if !has_selection && next == Some(c) {
+            if let Some(i) = self.pending.iter().position(|p| *p == (Position::new(x1, y1), c)) {
+                self.pending.remove(i);
+                buffer.cursor_right(false);
+                return true;
+            }
+        }
+
+        let close = match self.closer_for(c) {
+            Some(close) => close,
+            None => return false,
+        };
+
+        // Strings and comments are left alone
+        if context != SyntaxContext::Code {
+            return false;
+        }
+
+        // Wrap the selection in the pair, leaving the original text selected
+        if has_selection {
+            let text = buffer.text_range(x1, y1, x2, y2);
+            let (end_x, end_y) = buffer.replace_text(x1, y1, x2, y2, format!("{}{}{}", c, text, close));
+            buffer.sel_x = x1 + 1;
+            buffer.sel_y = y1;
+            buffer.cursor_x = end_x - 1;
+            buffer.cursor_y = end_y;
+            buffer.max_cursor_x = buffer.cursor_x;
+            return true;
+        }
+
+        // Don't pair right before a word, or for a quote straight after a word
+        // (apostrophes, lifetimes)
+        let is_quote = c == close;
+        if next.is_some_and(|n| n.is_alphanumeric() || n == '_') || (is_quote && prev.is_some_and(|p| p.is_alphanumeric() || p == '_')) {
+            return false;
+        }
+        buffer.insert_text(x1, y1, format!("{}{}", c, close));
+        buffer.cursor_x = x1 + 1;
+        buffer.max_cursor_x = buffer.cursor_x;
+        buffer.set_selection(false);
+        self.track_changes(buffer);
+        self.pending.push((Position::new(x1 + 1, y1), close));
+        true
+    }
+
+    // Backspace between an empty pair removes both halves. Returns false if it
+    // should be handled as a normal backspace.
+    pub fn remove_pair(&mut self, buffer: &mut Buffer) -> bool {
+        let (x1, y1, x2, y2) = buffer.get_selection();
+        if !self.enabled || x1 != x2 || y1 != y2 || x1 == 0 {
+            return false;
+        }
+        let line = buffer.line_graphemes(y1);
+        let prev = line[x1 - 1].chars().next();
+        let next = line.get(x1).and_then(|g| g.chars().next());
+        match prev.and_then(|p| self.closer_for(p)) {
+            Some(close) if next == Some(close) => {
+                buffer.delete_text(x1 - 1, y1, x1 + 1, y1);
+                buffer.cursor_x = x1 - 1;
+                buffer.max_cursor_x = buffer.cursor_x;
+                buffer.set_selection(false);
+                self.track_changes(buffer);
+                true
+            }
+            _ => false,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(line: &str, cursor_x: usize) -> Buffer {
+        let mut buffer = Buffer::from_lines([line]);
+        buffer.cursor_x = cursor_x;
+        buffer.set_selection(false);
+        buffer
+    }
+
+    #[test]
+    fn types_over_inserted_closers() {
+        let mut pairs = AutoPairs::new();
+        let mut buffer = buffer("f", 1);
+        assert!(pairs.type_char(&mut buffer, '(', SyntaxContext::Code));
+        assert_eq!((buffer.contents[0].as_str(), buffer.cursor_x), ("f()", 2));
+        assert!(pairs.type_char(&mut buffer, '"', SyntaxContext::Code));
+        buffer.action_type_text("ab".to_string());
+        assert!(pairs.type_char(&mut buffer, '"', SyntaxContext::Code));
+        assert!(pairs.type_char(&mut buffer, ')', SyntaxContext::Code));
+        assert_eq!((buffer.contents[0].as_str(), buffer.cursor_x), ("f(\"ab\")", 7));
+        // Nothing left to step over
+        assert!(!pairs.type_char(&mut buffer, ')', SyntaxContext::Code));
+    }
+
+    #[test]
+    fn only_steps_over_its_own_closer() {
+        let mut pairs = AutoPairs::new();
+        let mut buffer = buffer("", 0);
+        assert!(pairs.type_char(&mut buffer, '(', SyntaxContext::Code));
+        // A closer that was there already doesn't count
+        buffer.insert_text(1, 0, ")".to_string());
+        assert!(!pairs.type_char(&mut buffer, ')', SyntaxContext::Code));
+        // The one we inserted moved along, but the cursor has to be right before it
+        buffer.action_type_text(")".to_string());
+        assert_eq!((buffer.contents[0].as_str(), buffer.cursor_x), ("()))", 2));
+        assert!(!pairs.type_char(&mut buffer, ')', SyntaxContext::Code));
+    }
+
+    #[test]
+    fn forgets_closers_when_the_cursor_leaves() {
+        let mut pairs = AutoPairs::new();
+        let mut buffer = buffer("", 0);
+        buffer.insert_text(0, 0, "\n".to_string());
+        assert!(pairs.type_char(&mut buffer, '[', SyntaxContext::Code));
+        buffer.cursor_y = 1;
+        buffer.cursor_x = 0;
+        pairs.type_char(&mut buffer, 'x', SyntaxContext::Code);
+        buffer.cursor_y = 0;
+        buffer.cursor_x = 1;
+        assert!(!pairs.type_char(&mut buffer, ']', SyntaxContext::Code));
+    }
+
+    #[test]
+    fn backspace_removes_an_empty_pair() {
+        let mut pairs = AutoPairs::new();
+        let mut buffer = buffer("a", 1);
+        assert!(pairs.type_char(&mut buffer, '[', SyntaxContext::Code));
+        assert!(pairs.remove_pair(&mut buffer));
+        assert_eq!((buffer.contents[0].as_str(), buffer.cursor_x), ("a", 1));
+        assert!(!pairs.remove_pair(&mut buffer));
+    }
+
+    #[test]
+    fn leaves_strings_comments_and_words_alone() {
+        let mut pairs = AutoPairs::new();
+        let mut buffer = buffer("ab", 0);
+        assert!(!pairs.type_char(&mut buffer, '(', SyntaxContext::Code));
+        buffer.cursor_x = 2;
+        buffer.set_selection(false);
+        assert!(!pairs.type_char(&mut buffer, '\'', SyntaxContext::Code));
+        assert!(!pairs.type_char(&mut buffer, '(', SyntaxContext::String));
+        assert!(!pairs.type_char(&mut buffer, '(', SyntaxContext::Comment));
+        assert_eq!(buffer.contents[0], "ab");
+    }
+
+    #[test]
+    fn wraps_the_selection() {
+        let mut pairs = AutoPairs::new();
+        let mut buffer = buffer("ab", 2);
+        buffer.sel_x = 0;
+        assert!(!pairs.type_char(&mut buffer, '[', SyntaxContext::String));
+        assert!(pairs.type_char(&mut buffer, '[', SyntaxContext::Code));
+        assert_eq!(buffer.contents[0], "[ab]");
+        assert_eq!((buffer.sel_x, buffer.cursor_x), (1, 3));
+    }
+}
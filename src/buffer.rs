@@ -1,23 +1,51 @@
 use std::cmp::{max, min};
-use std::fs::OpenOptions;
-use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufWriter, ErrorKind, Write};
 use std::path::Path;
 
 use clipboard::{ClipboardContext, ClipboardProvider};
 
 use unicode_segmentation::UnicodeSegmentation;
 
+use crate::change::{ChangeEvent, Position, Range, SubscriptionId};
+use crate::encoding::{self, Encoding, LineEnding};
+use crate::indent::{self, IndentStyle};
+use crate::language;
+
+// How many change events are kept around for changes_since()
+const CHANGE_LOG_SIZE: usize = 1024;
+
+type Subscriber = Box<dyn FnMut(&ChangeEvent)>;
+
 pub struct Buffer {
     pub name: String,
     pub contents: Vec<String>,
     pub is_dirty: bool,
-    pub undo_stack: Vec<Action>,
-    pub redo_stack: Vec<Action>,
+    // The file can't be written
+    pub read_only: bool,
+    pub encoding: Encoding,
+    pub line_ending: LineEnding,
+    pub indent_style: IndentStyle,
+    // How many columns a tab takes up, from the settings
+    pub tab_width: usize,
+    // The language ID, see language.rs
+    pub language: String,
+    // Each entry is one undo step, made up of one or more actions
+    pub undo_stack: Vec<Vec<Action>>,
+    pub redo_stack: Vec<Vec<Action>>,
+    undo_group_depth: usize,
     pub cursor_x: usize,
     pub max_cursor_x: usize,
     pub cursor_y: usize,
     pub sel_x: usize,
     pub sel_y: usize,
+    // Bookmarked lines, in order
+    pub bookmarks: Vec<usize>,
+    version: u64,
+    changes: VecDeque<ChangeEvent>,
+    subscribers: Vec<(SubscriptionId, Subscriber)>,
+    next_subscription_id: SubscriptionId,
 }
 
 #[derive(Clone)]
@@ -32,51 +60,86 @@ pub struct Action {
 
 impl Buffer {
     pub fn new() -> Self {
-        let mut buffer = Self {
-            contents: Vec::new(),
+        Self::from_lines([""])
+    }
+
+    // A buffer holding `lines` as they are, at version 0 with nothing in the change log
+    pub(crate) fn from_lines(lines: impl IntoIterator<Item = impl Into<String>>) -> Self {
+        Self {
+            contents: lines.into_iter().map(Into::into).collect(),
             name: "UNNAMED".to_string(),
             is_dirty: false,
+            read_only: false,
+            encoding: Encoding::Utf8,
+            line_ending: LineEnding::Lf,
+            indent_style: IndentStyle::Spaces(4),
+            tab_width: indent::DEFAULT_TAB_WIDTH,
+            language: "text".to_string(),
             undo_stack: Vec::new(),
             redo_stack: Vec::new(),
+            undo_group_depth: 0,
             cursor_x: 0,
             max_cursor_x: 0,
             cursor_y: 0,
             sel_x: 0,
             sel_y: 0,
-        };
-        buffer.push_line(String::new());
-        buffer
+            bookmarks: Vec::new(),
+            version: 0,
+            changes: VecDeque::new(),
+            subscribers: Vec::new(),
+            next_subscription_id: 0,
+        }
     }
 
     pub fn from_path(path: impl AsRef<Path>) -> Self {
-        let mut buffer = Self {
-            contents: Vec::new(),
-            name: path.as_ref().to_string_lossy().into_owned(),
-            is_dirty: false,
-            undo_stack: Vec::new(),
-            redo_stack: Vec::new(),
-            cursor_x: 0,
-            max_cursor_x: 0,
-            cursor_y: 0,
-            sel_x: 0,
-            sel_y: 0,
-        };
-        let file = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .read(true)
-            .open(path.as_ref())
-            .unwrap();
-        let reader = BufReader::new(file);
-        for line in reader.lines() {
-            buffer.push_line(line.unwrap());
-        }
-        if buffer.is_empty() {
-            buffer.push_line(String::new());
+        let path = path.as_ref();
+        let mut contents = Vec::new();
+        let (mut encoding, mut line_ending) = (Encoding::Utf8, LineEnding::Lf);
+        // Saving over a file that couldn't be read properly would lose what's in it
+        let mut read_only = false;
+        // A file that isn't there yet is made on saving
+        match fs::read(path) {
+            Ok(bytes) => {
+                let text = match encoding::decode(&bytes) {
+                    Some((text, text_encoding)) => {
+                        encoding = text_encoding;
+                        text
+                    }
+                    None => {
+                        eprintln!("Open: {}: not UTF-8", path.display());
+                        read_only = true;
+                        String::from_utf8_lossy(&bytes).into_owned()
+                    }
+                };
+                line_ending = LineEnding::detect(&text);
+                contents = text.lines().map(|line| line.to_string()).collect();
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                eprintln!("Open: {}: {}", path.display(), e);
+                read_only = true;
+            }
+        }
+        if contents.is_empty() {
+            contents.push(String::new());
         }
+        let mut buffer = Self::from_lines(contents);
+        buffer.name = path.to_string_lossy().into_owned();
+        buffer.encoding = encoding;
+        buffer.line_ending = line_ending;
+        buffer.read_only = read_only || fs::metadata(path).is_ok_and(|m| m.permissions().readonly());
+        let language = language::detect(&buffer.name, &buffer.contents);
+        buffer.set_language(language);
         buffer
     }
 
+    // Switch languages. The indentation style follows the new language's default unless
+    // the file's own indentation says otherwise.
+    pub fn set_language(&mut self, id: &str) {
+        self.language = id.to_string();
+        self.indent_style = IndentStyle::detect(&self.contents).unwrap_or_else(|| IndentStyle::default_for_language(id));
+    }
+
     pub fn len(&self) -> usize {
         self.contents.len()
     }
@@ -90,7 +153,14 @@ impl Buffer {
     }
 
     pub fn clear(&mut self) {
+        if self.is_empty() {
+            return;
+        }
+        let y2 = self.len() - 1;
+        let old_range = Range::new(0, 0, self.line_len(y2), y2);
+        let old_text = self.contents.join("\n");
         self.contents.clear();
+        self.emit_change(old_range, Range::new(0, 0, 0, 0), String::new(), old_text);
     }
 
     pub fn is_empty(&self) -> bool {
@@ -98,35 +168,144 @@ impl Buffer {
     }
 
     pub fn push_line(&mut self, s: String) {
+        let len = s.graphemes(true).count();
+        let (old_range, new_range, text) = if self.is_empty() {
+            (Range::new(0, 0, 0, 0), Range::new(0, 0, len, 0), s.clone())
+        } else {
+            let y = self.len() - 1;
+            let x = self.line_len(y);
+            (Range::new(x, y, x, y), Range::new(x, y, len, y + 1), format!("\n{}", s))
+        };
         self.contents.push(s);
+        self.emit_change(old_range, new_range, text, String::new());
     }
 
+    // The version goes up by one with every change to the contents
+    pub fn version(&self) -> u64 {
+        self.version
+    }
+
+    // Call `f` with every change made to the buffer from now on
+    pub fn subscribe(&mut self, f: impl FnMut(&ChangeEvent) + 'static) -> SubscriptionId {
+        let id = self.next_subscription_id;
+        self.next_subscription_id += 1;
+        self.subscribers.push((id, Box::new(f)));
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: SubscriptionId) {
+        self.subscribers.retain(|(i, _)| *i != id);
+    }
+
+    // The changes made after `version`, oldest first. Returns None if they're no longer
+    // all in the log (or the version is from some other buffer), in which case the caller
+    // should assume everything changed.
+    pub fn changes_since(&self, version: u64) -> Option<Vec<ChangeEvent>> {
+        if version > self.version {
+            return None;
+        }
+        if version == self.version {
+            return Some(Vec::new());
+        }
+        match self.changes.front() {
+            Some(first) if first.version <= version + 1 => Some(
+                self.changes
+                    .iter()
+                    .filter(|c| c.version > version)
+                    .cloned()
+                    .collect(),
+            ),
+            _ => None,
+        }
+    }
+
+    fn emit_change(&mut self, old_range: Range, new_range: Range, text: String, old_text: String) {
+        self.version += 1;
+        let event = ChangeEvent {
+            version: self.version,
+            old_range,
+            new_range,
+            text,
+            old_text,
+        };
+        for (_id, subscriber) in self.subscribers.iter_mut() {
+            subscriber(&event);
+        }
+        // Bookmarks move with their lines. Those on deleted lines end up where the text was.
+        for line in &mut self.bookmarks {
+            *line = event.adjust(Position::new(0, *line), false).y;
+        }
+        self.bookmarks.dedup();
+        if self.changes.len() == CHANGE_LOG_SIZE {
+            self.changes.pop_front();
+        }
+        self.changes.push_back(event);
+    }
+
+    // Insert `s` as a new line y, pushing the lines from y on down. y can be one past
+    // the last line to add a line at the end.
     pub fn insert_line(&mut self, y: usize, s: String) {
-        self.contents.insert(y, s);
-        self.undo_stack.push(Action {
-            deleted_text: None,
-            inserted_text: Some("\n".to_string()),
-            x1: 0,
-            y1: y,
-            x2: 0,
-            y2: y,
-        });
+        if self.is_empty() {
+            self.push_line(s);
+        } else if y < self.len() {
+            self.insert_text(0, y, format!("{}\n", s));
+        } else {
+            let x = self.line_len(y - 1);
+            self.insert_text(x, y - 1, format!("\n{}", s));
+        }
+    }
+
+    // Everything pushed to the undo stack between begin_undo_group() and
+    // end_undo_group() is undone and redone as a single step. Groups can nest.
+    pub fn begin_undo_group(&mut self) {
+        if self.undo_group_depth == 0 {
+            self.undo_stack.push(Vec::new());
+        }
+        self.undo_group_depth += 1;
+    }
+
+    // Like begin_undo_group(), but adds to the last undo step instead of starting a
+    // new one, so follow-up edits are undone together with the edit that caused them.
+    pub fn continue_undo_group(&mut self) {
+        if self.undo_group_depth == 0 && self.undo_stack.is_empty() {
+            self.undo_stack.push(Vec::new());
+        }
+        self.undo_group_depth += 1;
+    }
+
+    pub fn end_undo_group(&mut self) {
+        self.undo_group_depth -= 1;
+        if self.undo_group_depth == 0 && self.undo_stack.last().is_some_and(|g| g.is_empty()) {
+            self.undo_stack.pop();
+        }
+    }
+
+    fn push_action(&mut self, action: Action) {
+        self.redo_stack.clear();
+        if self.undo_group_depth > 0 {
+            if let Some(group) = self.undo_stack.last_mut() {
+                group.push(action);
+                return;
+            }
+        }
+        self.undo_stack.push(vec![action]);
     }
 
+    // Write the file back with the encoding and line endings it was read with
     pub fn save(&mut self) {
-        let f = OpenOptions::new()
-            .write(true)
-            .create(true)
-            .truncate(true)
-            .open(&self.name)
-            .unwrap();
-
-        let mut f = BufWriter::new(f);
+        if self.read_only {
+            eprintln!("Save: {} is read-only", self.name);
+            return;
+        }
+        let mut text = String::new();
         for line in &self.contents {
-            writeln!(&mut f, "{}", line).unwrap();
+            text.push_str(line);
+            text.push_str(self.line_ending.text());
+        }
+        match fs::write(&self.name, encoding::encode(&text, self.encoding)) {
+            Ok(()) => self.is_dirty = false,
+            Err(e) => eprintln!("Save: {}: {}", self.name, e),
         }
-        f.flush().unwrap();
-        self.is_dirty = false;
     }
 
     pub fn print(&self) {
@@ -141,7 +320,7 @@ impl Buffer {
     pub fn delete_text(&mut self, x1: usize, y1: usize, x2: usize, y2: usize) {
         let text = self.do_delete(x1, y1, x2, y2);
         self.is_dirty = true;
-        self.undo_stack.push(Action {
+        self.push_action(Action {
             deleted_text: Some(text),
             inserted_text: None,
             x1,
@@ -175,33 +354,191 @@ impl Buffer {
     }
 
     pub fn break_line(&mut self) {
-        let mut g = self.line_graphemes(self.cursor_y);
-        let first_half = g.drain(..self.cursor_x).collect::<Vec<&str>>().concat();
-        let last_half = g.concat();
-        self.contents[self.cursor_y] = first_half;
-        self.cursor_y += 1;
-        self.cursor_x = 0;
+        let (x, y) = self.insert_text(self.cursor_x, self.cursor_y, "\n".to_string());
+        self.cursor_x = x;
+        self.cursor_y = y;
         self.max_cursor_x = self.cursor_x;
-        self.insert_line(self.cursor_y, last_half);
         self.set_selection(false);
     }
 
+    // Break the line, keeping the current indentation. One more level is added after one
+    // of the language's opening brackets or a line matching its indent_after pattern, and
+    // if the cursor sits right between a pair of brackets the closing one goes on its own
+    // line at the original indentation.
     pub fn break_line_with_auto_indent(&mut self) {
-        self.break_line();
-        let last_line = self.contents[self.cursor_y - 1].clone();
-        for c in last_line.chars() {
-            if c.is_whitespace() {
-                self.action_insert_text(c.to_string());
+        let (x1, y1, x2, y2) = self.get_selection();
+        let line = self.line_graphemes(y1);
+        let base_indent = indent::leading_whitespace(&self.contents[y1]).to_string();
+        let before = line[..x1].concat();
+        let after = self.line_graphemes(y2)[x2..].concat();
+
+        let language = language::get(&self.language);
+        let brackets = language.map_or_else(|| vec![('(', ')'), ('[', ']'), ('{', '}')], |l| l.brackets.clone());
+        let opener = before.trim_end().chars().last().and_then(|c| brackets.iter().find(|(open, _)| *open == c));
+        let indent_after = language.and_then(|l| l.indent_after.as_ref()).is_some_and(|r| r.is_match(&before));
+        let mut text = format!("\n{}", base_indent);
+        if opener.is_some() || indent_after {
+            text.push_str(&self.indent_style.unit());
+        }
+        let mut closer_text = String::new();
+        if let Some((_, close)) = opener {
+            if after.trim_start().starts_with(*close) {
+                closer_text = format!("\n{}", base_indent);
+            }
+        }
+
+        self.begin_undo_group();
+        // Don't leave trailing whitespace behind on the line being broken
+        let trimmed_x = before.trim_end().graphemes(true).count();
+        let (x, y) = self.replace_text(trimmed_x, y1, x2, y2, text);
+        if !closer_text.is_empty() {
+            let whitespace = after.graphemes(true).count() - after.trim_start().graphemes(true).count();
+            self.replace_text(x, y, x + whitespace, y, closer_text);
+        }
+        self.end_undo_group();
+
+        self.cursor_x = x;
+        self.cursor_y = y;
+        self.max_cursor_x = self.cursor_x;
+        self.set_selection(false);
+    }
+
+    // Tab indents every line of a multi-line selection, and otherwise inserts
+    // indentation up to the next tab stop.
+    pub fn action_tab(&mut self) {
+        let (x1, y1, _x2, y2) = self.get_selection();
+        if y1 != y2 {
+            self.indent_selection();
+            return;
+        }
+        let text = match self.indent_style {
+            IndentStyle::Tabs => "\t".to_string(),
+            IndentStyle::Spaces(n) => {
+                let col = indent::visual_column(&self.line_graphemes(y1), x1, self.tab_width);
+                " ".repeat(n - col % n)
+            }
+        };
+        self.action_insert_text(text);
+        self.max_cursor_x = self.cursor_x;
+    }
+
+    // The lines touched by the selection. A selection ending at the very start of a
+    // line doesn't count that line.
+    pub fn selected_lines(&self) -> (usize, usize) {
+        let (_x1, y1, x2, y2) = self.get_selection();
+        if y2 > y1 && x2 == 0 {
+            (y1, y2 - 1)
+        } else {
+            (y1, y2)
+        }
+    }
+
+    pub fn indent_selection(&mut self) {
+        let (y1, y2) = self.selected_lines();
+        let unit = self.indent_style.unit();
+        let unit_len = unit.graphemes(true).count();
+        self.begin_undo_group();
+        for y in y1..=y2 {
+            if self.contents[y].is_empty() {
+                continue;
+            }
+            self.insert_text(0, y, unit.clone());
+            if self.cursor_y == y {
+                self.cursor_x += unit_len;
+            }
+            if self.sel_y == y {
+                self.sel_x += unit_len;
+            }
+        }
+        self.end_undo_group();
+        self.max_cursor_x = self.cursor_x;
+    }
+
+    pub fn dedent_selection(&mut self) {
+        let (y1, y2) = self.selected_lines();
+        let width = self.indent_style.width(self.tab_width);
+        self.begin_undo_group();
+        for y in y1..=y2 {
+            let removed = if self.contents[y].starts_with('\t') {
+                1
             } else {
-                return
+                self.contents[y].chars().take(width).take_while(|c| *c == ' ').count()
+            };
+            if removed == 0 {
+                continue;
+            }
+            self.delete_text(0, y, removed, y);
+            if self.cursor_y == y {
+                self.cursor_x = self.cursor_x.saturating_sub(removed);
+            }
+            if self.sel_y == y {
+                self.sel_x = self.sel_x.saturating_sub(removed);
             }
         }
+        self.end_undo_group();
+        self.max_cursor_x = self.cursor_x;
+    }
+
+    // Insert typed text. A closing bracket typed at the start of a line snaps the line
+    // to the indentation of the line holding the matching opening bracket.
+    pub fn action_type_text(&mut self, text: String) {
+        let (x1, y1, x2, y2) = self.get_selection();
+        let c = text.chars().next().unwrap_or(' ');
+        let line = self.line_graphemes(y1);
+        let only_whitespace_before = line[..x1].concat().trim().is_empty();
+        if text.chars().count() != 1 || !indent::is_close_bracket(c) || !only_whitespace_before || x1 == 0 {
+            self.action_insert_text(text);
+            return;
+        }
+
+        let target_indent = match self.find_open_bracket(x1, y1, c) {
+            Some((_, y)) => indent::leading_whitespace(&self.contents[y]).to_string(),
+            None => {
+                let current = indent::leading_whitespace(&self.contents[y1]);
+                let width = self.indent_style.width(self.tab_width);
+                let unit = self.indent_style.unit();
+                if current.ends_with(&unit) {
+                    current[..current.len() - unit.len()].to_string()
+                } else {
+                    current[..current.len() - min(current.len(), width)].to_string()
+                }
+            }
+        };
+        self.begin_undo_group();
+        let (x, y) = self.replace_text(0, y1, x2, y2, format!("{}{}", target_indent, text));
+        self.end_undo_group();
+        self.cursor_x = x;
+        self.cursor_y = y;
+        self.max_cursor_x = self.cursor_x;
+        self.set_selection(false);
+    }
+
+    // Search backwards from (x, y) for the bracket that the closing bracket `close` would match
+    pub fn find_open_bracket(&self, x: usize, y: usize, close: char) -> Option<(usize, usize)> {
+        let open = indent::matching_bracket(close).to_string();
+        let close = close.to_string();
+        let mut depth = 0;
+        for line_y in (0..=y).rev() {
+            let g = self.line_graphemes(line_y);
+            let end = if line_y == y { min(x, g.len()) } else { g.len() };
+            for line_x in (0..end).rev() {
+                if g[line_x] == close {
+                    depth += 1;
+                } else if g[line_x] == open {
+                    if depth == 0 {
+                        return Some((line_x, line_y));
+                    }
+                    depth -= 1;
+                }
+            }
+        }
+        None
     }
 
     pub fn remove_selection(&mut self) {
         let (x1, y1, x2, y2) = self.get_selection();
         if x1 == x2 && y1 == y2 {
-            self.remove_char();
+            self.remove_char_or_soft_tab();
         } else {
             self.delete_text(x1, y1, x2, y2);
             self.cursor_x = x1;
@@ -292,6 +629,25 @@ impl Buffer {
         ctx.set_contents(s).unwrap();
     }
 
+    pub fn toggle_bookmark(&mut self, line: usize) {
+        match self.bookmarks.binary_search(&line) {
+            Ok(i) => {
+                self.bookmarks.remove(i);
+            }
+            Err(i) => self.bookmarks.insert(i, line),
+        }
+    }
+
+    // The next bookmark after `line`, or the one before it going backwards, wrapping
+    // around the ends of the buffer
+    pub fn next_bookmark(&self, line: usize, forward: bool) -> Option<usize> {
+        if forward {
+            self.bookmarks.iter().find(|b| **b > line).or(self.bookmarks.first()).copied()
+        } else {
+            self.bookmarks.iter().rev().find(|b| **b < line).or(self.bookmarks.last()).copied()
+        }
+    }
+
     pub fn select_line(&mut self, line: usize) {
         self.cursor_y = min(line, max(0, self.len() as i32 - 1) as usize);
         self.sel_y = self.cursor_y;
@@ -310,6 +666,16 @@ impl Buffer {
         (self.sel_x, self.sel_y, self.cursor_x, self.cursor_y)
     }
 
+    pub fn text_range(&self, x1: usize, y1: usize, x2: usize, y2: usize) -> String {
+        if y1 == y2 {
+            return self.line_graphemes(y1)[x1..x2].concat();
+        }
+        let mut lines = vec![self.line_graphemes(y1)[x1..].concat()];
+        lines.extend(self.contents[y1 + 1..y2].iter().cloned());
+        lines.push(self.line_graphemes(y2)[..x2].concat());
+        lines.join("\n")
+    }
+
     pub fn remove_char(&mut self) {
         let (x1, y1) = self.prev_char(self.cursor_x, self.cursor_y);
         self.delete_text(x1, y1, self.cursor_x, self.cursor_y);
@@ -317,10 +683,27 @@ impl Buffer {
         self.cursor_y = y1;
     }
 
+    // Like remove_char(), but inside leading space indentation a whole level is
+    // removed back to the previous tab stop.
+    pub fn remove_char_or_soft_tab(&mut self) {
+        let (x, y) = (self.cursor_x, self.cursor_y);
+        if let IndentStyle::Spaces(n) = self.indent_style {
+            let line = self.line_graphemes(y);
+            if x > 0 && line[..x].iter().all(|g| *g == " ") {
+                let count = (x - 1) % n + 1;
+                self.delete_text(x - count, y, x, y);
+                self.cursor_x = x - count;
+                self.max_cursor_x = self.cursor_x;
+                return;
+            }
+        }
+        self.remove_char();
+    }
+
     pub fn insert_text(&mut self, x: usize, y: usize, text: String) -> (usize, usize) {
         let (x2, y2) = self.do_insert(x, y, text.clone());
         self.is_dirty = true;
-        self.undo_stack.push(Action {
+        self.push_action(Action {
             deleted_text: None,
             inserted_text: Some(text),
             x1: x,
@@ -342,7 +725,7 @@ impl Buffer {
         let deleted_text = self.do_delete(x1, y1, x2, y2);
         let (x2, y2) = self.do_insert(x1, y1, text.clone());
         self.is_dirty = true;
-        self.undo_stack.push(Action {
+        self.push_action(Action {
             deleted_text: Some(deleted_text),
             inserted_text: Some(text),
             x1,
@@ -372,64 +755,72 @@ impl Buffer {
             let end = undo_buffer.len() - 1;
             undo_buffer[0] = npre;
             undo_buffer[end] = npost;
-            self.insert_line(y1, format!("{}{}", pre, post));
+            self.contents.insert(y1, format!("{}{}", pre, post));
         }
-        undo_buffer.join("\n")
+        let text = undo_buffer.join("\n");
+        self.emit_change(Range::new(x1, y1, x2, y2), Range::new(x1, y1, x1, y1), String::new(), text.clone());
+        text
     }
 
     pub fn do_insert(&mut self, x: usize, y: usize, text: String) -> (usize, usize) {
+        let (start_x, start_y) = (x, y);
         let mut l = self.line_graphemes(y);
         let start = l.drain(..x).collect::<Vec<&str>>().concat();
         let end = l.concat();
-        self.contents.remove(y);
-        self.insert_line(y, start);
-        let mut x = x;
+        let mut lines = text.split('\n');
+        let first = lines.next().unwrap_or("");
+        self.contents[y] = format!("{}{}", start, first);
+        let mut x = x + first.graphemes(true).count();
         let mut y = y;
-        for c in text.chars() {
-            if c == '\n' {
-                y += 1;
-                x = 0;
-                self.insert_line(y, String::new());
-            } else {
-                self.contents[y].push(c);
-                x += 1;
-            }
+        for line in lines {
+            y += 1;
+            x = line.graphemes(true).count();
+            self.contents.insert(y, line.to_string());
         }
         self.contents[y].push_str(&end);
+        self.emit_change(Range::new(start_x, start_y, start_x, start_y), Range::new(start_x, start_y, x, y), text, String::new());
         (x, y)
     }
 
+    // Apply the inverse of an action, returning the action that would reverse it again
     fn undo_action(&mut self, a: Action) -> Action {
+        let deleted_text = match a.inserted_text {
+            Some(_text) => Some(self.do_delete(a.x1, a.y1, a.x2, a.y2)),
+            None => None,
+        };
+        let (x2, y2) = match &a.deleted_text {
+            Some(text) => self.do_insert(a.x1, a.y1, text.clone()),
+            None => (a.x1, a.y1),
+        };
+        self.cursor_x = x2;
+        self.cursor_y = y2;
         Action {
-            inserted_text: match a.deleted_text {
-                Some(text) => {
-                    self.do_insert(a.x1, a.y1, text.clone());
-                    Some(text)
-                }
-                None => None,
-            },
-            deleted_text: match a.inserted_text {
-                Some(_text) => Some(self.do_delete(a.x1, a.y1, a.x2, a.y2)),
-                None => None,
-            },
+            inserted_text: a.deleted_text,
+            deleted_text,
             x1: a.x1,
             y1: a.y1,
-            x2: a.x2,
-            y2: a.y2,
+            x2,
+            y2,
         }
     }
 
     pub fn undo(&mut self) {
-        if let Some(a) = self.undo_stack.pop() {
-            let a = self.undo_action(a);
-            self.redo_stack.push(a);
+        if let Some(group) = self.undo_stack.pop() {
+            let group = group.into_iter().rev().map(|a| self.undo_action(a)).collect();
+            self.redo_stack.push(group);
+            self.is_dirty = true;
+            self.max_cursor_x = self.cursor_x;
+            self.set_selection(false);
         }
     }
 
     pub fn redo(&mut self) {
-        if let Some(a) = self.redo_stack.pop() {
-            let a = self.undo_action(a);
-            self.undo_stack.push(a);
+        if let Some(group) = self.redo_stack.pop() {
+            let group = group.into_iter().rev().map(|a| self.undo_action(a)).collect();
+            self.undo_stack.push(group);
+            self.is_dirty = true;
+            self.max_cursor_x = self.cursor_x;
+            self.set_selection(false);
         }
     }
 
@@ -451,14 +842,44 @@ impl Buffer {
         (x, y)
     }
 
-    pub fn next_word(&self, x: usize, y: usize) -> (usize, usize) {
-        let mut bounds = self.contents[y]
+    // The grapheme index where each word segment of line y starts, followed by the end of
+    // the line. split_word_bound_indices() gives byte offsets, so they're converted here.
+    pub fn word_bounds(&self, y: usize) -> Vec<usize> {
+        let line = &self.contents[y];
+        let starts = line
             .split_word_bound_indices()
             .map(|(i, _word)| i)
             .collect::<Vec<usize>>();
+        let mut bounds = line
+            .grapheme_indices(true)
+            .enumerate()
+            .filter(|(_x, (i, _g))| starts.binary_search(i).is_ok())
+            .map(|(x, _)| x)
+            .collect::<Vec<usize>>();
         bounds.push(self.line_len(y));
+        bounds
+    }
+
+    // The range of the word containing or directly before x on line y, as (x1, x2)
+    pub fn word_at(&self, x: usize, y: usize) -> (usize, usize) {
+        let g = self.line_graphemes(y);
+        let is_word = |x1: usize, x2: usize| g[x1..x2].iter().any(|s| s.chars().any(|c| c.is_alphanumeric() || c == '_'));
+        let bounds = self.word_bounds(y);
+        let mut before = None;
+        for pair in bounds.windows(2) {
+            let (x1, x2) = (pair[0], pair[1]);
+            if x1 <= x && x < x2 && is_word(x1, x2) {
+                return (x1, x2);
+            }
+            if x2 == x && is_word(x1, x2) {
+                before = Some((x1, x2));
+            }
+        }
+        before.unwrap_or((x, x))
+    }
 
-        for i in bounds {
+    pub fn next_word(&self, x: usize, y: usize) -> (usize, usize) {
+        for i in self.word_bounds(y) {
             if i > x {
                 return (i, y);
             }
@@ -470,7 +891,7 @@ impl Buffer {
     }
 
     pub fn prev_word(&self, x: usize, y: usize) -> (usize, usize) {
-        for (i, _words) in self.contents[y].split_word_bound_indices().rev() {
+        for i in self.word_bounds(y).into_iter().rev() {
             if i < x {
                 return (i, y);
             }
@@ -481,3 +902,127 @@ impl Buffer {
         (x, y)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn loading_starts_with_an_empty_change_log() {
+        let path = std::env::temp_dir().join("sdleditor-buffer-load.txt");
+        fs::write(&path, "one\ntwo\nthree\n").unwrap();
+        let buffer = Buffer::from_path(&path);
+        fs::remove_file(&path).unwrap();
+        assert_eq!(buffer.contents, vec!["one", "two", "three"]);
+        assert_eq!(buffer.version(), 0);
+        assert_eq!(buffer.changes_since(0).map(|c| c.len()), Some(0));
+    }
+
+    #[test]
+    fn saves_with_the_encoding_and_line_endings_it_was_read_with() {
+        let path = std::env::temp_dir().join("sdleditor-buffer-save.txt");
+        fs::write(&path, "\u{feff}one\r\ntwo\r\n").unwrap();
+        let mut buffer = Buffer::from_path(&path);
+        assert_eq!((buffer.encoding, buffer.line_ending), (Encoding::Utf8Bom, LineEnding::CrLf));
+        assert_eq!(buffer.contents, vec!["one", "two"]);
+        buffer.insert_text(3, 1, "!".to_string());
+        buffer.save();
+        let saved = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(saved, "\u{feff}one\r\ntwo!\r\n".as_bytes());
+        assert!(!buffer.is_dirty);
+    }
+
+    #[test]
+    fn files_that_are_not_utf8_open_read_only() {
+        let path = std::env::temp_dir().join("sdleditor-buffer-latin1.txt");
+        fs::write(&path, b"caf\xe9\r\nok\r\n").unwrap();
+        let buffer = Buffer::from_path(&path);
+        fs::remove_file(&path).unwrap();
+        assert!(buffer.read_only);
+        assert_eq!(buffer.contents, vec!["caf\u{fffd}", "ok"]);
+        assert_eq!(buffer.line_ending, LineEnding::CrLf);
+    }
+
+    #[test]
+    fn files_that_cant_be_read_open_read_only() {
+        // A directory exists but can't be read as a file
+        let path = std::env::temp_dir().join("sdleditor-buffer-unreadable");
+        fs::create_dir_all(&path).unwrap();
+        let buffer = Buffer::from_path(&path);
+        fs::remove_dir(&path).unwrap();
+        assert!(buffer.read_only);
+        assert_eq!(buffer.contents, vec![""]);
+        let missing = Buffer::from_path(std::env::temp_dir().join("sdleditor-buffer-missing.txt"));
+        assert!(!missing.read_only);
+    }
+
+    #[test]
+    fn subscribers_get_each_edit_with_its_text() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        let mut buffer = Buffer::from_lines(["hello", "world"]);
+        let seen = Rc::new(RefCell::new(Vec::new()));
+        let record = seen.clone();
+        let id = buffer.subscribe(move |change| record.borrow_mut().push(change.clone()));
+        buffer.insert_text(2, 0, "AB\nC".to_string());
+        buffer.delete_text(1, 1, 2, 2);
+        assert_eq!(buffer.contents, vec!["heAB", "Crld"]);
+        {
+            let seen = seen.borrow();
+            assert_eq!(seen.len(), 2);
+            assert_eq!((seen[0].text.as_str(), seen[0].old_text.as_str()), ("AB\nC", ""));
+            assert_eq!((seen[0].old_range, seen[0].new_range), (Range::new(2, 0, 2, 0), Range::new(2, 0, 1, 1)));
+            assert_eq!((seen[1].text.as_str(), seen[1].old_text.as_str()), ("", "llo\nwo"));
+            assert_eq!(seen[1].old_range, Range::new(1, 1, 2, 2));
+            assert_eq!(seen[1].version, buffer.version());
+        }
+        buffer.unsubscribe(id);
+        buffer.insert_text(0, 0, "x".to_string());
+        assert_eq!(seen.borrow().len(), 2);
+    }
+
+    #[test]
+    fn inserts_whole_lines() {
+        let mut buffer = Buffer::from_lines(["a", "c"]);
+        buffer.insert_line(1, "b".to_string());
+        buffer.insert_line(0, "0".to_string());
+        buffer.insert_line(4, "d".to_string());
+        assert_eq!(buffer.contents, vec!["0", "a", "b", "c", "d"]);
+        buffer.undo();
+        assert_eq!(buffer.contents, vec!["0", "a", "b", "c"]);
+    }
+
+    #[test]
+    fn bookmarks_follow_their_lines() {
+        let mut buffer = Buffer::from_lines(["a", "b", "c", "d"]);
+        buffer.toggle_bookmark(3);
+        buffer.toggle_bookmark(1);
+        assert_eq!(buffer.bookmarks, vec![1, 3]);
+        buffer.insert_text(1, 0, "\nx".to_string());
+        assert_eq!(buffer.bookmarks, vec![2, 4]);
+        buffer.delete_text(0, 2, 0, 4);
+        assert_eq!(buffer.bookmarks, vec![2]);
+        assert_eq!(buffer.next_bookmark(2, true), Some(2));
+        buffer.toggle_bookmark(0);
+        assert_eq!((buffer.next_bookmark(0, true), buffer.next_bookmark(0, false)), (Some(2), Some(2)));
+        assert_eq!(buffer.next_bookmark(1, false), Some(0));
+        buffer.toggle_bookmark(2);
+        assert_eq!(buffer.bookmarks, vec![0]);
+    }
+
+    #[test]
+    fn edits_are_logged_in_order() {
+        let mut buffer = Buffer::new();
+        buffer.insert_text(0, 0, "ab\ncd".to_string());
+        buffer.delete_text(1, 0, 1, 1);
+        assert_eq!(buffer.contents, vec!["ad"]);
+        let changes = buffer.changes_since(0).unwrap();
+        assert_eq!(changes.iter().map(|c| c.version).collect::<Vec<u64>>(), vec![1, 2]);
+        assert_eq!(changes[0].new_range, Range::new(0, 0, 2, 1));
+        assert_eq!(changes[1].old_range, Range::new(1, 0, 1, 1));
+        assert_eq!(buffer.changes_since(1).unwrap().len(), 1);
+        assert!(buffer.changes_since(3).is_none());
+    }
+}
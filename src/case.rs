@@ -0,0 +1,275 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Case {
+    Upper,
+    Lower,
+    Title,
+    Snake,
+    Camel,
+    Pascal,
+    Kebab,
+    Screaming,
+}
+
+impl Case {
+    fn is_identifier_style(&self) -> bool {
+        !matches!(self, Case::Upper | Case::Lower | Case::Title)
+    }
+
+    // Guess which identifier style a word is written in
+    pub fn of_identifier(ident: &str) -> Case {
+        let has_lower = ident.chars().any(|c| c.is_lowercase());
+        let has_upper = ident.chars().any(|c| c.is_uppercase());
+        let starts_upper = ident.trim_start_matches('_').chars().next().is_some_and(|c| c.is_uppercase());
+        if ident.contains('-') {
+            Case::Kebab
+        } else if ident.contains('_') && !has_lower {
+            Case::Screaming
+        } else if ident.contains('_') || !has_upper {
+            Case::Snake
+        } else if starts_upper {
+            Case::Pascal
+        } else {
+            Case::Camel
+        }
+    }
+
+    // The style that follows this one when cycling through identifier styles
+    pub fn next_identifier_style(&self) -> Case {
+        match self {
+            Case::Snake => Case::Camel,
+            Case::Camel => Case::Pascal,
+            Case::Pascal => Case::Kebab,
+            Case::Kebab => Case::Screaming,
+            _ => Case::Snake,
+        }
+    }
+}
+
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(c) => c.to_uppercase().chain(chars.flat_map(|c| c.to_lowercase())).collect(),
+        None => String::new(),
+    }
+}
+
+fn is_word(segment: &str) -> bool {
+    segment.chars().any(|c| c.is_alphanumeric() || c == '_')
+}
+
+// Split an identifier into its words, breaking on underscores, hyphens and changes
+// of case, so "parseHTTPResponse2" becomes ["parse", "HTTP", "Response2"]
+pub fn split_identifier(ident: &str) -> Vec<String> {
+    let chars = ident.chars().collect::<Vec<char>>();
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for (i, c) in chars.iter().enumerate() {
+        if *c == '_' || *c == '-' {
+            if !word.is_empty() {
+                words.push(word.clone());
+                word.clear();
+            }
+            continue;
+        }
+        if !word.is_empty() && c.is_uppercase() {
+            let prev = chars[i - 1];
+            let next_is_lower = chars.get(i + 1).is_some_and(|n| n.is_lowercase());
+            if prev.is_lowercase() || prev.is_numeric() || (prev.is_uppercase() && next_is_lower) {
+                words.push(word.clone());
+                word.clear();
+            }
+        }
+        word.push(*c);
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+fn convert_identifier(ident: &str, case: Case) -> String {
+    // Leading and trailing underscores usually mean something (private, dunder), so keep them
+    let start = ident.len() - ident.trim_start_matches('_').len();
+    let end = ident.trim_end_matches('_').len().max(start);
+    let words = split_identifier(&ident[start..end]);
+    let body = match case {
+        Case::Snake => words.iter().map(|w| w.to_lowercase()).collect::<Vec<_>>().join("_"),
+        Case::Screaming => words.iter().map(|w| w.to_uppercase()).collect::<Vec<_>>().join("_"),
+        Case::Kebab => words.iter().map(|w| w.to_lowercase()).collect::<Vec<_>>().join("-"),
+        Case::Pascal => words.iter().map(|w| capitalize(w)).collect::<String>(),
+        Case::Camel => words
+            .iter()
+            .enumerate()
+            .map(|(i, w)| if i == 0 { w.to_lowercase() } else { capitalize(w) })
+            .collect::<String>(),
+        _ => ident[start..end].to_string(),
+    };
+    format!("{}{}{}", &ident[..start], body, &ident[end..])
+}
+
+// Convert every word (or identifier) in `text` to `case`, leaving everything between them alone
+pub fn convert(text: &str, case: Case) -> String {
+    match case {
+        Case::Upper => return text.to_uppercase(),
+        Case::Lower => return text.to_lowercase(),
+        _ => {}
+    }
+    let segments = text.split_word_bounds().collect::<Vec<&str>>();
+    let mut result = String::new();
+    let mut i = 0;
+    while i < segments.len() {
+        if !is_word(segments[i]) {
+            result.push_str(segments[i]);
+            i += 1;
+            continue;
+        }
+        if !case.is_identifier_style() {
+            result.push_str(&capitalize(segments[i]));
+            i += 1;
+            continue;
+        }
+        // Unicode word boundaries split on hyphens, so glue kebab-case back together
+        let mut j = i + 1;
+        while j + 1 < segments.len() && segments[j] == "-" && is_word(segments[j + 1]) {
+            j += 2;
+        }
+        result.push_str(&convert_identifier(&segments[i..j].concat(), case));
+        i = j;
+    }
+    result
+}
+
+impl Buffer {
+    // The selection, or the word at the cursor if nothing is selected. A kebab-case
+    // identifier counts as one word for identifier-style conversions.
+    fn case_target(&self, case: Case) -> (usize, usize, usize, usize) {
+        let (x1, y1, x2, y2) = self.get_selection();
+        if x1 != x2 || y1 != y2 {
+            return (x1, y1, x2, y2);
+        }
+        let (mut x1, mut x2) = self.word_at(self.cursor_x, self.cursor_y);
+        if case.is_identifier_style() && x1 != x2 {
+            let g = self.line_graphemes(self.cursor_y);
+            while x1 >= 2 && g[x1 - 1] == "-" && is_word(g[x1 - 2]) {
+                x1 = self.word_at(x1 - 2, self.cursor_y).0;
+            }
+            while x2 + 1 < g.len() && g[x2] == "-" && is_word(g[x2 + 1]) {
+                x2 = self.word_at(x2 + 1, self.cursor_y).1;
+            }
+        }
+        (x1, self.cursor_y, x2, self.cursor_y)
+    }
+
+    // Convert the selection or the word under the cursor. The converted text stays
+    // selected, with the cursor on the same side it started on.
+    pub fn convert_case(&mut self, case: Case) {
+        let (x1, y1, x2, y2) = self.case_target(case);
+        if x1 == x2 && y1 == y2 {
+            return;
+        }
+        let text = self.text_range(x1, y1, x2, y2);
+        let converted = convert(&text, case);
+        if converted == text {
+            return;
+        }
+        let cursor_at_start = self.cursor_x == x1 && self.cursor_y == y1 && (self.sel_x != x1 || self.sel_y != y1);
+        let (end_x, end_y) = self.replace_text(x1, y1, x2, y2, converted);
+        if cursor_at_start {
+            self.sel_x = end_x;
+            self.sel_y = end_y;
+        } else {
+            self.sel_x = x1;
+            self.sel_y = y1;
+            self.cursor_x = end_x;
+            self.cursor_y = end_y;
+        }
+        self.max_cursor_x = self.cursor_x;
+    }
+
+    // Step the identifier at the cursor through snake_case, camelCase, PascalCase,
+    // kebab-case and SCREAMING_CASE
+    pub fn cycle_identifier_case(&mut self) {
+        let (x1, y1, x2, y2) = self.case_target(Case::Snake);
+        let text = self.text_range(x1, y1, x2, y2);
+        let ident = text.split_whitespace().next().unwrap_or("");
+        // Single words look the same in several styles, so skip the ones that change nothing
+        let mut case = Case::of_identifier(ident);
+        for _ in 0..5 {
+            case = case.next_identifier_style();
+            if convert(&text, case) != text {
+                self.convert_case(case);
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(line: &str, cursor_x: usize) -> Buffer {
+        let mut buffer = Buffer::from_lines([line]);
+        buffer.cursor_x = cursor_x;
+        buffer.set_selection(false);
+        buffer
+    }
+
+    #[test]
+    fn splits_identifiers() {
+        assert_eq!(split_identifier("parseHTTPResponse2x"), vec!["parse", "HTTP", "Response2x"]);
+        assert_eq!(split_identifier("foo_bar-baz"), vec!["foo", "bar", "baz"]);
+    }
+
+    #[test]
+    fn converts_to_each_style() {
+        assert_eq!(convert("fooBar", Case::Snake), "foo_bar");
+        assert_eq!(convert("foo_bar-x", Case::Camel), "fooBarX");
+        assert_eq!(convert("foo_bar", Case::Pascal), "FooBar");
+        assert_eq!(convert("FooBar", Case::Kebab), "foo-bar");
+        assert_eq!(convert("foo-bar", Case::Screaming), "FOO_BAR");
+        assert_eq!(convert("__init__", Case::Pascal), "__Init__");
+        assert_eq!(convert("hello wörld", Case::Title), "Hello Wörld");
+        assert_eq!(convert("straße", Case::Upper), "STRASSE");
+    }
+
+    #[test]
+    fn converts_only_the_words() {
+        assert_eq!(convert("fooBar baz_qux, quxQuux", Case::Snake), "foo_bar baz_qux, qux_quux");
+    }
+
+    #[test]
+    fn guesses_styles() {
+        assert_eq!(Case::of_identifier("foo_bar"), Case::Snake);
+        assert_eq!(Case::of_identifier("fooBar"), Case::Camel);
+        assert_eq!(Case::of_identifier("FooBar"), Case::Pascal);
+        assert_eq!(Case::of_identifier("foo-bar"), Case::Kebab);
+        assert_eq!(Case::of_identifier("FOO_BAR"), Case::Screaming);
+    }
+
+    #[test]
+    fn converts_word_at_cursor() {
+        let mut b = buffer("let my-var = 1;", 6);
+        b.convert_case(Case::Camel);
+        assert_eq!(b.contents[0], "let myVar = 1;");
+        assert_eq!((b.sel_x, b.cursor_x), (4, 9));
+        let mut b = buffer("añb wörd", 5);
+        b.convert_case(Case::Upper);
+        assert_eq!(b.contents[0], "añb WÖRD");
+    }
+
+    #[test]
+    fn cycles_identifier_styles() {
+        let mut b = buffer("let my-var = 1;", 6);
+        b.cycle_identifier_case();
+        assert_eq!(b.contents[0], "let MY_VAR = 1;");
+        b.cycle_identifier_case();
+        assert_eq!(b.contents[0], "let my_var = 1;");
+        b.cycle_identifier_case();
+        assert_eq!(b.contents[0], "let myVar = 1;");
+    }
+}
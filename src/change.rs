@@ -0,0 +1,79 @@
+use std::cmp::Ordering;
+
+// Positions are in the same units as the buffer's cursor: x is a grapheme index
+// into line y.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Position {
+    pub x: usize,
+    pub y: usize,
+}
+
+impl Position {
+    pub fn new(x: usize, y: usize) -> Self {
+        Self { x, y }
+    }
+}
+
+// Positions are ordered by where they are in the buffer, so by line first
+impl Ord for Position {
+    fn cmp(&self, other: &Self) -> Ordering {
+        (self.y, self.x).cmp(&(other.y, other.x))
+    }
+}
+
+impl PartialOrd for Position {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Range {
+    pub start: Position,
+    pub end: Position,
+}
+
+impl Range {
+    pub fn new(x1: usize, y1: usize, x2: usize, y2: usize) -> Self {
+        Self {
+            start: Position::new(x1, y1),
+            end: Position::new(x2, y2),
+        }
+    }
+}
+
+// A single edit to a buffer: the text in old_range (in coordinates from before the edit)
+// was replaced with `text`, which now occupies new_range.
+#[derive(Clone, Debug)]
+pub struct ChangeEvent {
+    // The buffer version after this change was applied
+    pub version: u64,
+    pub old_range: Range,
+    pub new_range: Range,
+    pub text: String,
+    pub old_text: String,
+}
+
+impl ChangeEvent {
+    // Move a position from before this change to where it ends up after it. Text
+    // inserted exactly at the position goes after it, unless `stick_right` is set,
+    // in which case the position moves along to the end of the new text.
+    pub fn adjust(&self, pos: Position, stick_right: bool) -> Position {
+        let old = self.old_range;
+        let new = self.new_range;
+        if pos == old.start && stick_right {
+            new.end
+        } else if pos <= old.start {
+            pos
+        } else if pos < old.end {
+            // Inside the replaced text, so snap to the end of the new text
+            new.end
+        } else if pos.y == old.end.y {
+            Position::new(new.end.x + pos.x - old.end.x, new.end.y)
+        } else {
+            Position::new(pos.x, pos.y + new.end.y - old.end.y)
+        }
+    }
+}
+
+pub type SubscriptionId = usize;
@@ -0,0 +1,197 @@
+use crate::completion;
+
+// Where a command runs. Editor commands work anywhere, the others need the active
+// pane to be of that type.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Scope {
+    Editor,
+    Buffer,
+    FileManager,
+}
+
+pub struct Command {
+    pub name: &'static str,
+    pub scope: Scope,
+    pub description: &'static str,
+}
+
+const fn command(name: &'static str, scope: Scope, description: &'static str) -> Command {
+    Command { name, scope, description }
+}
+
+// Every command that keys, the palette, modes and macros can run
+pub const COMMANDS: &[Command] = &[
+    command("pane.split", Scope::Editor, "Split the pane"),
+    command("pane.close", Scope::Editor, "Close the pane"),
+    command("pane.close_others", Scope::Editor, "Close all other panes"),
+    command("pane.next", Scope::Editor, "Go to the next pane"),
+    command("pane.prev", Scope::Editor, "Go to the previous pane"),
+    command("buffer.next", Scope::Editor, "Show the next buffer"),
+    command("buffer.prev", Scope::Editor, "Show the previous buffer"),
+    command("file.new", Scope::Editor, "Start a new, empty buffer"),
+    command("file.open", Scope::Editor, "Open a file"),
+    command("editor.quit", Scope::Editor, "Quit"),
+    command("completion.show", Scope::Editor, "Complete the word before the cursor"),
+    command("palette.show", Scope::Editor, "Show the command palette"),
+    command("mode.next", Scope::Editor, "Switch to the next input mode"),
+    command("mode.default", Scope::Editor, "Use the default bindings"),
+    command("mode.vim", Scope::Editor, "Use Vim bindings"),
+    command("mode.emacs", Scope::Editor, "Use Emacs bindings"),
+    command("theme.next", Scope::Editor, "Switch to the next theme"),
+    command("theme.prev", Scope::Editor, "Switch to the previous theme"),
+    command("theme.toggle_variant", Scope::Editor, "Switch between the light and dark theme"),
+    command("macro.record", Scope::Editor, "Start or stop recording a macro"),
+    command("macro.replay", Scope::Editor, "Replay the last macro"),
+    command("macro.replay_lines", Scope::Editor, "Replay the last macro on every selected line"),
+    command("macro.save", Scope::Editor, "Save the last macro under the next key"),
+    command("macro.play", Scope::Editor, "Play the macro saved under the next key"),
+    command("cursor.up", Scope::Buffer, "Move the cursor up"),
+    command("cursor.down", Scope::Buffer, "Move the cursor down"),
+    command("cursor.left", Scope::Buffer, "Move the cursor left"),
+    command("cursor.right", Scope::Buffer, "Move the cursor right"),
+    command("cursor.word_left", Scope::Buffer, "Move the cursor to the previous word"),
+    command("cursor.word_right", Scope::Buffer, "Move the cursor to the next word"),
+    command("select.up", Scope::Buffer, "Extend the selection up"),
+    command("select.down", Scope::Buffer, "Extend the selection down"),
+    command("select.left", Scope::Buffer, "Extend the selection left"),
+    command("select.right", Scope::Buffer, "Extend the selection right"),
+    command("select.word_left", Scope::Buffer, "Extend the selection to the previous word"),
+    command("select.word_right", Scope::Buffer, "Extend the selection to the next word"),
+    command("select.all", Scope::Buffer, "Select everything"),
+    command("view.page_up", Scope::Buffer, "Scroll up a page, taking the cursor along"),
+    command("view.page_down", Scope::Buffer, "Scroll down a page, taking the cursor along"),
+    command("view.center_on_cursor", Scope::Buffer, "Scroll the cursor's line to the middle of the view"),
+    command("view.cursor_to_top", Scope::Buffer, "Scroll the cursor's line to the top of the view"),
+    command("view.cursor_to_bottom", Scope::Buffer, "Scroll the cursor's line to the bottom of the view"),
+    command("view.line_numbers", Scope::Buffer, "Switch between absolute, relative, hybrid and no line numbers"),
+    command("bookmark.toggle", Scope::Buffer, "Bookmark the cursor's line, or remove its bookmark"),
+    command("bookmark.next", Scope::Buffer, "Go to the next bookmark"),
+    command("bookmark.prev", Scope::Buffer, "Go to the previous bookmark"),
+    command("edit.newline", Scope::Buffer, "Break the line, keeping the indentation"),
+    command("edit.newline_plain", Scope::Buffer, "Break the line"),
+    command("edit.backspace", Scope::Buffer, "Delete backwards"),
+    command("edit.delete_selection", Scope::Buffer, "Delete the selection or the character before the cursor"),
+    command("edit.delete_word_left", Scope::Buffer, "Delete the previous word"),
+    command("edit.tab", Scope::Buffer, "Insert a tab or indent the selection"),
+    command("edit.dedent", Scope::Buffer, "Dedent the selected lines"),
+    command("edit.undo", Scope::Buffer, "Undo"),
+    command("edit.redo", Scope::Buffer, "Redo"),
+    command("edit.fill_paragraph", Scope::Buffer, "Reflow the paragraph"),
+    command("clipboard.copy", Scope::Buffer, "Copy"),
+    command("clipboard.cut", Scope::Buffer, "Cut"),
+    command("clipboard.paste", Scope::Buffer, "Paste"),
+    command("lines.move_up", Scope::Buffer, "Move the selected lines up"),
+    command("lines.move_down", Scope::Buffer, "Move the selected lines down"),
+    command("lines.duplicate", Scope::Buffer, "Duplicate the selected lines"),
+    command("lines.join", Scope::Buffer, "Join the selected lines"),
+    command("lines.delete", Scope::Buffer, "Delete the selected lines"),
+    command("lines.sort", Scope::Buffer, "Sort the selected lines"),
+    command("lines.sort_reverse", Scope::Buffer, "Sort the selected lines in reverse"),
+    command("lines.sort_numeric", Scope::Buffer, "Sort the selected lines by number"),
+    command("lines.unique", Scope::Buffer, "Remove duplicate lines"),
+    command("case.upper", Scope::Buffer, "Convert to upper case"),
+    command("case.lower", Scope::Buffer, "Convert to lower case"),
+    command("case.title", Scope::Buffer, "Convert to title case"),
+    command("case.snake", Scope::Buffer, "Convert to snake_case"),
+    command("case.camel", Scope::Buffer, "Convert to camelCase"),
+    command("case.pascal", Scope::Buffer, "Convert to PascalCase"),
+    command("case.kebab", Scope::Buffer, "Convert to kebab-case"),
+    command("case.screaming", Scope::Buffer, "Convert to SCREAMING_CASE"),
+    command("case.cycle", Scope::Buffer, "Cycle the identifier case"),
+    command("language.next", Scope::Buffer, "Switch the buffer to the next language"),
+    command("language.prev", Scope::Buffer, "Switch the buffer to the previous language"),
+    command("language.detect", Scope::Buffer, "Detect the buffer's language again"),
+    command("buffer.save", Scope::Buffer, "Save the buffer"),
+    command("buffer.print", Scope::Buffer, "Print the buffer and quit"),
+    command("files.open", Scope::FileManager, "Open the selected entry"),
+    command("files.up", Scope::FileManager, "Select the previous entry"),
+    command("files.down", Scope::FileManager, "Select the next entry"),
+    command("files.search_backspace", Scope::FileManager, "Delete the last search character"),
+    command("files.clear_search", Scope::FileManager, "Clear the search"),
+];
+
+pub fn find(name: &str) -> Option<&'static Command> {
+    COMMANDS.iter().find(|c| c.name == name)
+}
+
+pub enum PaletteKey {
+    Handled,
+    Close,
+    Run(&'static str),
+}
+
+// The command palette: type to narrow the commands down, return runs the selected one
+pub struct Palette {
+    pub query: String,
+    pub items: Vec<&'static Command>,
+    pub selected: usize,
+    scopes: Vec<Scope>,
+}
+
+impl Palette {
+    // Only commands in `scopes` are offered
+    pub fn new(scopes: Vec<Scope>) -> Self {
+        let mut palette = Palette {
+            query: String::new(),
+            items: Vec::new(),
+            selected: 0,
+            scopes,
+        };
+        palette.update();
+        palette
+    }
+
+    fn update(&mut self) {
+        let mut scored = COMMANDS
+            .iter()
+            .filter(|c| self.scopes.contains(&c.scope))
+            .filter_map(|c| {
+                let score = if self.query.is_empty() {
+                    Some(0)
+                } else {
+                    let name = completion::fuzzy_score(&self.query, c.name);
+                    let description = completion::fuzzy_score(&self.query, c.description);
+                    name.max(description)
+                };
+                score.map(|s| (s, c))
+            })
+            .collect::<Vec<(i64, &'static Command)>>();
+        scored.sort_by(|a, b| b.0.cmp(&a.0).then(a.1.name.cmp(b.1.name)));
+        self.items = scored.into_iter().map(|(_, c)| c).collect();
+        self.selected = 0;
+    }
+
+    // The first item shown, so the selection stays in view
+    pub fn scroll(&self) -> usize {
+        (self.selected + 1).saturating_sub(completion::VISIBLE_ITEMS)
+    }
+
+    pub fn handle_key(&mut self, kstr: &str) -> PaletteKey {
+        match kstr {
+            "down" | "c-n" if !self.items.is_empty() => {
+                self.selected = (self.selected + 1) % self.items.len();
+            }
+            "up" | "c-p" if !self.items.is_empty() => {
+                self.selected = (self.selected + self.items.len() - 1) % self.items.len();
+            }
+            "return" => {
+                return match self.items.get(self.selected) {
+                    Some(command) => PaletteKey::Run(command.name),
+                    None => PaletteKey::Close,
+                }
+            }
+            "escape" | "c-g" => return PaletteKey::Close,
+            "backspace" => {
+                self.query.pop();
+                self.update();
+            }
+            _ => {}
+        }
+        PaletteKey::Handled
+    }
+
+    pub fn handle_text(&mut self, text: &str) {
+        self.query.push_str(text);
+        self.update();
+    }
+}
@@ -0,0 +1,184 @@
+use std::collections::HashMap;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+
+// How many characters of a word have to be typed before suggestions pop up by themselves
+pub const AUTO_TRIGGER_LENGTH: usize = 3;
+const MAX_ITEMS: usize = 50;
+pub const VISIBLE_ITEMS: usize = 10;
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// Score how well `pattern` fuzzy-matches `candidate`, or None if it doesn't match at all.
+// Every character of the pattern has to appear in order (ignoring case). Matches at the
+// start, at word boundaries and in runs score higher; unmatched characters cost a little.
+pub fn fuzzy_score(pattern: &str, candidate: &str) -> Option<i64> {
+    let candidate_chars = candidate.chars().collect::<Vec<char>>();
+    let mut score = 0;
+    let mut ci = 0;
+    let mut prev_match: Option<usize> = None;
+    for p in pattern.chars() {
+        let p_lower = p.to_lowercase().collect::<String>();
+        loop {
+            let c = *candidate_chars.get(ci)?;
+            if c.to_lowercase().collect::<String>() == p_lower {
+                break;
+            }
+            ci += 1;
+        }
+        let c = candidate_chars[ci];
+        score += 1;
+        if c == p {
+            score += 1;
+        }
+        if ci == 0 {
+            score += 8;
+        } else {
+            let before = candidate_chars[ci - 1];
+            let is_boundary = !is_word_char(before)
+                || before == '_'
+                || (before.is_lowercase() && c.is_uppercase());
+            if is_boundary {
+                score += 6;
+            }
+        }
+        if prev_match.is_some_and(|m| m + 1 == ci) {
+            score += 4;
+        }
+        prev_match = Some(ci);
+        ci += 1;
+    }
+    score -= (candidate_chars.len() - pattern.chars().count()) as i64 / 4;
+    Some(score)
+}
+
+// The identifier characters directly before (x, y), as (start x, text)
+pub fn prefix_at(buffer: &Buffer, x: usize, y: usize) -> (usize, String) {
+    let g = buffer.line_graphemes(y);
+    let mut start = x;
+    while start > 0 && g[start - 1].chars().all(is_word_char) {
+        start -= 1;
+    }
+    (start, g[start..x].concat())
+}
+
+fn add_words(words: &mut HashMap<String, usize>, line: &str, distance: usize) {
+    for word in line.split_word_bounds() {
+        if word.chars().count() < 2 || !word.chars().all(is_word_char) || word.chars().all(|c| c.is_numeric()) {
+            continue;
+        }
+        let d = words.entry(word.to_string()).or_insert(distance);
+        if distance < *d {
+            *d = distance;
+        }
+    }
+}
+
+pub struct Completion {
+    pub x: usize,
+    pub y: usize,
+    pub items: Vec<String>,
+    pub selected: usize,
+}
+
+pub enum CompletionKey {
+    // The popup used the key and stays open
+    Handled,
+    // The popup used the key and should close
+    Close,
+    // The popup should close and the key should be handled normally
+    PassThrough,
+}
+
+impl Completion {
+    // Suggest words for the prefix before the cursor in buffers[current], gathered from
+    // every open buffer. Words are ranked by how well they match and how close to the
+    // cursor they appear. Returns None if there's nothing worth showing.
+    pub fn new(buffers: &[Buffer], current: usize) -> Option<Self> {
+        let buffer = &buffers[current];
+        let (x, prefix) = prefix_at(buffer, buffer.cursor_x, buffer.cursor_y);
+        if prefix.is_empty() {
+            return None;
+        }
+
+        let mut words = HashMap::new();
+        for (y, line) in buffer.contents.iter().enumerate() {
+            let distance = y.abs_diff(buffer.cursor_y);
+            add_words(&mut words, line, distance);
+        }
+        // The word being typed isn't a suggestion, unless it also appears somewhere else
+        let line = &buffer.contents[buffer.cursor_y];
+        if line.split_word_bounds().filter(|w| *w == prefix).count() <= 1 {
+            words.remove(&prefix);
+        }
+        for (i, other) in buffers.iter().enumerate() {
+            if i != current {
+                for line in &other.contents {
+                    add_words(&mut words, line, usize::MAX);
+                }
+            }
+        }
+
+        let mut items = words
+            .into_iter()
+            .filter_map(|(word, distance)| {
+                let score = fuzzy_score(&prefix, &word)?;
+                // Worth up to a few matched characters, falling off quickly with distance
+                let proximity = match distance {
+                    usize::MAX => 0,
+                    d => 400 / (d as i64 + 1),
+                };
+                Some((score * 100 + proximity, word))
+            })
+            .collect::<Vec<(i64, String)>>();
+        if items.is_empty() {
+            return None;
+        }
+        items.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+        items.truncate(MAX_ITEMS);
+
+        Some(Completion {
+            x,
+            y: buffer.cursor_y,
+            items: items.into_iter().map(|(_, word)| word).collect(),
+            selected: 0,
+        })
+    }
+
+    // The first item shown, so the selected one is always visible
+    pub fn scroll(&self) -> usize {
+        (self.selected + 1).saturating_sub(VISIBLE_ITEMS)
+    }
+
+    pub fn accept(&self, buffer: &mut Buffer) {
+        let word = self.items[self.selected].clone();
+        let (x, y) = buffer.replace_text(self.x, self.y, buffer.cursor_x, buffer.cursor_y, word);
+        buffer.cursor_x = x;
+        buffer.cursor_y = y;
+        buffer.max_cursor_x = x;
+        buffer.set_selection(false);
+    }
+
+    pub fn handle_key(&mut self, buffer: &mut Buffer, kstr: &str) -> CompletionKey {
+        match kstr {
+            "down" | "c-n" => {
+                self.selected = (self.selected + 1) % self.items.len();
+                CompletionKey::Handled
+            }
+            "up" | "c-p" => {
+                self.selected = (self.selected + self.items.len() - 1) % self.items.len();
+                CompletionKey::Handled
+            }
+            "tab" | "return" => {
+                self.accept(buffer);
+                CompletionKey::Close
+            }
+            "escape" => CompletionKey::Close,
+            _ => CompletionKey::PassThrough,
+        }
+    }
+}
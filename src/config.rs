@@ -0,0 +1,67 @@
+use std::env;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime};
+
+// Where user files like saved macros live: $XDG_CONFIG_HOME/sdleditor, falling back
+// to ~/.config/sdleditor (or %APPDATA%\sdleditor on Windows)
+pub fn config_dir() -> Option<PathBuf> {
+    if let Some(dir) = env::var_os("XDG_CONFIG_HOME").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(dir).join("sdleditor"));
+    }
+    if let Some(home) = env::var_os("HOME").filter(|d| !d.is_empty()) {
+        return Some(PathBuf::from(home).join(".config").join("sdleditor"));
+    }
+    env::var_os("APPDATA").map(|dir| PathBuf::from(dir).join("sdleditor"))
+}
+
+// The closest directory above the current one that's the root of a repository, or
+// the current directory if there isn't one
+pub fn project_dir() -> PathBuf {
+    let current = env::current_dir().unwrap_or_default();
+    let mut dir = current.as_path();
+    loop {
+        if dir.join(".git").exists() {
+            return dir.to_path_buf();
+        }
+        match dir.parent() {
+            Some(parent) => dir = parent,
+            None => return current,
+        }
+    }
+}
+
+fn modified_time(path: &Path) -> Option<SystemTime> {
+    fs::metadata(path).and_then(|m| m.modified()).ok()
+}
+
+// Notices when any of a set of files is created, changed or deleted
+pub struct FileWatcher {
+    pub paths: Vec<PathBuf>,
+    modified: Vec<Option<SystemTime>>,
+    last_check: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(paths: Vec<PathBuf>) -> Self {
+        Self {
+            modified: paths.iter().map(|p| modified_time(p)).collect(),
+            paths,
+            last_check: Instant::now(),
+        }
+    }
+
+    // Checks at most once a second
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed() < Duration::from_secs(1) {
+            return false;
+        }
+        self.last_check = Instant::now();
+        let modified = self.paths.iter().map(|p| modified_time(p)).collect::<Vec<Option<SystemTime>>>();
+        if modified == self.modified {
+            return false;
+        }
+        self.modified = modified;
+        true
+    }
+}
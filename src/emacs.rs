@@ -0,0 +1,519 @@
+use std::cmp::min;
+
+use clipboard::{ClipboardContext, ClipboardProvider};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::application::{Mode, MouseEvent};
+use crate::buffer::Buffer;
+use crate::case::{self, Case};
+use crate::keymap::{KeyResult, Keymap};
+use crate::pane::Pane;
+
+const KILL_RING_SIZE: usize = 60;
+
+type EditFn = fn(&mut Emacs, &mut Pane, &mut Buffer);
+
+#[derive(Clone, Copy)]
+enum Binding {
+    Edit(EditFn),
+    // A command for the editor, since a mode only sees one pane
+    Editor(&'static str),
+}
+
+// What the last command was, for kills that append and m-y after a yank
+#[derive(Clone, Copy, PartialEq)]
+enum LastCommand {
+    Kill,
+    Yank,
+    Other,
+}
+
+pub struct Emacs {
+    keymap: Keymap<Binding>,
+    // The mark is kept in the buffer's selection anchor while it's active
+    mark: Option<(usize, usize)>,
+    mark_active: bool,
+    kill_ring: Vec<String>,
+    // Where the last yank went and which kill it was, so m-y can swap it out
+    yank: Option<(usize, usize, usize, usize, usize)>,
+    last_command: LastCommand,
+    this_command: LastCommand,
+    // Plain keys used in a chord, like the 2 in c-x 2, also come through as text
+    swallow_text: bool,
+    commands: Vec<&'static str>,
+}
+
+fn is_word_char(g: &str) -> bool {
+    g.chars().any(|c| c.is_alphanumeric() || c == '_')
+}
+
+// Where m-f ends up: the end of the next word
+fn forward_word(buffer: &Buffer, x: usize, y: usize) -> (usize, usize) {
+    let (mut x, mut y) = (x, y);
+    let mut line = buffer.line_graphemes(y);
+    let mut in_word = false;
+    loop {
+        if x < line.len() {
+            let is_word = is_word_char(line[x]);
+            if in_word && !is_word {
+                return (x, y);
+            }
+            in_word |= is_word;
+            x += 1;
+        } else if in_word || y + 1 >= buffer.len() {
+            return (x, y);
+        } else {
+            y += 1;
+            x = 0;
+            line = buffer.line_graphemes(y);
+        }
+    }
+}
+
+// Where m-b ends up: the start of the previous word
+fn backward_word(buffer: &Buffer, x: usize, y: usize) -> (usize, usize) {
+    let (mut x, mut y) = (x, y);
+    let mut line = buffer.line_graphemes(y);
+    let mut in_word = false;
+    loop {
+        if x > 0 {
+            let is_word = is_word_char(line[x - 1]);
+            if in_word && !is_word {
+                return (x, y);
+            }
+            in_word |= is_word;
+            x -= 1;
+        } else if in_word || y == 0 {
+            return (x, y);
+        } else {
+            y -= 1;
+            line = buffer.line_graphemes(y);
+            x = line.len();
+        }
+    }
+}
+
+fn ordered(a: (usize, usize), b: (usize, usize)) -> (usize, usize, usize, usize) {
+    if (a.1, a.0) <= (b.1, b.0) {
+        (a.0, a.1, b.0, b.1)
+    } else {
+        (b.0, b.1, a.0, a.1)
+    }
+}
+
+impl Emacs {
+    pub fn new() -> Self {
+        let mut keymap = Keymap::new();
+        let edits: Vec<(&str, EditFn)> = vec![
+            ("c-f", |e, _, b| e.motion(b, |b, extend| b.cursor_right(extend))),
+            ("c-b", |e, _, b| e.motion(b, |b, extend| b.cursor_left(extend))),
+            ("c-n", |e, p, b| e.motion(b, |b, extend| p.move_rows(b, 1, extend))),
+            ("c-p", |e, p, b| e.motion(b, |b, extend| p.move_rows(b, -1, extend))),
+            ("c-a", |e, _, b| e.motion(b, |b, _| {
+                b.cursor_x = 0;
+                b.max_cursor_x = 0;
+            })),
+            ("c-e", |e, _, b| e.motion(b, |b, _| {
+                b.cursor_x = b.line_len(b.cursor_y);
+                b.max_cursor_x = b.cursor_x;
+            })),
+            ("m-f", |e, _, b| e.motion(b, |b, _| {
+                let (x, y) = forward_word(b, b.cursor_x, b.cursor_y);
+                b.cursor_x = x;
+                b.cursor_y = y;
+                b.max_cursor_x = x;
+            })),
+            ("m-b", |e, _, b| e.motion(b, |b, _| {
+                let (x, y) = backward_word(b, b.cursor_x, b.cursor_y);
+                b.cursor_x = x;
+                b.cursor_y = y;
+                b.max_cursor_x = x;
+            })),
+            ("m-<", Emacs::beginning_of_buffer),
+            ("m-s-,", Emacs::beginning_of_buffer),
+            ("m->", Emacs::end_of_buffer),
+            ("m-s-.", Emacs::end_of_buffer),
+            ("c-v", |e, p, b| {
+                let lines = e.page_lines(p) as isize;
+                e.motion(b, |b, extend| p.move_rows(b, lines, extend));
+            }),
+            ("m-v", |e, p, b| {
+                let lines = e.page_lines(p) as isize;
+                e.motion(b, |b, extend| p.move_rows(b, -lines, extend));
+            }),
+            ("c-l", |_, p, b| {
+                p.run_command(b, "view.center_on_cursor");
+            }),
+            ("c-space", Emacs::set_mark),
+            ("c-s-2", Emacs::set_mark),
+            ("c-x c-x", Emacs::exchange_point_and_mark),
+            ("c-x h", |e, _, b| {
+                b.select_all();
+                e.mark = Some((0, 0));
+                e.mark_active = true;
+            }),
+            ("c-g", |e, _, b| e.deactivate_mark(b)),
+            ("c-w", |e, _, b| {
+                if let Some((x1, y1, x2, y2)) = e.region(b) {
+                    let text = b.text_range(x1, y1, x2, y2);
+                    b.delete_text(x1, y1, x2, y2);
+                    b.cursor_x = x1;
+                    b.cursor_y = y1;
+                    e.kill(text, false);
+                }
+                e.deactivate_mark(b);
+            }),
+            ("m-w", |e, _, b| {
+                if let Some((x1, y1, x2, y2)) = e.region(b) {
+                    let text = b.text_range(x1, y1, x2, y2);
+                    e.kill(text, false);
+                }
+                e.deactivate_mark(b);
+            }),
+            ("c-k", |e, _, b| {
+                let (x, y) = (b.cursor_x, b.cursor_y);
+                let rest = b.line_graphemes(y)[x..].concat();
+                // At the end of a line (or before trailing blanks) the line break goes
+                let (x2, y2) = if rest.trim().is_empty() && y + 1 < b.len() { (0, y + 1) } else { (b.line_len(y), y) };
+                if (x2, y2) != (x, y) {
+                    let text = b.text_range(x, y, x2, y2);
+                    b.delete_text(x, y, x2, y2);
+                    e.kill(text, false);
+                }
+                e.deactivate_mark(b);
+            }),
+            ("m-d", |e, _, b| {
+                let (x, y) = (b.cursor_x, b.cursor_y);
+                let (x2, y2) = forward_word(b, x, y);
+                if (x2, y2) != (x, y) {
+                    let text = b.text_range(x, y, x2, y2);
+                    b.delete_text(x, y, x2, y2);
+                    e.kill(text, false);
+                }
+                e.deactivate_mark(b);
+            }),
+            ("m-backspace", |e, _, b| {
+                let (x2, y2) = (b.cursor_x, b.cursor_y);
+                let (x, y) = backward_word(b, x2, y2);
+                if (x2, y2) != (x, y) {
+                    let text = b.text_range(x, y, x2, y2);
+                    b.delete_text(x, y, x2, y2);
+                    b.cursor_x = x;
+                    b.cursor_y = y;
+                    e.kill(text, true);
+                }
+                e.deactivate_mark(b);
+            }),
+            ("c-d", |e, _, b| {
+                let (x, y) = (b.cursor_x, b.cursor_y);
+                let (x2, y2) = b.next_char(x, y);
+                if (x2, y2) != (x, y) {
+                    b.delete_text(x, y, x2, y2);
+                }
+                e.deactivate_mark(b);
+            }),
+            ("c-y", Emacs::yank),
+            ("m-y", Emacs::yank_pop),
+            ("c-o", |e, _, b| {
+                let (x, y) = (b.cursor_x, b.cursor_y);
+                b.insert_text(x, y, "\n".to_string());
+                e.deactivate_mark(b);
+            }),
+            ("c-j", |e, _, b| {
+                e.deactivate_mark(b);
+                b.break_line_with_auto_indent();
+            }),
+            ("c-t", |e, _, b| {
+                // Swap the characters around the cursor, or the last two at the end of a line
+                let (x, y) = (b.cursor_x, b.cursor_y);
+                let len = b.line_len(y);
+                if len >= 2 && x > 0 {
+                    let x = min(x, len - 1);
+                    let line = b.line_graphemes(y);
+                    let swapped = format!("{}{}", line[x], line[x - 1]);
+                    b.replace_text(x - 1, y, x + 1, y, swapped);
+                    b.cursor_x = x + 1;
+                    b.max_cursor_x = b.cursor_x;
+                }
+                e.deactivate_mark(b);
+            }),
+            ("c-/", Emacs::undo),
+            ("c-_", Emacs::undo),
+            ("c-s--", Emacs::undo),
+            ("c-x u", Emacs::undo),
+            ("m-u", |e, _, b| e.case_word(b, Case::Upper)),
+            ("m-l", |e, _, b| e.case_word(b, Case::Lower)),
+            ("m-c", |e, _, b| e.case_word(b, Case::Title)),
+            ("m-q", |e, p, b| {
+                e.deactivate_mark(b);
+                b.fill_paragraph(p.fill_column);
+            }),
+            ("c-x c-s", |_, _, b| b.save()),
+            ("c-x s", |_, _, b| b.save()),
+        ];
+        for (chord, f) in edits {
+            keymap.bind(chord, Binding::Edit(f));
+        }
+        let editor_commands = [
+            ("c-x 2", "pane.split"),
+            ("c-x 3", "pane.split"),
+            ("c-x o", "pane.next"),
+            ("c-x 0", "pane.close"),
+            ("c-x 1", "pane.close_others"),
+            ("c-x b", "buffer.next"),
+            ("c-x c-f", "file.open"),
+            ("c-x c-c", "editor.quit"),
+        ];
+        for (chord, command) in editor_commands.iter() {
+            keymap.bind(chord, Binding::Editor(command));
+        }
+
+        Self {
+            keymap,
+            mark: None,
+            mark_active: false,
+            kill_ring: Vec::new(),
+            yank: None,
+            last_command: LastCommand::Other,
+            this_command: LastCommand::Other,
+            swallow_text: false,
+            commands: Vec::new(),
+        }
+    }
+
+    fn page_lines(&self, pane: &Pane) -> usize {
+        ((pane.rect.height / pane.line_height) as usize).saturating_sub(2).max(1)
+    }
+
+    // Move the cursor, extending the region if the mark is active
+    fn motion(&mut self, buffer: &mut Buffer, f: impl FnOnce(&mut Buffer, bool)) {
+        let extend = self.mark_active;
+        f(buffer, extend);
+        buffer.set_selection(extend);
+    }
+
+    fn beginning_of_buffer(&mut self, _pane: &mut Pane, buffer: &mut Buffer) {
+        self.push_mark(buffer);
+        self.motion(buffer, |b, _| {
+            b.cursor_x = 0;
+            b.cursor_y = 0;
+            b.max_cursor_x = 0;
+        });
+    }
+
+    fn end_of_buffer(&mut self, _pane: &mut Pane, buffer: &mut Buffer) {
+        self.push_mark(buffer);
+        self.motion(buffer, |b, _| {
+            b.cursor_y = b.len() - 1;
+            b.cursor_x = b.line_len(b.cursor_y);
+            b.max_cursor_x = b.cursor_x;
+        });
+    }
+
+    // Leave the mark behind without activating it, like big jumps do
+    fn push_mark(&mut self, buffer: &Buffer) {
+        if !self.mark_active {
+            self.mark = Some((buffer.cursor_x, buffer.cursor_y));
+        }
+    }
+
+    fn set_mark(&mut self, _pane: &mut Pane, buffer: &mut Buffer) {
+        self.mark = Some((buffer.cursor_x, buffer.cursor_y));
+        self.mark_active = true;
+        buffer.set_selection(false);
+    }
+
+    fn exchange_point_and_mark(&mut self, _pane: &mut Pane, buffer: &mut Buffer) {
+        if let Some((x, y)) = self.mark {
+            self.mark = Some((buffer.cursor_x, buffer.cursor_y));
+            self.mark_active = true;
+            buffer.sel_x = buffer.cursor_x;
+            buffer.sel_y = buffer.cursor_y;
+            buffer.cursor_x = x;
+            buffer.cursor_y = y;
+            buffer.max_cursor_x = x;
+        }
+    }
+
+    fn deactivate_mark(&mut self, buffer: &mut Buffer) {
+        if self.mark_active {
+            self.mark = Some((buffer.sel_x, buffer.sel_y));
+            self.mark_active = false;
+        }
+        buffer.set_selection(false);
+    }
+
+    // The region between point and mark, if there is one
+    fn region(&self, buffer: &Buffer) -> Option<(usize, usize, usize, usize)> {
+        let mark = if self.mark_active { (buffer.sel_x, buffer.sel_y) } else { self.mark? };
+        if mark.1 >= buffer.len() || mark.0 > buffer.line_len(mark.1) {
+            return None;
+        }
+        let region = ordered(mark, (buffer.cursor_x, buffer.cursor_y));
+        if (region.0, region.1) == (region.2, region.3) {
+            None
+        } else {
+            Some(region)
+        }
+    }
+
+    // Put text on the kill ring. Kills straight after each other add up to one entry,
+    // with backward kills going in front.
+    fn kill(&mut self, text: String, backward: bool) {
+        if self.last_command == LastCommand::Kill {
+            if let Some(last) = self.kill_ring.last_mut() {
+                if backward {
+                    last.insert_str(0, &text);
+                } else {
+                    last.push_str(&text);
+                }
+            }
+        } else {
+            self.kill_ring.push(text);
+            if self.kill_ring.len() > KILL_RING_SIZE {
+                self.kill_ring.remove(0);
+            }
+        }
+        self.this_command = LastCommand::Kill;
+        let ctx: Result<ClipboardContext, _> = ClipboardProvider::new();
+        if let (Ok(mut ctx), Some(last)) = (ctx, self.kill_ring.last()) {
+            let _ = ctx.set_contents(last.clone());
+        }
+    }
+
+    fn yank(&mut self, _pane: &mut Pane, buffer: &mut Buffer) {
+        // Text copied in other programs counts as the latest kill
+        let ctx: Result<ClipboardContext, _> = ClipboardProvider::new();
+        if let Some(text) = ctx.ok().and_then(|mut ctx| ctx.get_contents().ok()) {
+            if !text.is_empty() && self.kill_ring.last() != Some(&text) {
+                self.kill_ring.push(text);
+            }
+        }
+        self.deactivate_mark(buffer);
+        if let Some(text) = self.kill_ring.last().cloned() {
+            self.insert_yank(buffer, text, self.kill_ring.len() - 1);
+        }
+    }
+
+    fn yank_pop(&mut self, _pane: &mut Pane, buffer: &mut Buffer) {
+        if self.last_command != LastCommand::Yank {
+            return;
+        }
+        if let Some((x1, y1, x2, y2, index)) = self.yank {
+            let index = (index + self.kill_ring.len() - 1) % self.kill_ring.len();
+            buffer.delete_text(x1, y1, x2, y2);
+            buffer.cursor_x = x1;
+            buffer.cursor_y = y1;
+            let text = self.kill_ring[index].clone();
+            self.insert_yank(buffer, text, index);
+        }
+    }
+
+    fn insert_yank(&mut self, buffer: &mut Buffer, text: String, index: usize) {
+        let (x1, y1) = (buffer.cursor_x, buffer.cursor_y);
+        let (x2, y2) = buffer.insert_text(x1, y1, text);
+        buffer.cursor_x = x2;
+        buffer.cursor_y = y2;
+        buffer.max_cursor_x = x2;
+        buffer.set_selection(false);
+        self.mark = Some((x1, y1));
+        self.yank = Some((x1, y1, x2, y2, index));
+        self.this_command = LastCommand::Yank;
+    }
+
+    fn undo(&mut self, _pane: &mut Pane, buffer: &mut Buffer) {
+        self.deactivate_mark(buffer);
+        buffer.undo();
+    }
+
+    fn case_word(&mut self, buffer: &mut Buffer, case: Case) {
+        self.deactivate_mark(buffer);
+        let (x1, y1) = (buffer.cursor_x, buffer.cursor_y);
+        let (x2, y2) = forward_word(buffer, x1, y1);
+        let text = buffer.text_range(x1, y1, x2, y2);
+        let converted = case::convert(&text, case);
+        if converted != text {
+            buffer.replace_text(x1, y1, x2, y2, converted);
+        }
+        buffer.cursor_x = x2;
+        buffer.cursor_y = y2;
+        buffer.max_cursor_x = x2;
+        buffer.set_selection(false);
+    }
+}
+
+impl Mode for Emacs {
+    fn name(&self) -> &'static str {
+        "emacs"
+    }
+
+    fn status(&self) -> String {
+        if self.keymap.is_pending() {
+            self.keymap.pending_text()
+        } else if self.mark_active {
+            "Mark".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    fn handle_key(&mut self, pane: &mut Pane, buffer: &mut Buffer, kstr: &str) -> bool {
+        let is_plain = kstr.trim_start_matches("s-").graphemes(true).count() == 1;
+        let was_pending = self.keymap.is_pending();
+        match self.keymap.feed(kstr) {
+            KeyResult::Matched(binding) => {
+                self.swallow_text = is_plain;
+                self.this_command = LastCommand::Other;
+                match binding {
+                    Binding::Edit(f) => f(self, pane, buffer),
+                    Binding::Editor(command) => self.commands.push(command),
+                }
+                self.last_command = self.this_command;
+                true
+            }
+            KeyResult::Pending => {
+                self.swallow_text = is_plain;
+                true
+            }
+            // A prefix followed by something unbound is dropped, like Emacs does
+            KeyResult::Unbound(keys) if keys.len() > 1 || was_pending => {
+                self.swallow_text = is_plain;
+                true
+            }
+            KeyResult::Unbound(_) => {
+                if kstr == "c-g" || kstr == "escape" {
+                    self.deactivate_mark(buffer);
+                }
+                self.last_command = LastCommand::Other;
+                false
+            }
+        }
+    }
+
+    fn handle_text(&mut self, _pane: &mut Pane, buffer: &mut Buffer, _text: &str) -> bool {
+        if self.swallow_text {
+            self.swallow_text = false;
+            return true;
+        }
+        // Typing doesn't replace the region, it just ends it
+        self.deactivate_mark(buffer);
+        self.last_command = LastCommand::Other;
+        false
+    }
+
+    fn handle_mouse(&mut self, _pane: &mut Pane, _buffer: &mut Buffer, event: &MouseEvent) -> bool {
+        if let MouseEvent::Down = event {
+            self.mark_active = false;
+            self.keymap.cancel();
+        }
+        false
+    }
+
+    fn take_commands(&mut self) -> Vec<&'static str> {
+        self.commands.drain(..).collect()
+    }
+
+    fn tick(&mut self) {
+        self.keymap.expire();
+    }
+}
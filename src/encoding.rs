@@ -0,0 +1,65 @@
+// How a file's text is stored, so that saving writes it back the same way
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Encoding {
+    Utf8,
+    // UTF-8 starting with a byte order mark, which Windows programs like to write
+    Utf8Bom,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineEnding {
+    Lf,
+    CrLf,
+}
+
+const BOM: &str = "\u{feff}";
+
+impl Encoding {
+    pub fn name(self) -> &'static str {
+        match self {
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf8Bom => "UTF-8 BOM",
+        }
+    }
+}
+
+impl LineEnding {
+    // Whichever ending the first line has
+    pub fn detect(text: &str) -> LineEnding {
+        match text.find('\n') {
+            Some(i) if text[..i].ends_with('\r') => LineEnding::CrLf,
+            _ => LineEnding::Lf,
+        }
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "LF",
+            LineEnding::CrLf => "CRLF",
+        }
+    }
+
+    pub fn text(self) -> &'static str {
+        match self {
+            LineEnding::Lf => "\n",
+            LineEnding::CrLf => "\r\n",
+        }
+    }
+}
+
+// A file's text and how it was stored, or None if it isn't UTF-8
+pub fn decode(bytes: &[u8]) -> Option<(String, Encoding)> {
+    let text = std::str::from_utf8(bytes).ok()?;
+    Some(match text.strip_prefix(BOM) {
+        Some(text) => (text.to_string(), Encoding::Utf8Bom),
+        None => (text.to_string(), Encoding::Utf8),
+    })
+}
+
+// The whole text of a file the way `encoding` stores it
+pub fn encode(text: &str, encoding: Encoding) -> Vec<u8> {
+    match encoding {
+        Encoding::Utf8 => text.as_bytes().to_vec(),
+        Encoding::Utf8Bom => format!("{}{}", BOM, text).into_bytes(),
+    }
+}
@@ -3,6 +3,7 @@ use std::fs;
 use std::path::{Path, PathBuf};
 
 use crate::buffer::Buffer;
+use crate::keymap::{KeyResult, Keymap};
 use crate::pane::{Pane, PaneType};
 
 pub struct FileManagerEntry {
@@ -14,6 +15,7 @@ pub struct FileManager {
     pub current_search: String,
     pub entries: Vec<FileManagerEntry>,
     pub current_dir: PathBuf,
+    pub keys: Keymap<&'static str>,
 }
 
 impl FileManager {
@@ -22,6 +24,7 @@ impl FileManager {
             current_dir: env::current_dir().unwrap(),
             current_search: String::new(),
             entries: Vec::new(),
+            keys: Keymap::new(),
         }
     }
 
@@ -51,15 +54,35 @@ impl FileManager {
         buffer.select_line(0);
     }
 
-    pub fn handle_key(&mut self, mut pane: &mut Pane, mut buffer: &mut Buffer, kstr: &str) {
-        match kstr {
-            "backspace" => {
-                if !self.current_search.is_empty() {
-                    self.current_search.remove(self.current_search.len() - 1);
-                    buffer.name = self.current_search.clone();
-                }
+    // Extend the type-to-search string and select the next entry starting with it.
+    // Returns false if nothing matches.
+    pub fn search(&mut self, buffer: &mut Buffer, text: &str) -> bool {
+        self.current_search.push_str(text);
+        buffer.name = self.current_search.clone();
+        let mut selection = None;
+        'searchloop: for (i, line) in buffer.contents[buffer.cursor_y..].iter().enumerate() {
+            if line.starts_with(&self.current_search) {
+                selection = Some(i + buffer.cursor_y);
+                break 'searchloop;
+            }
+        }
+        buffer.select_line(selection.unwrap_or(buffer.cursor_y));
+        selection.is_some()
+    }
+
+    pub fn handle_key(&mut self, pane: &mut Pane, buffer: &mut Buffer, kstr: &str) {
+        if let KeyResult::Matched(command) = self.keys.feed(kstr) {
+            self.run_command(pane, buffer, command);
+        }
+    }
+
+    pub fn run_command(&mut self, pane: &mut Pane, buffer: &mut Buffer, name: &str) {
+        match name {
+            "files.search_backspace" if !self.current_search.is_empty() => {
+                self.current_search.remove(self.current_search.len() - 1);
+                buffer.name = self.current_search.clone();
             }
-            "return" => {
+            "files.open" => {
                 if self.entries[buffer.sel_y].is_dir {
                     let entry = &self.entries[buffer.sel_y].name;
                     if entry == ".." {
@@ -74,22 +97,25 @@ impl FileManager {
                 } else {
                     let path = Path::join(&env::current_dir().unwrap(), &self.current_dir);
                     let f = Path::join(&path, self.entries[buffer.sel_y].name.clone());
+                    let tab_width = buffer.tab_width;
                     *buffer = Buffer::from_path(f.display().to_string());
+                    buffer.tab_width = tab_width;
+                    pane.detect_language(buffer);
                     pane.pane_type = PaneType::Buffer;
                     buffer.cursor_x = 0;
                     buffer.cursor_y = 0;
                     buffer.set_selection(false);
                 }
             }
-            "down" => {
+            "files.down" => {
                 buffer.cursor_down(1, false);
                 buffer.select_line(buffer.cursor_y);
             }
-            "up" => {
+            "files.up" => {
                 buffer.cursor_up(1, false);
                 buffer.select_line(buffer.cursor_y);
             }
-            "escape" => {
+            "files.clear_search" => {
                 self.current_search.clear();
                 buffer.name = self.current_search.clone();
             }
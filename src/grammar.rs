@@ -0,0 +1,884 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use regex::Regex;
+
+use crate::config;
+use crate::json::{self, Value};
+use crate::language::{self, Language};
+use crate::plist;
+use crate::yaml;
+
+// Where an include points. Includes of other grammars are only looked up once every
+// grammar is loaded.
+#[derive(Clone)]
+enum Target {
+    Context(usize),
+    // A grammar's scope name, and optionally one of its repository entries
+    Grammar(String, Option<String>),
+}
+
+#[derive(Clone)]
+enum Pattern {
+    Rule(usize),
+    Include(Target),
+}
+
+// A list of rules to try, like a TextMate repository entry or a sublime-syntax context
+#[derive(Default)]
+pub struct Context {
+    patterns: Vec<Pattern>,
+    // Every rule to try with includes expanded, filled in by link()
+    pub rules: Vec<usize>,
+    pub meta_scope: Vec<String>,
+    pub meta_content_scope: Vec<String>,
+}
+
+// What a match does to the stack of contexts, for sublime-syntax rules
+pub enum Action {
+    None,
+    Push(Vec<usize>),
+    Set(Vec<usize>),
+    Pop(usize),
+}
+
+// Scopes for capture groups, by group number
+pub type Captures = Vec<(usize, Vec<String>)>;
+
+pub enum Rule {
+    Match {
+        regex: Regex,
+        scope: Vec<String>,
+        captures: Captures,
+        action: Action,
+    },
+    // A region from a begin match to an end match, or for as long as following lines
+    // start with a while match
+    Begin {
+        regex: Regex,
+        scope: Vec<String>,
+        content_scope: Vec<String>,
+        captures: Captures,
+        // The end pattern is compiled when the region starts if it refers back to the
+        // begin match with \1 and so on
+        end: String,
+        end_regex: Option<Regex>,
+        end_captures: Captures,
+        is_while: bool,
+        // Whether the region's own rules get the first chance to match
+        end_last: bool,
+        context: usize,
+    },
+}
+
+pub struct Grammar {
+    pub scope_name: String,
+    // Extensions, or whole file names like "Makefile"
+    pub file_types: Vec<String>,
+    first_line: Option<Regex>,
+    pub root: usize,
+    repository: HashMap<String, usize>,
+}
+
+#[derive(Default)]
+pub struct GrammarLibrary {
+    pub grammars: Vec<Grammar>,
+    pub contexts: Vec<Context>,
+    pub rules: Vec<Rule>,
+}
+
+// Translate an Oniguruma pattern, as TextMate grammars use, into what the regex crate
+// understands. ^ and $ match at line ends there, so multi-line mode is turned on.
+// Lookarounds and backreferences have no equivalent, so those patterns fail to compile.
+fn translate(pattern: &str) -> String {
+    let chars = pattern.chars().collect::<Vec<char>>();
+    let mut out = String::from("(?m)");
+    let mut class_depth = 0;
+    let mut after_quantifier = false;
+    let mut i = 0;
+    while i < chars.len() {
+        let c = chars[i];
+        let next = chars.get(i + 1).copied();
+        let was_quantifier = after_quantifier;
+        after_quantifier = false;
+        if c == '\\' {
+            match next {
+                Some('h') if class_depth > 0 => out.push_str("0-9a-fA-F"),
+                Some('h') => out.push_str("[0-9a-fA-F]"),
+                Some('H') if class_depth == 0 => out.push_str("[^0-9a-fA-F]"),
+                Some('Z') => out.push('$'),
+                Some('G') => {}
+                Some('e') => out.push_str("\\x1B"),
+                Some(n) => {
+                    out.push('\\');
+                    out.push(n);
+                }
+                None => out.push_str("\\\\"),
+            }
+            i += 2;
+            continue;
+        }
+        if class_depth > 0 {
+            match c {
+                '[' if next == Some(':') => {
+                    let end = (i + 2..chars.len()).find(|&j| chars[j] == ']').unwrap_or(chars.len() - 1);
+                    out.extend(&chars[i..=end]);
+                    i = end + 1;
+                    continue;
+                }
+                '[' => {
+                    class_depth += 1;
+                    out.push('[');
+                    if next == Some('^') {
+                        out.push('^');
+                        i += 1;
+                    }
+                }
+                ']' => {
+                    class_depth -= 1;
+                    out.push(']');
+                }
+                _ => out.push(c),
+            }
+            i += 1;
+            continue;
+        }
+        match c {
+            '[' => {
+                class_depth = 1;
+                out.push('[');
+                if next == Some('^') {
+                    out.push('^');
+                    i += 1;
+                }
+                // A ] straight after the opening bracket is literal
+                if chars.get(i + 1) == Some(&']') {
+                    out.push_str("\\]");
+                    i += 1;
+                }
+            }
+            '(' if next == Some('?') => {
+                let rest = chars[i + 2..].iter().take(2).collect::<String>();
+                if rest.starts_with('>') {
+                    out.push_str("(?:");
+                    i += 2;
+                } else if rest.starts_with('<') && rest != "<=" && rest != "<!" {
+                    out.push_str("(?P<");
+                    i += 2;
+                } else {
+                    out.push('(');
+                }
+            }
+            // Possessive quantifiers become greedy ones
+            '+' if was_quantifier => {}
+            '*' | '+' | '?' => {
+                out.push(c);
+                after_quantifier = true;
+            }
+            '{' => {
+                let end = (i + 1..chars.len()).find(|&j| chars[j] == '}');
+                let is_quantifier = end.is_some_and(|end| {
+                    let inside = chars[i + 1..end].iter().collect::<String>();
+                    !inside.is_empty() && inside.chars().all(|c| c.is_ascii_digit() || c == ',') && !inside.starts_with(',')
+                });
+                match end {
+                    Some(end) if is_quantifier => {
+                        out.extend(&chars[i..=end]);
+                        i = end;
+                        after_quantifier = true;
+                    }
+                    _ => out.push_str("\\{"),
+                }
+            }
+            '}' => out.push_str("\\}"),
+            _ => out.push(c),
+        }
+        i += 1;
+    }
+    out
+}
+
+pub fn compile(pattern: &str) -> Result<Regex, String> {
+    Regex::new(&translate(pattern)).map_err(|e| e.to_string())
+}
+
+// Whether an end pattern refers to the begin match's groups
+fn has_backreferences(pattern: &str) -> bool {
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' && chars.next().is_some_and(|n| ('1'..='9').contains(&n)) {
+            return true;
+        }
+    }
+    false
+}
+
+// Fill in \1 to \9 in an end pattern with the text the begin pattern's groups matched
+pub fn fill_backreferences(pattern: &str, groups: &[Option<&str>]) -> String {
+    let mut out = String::new();
+    let mut chars = pattern.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            out.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some(n @ '1'..='9') => {
+                let group = n.to_digit(10).unwrap() as usize;
+                out.push_str(&regex::escape(groups.get(group).copied().flatten().unwrap_or("")));
+            }
+            Some(n) => {
+                out.push('\\');
+                out.push(n);
+            }
+            None => out.push('\\'),
+        }
+    }
+    out
+}
+
+fn scopes(value: Option<&Value>) -> Vec<String> {
+    value.and_then(|v| v.as_str()).map_or(Vec::new(), |s| s.split_whitespace().map(|s| s.to_string()).collect())
+}
+
+fn captures(value: Option<&Value>) -> Captures {
+    let entries = match value {
+        Some(Value::Object(entries)) => entries,
+        _ => return Vec::new(),
+    };
+    let mut captures = entries
+        .iter()
+        .filter_map(|(group, v)| {
+            let group = group.parse::<usize>().ok()?;
+            // TextMate captures are {"name": ...} and sublime-syntax ones are just the scope
+            let scope = match v {
+                Value::Str(_) => scopes(Some(v)),
+                _ => scopes(v.get("name")),
+            };
+            Some((group, scope))
+        })
+        .filter(|(_, scope)| !scope.is_empty())
+        .collect::<Captures>();
+    captures.sort_by_key(|(group, _)| *group);
+    captures
+}
+
+// Compiles one grammar file's rules into the library
+struct Loader<'a> {
+    library: &'a mut GrammarLibrary,
+    repository: HashMap<String, usize>,
+    root: usize,
+    // sublime-syntax {{variables}}
+    variables: Vec<(String, String)>,
+    prototype: Option<usize>,
+    // Patterns that didn't compile, which are left out
+    skipped: Vec<String>,
+}
+
+impl<'a> Loader<'a> {
+    fn new(library: &'a mut GrammarLibrary) -> Self {
+        let root = library.new_context();
+        Loader {
+            library,
+            repository: HashMap::new(),
+            root,
+            variables: Vec::new(),
+            prototype: None,
+            skipped: Vec::new(),
+        }
+    }
+
+    fn regex(&mut self, pattern: &str) -> Option<Regex> {
+        let pattern = self.expand_variables(pattern);
+        match compile(&pattern) {
+            Ok(regex) => Some(regex),
+            Err(_) => {
+                self.skipped.push(pattern);
+                None
+            }
+        }
+    }
+
+    fn expand_variables(&self, pattern: &str) -> String {
+        let mut pattern = pattern.to_string();
+        // Variables can use other variables
+        for _ in 0..10 {
+            if !pattern.contains("{{") {
+                break;
+            }
+            for (name, value) in &self.variables {
+                pattern = pattern.replace(&format!("{{{{{}}}}}", name), value);
+            }
+        }
+        pattern
+    }
+
+    fn add_rule(&mut self, rule: Rule) -> Pattern {
+        self.library.rules.push(rule);
+        Pattern::Rule(self.library.rules.len() - 1)
+    }
+
+    fn include(&mut self, name: &str) -> Option<Pattern> {
+        let target = match name {
+            "$self" | "$base" => Target::Context(self.root),
+            _ if name.starts_with('#') => Target::Context(*self.repository.get(&name[1..])?),
+            _ if name.starts_with("scope:") => Target::Grammar(name[6..].to_string(), None),
+            _ if name.starts_with("source.") || name.starts_with("text.") => match name.find('#') {
+                Some(hash) => Target::Grammar(name[..hash].to_string(), Some(name[hash + 1..].to_string())),
+                None => Target::Grammar(name.to_string(), None),
+            },
+            _ => Target::Context(*self.repository.get(name)?),
+        };
+        Some(Pattern::Include(target))
+    }
+
+    fn tm_patterns(&mut self, rules: &[Value]) -> Vec<Pattern> {
+        rules.iter().flat_map(|rule| self.tm_rule(rule)).collect()
+    }
+
+    fn tm_rule(&mut self, rule: &Value) -> Vec<Pattern> {
+        let string = |key: &str| rule.get(key).and_then(|v| v.as_str());
+        if let Some(name) = string("include") {
+            return self.include(name).into_iter().collect();
+        }
+        if let Some(pattern) = string("match") {
+            return match self.regex(pattern) {
+                Some(regex) => vec![self.add_rule(Rule::Match {
+                    regex,
+                    scope: scopes(rule.get("name")),
+                    captures: captures(rule.get("captures")),
+                    action: Action::None,
+                })],
+                None => Vec::new(),
+            };
+        }
+        if let Some(begin) = string("begin") {
+            let is_while = rule.get("while").is_some();
+            // A region with no end lasts for the rest of the file
+            let end = string(if is_while { "while" } else { "end" }).unwrap_or(r"\b\B").to_string();
+            let end_regex = if has_backreferences(&end) {
+                // Check it'll compile once the backreferences are filled in
+                if compile(&fill_backreferences(&end, &[])).is_err() {
+                    self.skipped.push(end);
+                    return Vec::new();
+                }
+                None
+            } else {
+                match self.regex(&end) {
+                    Some(regex) => Some(regex),
+                    None => return Vec::new(),
+                }
+            };
+            let regex = match self.regex(begin) {
+                Some(regex) => regex,
+                None => return Vec::new(),
+            };
+            let context = self.library.new_context();
+            let patterns = self.tm_patterns(rule.get("patterns").map_or(&[], |p| p.as_array()));
+            self.library.contexts[context].patterns = patterns;
+            let both = rule.get("captures");
+            return vec![self.add_rule(Rule::Begin {
+                regex,
+                scope: scopes(rule.get("name")),
+                content_scope: scopes(rule.get("contentName")),
+                captures: captures(rule.get("beginCaptures").or(both)),
+                end,
+                end_regex,
+                end_captures: captures(rule.get(if is_while { "whileCaptures" } else { "endCaptures" }).or(both)),
+                is_while,
+                end_last: matches!(rule.get("applyEndPatternLast"), Some(Value::Number(n)) if *n != 0.0)
+                    || rule.get("applyEndPatternLast") == Some(&Value::Bool(true)),
+                context,
+            })];
+        }
+        // A rule that only groups other rules
+        self.tm_patterns(rule.get("patterns").map_or(&[], |p| p.as_array()))
+    }
+
+    fn tm_grammar(mut self, value: &Value) -> Result<(Grammar, Vec<String>), String> {
+        let scope_name = value.get("scopeName").and_then(|v| v.as_str()).ok_or("the grammar has no scopeName")?.to_string();
+        // Entries can include each other in any order, so they all get a context first
+        let repository = match value.get("repository") {
+            Some(Value::Object(entries)) => entries.as_slice(),
+            _ => &[],
+        };
+        for (name, _) in repository {
+            let context = self.library.new_context();
+            self.repository.insert(name.clone(), context);
+        }
+        for (name, rule) in repository {
+            let patterns = self.tm_rule(rule);
+            let context = self.repository[name];
+            self.library.contexts[context].patterns = patterns;
+        }
+        let patterns = self.tm_patterns(value.get("patterns").map_or(&[], |p| p.as_array()));
+        self.library.contexts[self.root].patterns = patterns;
+        let first_line = value.get("firstLineMatch").and_then(|v| v.as_str()).and_then(|p| compile(p).ok());
+        let grammar = Grammar {
+            file_types: value.get("fileTypes").map_or(&[][..], |v| v.as_array()).iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect(),
+            scope_name,
+            first_line,
+            root: self.root,
+            repository: self.repository,
+        };
+        Ok((grammar, self.skipped))
+    }
+
+    // The contexts a sublime-syntax push or set names: one name, a list of names, or an
+    // anonymous context written in place
+    fn sublime_targets(&mut self, value: &Value) -> Vec<usize> {
+        match value {
+            Value::Str(name) => self.sublime_target(name).into_iter().collect(),
+            Value::Array(items) if items.iter().all(|i| matches!(i, Value::Str(_))) => {
+                items.iter().filter_map(|i| i.as_str()).filter_map(|name| self.sublime_target(name)).collect()
+            }
+            Value::Array(items) if items.iter().all(|i| matches!(i, Value::Array(_))) => items.iter().flat_map(|i| self.sublime_targets(i)).collect(),
+            Value::Array(items) => {
+                let context = self.library.new_context();
+                self.sublime_context(context, items, false);
+                vec![context]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn sublime_target(&mut self, name: &str) -> Option<usize> {
+        match self.repository.get(name) {
+            Some(context) => Some(*context),
+            None => {
+                let pattern = self.include(name)?;
+                let context = self.library.new_context();
+                self.library.contexts[context].patterns.push(pattern);
+                Some(context)
+            }
+        }
+    }
+
+    fn sublime_context(&mut self, context: usize, items: &[Value], is_prototype: bool) {
+        let mut patterns = Vec::new();
+        let mut include_prototype = !is_prototype;
+        for item in items {
+            let string = |key: &str| item.get(key).and_then(|v| v.as_str());
+            if let Some(scope) = item.get("meta_scope") {
+                self.library.contexts[context].meta_scope = scopes(Some(scope));
+            } else if let Some(scope) = item.get("meta_content_scope") {
+                self.library.contexts[context].meta_content_scope = scopes(Some(scope));
+            } else if item.get("meta_include_prototype") == Some(&Value::Bool(false)) {
+                include_prototype = false;
+            } else if let Some(name) = string("include") {
+                patterns.extend(self.include(name));
+            } else if let Some(pattern) = string("match") {
+                let regex = match self.regex(pattern) {
+                    Some(regex) => regex,
+                    None => continue,
+                };
+                // An embedded grammar lasts until the escape pattern, like a TextMate region
+                if let (Some(embed), Some(escape)) = (string("embed"), string("escape")) {
+                    let escape = self.expand_variables(escape);
+                    let end_regex = match compile(&escape) {
+                        Ok(regex) => regex,
+                        Err(_) => {
+                            self.skipped.push(escape);
+                            continue;
+                        }
+                    };
+                    let inner = self.library.new_context();
+                    let embedded = self.sublime_target(embed);
+                    self.library.contexts[inner].patterns = embedded.map(|c| Pattern::Include(Target::Context(c))).into_iter().collect();
+                    patterns.push(self.add_rule(Rule::Begin {
+                        regex,
+                        scope: scopes(item.get("scope")),
+                        content_scope: scopes(item.get("embed_scope")),
+                        captures: captures(item.get("captures")),
+                        end: escape,
+                        end_regex: Some(end_regex),
+                        end_captures: captures(item.get("escape_captures")),
+                        is_while: false,
+                        end_last: false,
+                        context: inner,
+                    }));
+                    continue;
+                }
+                let action = if let Some(targets) = item.get("push") {
+                    Action::Push(self.sublime_targets(targets))
+                } else if let Some(targets) = item.get("set") {
+                    Action::Set(self.sublime_targets(targets))
+                } else {
+                    match item.get("pop") {
+                        Some(Value::Bool(true)) => Action::Pop(1),
+                        Some(Value::Number(n)) if *n >= 1.0 => Action::Pop(*n as usize),
+                        _ => Action::None,
+                    }
+                };
+                patterns.push(self.add_rule(Rule::Match {
+                    regex,
+                    scope: scopes(item.get("scope")),
+                    captures: captures(item.get("captures")),
+                    action,
+                }));
+            }
+        }
+        if let (true, Some(prototype)) = (include_prototype, self.prototype) {
+            patterns.insert(0, Pattern::Include(Target::Context(prototype)));
+        }
+        self.library.contexts[context].patterns = patterns;
+    }
+
+    fn sublime_grammar(mut self, value: &Value) -> Result<(Grammar, Vec<String>), String> {
+        let scope_name = value.get("scope").and_then(|v| v.as_str()).ok_or("the grammar has no scope")?.to_string();
+        if let Some(Value::Object(variables)) = value.get("variables") {
+            self.variables = variables.iter().filter_map(|(k, v)| v.as_str().map(|v| (k.clone(), v.to_string()))).collect();
+        }
+        let contexts = match value.get("contexts") {
+            Some(Value::Object(contexts)) => contexts.as_slice(),
+            _ => return Err("the grammar has no contexts".to_string()),
+        };
+        for (name, _) in contexts {
+            let context = if name == "main" { self.root } else { self.library.new_context() };
+            self.repository.insert(name.clone(), context);
+        }
+        if !self.repository.contains_key("main") {
+            return Err("the grammar has no main context".to_string());
+        }
+        self.prototype = self.repository.get("prototype").copied();
+        for (name, items) in contexts {
+            let context = self.repository[name];
+            self.sublime_context(context, items.as_array(), name == "prototype");
+        }
+        let first_line = value.get("first_line_match").and_then(|v| v.as_str()).and_then(|p| compile(p).ok());
+        let grammar = Grammar {
+            file_types: value.get("file_extensions").map_or(&[][..], |v| v.as_array()).iter().filter_map(|v| v.as_str().map(|s| s.to_string())).collect(),
+            scope_name,
+            first_line,
+            root: self.root,
+            repository: self.repository,
+        };
+        Ok((grammar, self.skipped))
+    }
+}
+
+impl GrammarLibrary {
+    fn new_context(&mut self) -> usize {
+        self.contexts.push(Context::default());
+        self.contexts.len() - 1
+    }
+
+    // Bundled grammars and the user's, reporting any that don't load
+    pub fn load() -> Self {
+        let mut library = GrammarLibrary::default();
+        for path in grammar_files() {
+            match library.add_file(&path) {
+                Ok(skipped) if !skipped.is_empty() => eprintln!(
+                    "Grammar: {}: left out {} patterns the regex crate can't handle, like {}",
+                    path.display(),
+                    skipped.len(),
+                    skipped[0]
+                ),
+                Ok(_) => {}
+                Err(e) => eprintln!("Grammar: {}: {}", path.display(), e),
+            }
+        }
+        // Languages without a grammar file get a simple one from their definition
+        for language in language::languages() {
+            if language.highlight && !library.grammars.iter().any(|g| g.scope_name == language.scope) {
+                library.add_language(language);
+            }
+        }
+        library.link();
+        library
+    }
+
+    fn push_rule(&mut self, rule: Rule) -> Pattern {
+        self.rules.push(rule);
+        Pattern::Rule(self.rules.len() - 1)
+    }
+
+    // A region like a string or block comment, with `patterns` inside it
+    fn push_region(&mut self, open: &str, close: &str, scope: &str, patterns: Vec<Pattern>) -> usize {
+        let context = self.new_context();
+        self.contexts[context].patterns = patterns;
+        let end = regex::escape(close);
+        self.push_rule(Rule::Begin {
+            regex: Regex::new(&regex::escape(open)).unwrap(),
+            scope: vec![scope.to_string()],
+            content_scope: Vec::new(),
+            captures: Vec::new(),
+            end_regex: Some(Regex::new(&end).unwrap()),
+            end,
+            end_captures: Vec::new(),
+            is_while: false,
+            end_last: false,
+            context,
+        });
+        self.rules.len() - 1
+    }
+
+    // A grammar for the comments, strings, keywords and numbers of a language definition
+    fn add_language(&mut self, language: &Language) {
+        let mut patterns = Vec::new();
+        if !language.line_comments.is_empty() {
+            let markers = language.line_comments.iter().map(|m| regex::escape(m)).collect::<Vec<String>>().join("|");
+            patterns.push(self.push_rule(Rule::Match {
+                regex: Regex::new(&format!("(?:{}).*", markers)).unwrap(),
+                scope: vec!["comment.line".to_string()],
+                captures: Vec::new(),
+                action: Action::None,
+            }));
+        }
+        if let Some((open, close)) = &language.block_comment {
+            let rule = self.push_region(open, close, "comment.block", Vec::new());
+            // A nested comment is the same rule again inside the comment
+            if language.nested_comments {
+                let context = match &self.rules[rule] {
+                    Rule::Begin { context, .. } => *context,
+                    Rule::Match { .. } => unreachable!(),
+                };
+                self.contexts[context].patterns.push(Pattern::Rule(rule));
+            }
+            patterns.push(Pattern::Rule(rule));
+        }
+        for string in &language.strings {
+            let mut inside = Vec::new();
+            if let Some(escape) = &string.escape {
+                inside.push(self.push_rule(Rule::Match {
+                    regex: Regex::new(&format!("(?s){}.", regex::escape(escape))).unwrap(),
+                    scope: vec!["constant.character.escape".to_string()],
+                    captures: Vec::new(),
+                    action: Action::None,
+                }));
+            }
+            let rule = self.push_region(&string.open, &string.close, "string.quoted", inside);
+            patterns.push(Pattern::Rule(rule));
+        }
+        let mut words = vec![
+            (&language.keywords, "keyword.control"),
+            (&language.types, "storage.type"),
+            (&language.constants, "constant.language"),
+        ];
+        words.retain(|(list, _)| !list.is_empty());
+        for (list, scope) in words {
+            let alternatives = list.iter().map(|w| regex::escape(w)).collect::<Vec<String>>().join("|");
+            patterns.push(self.push_rule(Rule::Match {
+                regex: Regex::new(&format!(r"\b(?:{})\b", alternatives)).unwrap(),
+                scope: vec![scope.to_string()],
+                captures: Vec::new(),
+                action: Action::None,
+            }));
+        }
+        if let Some(number) = &language.number {
+            match Regex::new(number) {
+                Ok(regex) => patterns.push(self.push_rule(Rule::Match {
+                    regex,
+                    scope: vec!["constant.numeric".to_string()],
+                    captures: Vec::new(),
+                    action: Action::None,
+                })),
+                Err(e) => eprintln!("Language: {}: bad number pattern: {}", language.id, e),
+            }
+        }
+        if patterns.is_empty() {
+            return;
+        }
+        let root = self.new_context();
+        self.contexts[root].patterns = patterns;
+        self.grammars.push(Grammar {
+            scope_name: language.scope.clone(),
+            file_types: Vec::new(),
+            first_line: None,
+            root,
+            repository: HashMap::new(),
+        });
+    }
+
+    // Load a .tmLanguage, .tmLanguage.json or .sublime-syntax file. A grammar for the
+    // same scope as one already loaded replaces it. Returns the patterns that were left out.
+    pub fn add_file(&mut self, path: &Path) -> Result<Vec<String>, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let file_name = path.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        let (grammar, skipped) = if file_name.ends_with(".sublime-syntax") {
+            Loader::new(self).sublime_grammar(&yaml::parse(&text)?)?
+        } else if file_name.ends_with(".json") {
+            Loader::new(self).tm_grammar(&json::parse(&text)?)?
+        } else {
+            Loader::new(self).tm_grammar(&plist::parse(&text)?)?
+        };
+        self.grammars.retain(|g| g.scope_name != grammar.scope_name);
+        self.grammars.push(grammar);
+        Ok(skipped)
+    }
+
+    fn resolve(&self, target: &Target) -> Option<usize> {
+        match target {
+            Target::Context(context) => Some(*context),
+            Target::Grammar(scope, key) => {
+                let grammar = self.grammars.iter().find(|g| g.scope_name == *scope)?;
+                match key {
+                    Some(key) => grammar.repository.get(key).copied(),
+                    None => Some(grammar.root),
+                }
+            }
+        }
+    }
+
+    fn flatten(&self, context: usize, seen: &mut Vec<usize>, rules: &mut Vec<usize>) {
+        if seen.contains(&context) {
+            return;
+        }
+        seen.push(context);
+        for pattern in &self.contexts[context].patterns {
+            match pattern {
+                Pattern::Rule(rule) => rules.push(*rule),
+                // Includes of grammars that aren't installed are quietly left out
+                Pattern::Include(target) => {
+                    if let Some(included) = self.resolve(target) {
+                        self.flatten(included, seen, rules);
+                    }
+                }
+            }
+        }
+    }
+
+    // Expand every context's includes into a flat list of rules
+    pub fn link(&mut self) {
+        for context in 0..self.contexts.len() {
+            let mut rules = Vec::new();
+            self.flatten(context, &mut Vec::new(), &mut rules);
+            self.contexts[context].rules = rules;
+        }
+    }
+
+    // The grammar for a language ID. A language the editor doesn't know about can still
+    // have a grammar, with a scope name like source.<id>.
+    pub fn for_language(&self, id: &str) -> Option<usize> {
+        match language::get(id) {
+            Some(language) => self.grammars.iter().position(|g| g.scope_name == language.scope),
+            None => self.grammars.iter().position(|g| g.scope_name.rsplit('.').next() == Some(id)),
+        }
+    }
+
+    // The language ID for a file that only a grammar recognises, from its scope name
+    pub fn language_for(&self, path: &str, first_line: &str) -> Option<String> {
+        let grammar = &self.grammars[self.find(path, first_line)?];
+        grammar.scope_name.rsplit('.').next().map(|id| id.to_string())
+    }
+
+    // The grammar for a file, from its name or else its first line
+    pub fn find(&self, path: &str, first_line: &str) -> Option<usize> {
+        let file_name = Path::new(path).file_name().map_or(String::new(), |s| s.to_string_lossy().into_owned());
+        let by_name = self
+            .grammars
+            .iter()
+            .enumerate()
+            .flat_map(|(i, g)| g.file_types.iter().map(move |t| (i, t)))
+            .filter(|(_, t)| file_name == **t || file_name.ends_with(&format!(".{}", t)))
+            .max_by_key(|(_, t)| t.len())
+            .map(|(i, _)| i);
+        by_name.or_else(|| self.grammars.iter().position(|g| g.first_line.as_ref().is_some_and(|r| r.is_match(first_line))))
+    }
+}
+
+// Grammar files from data/grammars and the user's grammars directory
+pub fn grammar_files() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data/grammars")];
+    if let Some(dir) = config::config_dir() {
+        dirs.push(dir.join("grammars"));
+    }
+    let mut files = Vec::new();
+    for dir in dirs {
+        let mut entries = match fs::read_dir(dir) {
+            Ok(entries) => entries.flatten().map(|e| e.path()).collect::<Vec<PathBuf>>(),
+            Err(_) => continue,
+        };
+        entries.sort();
+        for path in entries {
+            let name = path.file_name().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+            if name.ends_with(".tmLanguage") || name.ends_with(".tmLanguage.json") || name.ends_with(".sublime-syntax") {
+                files.push(path);
+            }
+        }
+    }
+    files
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn matches(pattern: &str, text: &str) -> Option<String> {
+        compile(pattern).unwrap().find(text).map(|m| m.as_str().to_string())
+    }
+
+    #[test]
+    fn translates_hex_digit_classes() {
+        assert_eq!(translate(r"0x\h+"), r"(?m)0x[0-9a-fA-F]+");
+        assert_eq!(translate(r"[\h_]"), r"(?m)[0-9a-fA-F_]");
+        assert_eq!(translate(r"\H"), r"(?m)[^0-9a-fA-F]");
+        assert_eq!(matches(r"0x[\h_]+", "let x = 0xdead_BEEF;").as_deref(), Some("0xdead_BEEF"));
+    }
+
+    #[test]
+    fn keeps_anchors_working() {
+        assert_eq!(matches(r"\Aab", "ab ab").as_deref(), Some("ab"));
+        assert_eq!(matches(r"\Aab", " ab"), None);
+        assert_eq!(matches(r"b\z", "ab\nab").as_deref(), Some("b"));
+        assert_eq!(matches(r"a\Z", "ba").as_deref(), Some("a"));
+        // ^ and $ match at line ends, as they do in Oniguruma
+        assert_eq!(matches(r"^b$", "a\nb\nc").as_deref(), Some("b"));
+    }
+
+    #[test]
+    fn makes_possessive_quantifiers_and_atomic_groups_greedy() {
+        assert_eq!(translate(r"a++b*+c?+d{2}+"), r"(?m)a+b*c?d{2}");
+        assert_eq!(translate(r"(?>ab|a)"), r"(?m)(?:ab|a)");
+        // A + after something that isn't a quantifier is left alone
+        assert_eq!(translate(r"\d+"), r"(?m)\d+");
+        assert_eq!(translate(r"x{a}+"), r"(?m)x\{a\}+");
+        assert_eq!(matches(r"[a-z]++\d", "abc1").as_deref(), Some("abc1"));
+    }
+
+    #[test]
+    fn names_groups_the_way_the_regex_crate_does() {
+        assert_eq!(translate(r"(?<name>\w+)"), r"(?m)(?P<name>\w+)");
+        assert_eq!(translate(r"(?<=a)(?<!b)"), r"(?m)(?<=a)(?<!b)");
+        let regex = compile(r"(?<word>\w+)").unwrap();
+        assert_eq!(regex.captures("hi there").and_then(|c| c.name("word")).map(|m| m.as_str()), Some("hi"));
+        // Backreferences, named or not, have no equivalent
+        assert!(compile(r#"(?<q>['"]).*?\k<q>"#).is_err());
+        assert!(compile(r"(a)\1").is_err());
+    }
+
+    #[test]
+    fn fills_in_backreferences_from_the_begin_match() {
+        assert!(has_backreferences(r"^\s*\1$"));
+        assert!(!has_backreferences(r"\\d\s"));
+        assert_eq!(fill_backreferences(r"\1\s\2\d", &[Some("<<EOF"), Some("E.F"), None]), r"E\.F\s\d");
+    }
+
+    #[test]
+    fn loads_grammars_and_skips_patterns_it_cant_handle() {
+        let mut library = GrammarLibrary::default();
+        let grammar = json::parse(
+            r##"{
+                "scopeName": "source.test",
+                "fileTypes": ["test"],
+                "patterns": [
+                    {"match": "\\bfn\\b", "name": "keyword"},
+                    {"match": "(?<=x)y\\1", "name": "invalid"},
+                    {"include": "#strings"}
+                ],
+                "repository": {
+                    "strings": {"begin": "\"", "end": "\"", "name": "string", "patterns": [{"match": "\\\\.", "name": "escape"}]}
+                }
+            }"##,
+        )
+        .unwrap();
+        let (grammar, skipped) = Loader::new(&mut library).tm_grammar(&grammar).unwrap();
+        assert_eq!(grammar.scope_name, "source.test");
+        assert_eq!(skipped, vec![r"(?<=x)y\1"]);
+        assert!(grammar.repository.contains_key("strings"));
+        assert!(Loader::new(&mut library).tm_grammar(&json::parse(r#"{"patterns": []}"#).unwrap()).is_err());
+        let sublime = yaml::parse("scope: source.test\ncontexts:\n  other: []\n").unwrap();
+        assert!(Loader::new(&mut library).sublime_grammar(&sublime).is_err());
+    }
+}
@@ -0,0 +1,141 @@
+use pgfx::Color;
+
+// How line numbers are shown. Relative numbers count lines away from the cursor, and
+// hybrid ones do that but give the cursor's line its real number.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum LineNumbers {
+    Off,
+    Absolute,
+    Relative,
+    Hybrid,
+}
+
+impl LineNumbers {
+    pub fn parse(name: &str) -> Option<LineNumbers> {
+        match name {
+            "off" => Some(LineNumbers::Off),
+            "absolute" => Some(LineNumbers::Absolute),
+            "relative" => Some(LineNumbers::Relative),
+            "hybrid" => Some(LineNumbers::Hybrid),
+            _ => None,
+        }
+    }
+
+    pub fn next(self) -> LineNumbers {
+        match self {
+            LineNumbers::Off => LineNumbers::Absolute,
+            LineNumbers::Absolute => LineNumbers::Relative,
+            LineNumbers::Relative => LineNumbers::Hybrid,
+            LineNumbers::Hybrid => LineNumbers::Off,
+        }
+    }
+}
+
+// A one character column of the gutter for something other than line numbers. Fold
+// arrows go after the numbers and the rest before them, in this order.
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Slot {
+    Git,
+    Bookmark,
+    Diagnostic,
+    Fold,
+}
+
+impl Slot {
+    pub fn parse(name: &str) -> Option<Slot> {
+        match name {
+            "git" => Some(Slot::Git),
+            "bookmark" => Some(Slot::Bookmark),
+            "diagnostic" => Some(Slot::Diagnostic),
+            "fold" => Some(Slot::Fold),
+            _ => None,
+        }
+    }
+}
+
+// Something drawn in a slot next to a line
+#[derive(Clone)]
+pub struct Mark {
+    pub slot: Slot,
+    pub line: usize,
+    pub text: String,
+    pub color: Color,
+}
+
+// What's down the left of a pane: marks and line numbers, then a space before the text
+pub struct Gutter {
+    pub line_numbers: LineNumbers,
+    // Slots that have a column, whether or not anything is in them, so the text doesn't
+    // move around as marks come and go
+    slots: Vec<Slot>,
+    pub marks: Vec<Mark>,
+}
+
+impl Gutter {
+    pub fn new() -> Self {
+        Gutter {
+            line_numbers: LineNumbers::Absolute,
+            slots: Vec::new(),
+            marks: Vec::new(),
+        }
+    }
+
+    // Give a slot a column. Features turn on their slot before adding marks to it.
+    pub fn reserve(&mut self, slot: Slot) {
+        if !self.slots.contains(&slot) {
+            self.slots.push(slot);
+            self.slots.sort_by_key(|s| *s as usize);
+        }
+    }
+
+    // Only the columns for these slots, as the settings ask for
+    pub fn set_slots(&mut self, slots: &[Slot]) {
+        self.slots.clear();
+        for slot in slots {
+            self.reserve(*slot);
+        }
+    }
+
+    // Digits needed for the numbers of a buffer this long
+    fn number_width(&self, line_count: usize) -> usize {
+        match self.line_numbers {
+            LineNumbers::Off => 0,
+            _ => line_count.max(1).to_string().len().max(2),
+        }
+    }
+
+    // How many characters wide the gutter is for a buffer this long
+    pub fn width(&self, line_count: usize) -> usize {
+        match self.slots.len() + self.number_width(line_count) {
+            0 => 0,
+            columns => columns + 1,
+        }
+    }
+
+    // What goes in the gutter on the first row of `line`: a character for each slot and
+    // the line number, one string per column from the left, with the colour of a mark.
+    // Rows that a wrapped line continues onto have nothing.
+    pub fn cells(&self, line: usize, cursor_line: usize, line_count: usize) -> Vec<(String, Option<Color>)> {
+        let mut cells = Vec::new();
+        let mark = |slot: Slot| match self.marks.iter().find(|m| m.slot == slot && m.line == line) {
+            Some(m) => (m.text.clone(), Some(m.color)),
+            None => (" ".to_string(), None),
+        };
+        for slot in self.slots.iter().filter(|s| **s != Slot::Fold) {
+            cells.push(mark(*slot));
+        }
+        let number = match self.line_numbers {
+            LineNumbers::Off => String::new(),
+            LineNumbers::Absolute => (line + 1).to_string(),
+            LineNumbers::Hybrid if line == cursor_line => (line + 1).to_string(),
+            LineNumbers::Relative | LineNumbers::Hybrid => line.abs_diff(cursor_line).to_string(),
+        };
+        for c in format!("{:>1$}", number, self.number_width(line_count)).chars() {
+            cells.push((c.to_string(), None));
+        }
+        if self.slots.contains(&Slot::Fold) {
+            cells.push(mark(Slot::Fold));
+        }
+        cells
+    }
+}
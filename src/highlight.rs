@@ -0,0 +1,531 @@
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use regex::{CaptureLocations, Regex};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+use crate::grammar::{self, Action, Captures, GrammarLibrary, Rule};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyntaxContext {
+    Code,
+    String,
+    Comment,
+}
+
+// One level of the tokenizer's stack: a context being matched in, with the scopes that
+// apply to it
+#[derive(Clone)]
+struct Frame {
+    context: usize,
+    // Scopes for the text that ends the frame, and for the text inside it
+    outer: Vec<String>,
+    inner: Vec<String>,
+    // The begin rule that started the frame, and its end or while pattern with any
+    // backreferences filled in
+    rule: Option<usize>,
+    end: Option<(String, Regex)>,
+}
+
+impl PartialEq for Frame {
+    fn eq(&self, other: &Self) -> bool {
+        self.context == other.context
+            && self.rule == other.rule
+            && self.inner == other.inner
+            && self.outer == other.outer
+            && self.end.as_ref().map(|(s, _)| s) == other.end.as_ref().map(|(s, _)| s)
+    }
+}
+
+// Where the tokenizer is at the end of a line
+#[derive(Clone, PartialEq)]
+pub struct State {
+    frames: Vec<Frame>,
+}
+
+// A run of text with the same scopes, from the outermost to the innermost. Byte offsets
+// are into the line, and the last token of a line also covers the newline after it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Token {
+    pub start: usize,
+    pub end: usize,
+    pub scopes: Vec<String>,
+}
+
+fn push_token(tokens: &mut Vec<Token>, start: usize, end: usize, scopes: &[String]) {
+    if start >= end {
+        return;
+    }
+    if let Some(last) = tokens.last_mut() {
+        if last.end == start && last.scopes == scopes {
+            last.end = end;
+            return;
+        }
+    }
+    tokens.push(Token { start, end, scopes: scopes.to_vec() });
+}
+
+// Tokens for a match, with its capture groups' scopes on top of `base`. Groups are
+// applied in order, so a group inside another gets both sets of scopes.
+fn push_match(tokens: &mut Vec<Token>, start: usize, end: usize, base: &[String], captures: &Captures, locations: &CaptureLocations) {
+    let groups = captures
+        .iter()
+        .filter_map(|(group, scopes)| locations.get(*group).filter(|(s, e)| s < e && *s >= start && *e <= end).map(|(s, e)| (s, e, scopes)))
+        .collect::<Vec<(usize, usize, &Vec<String>)>>();
+    let mut bounds = vec![start, end];
+    for (s, e, _) in &groups {
+        bounds.push(*s);
+        bounds.push(*e);
+    }
+    bounds.sort_unstable();
+    bounds.dedup();
+    for pair in bounds.windows(2) {
+        let mut scopes = base.to_vec();
+        for (s, e, group_scopes) in &groups {
+            if *s <= pair[0] && pair[1] <= *e {
+                scopes.extend(group_scopes.iter().cloned());
+            }
+        }
+        push_token(tokens, pair[0], pair[1], &scopes);
+    }
+}
+
+fn with(scopes: &[String], more: &[String]) -> Vec<String> {
+    let mut scopes = scopes.to_vec();
+    scopes.extend(more.iter().cloned());
+    scopes
+}
+
+fn search(regex: &Regex, text: &str, pos: usize) -> Option<(usize, usize, CaptureLocations)> {
+    let mut locations = regex.capture_locations();
+    let m = regex.captures_read_at(&mut locations, text, pos)?;
+    Some((m.start(), m.end(), locations))
+}
+
+fn next_boundary(text: &str, pos: usize) -> usize {
+    pos + text[pos..].chars().next().map_or(1, |c| c.len_utf8())
+}
+
+enum Found {
+    End,
+    Rule(usize),
+}
+
+// Tokenizes lines with one grammar from a library
+pub struct Highlighter {
+    pub library: Rc<GrammarLibrary>,
+    pub grammar: usize,
+}
+
+impl Highlighter {
+    pub fn new(library: Rc<GrammarLibrary>, grammar: usize) -> Self {
+        Highlighter { library, grammar }
+    }
+
+    // The state at the top of a file
+    pub fn start(&self) -> State {
+        let grammar = &self.library.grammars[self.grammar];
+        State {
+            frames: vec![Frame {
+                context: grammar.root,
+                outer: vec![grammar.scope_name.clone()],
+                inner: vec![grammar.scope_name.clone()],
+                rule: None,
+                end: None,
+            }],
+        }
+    }
+
+    fn frame_for(&self, frames: &[Frame], context: usize) -> Frame {
+        let parent = &frames[frames.len() - 1].inner;
+        let outer = with(parent, &self.library.contexts[context].meta_scope);
+        let inner = with(&outer, &self.library.contexts[context].meta_content_scope);
+        Frame { context, outer, inner, rule: None, end: None }
+    }
+
+    // Split a line into tokens, carrying the state on to the next line. Like TextMate,
+    // this sees the line with its newline, which patterns like `//.*\n` rely on.
+    pub fn highlight_line(&self, state: &mut State, line: &str) -> Vec<Token> {
+        let library = &*self.library;
+        let text = format!("{}\n", line);
+        let mut tokens = Vec::new();
+        let mut pos = 0;
+
+        // While regions only carry on if the line starts with their pattern
+        for i in 1..state.frames.len() {
+            let frame = &state.frames[i];
+            let captures = match frame.rule.map(|r| &library.rules[r]) {
+                Some(Rule::Begin { is_while: true, end_captures, .. }) => end_captures,
+                _ => continue,
+            };
+            match search(&frame.end.as_ref().unwrap().1, &text, pos) {
+                Some((start, end, locations)) if start == pos => {
+                    push_match(&mut tokens, pos, end, &frame.outer, captures, &locations);
+                    pos = end;
+                }
+                _ => {
+                    state.frames.truncate(i);
+                    break;
+                }
+            }
+        }
+
+        // Rules that pushed without using up any text at this position, which mustn't be
+        // tried again here or they'd push forever
+        let mut empty_pushes: Vec<usize> = Vec::new();
+        // Where each rule next matches on this line. A match found from an earlier position
+        // still stands until the position passes its start.
+        let mut searches: HashMap<usize, Option<(usize, usize, CaptureLocations)>> = HashMap::new();
+        let mut steps = 0;
+        while pos < text.len() && steps < text.len() * 4 + 100 {
+            steps += 1;
+            let frame = state.frames.last().unwrap();
+            let context = &library.contexts[frame.context];
+            let (end_last, end) = match frame.rule.map(|r| &library.rules[r]) {
+                Some(Rule::Begin { is_while: false, end_last, .. }) => (*end_last, frame.end.as_ref().map(|(_, r)| r)),
+                _ => (false, None),
+            };
+
+            // The earliest match wins, and the first rule wins a tie
+            let end_match = end.and_then(|regex| search(regex, &text, pos));
+            let mut best = match (end_last, end_match.clone()) {
+                (false, Some((start, end, locations))) => Some((start, end, Found::End, locations)),
+                _ => None,
+            };
+            for &rule in &context.rules {
+                if best.as_ref().is_some_and(|b| b.0 == pos) {
+                    break;
+                }
+                let found = match searches.get(&rule) {
+                    Some(Some(found)) if found.0 >= pos => Some(found.clone()),
+                    Some(None) => None,
+                    _ => {
+                        let regex = match &library.rules[rule] {
+                            Rule::Match { regex, .. } | Rule::Begin { regex, .. } => regex,
+                        };
+                        let found = search(regex, &text, pos);
+                        searches.insert(rule, found.clone());
+                        found
+                    }
+                };
+                if let Some((start, end, locations)) = found {
+                    let repeated = start == end && start == pos && empty_pushes.contains(&rule);
+                    if !repeated && best.as_ref().is_none_or(|b| start < b.0) {
+                        best = Some((start, end, Found::Rule(rule), locations));
+                    }
+                }
+            }
+            if let (true, Some((start, end, locations))) = (end_last, end_match) {
+                if best.as_ref().is_none_or(|b| start < b.0) {
+                    best = Some((start, end, Found::End, locations));
+                }
+            }
+
+            let (start, end, found, locations) = match best {
+                Some(best) => best,
+                None => break,
+            };
+            let inner = frame.inner.clone();
+            let outer = frame.outer.clone();
+            let frame_rule = frame.rule;
+            push_token(&mut tokens, pos, start, &inner);
+            if start > pos {
+                empty_pushes.clear();
+            }
+            pos = start;
+
+            match found {
+                Found::End => {
+                    let captures = match &library.rules[frame_rule.unwrap()] {
+                        Rule::Begin { end_captures, .. } => end_captures,
+                        _ => unreachable!(),
+                    };
+                    push_match(&mut tokens, start, end, &outer, captures, &locations);
+                    state.frames.pop();
+                }
+                Found::Rule(rule) => match &library.rules[rule] {
+                    Rule::Match { scope, captures, action, .. } => match action {
+                        Action::None => {
+                            push_match(&mut tokens, start, end, &with(&inner, scope), captures, &locations);
+                            // Step over a character rather than match nothing forever
+                            if start == end {
+                                let next = next_boundary(&text, pos);
+                                push_token(&mut tokens, pos, next, &inner);
+                                pos = next;
+                                continue;
+                            }
+                        }
+                        Action::Pop(count) => {
+                            push_match(&mut tokens, start, end, &with(&outer, scope), captures, &locations);
+                            let keep = state.frames.len().saturating_sub(*count).max(1);
+                            state.frames.truncate(keep);
+                        }
+                        Action::Push(contexts) | Action::Set(contexts) => {
+                            if let Action::Set(_) = action {
+                                if state.frames.len() > 1 {
+                                    state.frames.pop();
+                                }
+                            }
+                            // The text that pushes gets the new contexts' meta scopes
+                            let mut frames = Vec::new();
+                            for &context in contexts {
+                                let mut stack = state.frames.clone();
+                                stack.extend(frames.iter().cloned());
+                                frames.push(self.frame_for(&stack, context));
+                            }
+                            let base = frames.last().map_or(state.frames.last().unwrap().inner.clone(), |f: &Frame| f.outer.clone());
+                            push_match(&mut tokens, start, end, &with(&base, scope), captures, &locations);
+                            state.frames.extend(frames);
+                            if start == end {
+                                empty_pushes.push(rule);
+                            }
+                        }
+                    },
+                    Rule::Begin { scope, content_scope, captures, end: end_pattern, end_regex, context, .. } => {
+                        let outer = with(&inner, scope);
+                        push_match(&mut tokens, start, end, &outer, captures, &locations);
+                        let end_regex = match end_regex {
+                            Some(regex) => regex.clone(),
+                            None => {
+                                let groups = (0..locations.len()).map(|i| locations.get(i).map(|(s, e)| &text[s..e])).collect::<Vec<Option<&str>>>();
+                                let filled = grammar::fill_backreferences(end_pattern, &groups);
+                                grammar::compile(&filled).unwrap_or_else(|_| grammar::compile(r"\b\B").unwrap())
+                            }
+                        };
+                        let source = end_regex.as_str().to_string();
+                        let inner = with(&outer, content_scope);
+                        state.frames.push(Frame { context: *context, outer, inner, rule: Some(rule), end: Some((source, end_regex)) });
+                        if start == end {
+                            empty_pushes.push(rule);
+                        }
+                    }
+                },
+            }
+            pos = end;
+        }
+        push_token(&mut tokens, pos, text.len(), &state.frames.last().unwrap().inner);
+        tokens
+    }
+}
+
+// How many lines past the bottom of the screen get highlighted ahead of time, so that
+// scrolling down a bit doesn't have to wait for the tokenizer
+pub const LOOKAHEAD: usize = 50;
+
+struct Line {
+    tokens: Vec<Token>,
+    // The state at the end of the line
+    end: State,
+}
+
+// The tokens for each line of a buffer, kept from frame to frame. Lines before `valid`
+// are up to date. The ones after it may be from before an edit (or None if they were
+// edited), and are only trusted again once the state after an edited line comes out the
+// same as it was before.
+#[derive(Default)]
+pub struct HighlightCache {
+    highlighter: Option<Highlighter>,
+    buffer_id: Option<usize>,
+    version: u64,
+    lines: Vec<Option<Line>>,
+    valid: usize,
+}
+
+impl HighlightCache {
+    // Catch up with the edits made since the last sync. Starts over if the buffer or its
+    // grammar changed, or the edits are no longer in the buffer's change log.
+    pub fn sync(&mut self, library: &Rc<GrammarLibrary>, buffer_id: usize, buffer: &Buffer) {
+        let grammar = library.for_language(&buffer.language);
+        let current = self.highlighter.as_ref().map(|h| (Rc::as_ptr(&h.library), h.grammar));
+        let changes = if self.buffer_id == Some(buffer_id) && current == grammar.map(|g| (Rc::as_ptr(library), g)) {
+            buffer.changes_since(self.version)
+        } else {
+            None
+        };
+        match changes {
+            Some(changes) => {
+                for change in &changes {
+                    let y = change.old_range.start.y.min(self.lines.len());
+                    let old_end = (change.old_range.end.y + 1).clamp(y, self.lines.len());
+                    let new_lines = change.new_range.end.y - change.new_range.start.y + 1;
+                    self.lines.splice(y..old_end, (0..new_lines).map(|_| None));
+                    self.valid = self.valid.min(y);
+                }
+            }
+            None => {
+                self.highlighter = grammar.map(|g| Highlighter::new(library.clone(), g));
+                self.buffer_id = Some(buffer_id);
+                self.lines.clear();
+                self.valid = 0;
+            }
+        }
+        self.lines.resize_with(buffer.len(), || None);
+        self.valid = self.valid.min(self.lines.len());
+        self.version = buffer.version();
+    }
+
+    // Bring lines up to `last` up to date, starting from the first one that isn't
+    pub fn update(&mut self, buffer: &Buffer, last: usize) {
+        let highlighter = match &self.highlighter {
+            Some(highlighter) => highlighter,
+            None => return,
+        };
+        let mut y = self.valid;
+        while y <= last && y < self.lines.len() {
+            let mut state = match y {
+                0 => highlighter.start(),
+                _ => self.lines[y - 1].as_ref().unwrap().end.clone(),
+            };
+            let tokens = highlighter.highlight_line(&mut state, &buffer.contents[y]);
+            let converged = matches!(&self.lines[y], Some(line) if line.end == state);
+            self.lines[y] = Some(Line { tokens, end: state });
+            y += 1;
+            // Nothing changes from here on until the next edited line
+            if converged {
+                while y < self.lines.len() && self.lines[y].is_some() {
+                    y += 1;
+                }
+            }
+            self.valid = y;
+        }
+    }
+
+    // The tokens for line y, or none if it hasn't been highlighted
+    pub fn tokens(&self, y: usize) -> &[Token] {
+        match self.lines.get(y) {
+            Some(Some(line)) if y < self.valid => &line.tokens,
+            _ => &[],
+        }
+    }
+
+    // Whether grapheme x of line y is in code, a string or a comment. A position between
+    // two characters counts as inside something only if both sides are.
+    pub fn context_at(&mut self, buffer: &Buffer, x: usize, y: usize) -> SyntaxContext {
+        let line = match (&self.highlighter, buffer.contents.get(y)) {
+            (Some(_), Some(line)) => line,
+            _ => return SyntaxContext::Code,
+        };
+        self.update(buffer, y);
+        let tokens = self.tokens(y);
+        let offsets = line.grapheme_indices(true).map(|(i, _)| i).collect::<Vec<usize>>();
+        let after = offsets.get(x).copied().unwrap_or(line.len());
+        let scopes_at = |byte: usize| tokens.iter().find(|t| t.start <= byte && byte < t.end).map_or(&[][..], |t| &t.scopes[..]);
+        let right = scopes_at(after);
+        // At the start of a line, the left side is the end of the line before
+        let left = if x > 0 {
+            scopes_at(offsets[x - 1])
+        } else if y > 0 {
+            self.tokens(y - 1).last().map_or(&[][..], |t| &t.scopes[..])
+        } else {
+            &[][..]
+        };
+        let shared = left.iter().zip(right).take_while(|(a, b)| a == b).map(|(a, _)| a);
+        let mut context = SyntaxContext::Code;
+        for scope in shared {
+            if scope.starts_with("comment") {
+                context = SyntaxContext::Comment;
+            } else if scope.starts_with("string") {
+                context = SyntaxContext::String;
+            }
+        }
+        context
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(lines: &[&str]) -> Buffer {
+        let mut buffer = Buffer::from_lines(lines.iter().copied());
+        buffer.language = "rust".to_string();
+        buffer
+    }
+
+    // The tokens of every line, highlighted from the top
+    fn fresh_tokens(library: &Rc<GrammarLibrary>, buffer: &Buffer) -> Vec<Vec<Token>> {
+        let highlighter = Highlighter::new(library.clone(), library.for_language("rust").unwrap());
+        let mut state = highlighter.start();
+        buffer.contents.iter().map(|l| highlighter.highlight_line(&mut state, l)).collect()
+    }
+
+    fn cached_tokens(cache: &HighlightCache, buffer: &Buffer) -> Vec<Vec<Token>> {
+        (0..buffer.len()).map(|y| cache.tokens(y).to_vec()).collect()
+    }
+
+    fn is_comment(tokens: &[Token]) -> bool {
+        tokens.iter().all(|t| t.scopes.iter().any(|s| s.starts_with("comment")))
+    }
+
+    #[test]
+    fn stops_after_the_edited_line_when_the_state_comes_out_the_same() {
+        let library = Rc::new(GrammarLibrary::load());
+        let mut buffer = buffer(&["fn a() {}", "let x = 1;", "let y = 2;", "let z = 3;"]);
+        let mut cache = HighlightCache::default();
+        cache.sync(&library, 0, &buffer);
+        cache.update(&buffer, 3);
+        assert_eq!(cache.valid, 4);
+
+        buffer.insert_text(8, 1, "0".to_string());
+        cache.sync(&library, 0, &buffer);
+        assert_eq!(cache.valid, 1);
+        cache.update(&buffer, 1);
+        assert_eq!(cache.valid, 2);
+        // The line after the edit ends the same as before, so the rest are kept
+        cache.update(&buffer, 2);
+        assert_eq!(cache.valid, 4);
+        assert_eq!(cached_tokens(&cache, &buffer), fresh_tokens(&library, &buffer));
+    }
+
+    #[test]
+    fn carries_on_past_the_edited_line_when_the_state_changes() {
+        let library = Rc::new(GrammarLibrary::load());
+        let mut buffer = buffer(&["let a = 1;", "let b = 2;", "let c = 3;", "*/ let d = 4;"]);
+        let mut cache = HighlightCache::default();
+        cache.sync(&library, 0, &buffer);
+        cache.update(&buffer, 3);
+        assert!(!is_comment(cache.tokens(1)));
+
+        buffer.insert_text(0, 1, "/* ".to_string());
+        cache.sync(&library, 0, &buffer);
+        cache.update(&buffer, 1);
+        // The line after the edit was highlighted outside the comment before, so it's
+        // not trusted yet
+        assert_eq!(cache.valid, 2);
+        assert!(cache.tokens(2).is_empty());
+        cache.update(&buffer, 3);
+        assert!(is_comment(cache.tokens(2)));
+        assert_eq!(cached_tokens(&cache, &buffer), fresh_tokens(&library, &buffer));
+    }
+
+    #[test]
+    fn follows_inserted_and_deleted_lines() {
+        let library = Rc::new(GrammarLibrary::load());
+        let mut buffer = buffer(&["fn a() {", "    1", "}", "// end"]);
+        let mut cache = HighlightCache::default();
+        cache.sync(&library, 0, &buffer);
+        cache.update(&buffer, 3);
+
+        buffer.insert_text(5, 1, "\n    \"two\n    three\"".to_string());
+        buffer.delete_text(0, 0, 0, 1);
+        cache.sync(&library, 0, &buffer);
+        assert_eq!(cache.lines.len(), buffer.len());
+        cache.update(&buffer, buffer.len() - 1);
+        assert_eq!(cached_tokens(&cache, &buffer), fresh_tokens(&library, &buffer));
+    }
+
+    #[test]
+    fn starts_over_for_another_buffer() {
+        let library = Rc::new(GrammarLibrary::load());
+        let first = buffer(&["let a = 1;"]);
+        let second = buffer(&["// a", "// b"]);
+        let mut cache = HighlightCache::default();
+        cache.sync(&library, 0, &first);
+        cache.update(&first, 0);
+        cache.sync(&library, 1, &second);
+        assert_eq!(cache.valid, 0);
+        cache.update(&second, 1);
+        assert!(is_comment(cache.tokens(0)) && is_comment(cache.tokens(1)));
+    }
+}
@@ -0,0 +1,116 @@
+use crate::language;
+
+pub const DEFAULT_TAB_WIDTH: usize = 4;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IndentStyle {
+    Tabs,
+    Spaces(usize),
+}
+
+impl IndentStyle {
+    // The text inserted for one level of indentation
+    pub fn unit(&self) -> String {
+        match self {
+            IndentStyle::Tabs => "\t".to_string(),
+            IndentStyle::Spaces(n) => " ".repeat(*n),
+        }
+    }
+
+    // How many columns one level of indentation takes up on screen
+    pub fn width(&self, tab_width: usize) -> usize {
+        match self {
+            IndentStyle::Tabs => tab_width,
+            IndentStyle::Spaces(n) => *n,
+        }
+    }
+
+    pub fn default_for_language(id: &str) -> Self {
+        language::get(id).and_then(|l| l.indent).unwrap_or(IndentStyle::Spaces(4))
+    }
+
+    // Guess the indentation style from existing file contents. Lines indented with tabs
+    // are counted against lines indented with spaces, and the space width is whatever
+    // indentation change shows up most often between consecutive lines.
+    // Returns None if there is no indentation to go on.
+    pub fn detect(lines: &[String]) -> Option<Self> {
+        let mut tab_lines = 0;
+        let mut space_lines = 0;
+        let mut deltas = [0usize; 9];
+        let mut prev_spaces = 0;
+
+        for line in lines {
+            if line.trim().is_empty() {
+                continue;
+            }
+            if line.starts_with('\t') {
+                tab_lines += 1;
+                continue;
+            }
+            let spaces = line.chars().take_while(|c| *c == ' ').count();
+            if spaces > 0 {
+                space_lines += 1;
+            }
+            let delta = spaces.abs_diff(prev_spaces);
+            // Odd one-space deltas are usually alignment or block comments, not indentation
+            if delta > 1 && delta < deltas.len() {
+                deltas[delta] += 1;
+            }
+            prev_spaces = spaces;
+        }
+
+        if tab_lines == 0 && space_lines == 0 {
+            return None;
+        }
+        if tab_lines > space_lines {
+            return Some(IndentStyle::Tabs);
+        }
+        let mut width = 4;
+        let mut best = 0;
+        for (delta, count) in deltas.iter().enumerate() {
+            if *count > best {
+                best = *count;
+                width = delta;
+            }
+        }
+        Some(IndentStyle::Spaces(width))
+    }
+}
+
+pub fn leading_whitespace(line: &str) -> &str {
+    let end = line.len() - line.trim_start().len();
+    &line[..end]
+}
+
+// The on-screen column of the grapheme at index x, taking tab stops into account
+pub fn visual_column(graphemes: &[&str], x: usize, tab_width: usize) -> usize {
+    let mut col = 0;
+    for g in graphemes.iter().take(x) {
+        if *g == "\t" {
+            col += tab_width - col % tab_width;
+        } else {
+            col += 1;
+        }
+    }
+    col
+}
+
+pub fn is_open_bracket(c: char) -> bool {
+    c == '(' || c == '[' || c == '{'
+}
+
+pub fn is_close_bracket(c: char) -> bool {
+    c == ')' || c == ']' || c == '}'
+}
+
+pub fn matching_bracket(c: char) -> char {
+    match c {
+        '(' => ')',
+        '[' => ']',
+        '{' => '}',
+        ')' => '(',
+        ']' => '[',
+        '}' => '{',
+        _ => c,
+    }
+}
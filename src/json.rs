@@ -0,0 +1,281 @@
+use std::iter::Peekable;
+use std::str::Chars;
+
+// A parsed JSON (or plist) document
+#[derive(Clone, Debug, PartialEq)]
+pub enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    Str(String),
+    Array(Vec<Value>),
+    // Keys keep their order from the file
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    pub fn get(&self, key: &str) -> Option<&Value> {
+        match self {
+            Value::Object(entries) => entries.iter().find(|(k, _)| k == key).map(|(_, v)| v),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Value::Str(s) => Some(s),
+            _ => None,
+        }
+    }
+
+    pub fn as_array(&self) -> &[Value] {
+        match self {
+            Value::Array(items) => items,
+            _ => &[],
+        }
+    }
+}
+
+struct Parser<'a> {
+    chars: Peekable<Chars<'a>>,
+    line: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        Err(format!("line {}: {}", self.line, message))
+    }
+
+    fn next(&mut self) -> Option<char> {
+        let c = self.chars.next();
+        if c == Some('\n') {
+            self.line += 1;
+        }
+        c
+    }
+
+    // Skip whitespace and the // and /* */ comments that editor config files often have
+    fn skip_space(&mut self) {
+        loop {
+            match self.chars.peek() {
+                Some(c) if c.is_whitespace() => {
+                    self.next();
+                }
+                Some('/') => {
+                    let mut ahead = self.chars.clone();
+                    ahead.next();
+                    match ahead.next() {
+                        Some('/') => {
+                            while let Some(c) = self.next() {
+                                if c == '\n' {
+                                    break;
+                                }
+                            }
+                        }
+                        Some('*') => {
+                            self.next();
+                            self.next();
+                            let mut prev = ' ';
+                            while let Some(c) = self.next() {
+                                if prev == '*' && c == '/' {
+                                    break;
+                                }
+                                prev = c;
+                            }
+                        }
+                        _ => return,
+                    }
+                }
+                _ => return,
+            }
+        }
+    }
+
+    fn expect(&mut self, expected: char) -> Result<(), String> {
+        self.skip_space();
+        match self.next() {
+            Some(c) if c == expected => Ok(()),
+            Some(c) => self.error(&format!("expected {} but found {}", expected, c)),
+            None => self.error(&format!("expected {} but the file ended", expected)),
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        self.skip_space();
+        match self.chars.peek() {
+            Some('{') => self.object(),
+            Some('[') => self.array(),
+            Some('"') => Ok(Value::Str(self.string()?)),
+            Some(c) if *c == '-' || c.is_ascii_digit() => self.number(),
+            Some(c) if c.is_alphabetic() => {
+                let mut word = String::new();
+                while let Some(c) = self.chars.peek().filter(|c| c.is_alphabetic()) {
+                    word.push(*c);
+                    self.next();
+                }
+                match word.as_str() {
+                    "true" => Ok(Value::Bool(true)),
+                    "false" => Ok(Value::Bool(false)),
+                    "null" => Ok(Value::Null),
+                    _ => self.error(&format!("unexpected {}", word)),
+                }
+            }
+            Some(c) => {
+                let c = *c;
+                self.error(&format!("unexpected {}", c))
+            }
+            None => self.error("unexpected end of file"),
+        }
+    }
+
+    // Trailing commas before a closing bracket are allowed
+    fn object(&mut self) -> Result<Value, String> {
+        self.expect('{')?;
+        let mut entries = Vec::new();
+        loop {
+            self.skip_space();
+            if self.chars.peek() == Some(&'}') {
+                self.next();
+                return Ok(Value::Object(entries));
+            }
+            let key = match self.chars.peek() {
+                Some('"') => self.string()?,
+                _ => return self.error("expected a key"),
+            };
+            self.expect(':')?;
+            entries.push((key, self.value()?));
+            self.skip_space();
+            match self.next() {
+                Some(',') => {}
+                Some('}') => return Ok(Value::Object(entries)),
+                _ => return self.error("expected , or }"),
+            }
+        }
+    }
+
+    fn array(&mut self) -> Result<Value, String> {
+        self.expect('[')?;
+        let mut items = Vec::new();
+        loop {
+            self.skip_space();
+            if self.chars.peek() == Some(&']') {
+                self.next();
+                return Ok(Value::Array(items));
+            }
+            items.push(self.value()?);
+            self.skip_space();
+            match self.next() {
+                Some(',') => {}
+                Some(']') => return Ok(Value::Array(items)),
+                _ => return self.error("expected , or ]"),
+            }
+        }
+    }
+
+    fn string(&mut self) -> Result<String, String> {
+        self.next();
+        let mut s = String::new();
+        loop {
+            match self.next() {
+                Some('"') => return Ok(s),
+                Some('\\') => match self.next() {
+                    Some('n') => s.push('\n'),
+                    Some('t') => s.push('\t'),
+                    Some('r') => s.push('\r'),
+                    Some('b') => s.push('\u{8}'),
+                    Some('f') => s.push('\u{c}'),
+                    Some('u') => {
+                        let hex = (0..4).filter_map(|_| self.next()).collect::<String>();
+                        let code = u32::from_str_radix(&hex, 16).or_else(|_| self.error("bad \\u escape"))?;
+                        // Surrogate pairs come as two escapes
+                        if (0xd800..0xdc00).contains(&code) && self.chars.peek() == Some(&'\\') {
+                            self.next();
+                            self.next();
+                            let hex = (0..4).filter_map(|_| self.next()).collect::<String>();
+                            let low = u32::from_str_radix(&hex, 16).or_else(|_| self.error("bad \\u escape"))?;
+                            let combined = 0x10000 + ((code - 0xd800) << 10) + (low.wrapping_sub(0xdc00) & 0x3ff);
+                            s.push(char::from_u32(combined).unwrap_or('\u{fffd}'));
+                        } else {
+                            s.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                        }
+                    }
+                    Some(c) => s.push(c),
+                    None => return self.error("unterminated string"),
+                },
+                Some(c) => s.push(c),
+                None => return self.error("unterminated string"),
+            }
+        }
+    }
+
+    fn number(&mut self) -> Result<Value, String> {
+        let mut text = String::new();
+        while let Some(c) = self.chars.peek().filter(|c| c.is_ascii_digit() || "+-.eE".contains(**c)) {
+            text.push(*c);
+            self.next();
+        }
+        match text.parse() {
+            Ok(n) => Ok(Value::Number(n)),
+            Err(_) => self.error(&format!("bad number {}", text)),
+        }
+    }
+}
+
+// Parse JSON, allowing the comments and trailing commas found in VS Code's files
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        chars: text.chars().peekable(),
+        line: 1,
+    };
+    let value = parser.value()?;
+    parser.skip_space();
+    if parser.chars.peek().is_some() {
+        return parser.error("unexpected text after the end");
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_values_in_order() {
+        let value = parse(r#"{"b": [1, -2.5e1, true, null], "a": {"x": {}, "y": []}}"#).unwrap();
+        assert_eq!(
+            value,
+            Value::Object(vec![
+                (
+                    "b".to_string(),
+                    Value::Array(vec![Value::Number(1.0), Value::Number(-25.0), Value::Bool(true), Value::Null])
+                ),
+                (
+                    "a".to_string(),
+                    Value::Object(vec![("x".to_string(), Value::Object(vec![])), ("y".to_string(), Value::Array(vec![]))])
+                ),
+            ])
+        );
+        assert_eq!(value.get("b").map(|b| b.as_array().len()), Some(4));
+    }
+
+    #[test]
+    fn decodes_escapes() {
+        let value = parse(r#""a\"b\\c\/d\n\t\u00e9\ud83d\ude00""#).unwrap();
+        assert_eq!(value.as_str(), Some("a\"b\\c/d\n\té😀"));
+    }
+
+    #[test]
+    fn allows_comments_and_trailing_commas() {
+        let value = parse("// theme\n{\n  /* colours */ \"a\": [1, 2,],\n  \"b\": \"c\", // last\n}\n").unwrap();
+        assert_eq!(value.get("a").map(|a| a.as_array().len()), Some(2));
+        assert_eq!(value.get("b").and_then(|b| b.as_str()), Some("c"));
+    }
+
+    #[test]
+    fn reports_malformed_input() {
+        for text in ["", "{", "[1 2]", "{\"a\" 1}", "{a: 1}", "\"open", "\"\\uzz\"", "tru", "1 2", "{\"a\": 1}}", "-"] {
+            assert!(parse(text).is_err(), "{:?} should not parse", text);
+        }
+        assert_eq!(parse("{\n\"a\": @}"), Err("line 2: unexpected @".to_string()));
+    }
+}
@@ -0,0 +1,445 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use crate::command::{self, Scope};
+use crate::config::{self, FileWatcher};
+
+// How long a half-typed chord like "c-x" waits for the next key
+pub const CHORD_TIMEOUT: Duration = Duration::from_millis(2000);
+
+pub enum KeyResult<T> {
+    Matched(T),
+    // The keys so far start a longer chord
+    Pending,
+    // Nothing is bound to these keys. More than one key means a prefix was typed
+    // and then something that doesn't continue it.
+    Unbound(Vec<String>),
+}
+
+// Key strings as written in bindings can use "m-" for alt and either case for the
+// modifiers, so "C-x", "M-f" and "c-x", "a-f" are the same
+pub fn normalize_key(key: &str) -> String {
+    let mut rest = key;
+    let mut result = String::new();
+    while rest.len() > 2 && rest.as_bytes()[1] == b'-' {
+        match rest.as_bytes()[0].to_ascii_lowercase() {
+            b'c' => result.push_str("c-"),
+            b's' => result.push_str("s-"),
+            b'a' | b'm' => result.push_str("a-"),
+            _ => break,
+        }
+        rest = &rest[2..];
+    }
+    result.push_str(rest);
+    result
+}
+
+pub fn parse_chord(chord: &str) -> Vec<String> {
+    chord.split_whitespace().map(normalize_key).collect()
+}
+
+pub struct Keymap<T> {
+    bindings: HashMap<Vec<String>, T>,
+    prefixes: HashSet<Vec<String>>,
+    pending: Vec<String>,
+    pending_since: Option<Instant>,
+    pub timeout: Duration,
+}
+
+impl<T: Clone> Keymap<T> {
+    pub fn new() -> Self {
+        Self {
+            bindings: HashMap::new(),
+            prefixes: HashSet::new(),
+            pending: Vec::new(),
+            pending_since: None,
+            timeout: CHORD_TIMEOUT,
+        }
+    }
+
+    pub fn bind(&mut self, chord: &str, value: T) {
+        let keys = parse_chord(chord);
+        for i in 1..keys.len() {
+            self.prefixes.insert(keys[..i].to_vec());
+        }
+        self.bindings.insert(keys, value);
+    }
+
+    // What a whole chord is bound to, without touching a half-typed one
+    pub fn get(&self, chord: &str) -> Option<T> {
+        self.bindings.get(&parse_chord(chord)).cloned()
+    }
+
+    pub fn feed(&mut self, key: &str) -> KeyResult<T> {
+        self.expire();
+        self.pending.push(normalize_key(key));
+        if let Some(value) = self.bindings.get(&self.pending) {
+            let value = value.clone();
+            self.cancel();
+            KeyResult::Matched(value)
+        } else if self.prefixes.contains(&self.pending) {
+            self.pending_since = Some(Instant::now());
+            KeyResult::Pending
+        } else {
+            let keys = self.pending.clone();
+            self.cancel();
+            KeyResult::Unbound(keys)
+        }
+    }
+
+    pub fn cancel(&mut self) {
+        self.pending.clear();
+        self.pending_since = None;
+    }
+
+    // Drop a half-typed chord once it's been waiting too long
+    pub fn expire(&mut self) {
+        if let Some(since) = self.pending_since {
+            if since.elapsed() > self.timeout {
+                self.cancel();
+            }
+        }
+    }
+
+    pub fn is_pending(&self) -> bool {
+        !self.pending.is_empty()
+    }
+
+    // What's been typed of a chord so far, like "c-x -"
+    pub fn pending_text(&self) -> String {
+        if self.pending.is_empty() {
+            String::new()
+        } else {
+            format!("{} -", self.pending.join(" "))
+        }
+    }
+}
+
+impl<T: Clone + PartialEq> Keymap<T> {
+    // Every chord bound to `value`, shortest first
+    pub fn chords_for(&self, value: &T) -> Vec<String> {
+        let mut chords = self
+            .bindings
+            .iter()
+            .filter(|(_, v)| *v == value)
+            .map(|(keys, _)| keys.join(" "))
+            .collect::<Vec<String>>();
+        chords.sort_by(|a, b| a.len().cmp(&b.len()).then(a.cmp(b)));
+        chords
+    }
+}
+
+// Sections of a keymap file that aren't named after an input mode, and where their
+// commands have to come from
+const SCOPES: &[(&str, Scope)] = &[
+    ("editor", Scope::Editor),
+    ("buffer", Scope::Buffer),
+    ("file_manager", Scope::FileManager),
+];
+
+// Input modes, which can each have a section of editor bindings that only apply while
+// they're on
+const MODE_SECTIONS: &[&str] = &["default", "vim", "emacs"];
+
+// Anything else is a mode's section, which holds editor commands
+fn section_scope(section: &str) -> Scope {
+    SCOPES.iter().find(|(name, _)| *name == section).map(|(_, scope)| *scope).unwrap_or(Scope::Editor)
+}
+
+pub struct KeymapLine {
+    pub line: usize,
+    pub section: String,
+    pub keys: Vec<String>,
+    pub command: String,
+}
+
+// Keymap files are sections of `chord command` lines:
+//
+//     [buffer]
+//     c-z      edit.undo
+//     c-x c-s  buffer.save
+//
+// Lines starting with # are comments. Returns the bindings and any lines that
+// couldn't be read.
+pub fn parse_keymap_file(contents: &str) -> (Vec<KeymapLine>, Vec<String>) {
+    let mut lines = Vec::new();
+    let mut problems = Vec::new();
+    let mut section = "editor".to_string();
+    for (i, line) in contents.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if line.starts_with('[') && line.ends_with(']') {
+            section = line[1..line.len() - 1].trim().to_string();
+            continue;
+        }
+        let mut words = line.split_whitespace().collect::<Vec<&str>>();
+        if words.len() < 2 {
+            problems.push(format!("line {}: expected a key and a command", i + 1));
+            continue;
+        }
+        let command = words.pop().unwrap().to_string();
+        lines.push(KeymapLine {
+            line: i + 1,
+            section: section.clone(),
+            keys: words.iter().map(|k| normalize_key(k)).collect(),
+            command,
+        });
+    }
+    (lines, problems)
+}
+
+// The bindings from the shipped default keymap with the user's keymap on top,
+// reloaded whenever either file changes
+pub struct Keymaps {
+    files: FileWatcher,
+    sections: HashMap<String, BTreeMap<Vec<String>, &'static str>>,
+}
+
+impl Keymaps {
+    pub fn load() -> Self {
+        let mut paths = vec![PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data/keymaps/default.keymap")];
+        if let Some(dir) = config::config_dir() {
+            paths.push(dir.join("keymap"));
+        }
+        let mut keymaps = Self {
+            files: FileWatcher::new(paths),
+            sections: HashMap::new(),
+        };
+        keymaps.reload();
+        keymaps
+    }
+
+    fn reload(&mut self) {
+        self.sections.clear();
+        let mut problems = Vec::new();
+        for path in &self.files.paths {
+            let contents = match fs::read_to_string(path) {
+                Ok(contents) => contents,
+                Err(_) => continue,
+            };
+            let (lines, file_problems) = parse_keymap_file(&contents);
+            problems.extend(file_problems.into_iter().map(|p| format!("{}: {}", path.display(), p)));
+            // Each file may bind a chord once per section; the user's file replaces the defaults
+            let mut seen: HashMap<(String, Vec<String>), usize> = HashMap::new();
+            for line in lines {
+                let at = format!("{}: line {}", path.display(), line.line);
+                if let Some(first) = seen.insert((line.section.clone(), line.keys.clone()), line.line) {
+                    problems.push(format!("{}: {} is already bound on line {}", at, line.keys.join(" "), first));
+                }
+                if !SCOPES.iter().any(|(name, _)| *name == line.section) && !MODE_SECTIONS.contains(&line.section.as_str()) {
+                    problems.push(format!("{}: unknown section [{}]", at, line.section));
+                    continue;
+                }
+                let section = self.sections.entry(line.section.clone()).or_default();
+                if line.command == "none" {
+                    section.remove(&line.keys);
+                    continue;
+                }
+                let command = match command::find(&line.command) {
+                    Some(c) => c,
+                    None => {
+                        problems.push(format!("{}: unknown command {}", at, line.command));
+                        continue;
+                    }
+                };
+                if command.scope != section_scope(&line.section) {
+                    problems.push(format!("{}: {} can't be used in [{}]", at, command.name, line.section));
+                    continue;
+                }
+                section.insert(line.keys, command.name);
+            }
+        }
+        problems.extend(self.conflicts());
+        for problem in problems {
+            eprintln!("Keymap: {}", problem);
+        }
+    }
+
+    // Bindings that can never be reached: a chord that starts with a key that's bound by
+    // itself, or a pane binding that the editor's bindings get to first
+    fn conflicts(&self) -> Vec<String> {
+        let mut problems = Vec::new();
+        let editor = self.sections.get("editor");
+        let mut names = self.sections.keys().collect::<Vec<&String>>();
+        names.sort();
+        for name in names {
+            let section = &self.sections[name];
+            for (keys, command) in section {
+                for i in 1..keys.len() {
+                    if let Some(other) = section.get(&keys[..i]) {
+                        problems.push(format!(
+                            "[{}] {} ({}) is hidden by {} ({})",
+                            name, keys.join(" "), command, keys[..i].join(" "), other
+                        ));
+                    }
+                }
+                if section_scope(name) == Scope::Editor {
+                    continue;
+                }
+                if let Some(other) = editor.and_then(|e| (1..=keys.len()).find_map(|i| e.get(&keys[..i]))) {
+                    problems.push(format!("[{}] {} ({}) is hidden by [editor] ({})", name, keys.join(" "), command, other));
+                }
+            }
+        }
+        problems
+    }
+
+    pub fn keymap(&self, section: &str) -> Keymap<&'static str> {
+        let mut keymap = Keymap::new();
+        if let Some(bindings) = self.sections.get(section) {
+            for (keys, command) in bindings {
+                keymap.bind(&keys.join(" "), *command);
+            }
+        }
+        keymap
+    }
+
+    // The editor's bindings with those for the input mode on top
+    pub fn editor_keymap(&self, mode: &str) -> Keymap<&'static str> {
+        let mut keymap = self.keymap("editor");
+        if let Some(bindings) = self.sections.get(mode) {
+            for (keys, command) in bindings {
+                keymap.bind(&keys.join(" "), *command);
+            }
+        }
+        keymap
+    }
+
+    // Reload if either file has changed since last time
+    pub fn check_for_changes(&mut self) -> bool {
+        if !self.files.changed() {
+            return false;
+        }
+        self.reload();
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Keymaps read from files with these contents, the last one winning
+    fn load(name: &str, files: &[&str]) -> Keymaps {
+        let paths = files
+            .iter()
+            .enumerate()
+            .map(|(i, contents)| {
+                let path = std::env::temp_dir().join(format!("sdleditor-{}-{}.keymap", name, i));
+                fs::write(&path, contents).unwrap();
+                path
+            })
+            .collect::<Vec<PathBuf>>();
+        let mut keymaps = Keymaps { files: FileWatcher::new(paths.clone()), sections: HashMap::new() };
+        keymaps.reload();
+        for path in paths {
+            fs::remove_file(path).unwrap();
+        }
+        keymaps
+    }
+
+    #[test]
+    fn normalizes_modifiers() {
+        assert_eq!(normalize_key("C-x"), "c-x");
+        assert_eq!(normalize_key("M-f"), "a-f");
+        assert_eq!(normalize_key("C-S-z"), "c-s-z");
+        assert_eq!(normalize_key("a-f9"), "a-f9");
+        assert_eq!(normalize_key("x"), "x");
+        assert_eq!(normalize_key("-"), "-");
+        assert_eq!(parse_chord(" C-x  c-S "), vec!["c-x", "c-S"]);
+    }
+
+    #[test]
+    fn matches_chords_key_by_key() {
+        let mut keymap = Keymap::new();
+        keymap.bind("c-x c-s", "save");
+        keymap.bind("c-x k", "close");
+        keymap.bind("c-s", "search");
+        assert!(matches!(keymap.feed("C-x"), KeyResult::Pending));
+        assert!(keymap.is_pending());
+        assert_eq!(keymap.pending_text(), "c-x -");
+        assert!(matches!(keymap.feed("c-s"), KeyResult::Matched("save")));
+        assert!(!keymap.is_pending());
+        assert!(matches!(keymap.feed("c-s"), KeyResult::Matched("search")));
+        keymap.feed("c-x");
+        match keymap.feed("j") {
+            KeyResult::Unbound(keys) => assert_eq!(keys, vec!["c-x", "j"]),
+            _ => panic!("c-x j isn't bound"),
+        }
+        assert!(matches!(keymap.feed("q"), KeyResult::Unbound(_)));
+        assert_eq!(keymap.chords_for(&"save"), vec!["c-x c-s"]);
+        assert_eq!(keymap.get("c-x k"), Some("close"));
+    }
+
+    #[test]
+    fn half_typed_chords_time_out() {
+        let mut keymap = Keymap::new();
+        keymap.bind("c-x c-s", "save");
+        keymap.bind("c-s", "search");
+        keymap.timeout = Duration::from_millis(0);
+        keymap.feed("c-x");
+        std::thread::sleep(Duration::from_millis(2));
+        // c-s could finish c-x c-s, but the prefix has expired so it's a key of its own
+        assert!(matches!(keymap.feed("c-s"), KeyResult::Matched("search")));
+        keymap.feed("c-x");
+        std::thread::sleep(Duration::from_millis(2));
+        keymap.expire();
+        assert!(!keymap.is_pending());
+    }
+
+    #[test]
+    fn finds_bindings_that_cant_be_reached() {
+        let keymaps = load("conflicts", &["[editor]\nc-o file.open\n[buffer]\nc-x cursor.up\nc-x c-x cursor.down\nc-o x cursor.left\n"]);
+        assert_eq!(
+            keymaps.conflicts(),
+            vec![
+                "[buffer] c-o x (cursor.left) is hidden by [editor] (file.open)",
+                "[buffer] c-x c-x (cursor.down) is hidden by c-x (cursor.up)",
+            ]
+        );
+        assert!(load("no-conflicts", &["[editor]\nc-o file.open\n[buffer]\nc-x c-x cursor.down\n"]).conflicts().is_empty());
+    }
+
+    #[test]
+    fn reports_lines_it_cant_read() {
+        let (lines, problems) = parse_keymap_file("# comment\n\n[buffer]\nc-z\n  C-x  c-s   buffer.save  \n");
+        assert_eq!(problems, vec!["line 4: expected a key and a command"]);
+        assert_eq!(lines.len(), 1);
+        assert_eq!((lines[0].line, lines[0].section.as_str()), (5, "buffer"));
+        assert_eq!(lines[0].keys, vec!["c-x", "c-s"]);
+        assert_eq!(lines[0].command, "buffer.save");
+        // Bindings before the first section are editor bindings
+        assert_eq!(parse_keymap_file("f2 mode.next").0[0].section, "editor");
+    }
+
+    #[test]
+    fn later_files_override_and_unbind() {
+        let keymaps = load(
+            "override",
+            &[
+                "[buffer]\nc-z edit.undo\nup cursor.up\n[editor]\nc-q editor.quit\n",
+                "[buffer]\nc-z cursor.down\nup none\n[editor]\nc-q none\n",
+            ],
+        );
+        let buffer = keymaps.keymap("buffer");
+        assert_eq!(buffer.get("c-z"), Some("cursor.down"));
+        assert_eq!(buffer.get("up"), None);
+        assert!(buffer.chords_for(&"edit.undo").is_empty());
+        assert_eq!(keymaps.keymap("editor").get("c-q"), None);
+    }
+
+    #[test]
+    fn skips_bindings_it_cant_use() {
+        let keymaps = load(
+            "invalid",
+            &["[buffer]\nc-a no.such.command\nc-b editor.quit\nc-c cursor.up\n[nowhere]\nc-d cursor.up\n"],
+        );
+        let buffer = keymaps.keymap("buffer");
+        assert_eq!((buffer.get("c-a"), buffer.get("c-b"), buffer.get("c-c")), (None, None, Some("cursor.up")));
+        assert!(!keymaps.sections.contains_key("nowhere"));
+    }
+}
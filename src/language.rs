@@ -0,0 +1,298 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
+
+use regex::Regex;
+
+use crate::config;
+use crate::indent::IndentStyle;
+use crate::settings;
+
+// A string's delimiters, and the character that escapes the next one inside it
+#[derive(Clone)]
+pub struct Delimiters {
+    pub open: String,
+    pub close: String,
+    pub escape: Option<String>,
+}
+
+// A language buffers can be in, from a .language file. Its ID is the file's name, and
+// picks the grammar, snippets, comment markers, brackets and indentation.
+#[derive(Clone, Default)]
+pub struct Language {
+    pub id: String,
+    // The scope name of the language's grammar
+    pub scope: String,
+    extensions: Vec<String>,
+    file_names: Vec<String>,
+    // Programs that run it from a #! line
+    interpreters: Vec<String>,
+    // Other names modelines use for it
+    aliases: Vec<String>,
+    // Line comment markers, longer ones first so "///" wins over "//"
+    pub line_comments: Vec<String>,
+    pub block_comment: Option<(String, String)>,
+    pub nested_comments: bool,
+    pub strings: Vec<Delimiters>,
+    pub keywords: Vec<String>,
+    pub types: Vec<String>,
+    pub constants: Vec<String>,
+    pub number: Option<String>,
+    pub brackets: Vec<(char, char)>,
+    pub indent: Option<IndentStyle>,
+    // Lines matching this get the line after them indented a level
+    pub indent_after: Option<Regex>,
+    // Whether to make a grammar from the comments, strings and so on when the language
+    // has no grammar file
+    pub highlight: bool,
+}
+
+impl Language {
+    // Language files are `name = value` lines, with # for comments. Lists are separated
+    // by spaces, and `string` can be given once for each kind of string:
+    //
+    //     extensions = go
+    //     line_comment = //
+    //     block_comment = /* */
+    //     string = " " \
+    //     keywords = break case chan const
+    //     brackets = () [] {}
+    //     indent = tabs
+    //
+    // `id` is the file's name without the extension.
+    pub fn parse(id: &str, text: &str) -> Result<Language, String> {
+        let mut language = Language {
+            id: id.to_string(),
+            scope: format!("source.{}", id),
+            highlight: true,
+            ..Default::default()
+        };
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (name, value) = match line.find('=') {
+                Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+                None => return Err(format!("line {}: expected name = value", i + 1)),
+            };
+            let list = || value.split_whitespace().map(|s| s.to_string()).collect::<Vec<String>>();
+            let error = |message: &str| format!("line {}: {}", i + 1, message);
+            match name {
+                "scope" => language.scope = value.to_string(),
+                "extensions" => language.extensions = list(),
+                "file_names" => language.file_names = list(),
+                "interpreters" => language.interpreters = list(),
+                "aliases" => language.aliases = list(),
+                "line_comment" => language.line_comments = list(),
+                "block_comment" => match &list()[..] {
+                    [open, close] => language.block_comment = Some((open.clone(), close.clone())),
+                    _ => return Err(error("expected block_comment = open close")),
+                },
+                "nested_comments" => language.nested_comments = value == "true",
+                "highlight" => language.highlight = value == "true",
+                "string" => match &list()[..] {
+                    [open, close] => language.strings.push(Delimiters { open: open.clone(), close: close.clone(), escape: None }),
+                    [open, close, escape] => language.strings.push(Delimiters { open: open.clone(), close: close.clone(), escape: Some(escape.clone()) }),
+                    _ => return Err(error("expected string = open close [escape]")),
+                },
+                "keywords" => language.keywords = list(),
+                "types" => language.types = list(),
+                "constants" => language.constants = list(),
+                "number" => language.number = Some(value.to_string()),
+                "brackets" => {
+                    language.brackets.clear();
+                    for pair in list() {
+                        match pair.chars().collect::<Vec<char>>()[..] {
+                            [open, close] => language.brackets.push((open, close)),
+                            _ => return Err(error("brackets are pairs of characters, like ()")),
+                        }
+                    }
+                }
+                "indent" => {
+                    language.indent = match value {
+                        "tabs" => Some(IndentStyle::Tabs),
+                        _ => Some(IndentStyle::Spaces(settings::parse_count(value, 1, 16).map_err(|e| error(&e))?)),
+                    }
+                }
+                "indent_after" => language.indent_after = Some(Regex::new(value).map_err(|e| error(&e.to_string()))?),
+                _ => return Err(error(&format!("unknown setting {}", name))),
+            }
+        }
+        // Longer markers have to be tried first
+        language.line_comments.sort_by_key(|m| std::cmp::Reverse(m.len()));
+        Ok(language)
+    }
+
+    // The characters typing one of inserts its partner: brackets, and strings that start
+    // and end with a single character
+    pub fn pairs(&self) -> Vec<(char, char)> {
+        let mut pairs = self.brackets.clone();
+        for string in &self.strings {
+            let mut open = string.open.chars();
+            let mut close = string.close.chars();
+            if let (Some(o), None, Some(c), None) = (open.next(), open.next(), close.next(), close.next()) {
+                if !pairs.contains(&(o, c)) {
+                    pairs.push((o, c));
+                }
+            }
+        }
+        pairs
+    }
+}
+
+// Language files from data/languages and the user's languages directory. A user file
+// with the same name as a bundled one replaces it.
+pub fn language_files() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data/languages")];
+    if let Some(dir) = config::config_dir() {
+        dirs.push(dir.join("languages"));
+    }
+    let mut files: Vec<(String, PathBuf)> = Vec::new();
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            if path.extension().and_then(|e| e.to_str()) != Some("language") {
+                continue;
+            }
+            let name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+            files.retain(|(n, _)| *n != name);
+            files.push((name, path));
+        }
+    }
+    files.sort();
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+fn load() -> Vec<Language> {
+    let mut languages = Vec::new();
+    for path in language_files() {
+        let id = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        match fs::read_to_string(&path).map_err(|e| e.to_string()).and_then(|text| Language::parse(&id, &text)) {
+            Ok(language) => languages.push(language),
+            Err(e) => eprintln!("Language: {}: {}", path.display(), e),
+        }
+    }
+    languages
+}
+
+// Every language, loaded the first time it's asked for. Buffers only store their
+// language's ID, and whatever deals with one looks the definition up here.
+pub fn languages() -> &'static [Language] {
+    static LANGUAGES: OnceLock<Vec<Language>> = OnceLock::new();
+    LANGUAGES.get_or_init(load)
+}
+
+pub fn get(id: &str) -> Option<&'static Language> {
+    languages().iter().find(|l| l.id == id)
+}
+
+// A language by its ID or one of the other names modelines use
+fn by_name(name: &str) -> Option<&'static Language> {
+    let name = name.to_lowercase();
+    languages().iter().find(|l| l.id == name || l.aliases.contains(&name))
+}
+
+// The language named by a Vim (`vim: set ft=rust:`) or Emacs (`-*- mode: rust -*-`)
+// modeline in the first or last five lines
+fn modeline(lines: &[String]) -> Option<&'static Language> {
+    let tail = lines.len().saturating_sub(5).max(5.min(lines.len()));
+    for line in lines[..5.min(lines.len())].iter().chain(&lines[tail..]) {
+        for marker in &["vim:", "vi:", "ex:"] {
+            let start = match line.find(marker) {
+                Some(i) if i == 0 || line[..i].ends_with(char::is_whitespace) => i + marker.len(),
+                _ => continue,
+            };
+            let options = line[start..].split(|c: char| c == ':' || c.is_whitespace());
+            for option in options {
+                let name = ["ft=", "filetype=", "syntax=", "syn="].iter().find_map(|o| option.strip_prefix(o));
+                if let Some(language) = name.and_then(by_name) {
+                    return Some(language);
+                }
+            }
+        }
+        let mut parts = line.splitn(3, "-*-");
+        if let (Some(_), Some(inside), Some(_)) = (parts.next(), parts.next(), parts.next()) {
+            let name = match inside.contains(':') {
+                true => inside.split(';').find_map(|v| v.trim().strip_prefix("mode:")).unwrap_or(""),
+                false => inside,
+            };
+            if let Some(language) = by_name(name.trim()) {
+                return Some(language);
+            }
+        }
+    }
+    None
+}
+
+// The language of the program a #! line runs, looking through `env`
+fn shebang(line: &str) -> Option<&'static Language> {
+    let mut words = line.strip_prefix("#!")?.split_whitespace();
+    let mut program = words.next()?.rsplit('/').next()?;
+    if program == "env" {
+        program = words.find(|w| !w.starts_with('-') && !w.contains('='))?;
+    }
+    // python3.11 runs python
+    let program = program.trim_end_matches(|c: char| c.is_ascii_digit() || c == '.');
+    languages().iter().find(|l| l.interpreters.iter().any(|i| i == program))
+}
+
+// The language of a file, from a modeline, the file name, the extension or a #! line,
+// in that order. Anything else is "text".
+pub fn detect(path: &str, lines: &[String]) -> &'static str {
+    let path = Path::new(path);
+    let name = path.file_name().and_then(|n| n.to_str()).unwrap_or("");
+    let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("").to_lowercase();
+    modeline(lines)
+        .or_else(|| languages().iter().find(|l| l.file_names.iter().any(|n| n == name)))
+        .or_else(|| languages().iter().find(|l| l.extensions.contains(&ext)))
+        .or_else(|| lines.first().and_then(|l| shebang(l)))
+        .map_or("text", |l| &l.id)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_indentation() {
+        assert_eq!(Language::parse("go", "indent = tabs").unwrap().indent, Some(IndentStyle::Tabs));
+        assert_eq!(Language::parse("py", "indent = 4").unwrap().indent, Some(IndentStyle::Spaces(4)));
+        assert!(Language::parse("x", "indent = 0").is_err());
+        assert!(Language::parse("x", "indent = 17").is_err());
+        assert!(Language::parse("x", "indent = wide").is_err());
+    }
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn detects_languages_by_file_name_and_extension() {
+        assert_eq!(detect("src/main.rs", &[]), "rust");
+        assert_eq!(detect("/tmp/SCRIPT.PY", &[]), "python");
+        assert_eq!(detect("project/Makefile", &[]), "make");
+        assert_eq!(detect("Dockerfile", &[]), "dockerfile");
+    }
+
+    #[test]
+    fn detects_languages_by_first_line() {
+        assert_eq!(detect("run", &lines(&["#!/usr/bin/env python3.11", "print()"])), "python");
+        assert_eq!(detect("run", &lines(&["#!/usr/bin/env -S VAR=1 bash -e"])), "shell");
+        assert_eq!(detect("run", &lines(&["#!/bin/sh"])), "shell");
+        // A modeline wins over the extension
+        assert_eq!(detect("notes.txt", &lines(&["# vim: set ft=rust:"])), "rust");
+        assert_eq!(detect("build", &lines(&["# -*- mode: Python; coding: utf-8 -*-"])), "python");
+    }
+
+    #[test]
+    fn falls_back_to_text() {
+        assert_eq!(detect("README", &[]), "text");
+        assert_eq!(detect("data.unknown", &lines(&["#!/usr/bin/perl"])), "text");
+        assert_eq!(detect("", &lines(&[""])), "text");
+    }
+}
@@ -0,0 +1,211 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+
+// How many columns a grapheme takes starting at `column`. Tabs reach the next tab stop
+// and everything else is one column, since text is monospaced.
+fn cell_width(g: &str, column: usize, tab_width: usize) -> usize {
+    if g == "\t" {
+        tab_width - column % tab_width
+    } else {
+        1
+    }
+}
+
+// Where each grapheme of a line goes on screen
+pub struct LineLayout {
+    // The row, counting from the line's first, and the column of each grapheme, then of
+    // the space after the last one where the cursor sits at the end of the line
+    pub cells: Vec<(usize, usize)>,
+    pub rows: usize,
+}
+
+impl LineLayout {
+    // A grapheme that doesn't fit on what's left of a row starts the next one, unless
+    // lines don't wrap, in which case everything is on one row however long it is
+    pub fn new(line: &str, width: usize, wrap: bool, tab_width: usize) -> Self {
+        let mut cells = Vec::new();
+        let (mut row, mut column) = (0, 0);
+        for g in line.graphemes(true).chain(std::iter::once(" ")) {
+            if wrap && column > 0 && column + cell_width(g, column, tab_width) > width {
+                row += 1;
+                column = 0;
+            }
+            cells.push((row, column));
+            column += cell_width(g, column, tab_width);
+        }
+        LineLayout { cells, rows: row + 1 }
+    }
+
+    // The grapheme at `column` of `row`. Past the end of a row that's the row's last
+    // grapheme, which on the last row is the end of the line.
+    pub fn index_at(&self, row: usize, column: usize) -> usize {
+        let row = row.min(self.rows - 1);
+        let first = self.cells.partition_point(|c| c.0 < row);
+        let end = self.cells.partition_point(|c| c.0 <= row);
+        first + self.cells[first..end].partition_point(|c| c.1 <= column).max(1) - 1
+    }
+}
+
+// The display rows of a whole buffer. Lines are laid out when they're first needed and
+// kept until they're edited, like the highlighting.
+#[derive(Default)]
+pub struct Layout {
+    buffer_id: Option<usize>,
+    version: u64,
+    width: usize,
+    wrap: bool,
+    tab_width: usize,
+    lines: Vec<Option<LineLayout>>,
+    // The first row of each line, for as many lines from the top as are known
+    starts: Vec<usize>,
+}
+
+impl Layout {
+    // Catch up with the edits made since the last sync. Starts over if the buffer, the
+    // width or the tab width changed, or the edits are no longer in the change log.
+    pub fn sync(&mut self, buffer_id: usize, buffer: &Buffer, width: usize, wrap: bool, tab_width: usize) {
+        let changes = if (self.buffer_id, self.width, self.wrap, self.tab_width) == (Some(buffer_id), width, wrap, tab_width) {
+            buffer.changes_since(self.version)
+        } else {
+            None
+        };
+        match changes {
+            Some(changes) => {
+                for change in &changes {
+                    let y = change.old_range.start.y.min(self.lines.len());
+                    let old_end = (change.old_range.end.y + 1).clamp(y, self.lines.len());
+                    let new_lines = change.new_range.end.y - change.new_range.start.y + 1;
+                    self.lines.splice(y..old_end, (0..new_lines).map(|_| None));
+                    // Where the edited line starts doesn't change, only the ones after it
+                    self.starts.truncate(y + 1);
+                }
+            }
+            None => {
+                self.buffer_id = Some(buffer_id);
+                self.width = width;
+                self.wrap = wrap;
+                self.tab_width = tab_width;
+                self.lines.clear();
+                self.starts.clear();
+            }
+        }
+        self.lines.resize_with(buffer.len(), || None);
+        self.starts.truncate(self.lines.len() + 1);
+        self.version = buffer.version();
+    }
+
+    pub fn line(&mut self, buffer: &Buffer, y: usize) -> &LineLayout {
+        let (width, wrap, tab_width) = (self.width, self.wrap, self.tab_width);
+        self.lines[y].get_or_insert_with(|| LineLayout::new(&buffer.contents[y], width, wrap, tab_width))
+    }
+
+    // The first row of line `y`. The line after the last one starts after the last row.
+    pub fn line_start(&mut self, buffer: &Buffer, y: usize) -> usize {
+        while self.starts.len() <= y {
+            let i = self.starts.len();
+            let start = match i {
+                0 => 0,
+                _ => self.starts[i - 1] + self.line(buffer, i - 1).rows,
+            };
+            self.starts.push(start);
+        }
+        self.starts[y]
+    }
+
+    pub fn row_count(&mut self, buffer: &Buffer) -> usize {
+        self.line_start(buffer, self.lines.len())
+    }
+
+    // The row and column grapheme `x` of line `y` is drawn at
+    pub fn display_position(&mut self, buffer: &Buffer, x: usize, y: usize) -> (usize, usize) {
+        let start = self.line_start(buffer, y);
+        let line = self.line(buffer, y);
+        let (row, column) = line.cells[x.min(line.cells.len() - 1)];
+        (start + row, column)
+    }
+
+    // The buffer position at a row and column, as (x, y). Rows past the end are on the
+    // last row.
+    pub fn buffer_position(&mut self, buffer: &Buffer, row: usize, column: usize) -> (usize, usize) {
+        if self.lines.is_empty() {
+            return (0, 0);
+        }
+        let row = row.min(self.row_count(buffer) - 1);
+        let y = self.starts.partition_point(|s| *s <= row) - 1;
+        let start = self.starts[y];
+        (self.line(buffer, y).index_at(row - start, column), y)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wraps_lines() {
+        let line = LineLayout::new("abcdefghij", 4, true, 4);
+        assert_eq!(line.rows, 3);
+        assert_eq!(line.cells[4], (1, 0));
+        assert_eq!(line.cells[10], (2, 2));
+        // The end of a line that exactly fills a row goes on the next one
+        let line = LineLayout::new("abc", 4, true, 4);
+        assert_eq!((line.rows, line.cells[3]), (1, (0, 3)));
+        let line = LineLayout::new("abcd", 4, true, 4);
+        assert_eq!((line.rows, line.cells[4]), (2, (1, 0)));
+        let line = LineLayout::new("abcdefghij", 4, false, 4);
+        assert_eq!((line.rows, line.cells[10]), (1, (0, 10)));
+    }
+
+    #[test]
+    fn tabs_reach_the_next_stop() {
+        let line = LineLayout::new("a\tb\tc", 6, true, 4);
+        assert_eq!(line.cells, vec![(0, 0), (0, 1), (0, 4), (1, 0), (1, 4), (1, 5)]);
+        assert_eq!(line.index_at(0, 2), 1);
+        assert_eq!(line.index_at(0, 9), 2);
+        assert_eq!(line.index_at(1, 9), 5);
+        let line = LineLayout::new("\tx", 20, true, 8);
+        assert_eq!(line.cells[1], (0, 8));
+    }
+
+    #[test]
+    fn maps_between_buffer_and_display_positions() {
+        let buffer = Buffer::from_lines(["abcdefghij", "", "xy"]);
+        let mut layout = Layout::default();
+        layout.sync(0, &buffer, 4, true, 4);
+        assert_eq!(layout.row_count(&buffer), 5);
+        assert_eq!(layout.display_position(&buffer, 9, 0), (2, 1));
+        assert_eq!(layout.display_position(&buffer, 0, 1), (3, 0));
+        assert_eq!(layout.display_position(&buffer, 1, 2), (4, 1));
+        // Past the end of a line is its last grapheme
+        assert_eq!(layout.display_position(&buffer, 99, 2), (4, 2));
+        assert_eq!(layout.buffer_position(&buffer, 1, 2), (6, 0));
+        assert_eq!(layout.buffer_position(&buffer, 3, 7), (0, 1));
+        // Rows past the end are on the last row
+        assert_eq!(layout.buffer_position(&buffer, 9, 9), (2, 2));
+        for (x, y) in [(0, 0), (5, 0), (10, 0), (0, 1), (0, 2), (2, 2)] {
+            let (row, column) = layout.display_position(&buffer, x, y);
+            assert_eq!(layout.buffer_position(&buffer, row, column), (x, y));
+        }
+    }
+
+    #[test]
+    fn follows_edits() {
+        let mut buffer = Buffer::from_lines(["abcdefghij", "", "xy"]);
+        let mut layout = Layout::default();
+        layout.sync(0, &buffer, 4, true, 4);
+        assert_eq!(layout.row_count(&buffer), 5);
+        buffer.insert_text(0, 1, "0123456".to_string());
+        layout.sync(0, &buffer, 4, true, 4);
+        assert_eq!(layout.row_count(&buffer), 6);
+        assert_eq!(layout.display_position(&buffer, 0, 2), (5, 0));
+        buffer.insert_text(2, 0, "\n".to_string());
+        layout.sync(0, &buffer, 4, true, 4);
+        assert_eq!(layout.row_count(&buffer), 7);
+        assert_eq!(layout.display_position(&buffer, 0, 1), (1, 0));
+        assert_eq!(layout.buffer_position(&buffer, 6, 0), (0, 3));
+        // A new width starts over
+        layout.sync(0, &buffer, 20, true, 4);
+        assert_eq!(layout.row_count(&buffer), 4);
+    }
+}
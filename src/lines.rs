@@ -0,0 +1,324 @@
+use std::cmp::{min, Ordering};
+use std::collections::HashSet;
+use std::iter::Peekable;
+use std::str::Chars;
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+use crate::indent;
+
+#[derive(Clone, Copy, Default)]
+pub struct SortOptions {
+    pub natural: bool,
+    pub case_insensitive: bool,
+    pub numeric: bool,
+    pub reverse: bool,
+}
+
+// Line-level editing commands. Each one operates on the lines touched by the
+// selection (or the cursor line) and is a single undo step.
+impl Buffer {
+    fn replace_lines(&mut self, y1: usize, y2: usize, lines: Vec<String>) {
+        let x2 = self.line_len(y2);
+        self.replace_text(0, y1, x2, y2, lines.join("\n"));
+    }
+
+    fn clamp_selection(&mut self) {
+        self.cursor_y = min(self.cursor_y, self.len() - 1);
+        self.sel_y = min(self.sel_y, self.len() - 1);
+        self.cursor_x = min(self.cursor_x, self.line_len(self.cursor_y));
+        self.sel_x = min(self.sel_x, self.line_len(self.sel_y));
+    }
+
+    pub fn move_lines_up(&mut self) {
+        let (y1, y2) = self.selected_lines();
+        if y1 == 0 {
+            return;
+        }
+        let mut lines = self.contents[y1 - 1..=y2].to_vec();
+        lines.rotate_left(1);
+        self.replace_lines(y1 - 1, y2, lines);
+        self.cursor_y -= 1;
+        self.sel_y -= 1;
+    }
+
+    pub fn move_lines_down(&mut self) {
+        let (y1, y2) = self.selected_lines();
+        if y2 + 1 >= self.len() {
+            return;
+        }
+        let mut lines = self.contents[y1..=y2 + 1].to_vec();
+        lines.rotate_right(1);
+        self.replace_lines(y1, y2 + 1, lines);
+        self.cursor_y += 1;
+        self.sel_y += 1;
+    }
+
+    pub fn duplicate_lines(&mut self) {
+        let (y1, y2) = self.selected_lines();
+        let text = format!("\n{}", self.contents[y1..=y2].join("\n"));
+        let x = self.line_len(y2);
+        self.insert_text(x, y2, text);
+        let count = y2 - y1 + 1;
+        self.cursor_y += count;
+        self.sel_y += count;
+    }
+
+    pub fn delete_lines(&mut self) {
+        let (y1, y2) = self.selected_lines();
+        if y2 + 1 < self.len() {
+            self.delete_text(0, y1, 0, y2 + 1);
+        } else if y1 > 0 {
+            // Last line of the buffer, so take the newline before it instead
+            let x1 = self.line_len(y1 - 1);
+            let x2 = self.line_len(y2);
+            self.delete_text(x1, y1 - 1, x2, y2);
+        } else {
+            let x2 = self.line_len(y2);
+            self.delete_text(0, 0, x2, y2);
+        }
+        self.cursor_y = min(y1, self.len() - 1);
+        self.cursor_x = min(self.max_cursor_x, self.line_len(self.cursor_y));
+        self.set_selection(false);
+    }
+
+    // Join the selected lines, or the cursor line with the one after it. Indentation and
+    // trailing whitespace at the seams is dropped, and the pieces are separated by a
+    // single space unless the next piece starts with a closing bracket.
+    pub fn join_lines(&mut self) {
+        let (x1, y1, _x2, y2) = self.get_selection();
+        let had_selection = y1 != y2;
+        let y2 = if had_selection {
+            y2
+        } else if y2 + 1 < self.len() {
+            y2 + 1
+        } else {
+            return;
+        };
+
+        let mut joined = self.contents[y1].trim_end().to_string();
+        let mut join_x = joined.graphemes(true).count();
+        for line in &self.contents[y1 + 1..=y2] {
+            let piece = line.trim();
+            join_x = joined.graphemes(true).count();
+            if piece.is_empty() {
+                continue;
+            }
+            let starts_with_closer = piece.chars().next().is_some_and(indent::is_close_bracket);
+            if !joined.trim().is_empty() && !starts_with_closer {
+                joined.push(' ');
+            }
+            joined.push_str(piece);
+        }
+        self.replace_lines(y1, y2, vec![joined]);
+
+        if had_selection {
+            self.sel_x = min(x1, self.line_len(y1));
+            self.sel_y = y1;
+            self.cursor_x = self.line_len(y1);
+        } else {
+            self.cursor_x = join_x;
+            self.set_selection(false);
+        }
+        self.cursor_y = y1;
+        self.max_cursor_x = self.cursor_x;
+    }
+
+    pub fn sort_lines(&mut self, options: SortOptions) {
+        let (y1, y2) = self.selected_lines();
+        if y1 == y2 {
+            return;
+        }
+        let mut lines = self.contents[y1..=y2].to_vec();
+        lines.sort_by(|a, b| compare_lines(a, b, options));
+        if options.reverse {
+            lines.reverse();
+        }
+        if lines[..] != self.contents[y1..=y2] {
+            self.replace_lines(y1, y2, lines);
+        }
+        self.clamp_selection();
+    }
+
+    // Remove repeated lines from the selection, keeping the first occurrence of each
+    pub fn unique_lines(&mut self) {
+        let (y1, y2) = self.selected_lines();
+        let mut seen = HashSet::new();
+        let lines = self.contents[y1..=y2]
+            .iter()
+            .filter(|line| seen.insert(line.as_str()))
+            .cloned()
+            .collect::<Vec<String>>();
+        let removed = y2 - y1 + 1 - lines.len();
+        if removed == 0 {
+            return;
+        }
+        self.replace_lines(y1, y2, lines);
+        if self.cursor_y > self.sel_y || self.cursor_y == self.sel_y && self.cursor_x > self.sel_x {
+            self.cursor_y -= removed;
+        } else {
+            self.sel_y -= removed;
+        }
+        self.clamp_selection();
+    }
+}
+
+fn compare_lines(a: &str, b: &str, options: SortOptions) -> Ordering {
+    let (a, b) = if options.case_insensitive {
+        (a.to_lowercase(), b.to_lowercase())
+    } else {
+        (a.to_string(), b.to_string())
+    };
+    if options.numeric {
+        // Like `sort -n`, lines that don't start with a number go first
+        match (leading_number(&a), leading_number(&b)) {
+            (Some(x), Some(y)) => {
+                let ordering = x.partial_cmp(&y).unwrap_or(Ordering::Equal);
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(_), None) => return Ordering::Greater,
+            (None, Some(_)) => return Ordering::Less,
+            (None, None) => {}
+        }
+    }
+    if options.natural {
+        natural_cmp(&a, &b)
+    } else {
+        a.cmp(&b)
+    }
+}
+
+fn leading_number(s: &str) -> Option<f64> {
+    let s = s.trim_start();
+    let end = s
+        .char_indices()
+        .take_while(|(i, c)| c.is_ascii_digit() || *c == '.' || (*i == 0 && (*c == '-' || *c == '+')))
+        .map(|(i, c)| i + c.len_utf8())
+        .last()?;
+    s[..end].parse().ok()
+}
+
+fn take_digits(chars: &mut Peekable<Chars>) -> String {
+    let mut digits = String::new();
+    while let Some(c) = chars.peek().copied() {
+        if !c.is_ascii_digit() {
+            break;
+        }
+        digits.push(c);
+        chars.next();
+    }
+    digits
+}
+
+// Compare strings with runs of digits ordered by their value, so "file2" comes before "file10"
+fn natural_cmp(a: &str, b: &str) -> Ordering {
+    let mut a = a.chars().peekable();
+    let mut b = b.chars().peekable();
+    loop {
+        match (a.peek().copied(), b.peek().copied()) {
+            (None, None) => return Ordering::Equal,
+            (None, Some(_)) => return Ordering::Less,
+            (Some(_), None) => return Ordering::Greater,
+            (Some(x), Some(y)) if x.is_ascii_digit() && y.is_ascii_digit() => {
+                let x = take_digits(&mut a);
+                let y = take_digits(&mut b);
+                let xt = x.trim_start_matches('0');
+                let yt = y.trim_start_matches('0');
+                let ordering = xt
+                    .len()
+                    .cmp(&yt.len())
+                    .then_with(|| xt.cmp(yt))
+                    .then_with(|| x.len().cmp(&y.len()));
+                if ordering != Ordering::Equal {
+                    return ordering;
+                }
+            }
+            (Some(x), Some(y)) => {
+                if x != y {
+                    return x.cmp(&y);
+                }
+                a.next();
+                b.next();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Select from the start of line y1 to the end of line y2
+    fn select(buffer: &mut Buffer, y1: usize, y2: usize) {
+        buffer.sel_x = 0;
+        buffer.sel_y = y1;
+        buffer.cursor_y = y2;
+        buffer.cursor_x = buffer.line_len(y2);
+    }
+
+    #[test]
+    fn sort_plain() {
+        let mut b = Buffer::from_lines(["pear", "Apple", "banana"]);
+        select(&mut b, 0, 2);
+        b.sort_lines(SortOptions::default());
+        assert_eq!(b.contents, vec!["Apple", "banana", "pear"]);
+    }
+
+    #[test]
+    fn sort_natural_case_insensitive() {
+        let mut b = Buffer::from_lines(["f10", "F2", "f1", "f2"]);
+        select(&mut b, 0, 3);
+        b.sort_lines(SortOptions { natural: true, case_insensitive: true, ..Default::default() });
+        assert_eq!(b.contents, vec!["f1", "F2", "f2", "f10"]);
+    }
+
+    #[test]
+    fn sort_numeric_and_reverse() {
+        let mut b = Buffer::from_lines(["10 a", "9 b", "x", "-1"]);
+        select(&mut b, 0, 3);
+        b.sort_lines(SortOptions { numeric: true, ..Default::default() });
+        assert_eq!(b.contents, vec!["x", "-1", "9 b", "10 a"]);
+        b.sort_lines(SortOptions { numeric: true, reverse: true, ..Default::default() });
+        assert_eq!(b.contents, vec!["10 a", "9 b", "-1", "x"]);
+    }
+
+    #[test]
+    fn sort_only_the_selected_lines() {
+        let mut b = Buffer::from_lines(["c", "b", "a", "0"]);
+        select(&mut b, 0, 2);
+        b.sort_lines(SortOptions::default());
+        assert_eq!(b.contents, vec!["a", "b", "c", "0"]);
+        b.undo();
+        assert_eq!(b.contents, vec!["c", "b", "a", "0"]);
+    }
+
+    #[test]
+    fn unique_keeps_first_occurrence() {
+        let mut b = Buffer::from_lines(["a", "b", "a", "b", "c"]);
+        select(&mut b, 0, 4);
+        b.unique_lines();
+        assert_eq!(b.contents, vec!["a", "b", "c"]);
+        assert_eq!((b.sel_y, b.cursor_y, b.cursor_x), (0, 2, 1));
+        b.undo();
+        assert_eq!(b.contents.len(), 5);
+    }
+
+    #[test]
+    fn unique_without_repeats_does_nothing() {
+        let mut b = Buffer::from_lines(["a", "b"]);
+        select(&mut b, 0, 1);
+        let version = b.version();
+        b.unique_lines();
+        assert_eq!(b.version(), version);
+    }
+
+    #[test]
+    fn natural_order() {
+        assert_eq!(natural_cmp("file2", "file10"), Ordering::Less);
+        assert_eq!(natural_cmp("a01", "a1"), Ordering::Greater);
+        assert_eq!(natural_cmp("b", "a9"), Ordering::Greater);
+    }
+}
@@ -0,0 +1,161 @@
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::PathBuf;
+
+use crate::config;
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum MacroEvent {
+    Key(String),
+    Text(String),
+}
+
+pub type Macro = Vec<MacroEvent>;
+
+// After c-f3 / c-f4 the next key names the macro to save or play
+pub enum MacroPrompt {
+    Save,
+    Play,
+}
+
+pub struct Macros {
+    pub recording: Option<Macro>,
+    pub last: Macro,
+    pub named: BTreeMap<String, Macro>,
+    pub prompt: Option<MacroPrompt>,
+    // Repeat count typed with a-0 .. a-9 before replaying
+    pub repeat: Option<usize>,
+    pub replaying: bool,
+}
+
+// Keys that only move the cursor. If one of these can't move during a replay we've
+// hit the end of the buffer and the replay stops.
+pub fn is_motion(kstr: &str) -> bool {
+    let key = kstr.trim_start_matches("s-").trim_start_matches("c-").trim_start_matches("s-");
+    matches!(key, "up" | "down" | "left" | "right" | "pageup" | "pagedown")
+}
+
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('\n', "\\n").replace('\t', "\\t")
+}
+
+fn unescape(s: &str) -> String {
+    let mut result = String::new();
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(c) => result.push(c),
+                None => result.push('\\'),
+            }
+        } else {
+            result.push(c);
+        }
+    }
+    result
+}
+
+// The macro file is a list of blocks like:
+//
+//   macro q
+//   key c-right
+//   text ;
+//   end
+pub fn parse_macros(text: &str) -> BTreeMap<String, Macro> {
+    let mut macros = BTreeMap::new();
+    let mut current: Option<(String, Macro)> = None;
+    for line in text.lines() {
+        if let Some(name) = line.strip_prefix("macro ") {
+            current = Some((unescape(name), Vec::new()));
+        } else if line == "end" {
+            if let Some((name, events)) = current.take() {
+                macros.insert(name, events);
+            }
+        } else if let Some((_, events)) = current.as_mut() {
+            if let Some(key) = line.strip_prefix("key ") {
+                events.push(MacroEvent::Key(unescape(key)));
+            } else if let Some(text) = line.strip_prefix("text ") {
+                events.push(MacroEvent::Text(unescape(text)));
+            }
+        }
+    }
+    macros
+}
+
+pub fn format_macros(macros: &BTreeMap<String, Macro>) -> String {
+    let mut text = String::new();
+    for (name, events) in macros {
+        text.push_str(&format!("macro {}\n", escape(name)));
+        for event in events {
+            match event {
+                MacroEvent::Key(k) => text.push_str(&format!("key {}\n", escape(k))),
+                MacroEvent::Text(t) => text.push_str(&format!("text {}\n", escape(t))),
+            }
+        }
+        text.push_str("end\n");
+    }
+    text
+}
+
+impl Macros {
+    pub fn new() -> Self {
+        let named = match Self::path().and_then(|p| fs::read_to_string(p).ok()) {
+            Some(text) => parse_macros(&text),
+            None => BTreeMap::new(),
+        };
+        Self {
+            recording: None,
+            last: Vec::new(),
+            named,
+            prompt: None,
+            repeat: None,
+            replaying: false,
+        }
+    }
+
+    fn path() -> Option<PathBuf> {
+        config::config_dir().map(|dir| dir.join("macros"))
+    }
+
+    pub fn record(&mut self, event: MacroEvent) {
+        if self.replaying {
+            return;
+        }
+        if let Some(events) = self.recording.as_mut() {
+            events.push(event);
+        }
+    }
+
+    pub fn toggle_recording(&mut self) {
+        match self.recording.take() {
+            Some(events) => self.last = events,
+            None => self.recording = Some(Vec::new()),
+        }
+    }
+
+    pub fn push_repeat_digit(&mut self, digit: usize) {
+        self.repeat = Some(self.repeat.unwrap_or(0).saturating_mul(10).saturating_add(digit));
+    }
+
+    pub fn take_repeat(&mut self) -> usize {
+        self.repeat.take().unwrap_or(1)
+    }
+
+    // Save the last recorded macro under `name`, both in memory and on disk
+    pub fn save(&mut self, name: &str) {
+        if self.last.is_empty() {
+            return;
+        }
+        self.named.insert(name.to_string(), self.last.clone());
+        if let Some(path) = Self::path() {
+            if let Some(dir) = path.parent() {
+                let _ = fs::create_dir_all(dir);
+            }
+            if let Err(e) = fs::write(&path, format_macros(&self.named)) {
+                eprintln!("Couldn't save macros to {}: {}", path.display(), e);
+            }
+        }
+    }
+}
@@ -1,9 +1,9 @@
 use std::env;
 use std::path::{PathBuf, Path};
-use std::thread::sleep;
-use std::time::{Duration, Instant};
+use std::rc::Rc;
+use std::time::Duration;
 
-use pgfx::{Engine, Texture, Color, Rect, Point};
+use pgfx::{Engine, Color, Rect};
 
 mod pane;
 use pane::{Pane, PaneType};
@@ -11,12 +11,80 @@ use pane::{Pane, PaneType};
 mod buffer;
 use buffer::Buffer;
 
+mod application;
+use application::{DefaultMode, Mode, MouseEvent};
+
+mod vim;
+use vim::Vim;
+
+mod keymap;
+use keymap::{KeyResult, Keymap, Keymaps};
+
+mod command;
+use command::{Palette, PaletteKey, Scope};
+
+mod emacs;
+use emacs::Emacs;
+
+mod change;
+use change::Position;
+
 mod file_manager;
 use file_manager::FileManager;
 
-fn select_font() -> Option<PathBuf> {
-    Some(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fonts/monospace.ttf"))
-}
+mod config;
+use config::FileWatcher;
+
+mod settings;
+use settings::Settings;
+
+mod json;
+
+mod plist;
+
+mod theme;
+use theme::Theme;
+
+mod yaml;
+
+mod grammar;
+use grammar::GrammarLibrary;
+
+mod highlight;
+
+mod layout;
+
+mod gutter;
+
+mod status;
+
+mod encoding;
+
+mod language;
+
+mod render;
+use render::{Headless, Renderer};
+
+mod terminal;
+use terminal::{Input, Terminal};
+
+mod indent;
+
+mod lines;
+
+mod case;
+
+mod reflow;
+
+mod autopair;
+
+mod snippet;
+
+mod completion;
+use completion::{Completion, CompletionKey};
+
+mod macros;
+use macros::{MacroEvent, MacroPrompt, Macros};
 
 fn next(idx: usize, len: usize) -> usize {
     (idx + 1) % len
@@ -37,21 +105,56 @@ struct Editor {
     window_height: f32,
     font_size: f32,
     should_quit: bool,
+
+    settings: Settings,
+    settings_files: FileWatcher,
+    theme: Theme,
+    grammars: Rc<GrammarLibrary>,
+
+    macros: Macros,
+    // Set when something goes wrong that should stop a macro replay
+    macro_failed: bool,
+
+    mode: Box<dyn Mode>,
+
+    keymaps: Keymaps,
+    // Editor-wide bindings, to command names
+    keys: Keymap<&'static str>,
+    palette: Option<Palette>,
 }
 
 impl Editor {
-    fn draw(&mut self, app: &mut Engine) {
+    fn draw(&mut self, app: &mut dyn Renderer) {
         app.clear(Color::new(0, 0, 0));
+        let mut status = if self.keys.is_pending() { self.keys.pending_text() } else { self.mode.status() };
+        if status.is_empty() && self.mode.name() != "default" {
+            status = self.mode.name().to_string();
+        }
         for (j, pane) in &mut self.panes.iter_mut().enumerate() {
-            pane.draw(app, &self.buffers[pane.buffer_id], j == self.pane_idx);
+            pane.draw(app, &self.buffers[pane.buffer_id], j == self.pane_idx, &status);
+        }
+        if let Some(palette) = &self.palette {
+            let pane = &self.panes[self.pane_idx];
+            let bindings = palette.items.iter().map(|c| self.binding_text(c.name, c.scope)).collect::<Vec<String>>();
+            pane.draw_palette(app, palette, &bindings);
         }
     }
 
+    // The keys that run a command, for showing next to it
+    fn binding_text(&self, name: &'static str, scope: Scope) -> String {
+        let chords = match scope {
+            Scope::Editor => self.keys.chords_for(&name),
+            Scope::Buffer => self.panes[self.pane_idx].keys.chords_for(&name),
+            Scope::FileManager => self.fm.keys.chords_for(&name),
+        };
+        chords.join(", ")
+    }
+
     fn arrange(&mut self) {
         let w = self.window_width;
         let h = self.window_height;
 
-        let padding = 5.0;
+        let padding = self.settings.padding;
         let pane_width = (w / self.panes.len() as f32).floor();
         let pane_height = h;
         let mut x = 0.0;
@@ -91,11 +194,16 @@ impl Editor {
         } else {
             0
         };
-        self.panes.push(Pane::new(
+        let mut pane = Pane::new(
             PaneType::Buffer,
             buffer_id,
             self.font_size,
-        ));
+        );
+        pane.keys = self.keymaps.keymap("buffer");
+        pane.apply_settings(&self.settings);
+        pane.theme = self.theme.clone();
+        pane.grammars = self.grammars.clone();
+        self.panes.push(pane);
         self.arrange();
         self.pane_idx = self.panes.len() - 1;
     }
@@ -108,53 +216,474 @@ impl Editor {
         }
     }
 
+    // Every new buffer goes through here so it picks up the settings
+    fn add_buffer(&mut self, mut buffer: Buffer, pane_type: PaneType) {
+        buffer.tab_width = self.settings.tab_width;
+        self.panes[self.pane_idx].buffer_id = self.buffers.len();
+        self.panes[self.pane_idx].pane_type = pane_type;
+        self.panes[self.pane_idx].scroll_offset = 0.0;
+        self.buffers.push(buffer);
+    }
+
     fn open_file_dialog(&mut self) {
         let mut buffer = Buffer::new();
         self.fm.current_dir = env::current_dir().unwrap();
         self.fm.update(&mut buffer);
-        self.panes[self.pane_idx].buffer_id = self.buffers.len();
-        self.panes[self.pane_idx].pane_type = PaneType::FileManager;
-        self.panes[self.pane_idx].scroll_offset = 0.0;
-        self.buffers.push(buffer);
+        self.add_buffer(buffer, PaneType::FileManager);
     }
 
     fn new_file(&mut self) {
-        let buffer = Buffer::new();
-        self.panes[self.pane_idx].buffer_id = self.buffers.len();
-        self.panes[self.pane_idx].pane_type = PaneType::FileManager;
-        self.panes[self.pane_idx].scroll_offset = 0.0;
-        self.buffers.push(buffer);
+        self.add_buffer(Buffer::new(), PaneType::Buffer);
     }
 
     fn quit(&mut self) {
         self.should_quit = true;
     }
 
+    fn set_mode(&mut self, name: &str) {
+        self.mode = match name {
+            "vim" => Box::new(Vim::new()),
+            "emacs" => Box::new(Emacs::new()),
+            _ => Box::new(DefaultMode),
+        };
+        for pane in &mut self.panes {
+            pane.highlight = None;
+        }
+        self.keys = self.keymaps.editor_keymap(self.mode.name());
+        let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
+        buf.set_selection(false);
+    }
+
+    fn next_mode(&mut self) {
+        match self.mode.name() {
+            "default" => self.set_mode("vim"),
+            "vim" => self.set_mode("emacs"),
+            _ => self.set_mode("default"),
+        }
+    }
+
+    // Reload the settings files and apply them to everything, the window's font included
+    fn reload_settings(&mut self, app: Option<&mut Engine>) {
+        let mut settings = Settings::load(&self.settings_files.paths);
+        match app {
+            Some(app) => {
+                if settings.font != self.settings.font || settings.font_size != self.settings.font_size {
+                    app.set_font(&settings.font, settings.font_size);
+                    self.font_size = app.font_size;
+                }
+            }
+            // A terminal's cells are too big to pad
+            None => settings.padding = 0.0,
+        }
+        if settings.theme != self.settings.theme {
+            self.load_theme(&settings.theme);
+        }
+        self.settings = settings;
+        self.apply_settings();
+    }
+
+    fn load_theme(&mut self, name: &str) {
+        match theme::load_named(name) {
+            Ok(theme) => self.theme = theme,
+            Err(e) => eprintln!("Theme: {}", e),
+        }
+        for pane in &mut self.panes {
+            pane.theme = self.theme.clone();
+        }
+    }
+
+    // Step through the theme files, or with `variant` go to the light version of a dark
+    // theme and back
+    fn switch_theme(&mut self, step: isize, variant: bool) {
+        let names = theme::theme_names();
+        if names.is_empty() {
+            return;
+        }
+        let current = names.iter().position(|n| *n == self.theme.name).unwrap_or(0);
+        if variant {
+            let (from, to) = if self.theme.dark { ("dark", "light") } else { ("light", "dark") };
+            let counterpart = self.theme.name.replace(from, to);
+            if names.contains(&counterpart) && counterpart != self.theme.name {
+                self.load_theme(&counterpart);
+                return;
+            }
+            // Otherwise the next theme of the other kind
+            let dark = self.theme.dark;
+            for i in 1..names.len() {
+                let name = &names[(current + i) % names.len()];
+                if let Ok(theme) = theme::load_named(name) {
+                    if theme.dark != dark {
+                        self.load_theme(name);
+                        return;
+                    }
+                }
+            }
+        } else {
+            let next = (current as isize + step).rem_euclid(names.len() as isize) as usize;
+            self.load_theme(&names[next]);
+        }
+    }
+
+    fn apply_settings(&mut self) {
+        for buffer in &mut self.buffers {
+            buffer.tab_width = self.settings.tab_width;
+        }
+        for pane in &mut self.panes {
+            pane.apply_settings(&self.settings);
+            pane.line_height = self.font_size;
+        }
+        self.arrange();
+    }
+
+    // Pick up changed key bindings
+    fn apply_keymaps(&mut self) {
+        self.keys = self.keymaps.editor_keymap(self.mode.name());
+        for pane in &mut self.panes {
+            pane.keys = self.keymaps.keymap("buffer");
+        }
+        self.fm.keys = self.keymaps.keymap("file_manager");
+    }
+
+    fn close_other_panes(&mut self) {
+        let pane = self.panes.remove(self.pane_idx);
+        self.panes = vec![pane];
+        self.pane_idx = 0;
+        self.arrange();
+    }
+
+    // Replay a macro `times` times, stopping early at the end of the buffer, on a
+    // failed search, or when a run doesn't change anything
+    fn replay_macro(&mut self, events: &[MacroEvent], times: usize) {
+        self.macros.replaying = true;
+        self.macro_failed = false;
+        for _ in 0..times {
+            let buffer_id = self.panes[self.pane_idx].buffer_id;
+            let buf = &self.buffers[buffer_id];
+            let before = (buffer_id, buf.cursor_x, buf.cursor_y, buf.version());
+            self.play_macro(events);
+            if self.macro_failed || self.should_quit {
+                break;
+            }
+            let buffer_id = self.panes[self.pane_idx].buffer_id;
+            let buf = &self.buffers[buffer_id];
+            if before == (buffer_id, buf.cursor_x, buf.cursor_y, buf.version()) {
+                break;
+            }
+        }
+        self.macros.replaying = false;
+    }
+
+    // Replay a macro once at the start of every selected line
+    fn replay_macro_on_lines(&mut self, events: &[MacroEvent]) {
+        let buffer_id = self.panes[self.pane_idx].buffer_id;
+        let (start, end) = self.buffers[buffer_id].selected_lines();
+        let mut lines = (start..=end).map(|y| Position::new(0, y)).collect::<Vec<Position>>();
+        self.macros.replaying = true;
+        self.macro_failed = false;
+        for i in 0..lines.len() {
+            let buf = &mut self.buffers[buffer_id];
+            if lines[i].y >= buf.contents.len() {
+                break;
+            }
+            let version = buf.version();
+            buf.cursor_x = 0;
+            buf.cursor_y = lines[i].y;
+            buf.max_cursor_x = 0;
+            buf.set_selection(false);
+            self.play_macro(events);
+            if self.macro_failed || self.should_quit || self.panes[self.pane_idx].buffer_id != buffer_id {
+                break;
+            }
+            // Keep the remaining lines pointing at the same text if the macro added or removed lines
+            if let Some(changes) = self.buffers[buffer_id].changes_since(version) {
+                for change in &changes {
+                    for line in &mut lines[i + 1..] {
+                        *line = change.adjust(*line, false);
+                    }
+                }
+            }
+        }
+        self.macros.replaying = false;
+    }
+
+    fn play_macro(&mut self, events: &[MacroEvent]) {
+        for event in events {
+            match event {
+                MacroEvent::Key(kstr) => self.handle_key(kstr),
+                MacroEvent::Text(text) => self.handle_text(text),
+            }
+            if self.macro_failed {
+                return;
+            }
+        }
+    }
+
+    // Open or refresh the completion popup for the word before the cursor. Unless `force`
+    // is set it only opens by itself once enough of the word has been typed.
+    fn update_completion(&mut self, force: bool) {
+        let pane = &mut self.panes[self.pane_idx];
+        let buffer = &self.buffers[pane.buffer_id];
+        let (_, prefix) = completion::prefix_at(buffer, buffer.cursor_x, buffer.cursor_y);
+        if force || pane.completion.is_some() || prefix.chars().count() >= self.settings.completion_min_length {
+            pane.completion = Completion::new(&self.buffers, pane.buffer_id);
+        } else {
+            pane.completion = None;
+        }
+    }
+
+    fn show_palette(&mut self) {
+        let scope = match self.panes[self.pane_idx].pane_type {
+            PaneType::Buffer => Scope::Buffer,
+            PaneType::FileManager => Scope::FileManager,
+        };
+        self.palette = Some(Palette::new(vec![Scope::Editor, scope]));
+    }
+
+    // Run any command by name, if it makes sense for the active pane
+    fn run_command(&mut self, name: &str) {
+        let command = match command::find(name) {
+            Some(c) => c,
+            None => return,
+        };
+        let pane = &mut self.panes[self.pane_idx];
+        let buf = &mut self.buffers[pane.buffer_id];
+        match (command.scope, pane.pane_type) {
+            (Scope::Editor, _) => self.run_editor_command(name),
+            (Scope::Buffer, PaneType::Buffer) => {
+                let should_quit = pane.run_command(buf, name);
+                if should_quit {
+                    self.quit()
+                }
+            }
+            (Scope::FileManager, PaneType::FileManager) => self.fm.run_command(pane, buf, name),
+            _ => {}
+        }
+    }
+
+    fn run_editor_command(&mut self, name: &str) {
+        match name {
+            "pane.split" => self.add_pane(),
+            "pane.close" => self.close_pane(),
+            "pane.close_others" => self.close_other_panes(),
+            "pane.next" => self.select_next_pane(),
+            "pane.prev" => self.select_prev_pane(),
+            "buffer.next" => self.select_next_buffer(),
+            "buffer.prev" => self.select_prev_buffer(),
+            "file.new" => self.new_file(),
+            "file.open" => self.open_file_dialog(),
+            "editor.quit" => self.quit(),
+            "completion.show" => self.update_completion(true),
+            "mode.next" => self.next_mode(),
+            "mode.default" => self.set_mode("default"),
+            "mode.vim" => self.set_mode("vim"),
+            "mode.emacs" => self.set_mode("emacs"),
+            "palette.show" => self.show_palette(),
+            "theme.next" => self.switch_theme(1, false),
+            "theme.prev" => self.switch_theme(-1, false),
+            "theme.toggle_variant" => self.switch_theme(0, true),
+            "macro.record" => self.macros.toggle_recording(),
+            "macro.save" => self.macros.prompt = Some(MacroPrompt::Save),
+            "macro.play" => self.macros.prompt = Some(MacroPrompt::Play),
+            // A macro can't replay itself while it's being recorded or replayed
+            "macro.replay" | "macro.replay_lines" if self.macros.recording.is_some() || self.macros.replaying => {}
+            "macro.replay" => {
+                let times = self.macros.take_repeat();
+                let events = self.macros.last.clone();
+                self.replay_macro(&events, times);
+            }
+            "macro.replay_lines" => {
+                let events = self.macros.last.clone();
+                self.replay_macro_on_lines(&events);
+            }
+            _ => {}
+        }
+    }
+
     //========================================
 
     // Utils
     
     fn open_file(&mut self, path: impl AsRef<Path>) {
-        let buffer = Buffer::from_path(path);
-        self.panes[self.pane_idx].buffer_id = self.buffers.len();
-        self.panes[self.pane_idx].pane_type = PaneType::Buffer;
-        self.panes[self.pane_idx].scroll_offset = 0.0;
-        self.buffers.push(buffer);
+        let mut buffer = Buffer::from_path(path);
+        self.panes[self.pane_idx].detect_language(&mut buffer);
+        self.add_buffer(buffer, PaneType::Buffer);
     }
 
     //========================================
 
-    fn new(app: &Engine) -> Self {
+    fn handle_key(&mut self, kstr: &str) {
+        if let Some(prompt) = self.macros.prompt.take() {
+            match prompt {
+                MacroPrompt::Save => self.macros.save(kstr),
+                MacroPrompt::Play => {
+                    let times = self.macros.take_repeat();
+                    if let Some(events) = self.macros.named.get(kstr).cloned() {
+                        self.replay_macro(&events, times);
+                    }
+                }
+            }
+            return;
+        }
+        // Macro commands aren't recorded themselves
+        if let Some(name) = self.keys.get(kstr) {
+            if name.starts_with("macro.") && !self.keys.is_pending() && self.palette.is_none() {
+                self.run_command(name);
+                return;
+            }
+        }
+        match kstr {
+            k if k.len() == 3 && k.starts_with("a-") && k.as_bytes()[2].is_ascii_digit() => {
+                self.macros.push_repeat_digit((k.as_bytes()[2] - b'0') as usize);
+            }
+            _ => {
+                self.macros.record(MacroEvent::Key(kstr.to_string()));
+                self.dispatch_key(kstr);
+            }
+        }
+    }
+
+    fn dispatch_key(&mut self, kstr: &str) {
+        // Keys go to an open completion popup first, then the mode, then the editor
+        // bindings, and whatever is left to the pane. A half-typed editor chord skips
+        // straight to the editor bindings.
+        if let Some(mut palette) = self.palette.take() {
+            match palette.handle_key(kstr) {
+                PaletteKey::Handled => self.palette = Some(palette),
+                PaletteKey::Close => {}
+                PaletteKey::Run(name) => self.run_command(name),
+            }
+            return;
+        }
+        let mut was_completing = false;
+        if !self.keys.is_pending() {
+            if let PaneType::Buffer = self.panes[self.pane_idx].pane_type {
+                let pane = &mut self.panes[self.pane_idx];
+                let buf = &mut self.buffers[pane.buffer_id];
+                if let Some(mut completion) = pane.completion.take() {
+                    match completion.handle_key(buf, kstr) {
+                        CompletionKey::Handled => {
+                            pane.completion = Some(completion);
+                            return;
+                        }
+                        CompletionKey::Close => return,
+                        CompletionKey::PassThrough => was_completing = true,
+                    }
+                }
+                let used = self.mode.handle_key(pane, buf, kstr);
+                for command in self.mode.take_commands() {
+                    self.run_command(command);
+                }
+                if used {
+                    self.panes[self.pane_idx].completion = None;
+                    return;
+                }
+            }
+        }
+
+        match self.keys.feed(kstr) {
+            KeyResult::Matched(command) => {
+                self.run_command(command);
+                return;
+            }
+            KeyResult::Pending => return,
+            // A prefix and then a key that doesn't continue it
+            KeyResult::Unbound(keys) if keys.len() > 1 => return,
+            KeyResult::Unbound(_) => {}
+        }
+
+        let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
+        match self.panes[self.pane_idx].pane_type {
+            PaneType::Buffer => {
+                let pane = &mut self.panes[self.pane_idx];
+                let before = (buf.cursor_x, buf.cursor_y);
+                let should_quit = pane.handle_keystroke(buf, kstr);
+                if self.macros.replaying && macros::is_motion(kstr) && before == (buf.cursor_x, buf.cursor_y) {
+                    self.macro_failed = true;
+                }
+                if should_quit {
+                    self.quit();
+                }
+                // Keep suggesting while the word is being corrected
+                if was_completing && kstr == "backspace" {
+                    self.update_completion(false);
+                }
+            }
+            PaneType::FileManager => {
+                self.fm.handle_key(&mut self.panes[self.pane_idx], buf, kstr);
+            }
+        }
+    }
+
+    fn handle_text(&mut self, text: &str) {
+        self.macros.record(MacroEvent::Text(text.to_string()));
+        if let Some(palette) = &mut self.palette {
+            palette.handle_text(text);
+            return;
+        }
+        let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
+        match self.panes[self.pane_idx].pane_type {
+            PaneType::Buffer => {
+                if self.mode.handle_text(&mut self.panes[self.pane_idx], buf, text) {
+                    return;
+                }
+                self.panes[self.pane_idx].type_text(buf, text);
+                self.update_completion(false);
+            }
+            PaneType::FileManager => {
+                if !self.fm.search(buf, text) {
+                    self.macro_failed = true;
+                }
+            }
+        }
+    }
+
+    // The left button going down at `mouse`
+    fn mouse_down(&mut self, mouse: (i32, i32), clicks: usize) {
+        self.panes[self.pane_idx].completion = None;
+        let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
+        if !self.mode.handle_mouse(&mut self.panes[self.pane_idx], buf, &MouseEvent::Down) {
+            self.panes[self.pane_idx].set_selection_from_screen(buf, mouse.0 as f32, mouse.1 as f32, false);
+            if clicks > 1 {
+                let (x, y) = buf.prev_word(buf.cursor_x, buf.cursor_y);
+                buf.sel_x = x;
+                buf.sel_y = y;
+                let (x, y) = buf.next_word(buf.cursor_x, buf.cursor_y);
+                buf.cursor_x = x;
+                buf.cursor_y = y;
+            }
+        }
+    }
+
+    fn mouse_drag(&mut self, mouse: (i32, i32)) {
+        let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
+        if !self.mode.handle_mouse(&mut self.panes[self.pane_idx], buf, &MouseEvent::Drag) {
+            self.panes[self.pane_idx].set_selection_from_screen(buf, mouse.0 as f32, mouse.1 as f32, true);
+        }
+    }
+
+    fn new(window_width: f32, window_height: f32, font_size: f32, settings: Settings) -> Self {
         let mut editor = Editor {
+            settings: settings.clone(),
             fm: FileManager::new(),
             buffers: Vec::new(),
             panes: Vec::new(),
             pane_idx: 0,
-            window_width: app.window_width,
-            window_height: app.window_height,
-            font_size: app.font_size,
+            window_width,
+            window_height,
+            font_size,
             should_quit: false,
+            settings_files: FileWatcher::new(settings::settings_paths()),
+            theme: Theme::default(),
+            grammars: Rc::new(GrammarLibrary::load()),
+            macros: Macros::new(),
+            macro_failed: false,
+            mode: Box::new(DefaultMode),
+            keymaps: Keymaps::load(),
+            keys: Keymap::new(),
+            palette: None,
         };
+        editor.apply_keymaps();
+        editor.load_theme(&settings.theme);
         editor.add_pane();
         // editor.new_file();
         editor.open_file(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("src/main.rs"));
@@ -163,34 +692,18 @@ impl Editor {
 
     fn run(&mut self, app: &mut Engine) {
         while app.update() {
-
-            let mut needs_redraw = app.has_events;
+            self.mode.tick();
+            self.keys.expire();
+            if self.keymaps.check_for_changes() {
+                self.apply_keymaps();
+            }
+            if self.settings_files.changed() {
+                self.reload_settings(Some(app));
+            }
 
             for key in &app.keys_pressed {
                 let kstr = app.get_key_string(key);
-                match kstr.as_str() {
-                    "c-'" => self.add_pane(),
-                    "c-w" => self.close_pane(),
-                    "c-j" => self.select_next_pane(),
-                    "c-k" => self.select_prev_pane(),
-                    "c-b" => self.select_next_buffer(),
-                    "c-s-b" => self.select_prev_buffer(),
-                    "c-o" => self.open_file_dialog(),
-                    "c-q" => self.quit(),
-                    _ => {
-                        let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
-                        match self.panes[self.pane_idx].pane_type {
-                            PaneType::Buffer => {
-                                if self.panes[self.pane_idx].handle_keystroke(buf, kstr.as_str()) {
-                                    self.quit();
-                                }
-                            }
-                            PaneType::FileManager => {
-                                self.fm.handle_key(&mut self.panes[self.pane_idx], buf, kstr.as_str());
-                            }
-                        }
-                    }
-                }
+                self.handle_key(&kstr);
             }
 
             if self.should_quit {
@@ -206,75 +719,106 @@ impl Editor {
             }
 
             for text in &app.text_entered {
-                let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
-                match self.panes[self.pane_idx].pane_type {
-                    PaneType::Buffer => {
-                        buf.action_insert_text(text.to_string());
-                    }
-                    PaneType::FileManager => {
-                        self.fm.current_search.push_str(text);
-                        buf.name = self.fm.current_search.clone();
-                        let mut selection = buf.cursor_y;
-                        'searchloop: for (i, line) in
-                            buf.contents[buf.cursor_y..].iter().enumerate()
-                        {
-                            if line.starts_with(&self.fm.current_search) {
-                                selection = i + buf.cursor_y;
-                                break 'searchloop;
-                            }
-                        }
-                        buf.select_line(selection);
-                    }
-                }
+                self.handle_text(text);
             }
 
+            let mouse = (app.mouse.x as i32, app.mouse.y as i32);
             if app.mouse_left_pressed {
-                let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
-                self.panes[self.pane_idx].set_selection_from_screen(buf, false);
-                if app.mouse_left_clicks > 1 {
-                    let (x, y) = buf.prev_word(buf.cursor_x, buf.cursor_y);
-                    buf.sel_x = x;
-                    buf.sel_y = y;
-                    let (x, y) = buf.next_word(buf.cursor_x, buf.cursor_y);
-                    buf.cursor_x = x;
-                    buf.cursor_y = y;
-                }
+                self.mouse_down(mouse, app.mouse_left_clicks as usize);
             }
             if app.mouse_left_down {
-                let buf = &mut self.buffers[self.panes[self.pane_idx].buffer_id];
-                self.panes[self.pane_idx].set_selection_from_screen(buf, true);
+                self.mouse_drag(mouse);
             }
             if app.scroll.y != 0.0 {
-                self.panes[self.pane_idx].scroll(-app.scroll.y * 5.0);
+                let buf = &self.buffers[self.panes[self.pane_idx].buffer_id];
+                self.panes[self.pane_idx].scroll(buf, -app.scroll.y * self.settings.scroll_lines);
             }
 
-            for pane in &self.panes {
-                if pane.scroll_lag != 0.0 {
-                    needs_redraw = true;
+            self.draw(app);
+
+            // sleep(Duration::from_millis(1));
+        }
+    }
+}
+
+// The same as run, in a terminal. Every cell is one unit square, so the window is
+// columns by rows and lines are one high.
+fn run_terminal(mut settings: Settings, path: Option<&String>) {
+    let mut terminal = match Terminal::new() {
+        Ok(terminal) => terminal,
+        Err(e) => {
+            eprintln!("Terminal: {}", e);
+            return;
+        }
+    };
+    settings.padding = 0.0;
+    let mut editor = Editor::new(terminal.columns as f32, terminal.rows as f32, 1.0, settings);
+    if let Some(path) = path {
+        editor.open_file(path);
+    }
+    while !editor.should_quit {
+        editor.mode.tick();
+        editor.keys.expire();
+        if editor.keymaps.check_for_changes() {
+            editor.apply_keymaps();
+        }
+        if editor.settings_files.changed() {
+            editor.reload_settings(None);
+        }
+        if terminal.resized() {
+            editor.window_width = terminal.columns as f32;
+            editor.window_height = terminal.rows as f32;
+            editor.arrange();
+        }
+
+        for input in terminal.read(Duration::from_millis(50)) {
+            match input {
+                Input::Key(kstr) => editor.handle_key(&kstr),
+                Input::Text(text) => editor.handle_text(&text),
+                Input::MouseDown { x, y, clicks } => editor.mouse_down((x as i32, y as i32), clicks),
+                Input::MouseDrag { x, y } => editor.mouse_drag((x as i32, y as i32)),
+                Input::MouseUp => {}
+                Input::Scroll(lines) => {
+                    let buf = &editor.buffers[editor.panes[editor.pane_idx].buffer_id];
+                    editor.panes[editor.pane_idx].scroll(buf, -lines * editor.settings.scroll_lines);
                 }
             }
+        }
 
-            needs_redraw = true;
-            if needs_redraw {
-                self.draw(app);
-                let buf = &self.buffers[self.panes[self.pane_idx].buffer_id];
-                let pane = &self.panes[self.pane_idx];
-                let percentage = pane.scroll_offset / (buf.contents.len() as f32 * pane.line_height);
-            }
+        editor.draw(&mut terminal.screen);
+        terminal.present();
+    }
+}
 
-            // sleep(Duration::from_millis(1));
-        }
+// Draw a file once without a window, on an 80x24 grid of one-unit cells, and print the
+// frame. This works anywhere, CI included.
+fn render(mut settings: Settings, path: &str) {
+    let (columns, rows) = (80, 24);
+    settings.padding = 0.0;
+    let mut editor = Editor::new(columns as f32, rows as f32, 1.0, settings);
+    editor.open_file(path);
+    let mut renderer = Headless::new(1.0, 1.0);
+    editor.draw(&mut renderer);
+    for line in renderer.text(columns, rows) {
+        println!("{}", line);
     }
 }
 
 fn main() {
-    let path = match select_font() {
-        Some(p) => p,
-        None => PathBuf::new(),
-    };
+    let settings = Settings::load(&settings::settings_paths());
+
+    let args = env::args().collect::<Vec<String>>();
+    if let Some(path) = args.iter().position(|a| a == "--render").and_then(|i| args.get(i + 1)) {
+        render(settings, path);
+        return;
+    }
+    if let Some(i) = args.iter().position(|a| a == "--tui") {
+        run_terminal(settings, args.get(i + 1));
+        return;
+    }
 
     let mut app = Engine::new("Sdleditor");
-    let mut editor = Editor::new(&app);
-    app.set_font(path, 32.0);
+    app.set_font(&settings.font, settings.font_size);
+    let mut editor = Editor::new(app.window_width, app.window_height, app.font_size, settings);
     editor.run(&mut app); 
 }
@@ -1,30 +1,45 @@
-use pgfx::{Engine, Color, Rect};
-use regex::Regex;
+use std::rc::Rc;
+
+use pgfx::{Color, Rect};
 use unicode_segmentation::UnicodeSegmentation;
 
+use crate::autopair::AutoPairs;
 use crate::buffer::Buffer;
-
-struct ColorScheme {
-    fg: Color,
-    bg: Color,
-    ui_fg: Color,
-    ui_bg: Color,
-    ui_inactive_fg: Color,
-    ui_inactive_bg: Color,
-    selection: Color,
-    comment: Color,
+use crate::case::Case;
+use crate::command::Palette;
+use crate::completion::{self, Completion};
+use crate::grammar::GrammarLibrary;
+use crate::gutter::{Gutter, Mark, Slot};
+use crate::highlight::{self, HighlightCache};
+use crate::keymap::{KeyResult, Keymap};
+use crate::language;
+use crate::layout::Layout;
+use crate::lines::SortOptions;
+use crate::render::Renderer;
+use crate::settings::Settings;
+use crate::snippet::{SnippetLibrary, SnippetSession};
+use crate::status;
+use crate::theme::Theme;
+
+// A selection drawn instead of the buffer's own one, for modes that keep track of
+// their selections themselves. Ranges end exclusively.
+#[derive(Clone, Copy)]
+pub enum Highlight {
+    Range(usize, usize, usize, usize),
+    Block(usize, usize, usize, usize),
 }
 
-struct Syntax {
-    line_comment: Regex,
-    block_comment_start: Regex,
-    block_comment_end: Regex,
-    keywords: Vec<String>,
-    string_start: Regex,
-    string_end: Regex,
-    has_nested_comments: bool,
+// Columns kept on screen either side of the cursor when lines don't wrap
+const SIDE_SCROLLOFF: i32 = 5;
+
+// Where in the view to put the cursor's line
+enum Place {
+    Top,
+    Center,
+    Bottom,
 }
 
+#[derive(Clone, Copy)]
 pub enum PaneType {
     Buffer,
     FileManager,
@@ -37,37 +52,46 @@ pub struct Pane {
     pub scroll_offset: f32,
     pub scroll_lag: f32,
     pub line_height: f32,
-    syntax: Syntax,
-    colors: ColorScheme,
+    pub fill_column: usize,
+    pub padding: f32,
+    pub page_lines: f32,
+    pub scroll_past_end: f32,
+    // Lines kept on screen above and below the cursor
+    pub scrolloff_top: usize,
+    pub scrolloff_bottom: usize,
+    // Whether long lines wrap. If not, the view scrolls sideways by whole columns.
+    pub wrap: bool,
+    scroll_x: i32,
+    // The buffer and cursor position the view last followed, to tell when the cursor moves
+    followed: (usize, usize, usize),
+    pub auto_pairs: AutoPairs,
+    snippets: SnippetLibrary,
+    snippet_session: Option<SnippetSession>,
+    // Text stashed with a-s for the next snippet's $TM_SELECTED_TEXT
+    snippet_selection: String,
+    pub completion: Option<Completion>,
+    pub highlight: Option<Highlight>,
+    pub keys: Keymap<&'static str>,
+    pub grammars: Rc<GrammarLibrary>,
+    syntax: HighlightCache,
+    pub theme: Theme,
+    pub gutter: Gutter,
+    // What the bar shows on the left and the right
+    pub status_left: Vec<status::Item>,
+    pub status_right: Vec<status::Item>,
     chars_per_line: i32,
-    cursor_x: usize,
-    cursor_y: usize,
-    display_line_count: i32,
+    char_width: f32,
+    // Where the buffer's lines are on screen
+    layout: Layout,
+    // The display column up and down keep to, and where they left the cursor, so it's
+    // only kept while nothing else moves the cursor
+    goal_column: Option<(usize, (usize, usize, usize))>,
+    // The line a drag along the gutter started on
+    gutter_anchor: Option<usize>,
 }
 
 impl Pane {
     pub fn new(pane_type: PaneType, buffer_id: usize, line_height: f32) -> Self {
-        // TODO Very incomplete
-        let syntax = Syntax {
-            line_comment: Regex::new(r"//").unwrap(),
-            block_comment_start: Regex::new(r"/\*").unwrap(),
-            block_comment_end: Regex::new(r"\*/").unwrap(),
-            string_start: Regex::new(r#"""#).unwrap(),
-            string_end: Regex::new(r#"""#).unwrap(),
-            keywords: vec!["fn".into(), "let".into()],
-            has_nested_comments: true,
-        };
-        let colors = ColorScheme {
-            fg: Color::new(253, 244, 193),
-            bg: Color::new(40, 40, 40),
-            ui_fg: Color::new(253, 244, 193),
-            ui_bg: Color::new(80, 73, 69),
-            ui_inactive_fg: Color::new(189, 174, 147),
-            ui_inactive_bg: Color::new(60, 56, 54),
-            selection: Color::new(168, 153, 132),
-            comment: Color::new(168, 153, 132), // TODO same as selection
-        };
-
         Pane {
             pane_type,
             rect: Rect::new(0.0, 0.0, 0.0, 0.0),
@@ -75,21 +99,128 @@ impl Pane {
             scroll_lag: 0.0,
             scroll_offset: 0.0,
             line_height,
-            syntax,
-            colors,
+            fill_column: 80,
+            padding: 5.0,
+            page_lines: 40.0,
+            scroll_past_end: 5.0,
+            scrolloff_top: 3,
+            scrolloff_bottom: 3,
+            wrap: true,
+            scroll_x: 0,
+            followed: (usize::MAX, 0, 0),
+            auto_pairs: AutoPairs::new(),
+            snippets: SnippetLibrary::new(),
+            snippet_session: None,
+            snippet_selection: String::new(),
+            completion: None,
+            highlight: None,
+            keys: Keymap::new(),
+            grammars: Rc::new(GrammarLibrary::default()),
+            syntax: HighlightCache::default(),
+            theme: Theme::default(),
+            gutter: Gutter::new(),
+            status_left: status::default_left(),
+            status_right: status::default_right(),
             chars_per_line: 1,
-            cursor_x: 0,
-            cursor_y: 0,
-            display_line_count: 0,
+            char_width: 1.0,
+            layout: Layout::default(),
+            goal_column: None,
+            gutter_anchor: None,
+        }
+    }
+
+    pub fn apply_settings(&mut self, settings: &Settings) {
+        self.fill_column = settings.fill_column;
+        self.padding = settings.padding;
+        self.page_lines = settings.page_lines;
+        self.scroll_past_end = settings.scroll_past_end;
+        self.scrolloff_top = settings.scrolloff_top;
+        self.scrolloff_bottom = settings.scrolloff_bottom;
+        self.wrap = settings.wrap;
+        self.auto_pairs.enabled = settings.auto_pairs;
+        self.gutter.line_numbers = settings.line_numbers;
+        self.gutter.set_slots(&settings.gutter_slots);
+        self.status_left = settings.status_left.clone();
+        self.status_right = settings.status_right.clone();
+    }
+
+    // Work out the buffer's language from its file. Files only a grammar knows about
+    // get the language named by the grammar's scope.
+    pub fn detect_language(&self, buffer: &mut Buffer) {
+        let mut id = language::detect(&buffer.name, &buffer.contents).to_string();
+        if id == "text" {
+            let first_line = buffer.contents.first().map_or("", |l| l.as_str());
+            id = self.grammars.language_for(&buffer.name, first_line).unwrap_or(id);
+        }
+        buffer.set_language(&id);
+    }
+
+    // Override the detected language with the next or previous known one
+    fn cycle_language(&self, buffer: &mut Buffer, step: isize) {
+        let languages = language::languages();
+        if languages.is_empty() {
+            return;
+        }
+        let next = match languages.iter().position(|l| l.id == buffer.language) {
+            Some(i) => (i as isize + step).rem_euclid(languages.len() as isize) as usize,
+            None => 0,
+        };
+        buffer.set_language(&languages[next].id);
+    }
+
+    // Pair up the brackets and quotes of the buffer's language
+    fn update_pairs(&mut self, buffer: &Buffer) {
+        self.auto_pairs.pairs = language::get(&buffer.language).map_or_else(AutoPairs::default_pairs, |l| l.pairs());
+    }
+
+    // Catch the highlighting up with the buffer's edits
+    fn sync_syntax(&mut self, buffer: &Buffer) {
+        self.syntax.sync(&self.grammars, self.buffer_id, buffer);
+    }
+
+    // Catch the layout up with the buffer's edits, at the width the pane was last drawn
+    fn sync_layout(&mut self, buffer: &Buffer) {
+        self.layout.sync(self.buffer_id, buffer, self.chars_per_line as usize, self.wrap, buffer.tab_width);
+    }
+
+    // How many columns the gutter takes. The file manager's lines aren't worth numbering.
+    fn gutter_columns(&self, buffer: &Buffer) -> usize {
+        match self.pane_type {
+            PaneType::Buffer => self.gutter.width(buffer.len()),
+            PaneType::FileManager => 0,
         }
     }
 
-    pub fn draw(&mut self, app: &mut Engine, buffer: &Buffer, is_active: bool) {
-        let padding = 5.0;
+    pub fn draw(&mut self, app: &mut dyn Renderer, buffer: &Buffer, is_active: bool, status: &str) {
+        let padding = self.padding;
 
         // Fill background with border
-        app.draw_rect(self.rect, self.colors.ui_bg);
-        app.draw_rect(Rect::new(self.rect.x + 5.0, self.rect.y + 5.0, self.rect.width - 10.0, self.rect.height - 10.0), self.colors.bg);
+        app.draw_rect(self.rect, self.theme.ui_bg);
+        app.draw_rect(Rect::new(self.rect.x + padding, self.rect.y + padding, self.rect.width - padding * 2.0, self.rect.height - padding * 2.0), self.theme.bg);
+
+        // Bookmarks get their slot once there is one
+        if !buffer.bookmarks.is_empty() {
+            self.gutter.reserve(Slot::Bookmark);
+        }
+        let color = self.theme.gutter_current_fg;
+        self.gutter.marks = buffer
+            .bookmarks
+            .iter()
+            .map(|&line| Mark { slot: Slot::Bookmark, line, text: "•".to_string(), color })
+            .collect();
+        let gutter_width = self.gutter_columns(buffer) as f32 * app.char_width();
+        self.chars_per_line = f32::max(1.0, (self.rect.width - padding * 4.0 - gutter_width) / app.char_width()) as i32;
+        self.char_width = app.char_width();
+        self.sync_layout(buffer);
+
+        // Bring the cursor into view when it's moved
+        if let (true, PaneType::Buffer) = (is_active, &self.pane_type) {
+            let cursor = (self.buffer_id, buffer.cursor_x, buffer.cursor_y);
+            if cursor != self.followed {
+                self.followed = cursor;
+                self.follow_cursor(buffer);
+            }
+        }
 
         // Calculate scroll offset
         if self.scroll_lag != 0.0 {
@@ -104,216 +235,518 @@ impl Pane {
         }
 
         let bar_height = self.line_height + padding * 2.0;
+        // The rows from the one partly hidden under the bar down to the bottom of the pane
+        let first_row = f32::max(0.0, self.scroll_offset / self.line_height - 1.0).ceil() as usize;
+        let end_row = f32::max(0.0, (self.scroll_offset + self.rect.height) / self.line_height).ceil() as usize;
+        let first_line = self.layout.buffer_position(buffer, first_row, 0).1;
+        let last_line = self.layout.buffer_position(buffer, end_row, 0).1;
+        self.sync_syntax(buffer);
+        self.syntax.update(buffer, last_line + highlight::LOOKAHEAD);
+
+        let (sel_start_x, sel_start_y, sel_end_x, sel_end_y) = match self.highlight {
+            Some(Highlight::Range(x1, y1, x2, y2)) => (x1, y1, x2, y2),
+            Some(Highlight::Block(..)) => (0, 0, 0, 0),
+            None => buffer.get_selection(),
+        };
+        let block = match self.highlight {
+            Some(Highlight::Block(x1, y1, x2, y2)) => Some((x1, y1, x2, y2)),
+            _ => None,
+        };
+        let mut completion_pos = None;
+        for i in first_line..buffer.contents.len().min(last_line + 1) {
+            let line = &buffer.contents[i];
+            let start = self.layout.line_start(buffer, i);
+
+            // The gutter, next to the first row of the line
+            if gutter_width > 0.0 && start >= first_row {
+                let line_y = start as f32 * self.line_height - self.scroll_offset + padding * 2.0 + bar_height;
+                let number_fg = if i == buffer.cursor_y { self.theme.gutter_current_fg } else { self.theme.gutter_fg };
+                for (k, (text, color)) in self.gutter.cells(i, buffer.cursor_y, buffer.contents.len()).iter().enumerate() {
+                    if !text.trim().is_empty() {
+                        let x = self.rect.x + padding * 2.0 + k as f32 * app.char_width();
+                        app.draw_text(text, x, self.rect.y + line_y, app.font_size(), color.unwrap_or(number_fg));
+                    }
+                }
+            }
 
-        let mut color;
-        let mut comment_level = 0;
+            let tokens = self.syntax.tokens(i);
+            let colors = tokens.iter().map(|t| self.theme.color_for_scopes(&t.scopes)).collect::<Vec<Color>>();
+            let mut token = 0;
 
-        self.chars_per_line = f32::max(1.0, (self.rect.width - padding * 4.0) / app.char_width) as i32;
-        let mut y = 0;
-        let (sel_start_x, sel_start_y, sel_end_x, sel_end_y) = buffer.get_selection();
-        for (i, line) in buffer.contents.iter().enumerate() {
+            let mut unicode_line = line.as_str().grapheme_indices(true).collect::<Vec<(usize, &str)>>();
+            // Needed to draw cursor even if we're on a blank line
+            unicode_line.push((line.len(), " "));
+            let cells = &self.layout.line(buffer, i).cells;
+            for (j, ((byte, c), (row, x))) in unicode_line.iter().zip(cells).enumerate() {
 
-            // let has_line_comment = self.syntax.line_comment.is_match(line);
-            let has_block_comment_start = self.syntax.block_comment_start.is_match(line);
-            let has_block_comment_end = self.syntax.block_comment_end.is_match(line);
-            // let has_line_comment = self.syntax.line_comment.is_match(line);
-            let block_comment_start = if has_block_comment_start {
-                self.syntax.block_comment_start.find_iter(line).collect::<Vec<_>>()
-            } else {
-                vec![]
-            };
-            let block_comment_end = if has_block_comment_end {
-                self.syntax.block_comment_end.find_iter(line).collect::<Vec<_>>()
-            } else {
-                vec![]
-            };
-            let mut is_line_comment = false;
-
-            if y as f32 * self.line_height < self.scroll_offset + self.rect.height {
-                let mut unicode_line = line.as_str().graphemes(true).collect::<Vec<&str>>();
-                // Needed to draw cursor even if we're on a blank line
-                unicode_line.push(" ");
-                let mut x = 0;
-                for (j, c) in unicode_line.iter().enumerate() {
-
-                    for m in &block_comment_start {
-                        if j == m.start() {
-                            comment_level += 1;
-                        }
-                    }
-                    for m in &block_comment_end {
-                        if j == m.end() {
-                            comment_level -= 1;
-                        }
-                    }
-                    if comment_level > 0 {
-                        color = self.colors.comment;
-                    } else {
-                        color = self.colors.fg;
-                        if let Some(m) = self.syntax.line_comment.find(line) {
-                            let mut block_overlaps = false;
-                            for m in &block_comment_end {
-                                if j == m.end() {
-                                    block_overlaps = true;
-                                }
-                            }
-                            if j == m.start() && (!has_block_comment_end || !block_overlaps) {
-                                is_line_comment = true;
-                            }
-                        }
-                    }
-                    if is_line_comment {
-                        color = self.colors.comment;
-                    }
-
-                    let screen_x = x as f32 * app.char_width + padding * 2.0;
-                    let screen_y = y as f32 * self.line_height - self.scroll_offset + padding * 2.0 + bar_height;
+                while token + 1 < tokens.len() && tokens[token].end <= *byte {
+                    token += 1;
+                }
+                let color = colors.get(token).copied().unwrap_or(self.theme.fg);
 
-                    // Set the selection for some reason
-                    if screen_y + self.rect.y + app.font_size > app.mouse.y && screen_y + self.rect.y <= app.mouse.y {
-                        self.cursor_y = i;
-                        if screen_x + self.rect.x + app.char_width > app.mouse.x && screen_x + self.rect.x <= app.mouse.x {
-                            self.cursor_x = j;
-                        }
-                    }
+                let row = start + row;
+                if row < first_row {
+                    continue;
+                } else if row >= end_row {
+                    break;
+                }
+                let x = *x as i32;
+                let screen_x = gutter_width + (x - self.scroll_x) as f32 * app.char_width() + padding * 2.0;
+                let screen_y = row as f32 * self.line_height - self.scroll_offset + padding * 2.0 + bar_height;
+                // Lines that don't wrap are cut off at both sides
+                if !self.wrap && (x < self.scroll_x || x >= self.scroll_x + self.chars_per_line) {
+                    continue;
+                }
 
-                    // Draw selection
-                    if i >= sel_start_y && i <= sel_end_y && ((j >= sel_start_x || i > sel_start_y) && (j < sel_end_x || i < sel_end_y)) {
-                        let rect = Rect::new(
-                            self.rect.x + screen_x,
-                            self.rect.y + screen_y,
-                            app.char_width,
-                            app.font_size,
-                        );
-                        app.draw_rect(rect, self.colors.selection);
-                    }
+                // Draw selection
+                let is_selected = match block {
+                    Some((x1, y1, x2, y2)) => i >= y1 && i <= y2 && j >= x1 && j < x2,
+                    None => i >= sel_start_y && i <= sel_end_y && ((j >= sel_start_x || i > sel_start_y) && (j < sel_end_x || i < sel_end_y)),
+                };
+                if is_selected {
+                    let rect = Rect::new(
+                        self.rect.x + screen_x,
+                        self.rect.y + screen_y,
+                        app.char_width(),
+                        app.font_size(),
+                    );
+                    app.draw_rect(rect, self.theme.selection);
+                }
 
-                    // Draw character
-                    if y as f32 * self.line_height >= self.scroll_offset - self.line_height && !c.trim().is_empty() {
-                        app.draw_text(
-                            c,
-                            self.rect.x + screen_x,
-                            self.rect.y + screen_y,
-                            app.font_size,
-                            color,
-                        );
-                    }
+                // Draw character
+                if !c.trim().is_empty() {
+                    app.draw_text(
+                        c,
+                        self.rect.x + screen_x,
+                        self.rect.y + screen_y,
+                        app.font_size(),
+                        color,
+                    );
+                }
 
-                    // Draw cursor
-                    if is_active && i == buffer.cursor_y && j == buffer.cursor_x {
-                        let rect = Rect::new(
-                            self.rect.x + screen_x,
-                            self.rect.y + screen_y,
-                            2.0,
-                            app.font_size,
-                        );
-                        app.draw_rect(rect, self.colors.fg);
+                if let Some(completion) = &self.completion {
+                    if i == completion.y && j == completion.x {
+                        completion_pos = Some((screen_x, screen_y));
                     }
+                }
 
-                    x += 1;
-                    if x == self.chars_per_line {
-                        x = 0;
-                        y += 1;
-                    }
+                // Draw cursor
+                if is_active && i == buffer.cursor_y && j == buffer.cursor_x {
+                    app.draw_cursor(self.rect.x + screen_x, self.rect.y + screen_y, self.theme.cursor);
                 }
             }
-            // TODO This will make display_line_count almost accorate but not quite
-            // because it won't account for wrapped lines. But right now we're only using
-            // display_line_count to calculate scrolloff, so we don't really care about
-            // wrapped lines that are off the end of the screen.
-            y += 1;
         }
-        self.display_line_count = y;
 
         // Draw the bar
+        let (bar_fg, bar_bg) = if is_active {
+            (self.theme.ui_fg, self.theme.ui_bg)
+        } else {
+            (self.theme.ui_inactive_fg, self.theme.ui_inactive_bg)
+        };
         let rect = Rect::new(self.rect.x, self.rect.y, self.rect.width, bar_height);
-        app.draw_rect(rect, self.colors.ui_bg);
-        let dirty_text = if buffer.is_dirty { "*" } else { "" };
-        let bar_text = vec![dirty_text, " ", &buffer.name];
-        for (i, c) in bar_text.iter().filter(|x| !x.is_empty()).enumerate() {
-            app.draw_text(c, self.rect.x + i as f32 * app.char_width + padding, self.rect.y + padding, app.font_size, self.colors.ui_fg);
+        app.draw_rect(rect, bar_bg);
+        let total_rows = self.layout.row_count(buffer) as f32;
+        let view = status::view_position(self.scroll_offset / self.line_height, self.visible_lines(), total_rows);
+        let mode = if is_active { status } else { "" };
+        let left = format!(" {}", status::texts(&self.status_left, buffer, mode, &view).join(" "));
+        app.draw_text(&left, self.rect.x + padding, self.rect.y + padding, app.font_size(), bar_fg);
+        // The file manager's buffer has no file to describe
+        let mut right = match self.pane_type {
+            PaneType::Buffer => status::texts(&self.status_right, buffer, mode, &view),
+            PaneType::FileManager => status::texts(&[status::Item::Mode], buffer, mode, &view),
+        };
+        // Leave items off the end until the right side fits next to the left
+        let columns = ((self.rect.width - padding * 2.0) / app.char_width()) as usize;
+        let left_width = left.graphemes(true).count();
+        let mut right_text = right.join("  ") + " ";
+        while !right.is_empty() && left_width + 1 + right_text.graphemes(true).count() > columns {
+            right.pop();
+            right_text = right.join("  ") + " ";
+        }
+        if !right.is_empty() {
+            let width = right_text.graphemes(true).count() as f32 * app.char_width();
+            app.draw_text(&right_text, self.rect.x + self.rect.width - width - padding, self.rect.y + padding, app.font_size(), bar_fg);
+        }
+
+        if let (true, Some((x, y))) = (is_active, completion_pos) {
+            self.draw_completion(app, x, y);
+        }
+    }
+
+    // Draw the completion popup under the word at (x, y), or above it if there's no room
+    fn draw_completion(&self, app: &mut dyn Renderer, x: f32, y: f32) {
+        let completion = match &self.completion {
+            Some(c) => c,
+            None => return,
+        };
+        let padding = self.padding;
+        let scroll = completion.scroll();
+        let items = &completion.items[scroll..completion.items.len().min(scroll + completion::VISIBLE_ITEMS)];
+        let longest = items.iter().map(|w| w.graphemes(true).count()).max().unwrap_or(0);
+        let width = longest as f32 * app.char_width() + padding * 2.0;
+        let height = items.len() as f32 * self.line_height + padding * 2.0;
+
+        let mut popup_x = self.rect.x + x - padding;
+        if popup_x + width > self.rect.x + self.rect.width {
+            popup_x = f32::max(self.rect.x, self.rect.x + self.rect.width - width);
+        }
+        let mut popup_y = self.rect.y + y + self.line_height;
+        if popup_y + height > self.rect.y + self.rect.height && self.rect.y + y - height >= self.rect.y {
+            popup_y = self.rect.y + y - height;
+        }
+
+        app.draw_rect(Rect::new(popup_x, popup_y, width, height), self.theme.ui_bg);
+        for (i, word) in items.iter().enumerate() {
+            let item_y = popup_y + padding + i as f32 * self.line_height;
+            if scroll + i == completion.selected {
+                app.draw_rect(Rect::new(popup_x, item_y, width, self.line_height), self.theme.selection);
+            }
+            let mut item_x = popup_x + padding;
+            for g in word.graphemes(true) {
+                app.draw_text(g, item_x, item_y, app.font_size(), self.theme.ui_fg);
+                item_x += app.char_width();
+            }
+        }
+    }
+
+    // Draw the command palette across the top of the pane. `bindings` has the keys for
+    // each of the palette's items.
+    pub fn draw_palette(&self, app: &mut dyn Renderer, palette: &Palette, bindings: &[String]) {
+        let padding = self.padding;
+        let scroll = palette.scroll();
+        let end = palette.items.len().min(scroll + completion::VISIBLE_ITEMS);
+        let x = self.rect.x + padding * 4.0;
+        let width = f32::max(0.0, self.rect.width - padding * 8.0);
+        let height = (end - scroll + 1) as f32 * self.line_height + padding * 2.0;
+        let y = self.rect.y + self.line_height + padding * 3.0;
+        let columns = (width / app.char_width()) as usize;
+
+        app.draw_rect(Rect::new(x, y, width, height), self.theme.ui_bg);
+        let mut text_x = x + padding;
+        for g in format!("> {}", palette.query).graphemes(true) {
+            app.draw_text(g, text_x, y + padding, app.font_size(), self.theme.ui_fg);
+            text_x += app.char_width();
+        }
+        for i in scroll..end {
+            let item_y = y + padding + (i - scroll + 1) as f32 * self.line_height;
+            if i == palette.selected {
+                app.draw_rect(Rect::new(x, item_y, width, self.line_height), self.theme.selection);
+            }
+            let command = palette.items[i];
+            let binding = bindings.get(i).map(|b| b.as_str()).unwrap_or("");
+            let left = format!("{}  {}", command.name, command.description);
+            let room = columns.saturating_sub(binding.graphemes(true).count() + 2);
+            let mut text_x = x + padding;
+            for g in left.graphemes(true).take(room) {
+                app.draw_text(g, text_x, item_y, app.font_size(), self.theme.ui_fg);
+                text_x += app.char_width();
+            }
+            let mut text_x = x + width - padding - binding.graphemes(true).count() as f32 * app.char_width();
+            for g in binding.graphemes(true) {
+                app.draw_text(g, text_x, item_y, app.font_size(), self.theme.ui_inactive_fg);
+                text_x += app.char_width();
+            }
         }
     }
 
     pub fn handle_keystroke(&mut self, buffer: &mut Buffer, kstr: &str) -> bool {
-        match kstr {
-            "up" => buffer.cursor_up(1, false),
-            "down" => buffer.cursor_down(1, false),
-            "left" => buffer.cursor_left(false),
-            "right" => buffer.cursor_right(false),
-            "pageup" => self.scroll(-40.0),
-            "pagedown" => self.scroll(40.0),
-            "return" => buffer.break_line_with_auto_indent(),
-            "s-return" => buffer.break_line(),
-            "backspace" => buffer.remove_selection(),
-            "s-backspace" => buffer.remove_selection(),
-            "tab" => buffer.action_insert_text("    ".to_string()),
-            "s-up" => buffer.cursor_up(1, true),
-            "s-down" => buffer.cursor_down(1, true),
-            "s-left" => buffer.cursor_left(true),
-            "s-right" => buffer.cursor_right(true),
-            "c-a" => self.select_all(buffer),
-            "c-c" => buffer.clipboard_copy(),
-            "c-s" => buffer.save(),
-            "c-v" => buffer.clipboard_paste(),
-            "c-x" => buffer.clipboard_cut(),
-            "c-z" => buffer.undo(),
-            "c-up" => buffer.cursor_up(1, false),
-            "c-down" => buffer.cursor_down(1, false),
-            "c-s-up" => buffer.cursor_up(1, true),
-            "c-s-down" => buffer.cursor_down(1, true),
-            "c-right" => {
+        if self.handle_snippet_key(buffer, kstr) {
+            self.sync_snippet(buffer);
+            return false;
+        }
+        match self.keys.feed(kstr) {
+            KeyResult::Matched(command) => self.run_command(buffer, command),
+            _ => false,
+        }
+    }
+
+    // Run a buffer command by name. Returns true if the editor should quit.
+    pub fn run_command(&mut self, buffer: &mut Buffer, name: &str) -> bool {
+        match name {
+            "cursor.up" => self.move_rows(buffer, -1, false),
+            "cursor.down" => self.move_rows(buffer, 1, false),
+            "cursor.left" => buffer.cursor_left(false),
+            "cursor.right" => buffer.cursor_right(false),
+            "view.page_up" => {
+                self.scroll(buffer, -self.page_lines);
+                self.move_rows(buffer, -(self.page_lines as isize), false);
+            }
+            "view.page_down" => {
+                self.scroll(buffer, self.page_lines);
+                self.move_rows(buffer, self.page_lines as isize, false);
+            }
+            "view.center_on_cursor" => self.scroll_cursor_to(buffer, Place::Center),
+            "view.cursor_to_top" => self.scroll_cursor_to(buffer, Place::Top),
+            "view.cursor_to_bottom" => self.scroll_cursor_to(buffer, Place::Bottom),
+            "view.line_numbers" => self.gutter.line_numbers = self.gutter.line_numbers.next(),
+            "bookmark.toggle" => buffer.toggle_bookmark(buffer.cursor_y),
+            "bookmark.next" | "bookmark.prev" => {
+                if let Some(line) = buffer.next_bookmark(buffer.cursor_y, name == "bookmark.next") {
+                    buffer.cursor_y = line;
+                    buffer.cursor_x = 0;
+                    buffer.set_selection(false);
+                }
+            }
+            "edit.newline" => buffer.break_line_with_auto_indent(),
+            "edit.newline_plain" => buffer.break_line(),
+            "edit.backspace" => {
+                self.update_pairs(buffer);
+                if !self.auto_pairs.remove_pair(buffer) {
+                    buffer.remove_selection();
+                }
+            }
+            "edit.delete_selection" => buffer.remove_selection(),
+            "edit.tab" => buffer.action_tab(),
+            "edit.dedent" => buffer.dedent_selection(),
+            "select.up" => self.move_rows(buffer, -1, true),
+            "select.down" => self.move_rows(buffer, 1, true),
+            "select.left" => buffer.cursor_left(true),
+            "select.right" => buffer.cursor_right(true),
+            "select.all" => self.select_all(buffer),
+            "clipboard.copy" => buffer.clipboard_copy(),
+            "buffer.save" => buffer.save(),
+            "clipboard.paste" => buffer.clipboard_paste(),
+            "clipboard.cut" => buffer.clipboard_cut(),
+            "edit.undo" => buffer.undo(),
+            "cursor.word_right" => {
                 let (x, y) = buffer.next_word(buffer.cursor_x, buffer.cursor_y);
                 buffer.cursor_x = x;
                 buffer.cursor_y = y;
                 buffer.set_selection(false);
             }
-            "c-left" => {
+            "cursor.word_left" => {
                 let (x, y) = buffer.prev_word(buffer.cursor_x, buffer.cursor_y);
                 buffer.cursor_x = x;
                 buffer.cursor_y = y;
                 buffer.set_selection(false);
             }
-            "c-s-right" => {
+            "select.word_right" => {
                 let (x, y) = buffer.next_word(buffer.cursor_x, buffer.cursor_y);
                 buffer.cursor_x = x;
                 buffer.cursor_y = y;
             }
-            "c-s-left" => {
+            "select.word_left" => {
                 let (x, y) = buffer.prev_word(buffer.cursor_x, buffer.cursor_y);
                 buffer.cursor_x = x;
                 buffer.cursor_y = y;
             }
-            "c-s-z" => buffer.redo(),
-            "c-backspace" => {
+            "edit.redo" => buffer.redo(),
+            "lines.move_up" => buffer.move_lines_up(),
+            "lines.move_down" => buffer.move_lines_down(),
+            "lines.duplicate" => buffer.duplicate_lines(),
+            "lines.join" => buffer.join_lines(),
+            "lines.delete" => buffer.delete_lines(),
+            "lines.sort" => buffer.sort_lines(SortOptions { natural: true, case_insensitive: true, ..Default::default() }),
+            "lines.sort_reverse" => buffer.sort_lines(SortOptions { natural: true, case_insensitive: true, reverse: true, ..Default::default() }),
+            "lines.sort_numeric" => buffer.sort_lines(SortOptions { numeric: true, ..Default::default() }),
+            "lines.unique" => buffer.unique_lines(),
+            "case.upper" => buffer.convert_case(Case::Upper),
+            "case.lower" => buffer.convert_case(Case::Lower),
+            "case.title" => buffer.convert_case(Case::Title),
+            "case.snake" => buffer.convert_case(Case::Snake),
+            "case.camel" => buffer.convert_case(Case::Camel),
+            "case.pascal" => buffer.convert_case(Case::Pascal),
+            "case.kebab" => buffer.convert_case(Case::Kebab),
+            "case.screaming" => buffer.convert_case(Case::Screaming),
+            "case.cycle" => buffer.cycle_identifier_case(),
+            "edit.fill_paragraph" => buffer.fill_paragraph(self.fill_column),
+            "edit.delete_word_left" => {
                 let (x, y) = buffer.prev_word(buffer.cursor_x, buffer.cursor_y);
                 buffer.cursor_x = x;
                 buffer.cursor_y = y;
                 buffer.remove_selection();
             }
-            "c-s-\\" => {
+            "language.next" => self.cycle_language(buffer, 1),
+            "language.prev" => self.cycle_language(buffer, -1),
+            "language.detect" => self.detect_language(buffer),
+            "buffer.print" => {
                 buffer.print();
                 return true;
             }
             _ => {}
         }
+        self.sync_snippet(buffer);
         false
     }
 
-    pub fn scroll(&mut self, lines: f32) {
-        let padding = 5.0;
-        let bar_height: f32 = self.line_height + padding * 2.0;
+    // Keys that mean something different while a snippet is being filled in.
+    // Returns true if the key was handled.
+    fn handle_snippet_key(&mut self, buffer: &mut Buffer, kstr: &str) -> bool {
+        match kstr {
+            "tab" => {
+                if let Some(session) = self.snippet_session.as_mut() {
+                    if !session.next_field(buffer) {
+                        self.snippet_session = None;
+                    }
+                    return true;
+                }
+                self.expand_snippet(buffer)
+            }
+            "s-tab" => match self.snippet_session.as_mut() {
+                Some(session) => {
+                    session.prev_field(buffer);
+                    true
+                }
+                None => false,
+            },
+            "up" | "down" => match self.snippet_session.as_mut() {
+                Some(session) if session.has_choices() => {
+                    session.cycle_choice(buffer, kstr == "down");
+                    true
+                }
+                _ => false,
+            },
+            "escape" => self.snippet_session.take().is_some(),
+            "a-s" => {
+                let (x1, y1, x2, y2) = buffer.get_selection();
+                if x1 == x2 && y1 == y2 {
+                    return false;
+                }
+                self.snippet_selection = buffer.text_range(x1, y1, x2, y2);
+                buffer.remove_selection();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Expand the snippet whose trigger is the word just before the cursor
+    fn expand_snippet(&mut self, buffer: &mut Buffer) -> bool {
+        let (x1, y1, x2, y2) = buffer.get_selection();
+        if x1 != x2 || y1 != y2 {
+            return false;
+        }
+        let (w1, w2) = buffer.word_at(x1, y1);
+        if w1 == w2 || w2 != x1 {
+            return false;
+        }
+        let trigger = buffer.line_graphemes(y1)[w1..w2].concat();
+        let body = match self.snippets.find(&buffer.language, &trigger) {
+            Some(s) => s.body.clone(),
+            None => return false,
+        };
+        buffer.begin_undo_group();
+        self.snippet_session = SnippetSession::expand(buffer, w1, w2, &body, &self.snippet_selection);
+        buffer.end_undo_group();
+        self.snippet_selection.clear();
+        true
+    }
 
+    fn sync_snippet(&mut self, buffer: &mut Buffer) {
+        if let Some(session) = self.snippet_session.as_mut() {
+            if !session.sync(buffer) {
+                self.snippet_session = None;
+            }
+        }
+    }
+
+    pub fn type_text(&mut self, buffer: &mut Buffer, text: &str) {
+        let mut chars = text.chars();
+        if let (Some(c), None) = (chars.next(), chars.next()) {
+            self.sync_syntax(buffer);
+            let context = self.syntax.context_at(buffer, buffer.cursor_x, buffer.cursor_y);
+            self.update_pairs(buffer);
+            if self.auto_pairs.type_char(buffer, c, context) {
+                self.sync_snippet(buffer);
+                return;
+            }
+        }
+        buffer.action_type_text(text.to_string());
+        self.sync_snippet(buffer);
+    }
+
+    // How many lines fit under the bar
+    fn visible_lines(&self) -> f32 {
+        let bar_height = self.line_height + self.padding * 2.0;
+        f32::max(1.0, ((self.rect.height - self.padding * 5.0 - bar_height) / self.line_height).floor())
+    }
+
+    // The cursor's display row counting from the top of the buffer, and its column
+    fn cursor_row(&mut self, buffer: &Buffer) -> (i32, i32) {
+        self.sync_layout(buffer);
+        if buffer.is_empty() {
+            return (0, 0);
+        }
+        let (row, column) = self.layout.display_position(buffer, buffer.cursor_x, buffer.cursor_y);
+        (row as i32, column as i32)
+    }
+
+    // Move the cursor up or down by display rows, so wrapped lines are gone through a
+    // row at a time. It keeps to the column it started from while it moves like this.
+    pub fn move_rows(&mut self, buffer: &mut Buffer, rows: isize, extend: bool) {
+        if buffer.is_empty() {
+            return;
+        }
+        let (row, column) = self.cursor_row(buffer);
+        let column = match self.goal_column {
+            Some((goal, cursor)) if cursor == (self.buffer_id, buffer.cursor_x, buffer.cursor_y) => goal,
+            _ => column as usize,
+        };
+        let last = self.layout.row_count(buffer) as isize - 1;
+        let row = (row as isize + rows).clamp(0, last) as usize;
+        let (x, y) = self.layout.buffer_position(buffer, row, column);
+        buffer.cursor_x = x;
+        buffer.cursor_y = y;
+        buffer.set_selection(extend);
+        self.goal_column = Some((column, (self.buffer_id, x, y)));
+    }
+
+    // Scroll as little as possible to get the cursor on screen with scrolloff_top lines
+    // above it and scrolloff_bottom below, or sideways with a few columns either side
+    fn follow_cursor(&mut self, buffer: &Buffer) {
+        let (row, column) = self.cursor_row(buffer);
+        let visible = self.visible_lines();
+        let most = ((visible - 1.0) / 2.0).floor();
+        let top_margin = f32::min(self.scrolloff_top as f32, most);
+        let bottom_margin = f32::min(self.scrolloff_bottom as f32, most);
+        let top = (self.scroll_offset + self.scroll_lag) / self.line_height;
+        let row = row as f32;
+        let new_top = if row < top + top_margin {
+            row - top_margin
+        } else if row + 1.0 > top + visible - bottom_margin {
+            row + 1.0 + bottom_margin - visible
+        } else {
+            top
+        };
+        self.scroll_lag = f32::max(0.0, new_top) * self.line_height - self.scroll_offset;
+
+        if self.wrap {
+            self.scroll_x = 0;
+            return;
+        }
+        let width = self.chars_per_line;
+        let margin = SIDE_SCROLLOFF.min((width - 1) / 2);
+        if column < self.scroll_x + margin {
+            self.scroll_x = (column - margin).max(0);
+        } else if column > self.scroll_x + width - 1 - margin {
+            self.scroll_x = column - width + 1 + margin;
+        }
+    }
+
+    // Scroll so the cursor's line is at the top, the middle or the bottom of the view,
+    // keeping to the scrolloff margins
+    fn scroll_cursor_to(&mut self, buffer: &Buffer, place: Place) {
+        let row = self.cursor_row(buffer).0 as f32;
+        let visible = self.visible_lines();
+        let most = ((visible - 1.0) / 2.0).floor();
+        let top = match place {
+            Place::Top => row - f32::min(self.scrolloff_top as f32, most),
+            Place::Center => row - most,
+            Place::Bottom => row + 1.0 + f32::min(self.scrolloff_bottom as f32, most) - visible,
+        };
+        self.scroll_lag = f32::max(0.0, top) * self.line_height - self.scroll_offset;
+    }
+
+    pub fn scroll(&mut self, buffer: &Buffer, lines: f32) {
         let mut new_value = self.scroll_lag + lines * self.line_height;
-        let new_offset = (self.scroll_offset as f32 + new_value) / self.line_height;
+        let new_offset = (self.scroll_offset + new_value) / self.line_height;
 
-        let scrolloff_start = 0.0;
-        let scrolloff_end = 5.0;
-        let end_val = f32::max(0.0, self.display_line_count as f32 + scrolloff_end - (self.rect.height - bar_height) / self.line_height);
-        if new_offset < -scrolloff_start {
-            new_value = -scrolloff_start * self.line_height - self.scroll_offset as f32;
+        self.sync_layout(buffer);
+        let end_val = f32::max(0.0, self.layout.row_count(buffer) as f32 + self.scroll_past_end - self.visible_lines());
+        if new_offset < 0.0 {
+            new_value = -self.scroll_offset;
         } else if new_offset > end_val {
-            new_value = end_val * self.line_height - self.scroll_offset as f32;
+            new_value = end_val * self.line_height - self.scroll_offset;
         }
 
         self.scroll_lag = new_value;
@@ -324,47 +757,43 @@ impl Pane {
         buffer.select_all();
     }
 
-    fn get_focused_cell(
-        &mut self,
-        app: &Engine,
-        x: f32,
-        y: f32,
-    ) -> (usize, usize) {
-        let padding = 5.0;
-        let bar_height = app.font_size + padding * 2.0;
-        let x_cell = ((x - padding * 2.0 - (app.char_width / 2.0)) / app.char_width) as usize;
-        let y_cell = ((y - padding - bar_height - (app.font_size / 2.0)) / app.font_size)
-            as usize;
-        (x_cell, y_cell)
-    }
-
-    // Set the selection/cursor positions based on screen coordinates.
-    // TODO This doesn't actually take screen coordinates because draw() handles that now - What is
-    // this function actually doing? Do I need to port this logic into draw()?
-    pub fn set_selection_from_screen(
-        &mut self,
-        // app: &Engine,
-        mut buffer: &mut Buffer,
-        // mouse_x: f32,
-        // mouse_y: f32,
-        extend: bool,
-    ) {
-        let mut x_target = 0;
-        let mut y_target = 0;
-        let line_lengths = buffer.contents.iter().map(|line| line.as_str().graphemes(true).collect::<Vec<&str>>().len() + 1).collect::<Vec<usize>>();
-        let line_count = line_lengths.len();
-        if self.cursor_y > 0 && self.cursor_y < line_count {
-            y_target = self.cursor_y;
-        } else if self.cursor_y >= line_count {
-            y_target = line_count - 1;
+    // Put the cursor where the mouse is, at (mouse_x, mouse_y) in the window
+    pub fn set_selection_from_screen(&mut self, buffer: &mut Buffer, mouse_x: f32, mouse_y: f32, extend: bool) {
+        if buffer.is_empty() {
+            return;
         }
-
-        let char_count = line_lengths[y_target];
-        if self.cursor_x > 0 && self.cursor_x < char_count {
-            x_target = self.cursor_x;
-        } else if self.cursor_x >= char_count {
-            x_target = char_count - 1;
+        self.sync_layout(buffer);
+        let padding = self.padding;
+        let bar_height = self.line_height + padding * 2.0;
+        let gutter_width = self.gutter_columns(buffer) as f32 * self.char_width;
+        let text_x = self.rect.x + padding * 2.0 + gutter_width;
+        let text_y = self.rect.y + padding * 2.0 + bar_height;
+        // Above the text is the top row and left of it is the first column
+        let row = ((f32::max(0.0, mouse_y - text_y) + self.scroll_offset) / self.line_height) as usize;
+        let column = (f32::max(0.0, mouse_x - text_x) / self.char_width) as usize + self.scroll_x as usize;
+        let (x_target, y_target) = self.layout.buffer_position(buffer, row, column);
+        let line_count = buffer.len();
+
+        // Clicking or dragging along the gutter selects whole lines
+        if gutter_width > 0.0 && mouse_x >= self.rect.x && mouse_x < text_x {
+            let anchor = match (extend, self.gutter_anchor) {
+                (true, Some(anchor)) => anchor.min(line_count - 1),
+                _ => y_target,
+            };
+            self.gutter_anchor = Some(anchor);
+            let line_end = |y: usize| if y + 1 < line_count { (0, y + 1) } else { (buffer.line_len(y), y) };
+            let ((sel_x, sel_y), (x, y)) = if y_target >= anchor {
+                ((0, anchor), line_end(y_target))
+            } else {
+                (line_end(anchor), (0, y_target))
+            };
+            buffer.sel_x = sel_x;
+            buffer.sel_y = sel_y;
+            buffer.cursor_x = x;
+            buffer.cursor_y = y;
+            return;
         }
+        self.gutter_anchor = None;
 
         buffer.cursor_x = x_target;
         buffer.cursor_y = y_target;
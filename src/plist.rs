@@ -0,0 +1,242 @@
+use crate::json::Value;
+
+// A piece of an XML file: a tag or the text between tags
+#[derive(Debug, PartialEq)]
+enum Token {
+    Open(String),
+    Close(String),
+    // A tag like <true/>
+    Empty(String),
+    Text(String),
+}
+
+fn decode_entities(text: &str) -> String {
+    let mut result = String::new();
+    let mut rest = text;
+    while let Some(start) = rest.find('&') {
+        result.push_str(&rest[..start]);
+        rest = &rest[start..];
+        let end = match rest.find(';') {
+            Some(end) => end,
+            None => break,
+        };
+        let entity = &rest[1..end];
+        let decoded = match entity {
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "amp" => Some('&'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ if entity.starts_with("#x") => u32::from_str_radix(&entity[2..], 16).ok().and_then(char::from_u32),
+            _ if entity.starts_with('#') => entity[1..].parse().ok().and_then(char::from_u32),
+            _ => None,
+        };
+        match decoded {
+            Some(c) => {
+                result.push(c);
+                rest = &rest[end + 1..];
+            }
+            None => {
+                result.push('&');
+                rest = &rest[1..];
+            }
+        }
+    }
+    result.push_str(rest);
+    result
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = text;
+    while !rest.is_empty() {
+        if let Some(after) = rest.strip_prefix("<!--") {
+            let end = after.find("-->").ok_or("unterminated comment")?;
+            rest = &after[end + 3..];
+        } else if let Some(after) = rest.strip_prefix("<![CDATA[") {
+            let end = after.find("]]>").ok_or("unterminated CDATA")?;
+            tokens.push(Token::Text(after[..end].to_string()));
+            rest = &after[end + 3..];
+        } else if rest.starts_with("<?") || rest.starts_with("<!") {
+            let end = rest.find('>').ok_or("unterminated declaration")?;
+            rest = &rest[end + 1..];
+        } else if rest.starts_with('<') {
+            let end = rest.find('>').ok_or("unterminated tag")?;
+            let tag = &rest[1..end];
+            // Attributes like version="1.0" don't matter here
+            let name = tag.trim_start_matches('/').trim_end_matches('/').split_whitespace().next().unwrap_or("").to_string();
+            tokens.push(if tag.starts_with('/') {
+                Token::Close(name)
+            } else if tag.ends_with('/') {
+                Token::Empty(name)
+            } else {
+                Token::Open(name)
+            });
+            rest = &rest[end + 1..];
+        } else {
+            let end = rest.find('<').unwrap_or(rest.len());
+            tokens.push(Token::Text(decode_entities(&rest[..end])));
+            rest = &rest[end..];
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    pos: usize,
+}
+
+impl Parser {
+    // Skip the whitespace between tags
+    fn next(&mut self) -> Option<&Token> {
+        while let Some(Token::Text(t)) = self.tokens.get(self.pos) {
+            if !t.trim().is_empty() {
+                break;
+            }
+            self.pos += 1;
+        }
+        self.pos += 1;
+        self.tokens.get(self.pos - 1)
+    }
+
+    // The text inside an element whose opening tag has been read, up to its closing tag
+    fn text(&mut self, name: &str) -> Result<String, String> {
+        let mut text = String::new();
+        loop {
+            match self.tokens.get(self.pos) {
+                Some(Token::Text(t)) => text.push_str(t),
+                Some(Token::Close(n)) if n == name => {
+                    self.pos += 1;
+                    return Ok(text);
+                }
+                _ => return Err(format!("expected </{}>", name)),
+            }
+            self.pos += 1;
+        }
+    }
+
+    fn value(&mut self) -> Result<Value, String> {
+        let (name, empty) = match self.next() {
+            Some(Token::Open(name)) => (name.clone(), false),
+            Some(Token::Empty(name)) => (name.clone(), true),
+            other => return Err(format!("expected a value, found {:?}", other)),
+        };
+        match name.as_str() {
+            "plist" => {
+                let value = self.value()?;
+                self.next();
+                Ok(value)
+            }
+            "true" | "false" => {
+                if !empty {
+                    self.text(&name)?;
+                }
+                Ok(Value::Bool(name == "true"))
+            }
+            "string" | "data" | "date" if empty => Ok(Value::Str(String::new())),
+            "string" | "data" | "date" => Ok(Value::Str(self.text(&name)?)),
+            "integer" | "real" => {
+                let text = self.text(&name)?;
+                text.trim().parse().map(Value::Number).map_err(|_| format!("bad number {}", text))
+            }
+            "array" if empty => Ok(Value::Array(Vec::new())),
+            "array" => {
+                let mut items = Vec::new();
+                loop {
+                    let save = self.pos;
+                    if let Some(Token::Close(n)) = self.next() {
+                        if n == "array" {
+                            return Ok(Value::Array(items));
+                        }
+                    }
+                    self.pos = save;
+                    items.push(self.value()?);
+                }
+            }
+            "dict" if empty => Ok(Value::Object(Vec::new())),
+            "dict" => {
+                let mut entries = Vec::new();
+                loop {
+                    let key = match self.next() {
+                        Some(Token::Close(n)) if n == "dict" => return Ok(Value::Object(entries)),
+                        Some(Token::Open(n)) if n == "key" => self.text("key")?,
+                        other => return Err(format!("expected <key>, found {:?}", other)),
+                    };
+                    entries.push((key, self.value()?));
+                }
+            }
+            _ => Err(format!("unknown element <{}>", name)),
+        }
+    }
+}
+
+// Parse an XML property list, like a .tmTheme or .tmLanguage file, into the same values
+// JSON gives
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut parser = Parser {
+        tokens: tokenize(text)?,
+        pos: 0,
+    };
+    parser.value()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_nested_dicts_and_arrays() {
+        let value = parse(
+            r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+    <!-- a comment -->
+    <key>name</key>
+    <string>Test</string>
+    <key>settings</key>
+    <array>
+        <dict>
+            <key>size</key>
+            <integer>12</integer>
+            <key>on</key>
+            <true/>
+        </dict>
+        <array/>
+        <string/>
+    </array>
+</dict>
+</plist>"#,
+        )
+        .unwrap();
+        assert_eq!(value.get("name").and_then(|n| n.as_str()), Some("Test"));
+        let settings = value.get("settings").map(|s| s.as_array()).unwrap();
+        assert_eq!(settings.len(), 3);
+        assert_eq!(settings[0], Value::Object(vec![("size".to_string(), Value::Number(12.0)), ("on".to_string(), Value::Bool(true))]));
+        assert_eq!((&settings[1], &settings[2]), (&Value::Array(vec![]), &Value::Str(String::new())));
+    }
+
+    #[test]
+    fn decodes_entities_and_cdata() {
+        let value = parse("<array><string>a &lt;b&gt; &amp; &#65;&#x42; &bogus; &</string><string><![CDATA[<x> & y]]></string></array>").unwrap();
+        assert_eq!(value.as_array()[0].as_str(), Some("a <b> & AB &bogus; &"));
+        assert_eq!(value.as_array()[1].as_str(), Some("<x> & y"));
+    }
+
+    #[test]
+    fn reports_malformed_input() {
+        for text in [
+            "",
+            "<dict><key>a</key>",
+            "<dict><string>a</string></dict>",
+            "<array><string>a</array>",
+            "<integer>x</integer>",
+            "<foo/>",
+            "<string>a",
+            "<!-- open",
+        ] {
+            assert!(parse(text).is_err(), "{:?} should not parse", text);
+        }
+    }
+}
@@ -0,0 +1,322 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+use crate::indent;
+use crate::language;
+
+// What comments look like in the language being reflowed
+#[derive(Default)]
+pub struct Comments<'a> {
+    // Line comment markers, longest first
+    pub line: Vec<&'a str>,
+    pub block: Option<(&'a str, &'a str)>,
+}
+
+impl Comments<'_> {
+    // Whether the end of `line` is inside a block comment, given whether its start is
+    fn in_block_after(&self, line: &str, mut in_block: bool) -> bool {
+        let (open, close) = match self.block {
+            Some(block) => block,
+            None => return false,
+        };
+        let mut rest = line;
+        loop {
+            let delimiter = if in_block { close } else { open };
+            match rest.find(delimiter) {
+                Some(i) => {
+                    rest = &rest[i + delimiter.len()..];
+                    in_block = !in_block;
+                }
+                None => return in_block,
+            }
+        }
+    }
+}
+
+// A line split into the part that gets repeated on every line of a reflowed paragraph
+// (indentation plus any comment marker) and the text that gets rewrapped
+struct Line {
+    prefix: String,
+    body: String,
+}
+
+impl Line {
+    // `in_block` is whether the line starts inside a block comment
+    fn parse(line: &str, comments: &Comments, in_block: bool) -> Self {
+        let indentation = indent::leading_whitespace(line);
+        let rest = &line[indentation.len()..];
+        let mut marker = comments.line.iter().find(|m| rest.starts_with(*m)).copied();
+        // A "*" is the leader down the side of a /* block comment */. Anywhere else it's
+        // a list bullet.
+        if marker.is_none() && in_block && (rest == "*" || rest.starts_with("* ")) {
+            marker = Some("*");
+        }
+        match marker {
+            Some(m) => {
+                let after = &rest[m.len()..];
+                let space = if after.starts_with(' ') { " " } else { "" };
+                Line {
+                    prefix: format!("{}{}{}", indentation, m, space),
+                    body: after[space.len()..].to_string(),
+                }
+            }
+            None => Line {
+                prefix: indentation.to_string(),
+                body: rest.to_string(),
+            },
+        }
+    }
+
+    // Lines only belong to the same paragraph if they have the same comment marker
+    fn key(&self) -> &str {
+        self.prefix.trim_end()
+    }
+
+    fn is_blank(&self) -> bool {
+        self.body.trim().is_empty()
+    }
+}
+
+// The list bullet ("- ", "* ", "+ ", "1. ", "2) ") at the start of some text, including
+// the whitespace around it
+fn bullet(text: &str) -> Option<&str> {
+    let rest = text.trim_start();
+    let marker_len = if rest.starts_with(['-', '*', '+']) {
+        1
+    } else {
+        let digits = rest.chars().take_while(|c| c.is_ascii_digit()).count();
+        if digits == 0 || !rest[digits..].starts_with(['.', ')']) {
+            return None;
+        }
+        digits + 1
+    };
+    let after = &rest[marker_len..];
+    let space = after.len() - after.trim_start().len();
+    if space == 0 || after.trim().is_empty() {
+        return None;
+    }
+    Some(&text[..text.len() - after.len() + space])
+}
+
+fn display_width(s: &str, tab_width: usize) -> usize {
+    let g = s.graphemes(true).collect::<Vec<&str>>();
+    indent::visual_column(&g, g.len(), tab_width)
+}
+
+fn fill(words: &[&str], first_prefix: &str, prefix: &str, width: usize, tab_width: usize) -> Vec<String> {
+    let mut lines = Vec::new();
+    let mut line = first_prefix.to_string();
+    let mut line_is_empty = true;
+    for word in words {
+        if !line_is_empty && display_width(&line, tab_width) + 1 + display_width(word, tab_width) > width {
+            lines.push(line);
+            line = prefix.to_string();
+            line_is_empty = true;
+        }
+        if !line_is_empty {
+            line.push(' ');
+        }
+        line.push_str(word);
+        line_is_empty = false;
+    }
+    lines.push(line);
+    lines
+}
+
+// Rewrap lines to `width` columns. Blank lines, changes of comment marker and list
+// bullets all start new paragraphs, and bullet items get a hanging indent. `in_block` is
+// whether the first line starts inside a block comment.
+pub fn reflow(lines: &[String], width: usize, tab_width: usize, comments: &Comments, mut in_block: bool) -> Vec<String> {
+    let lines = lines
+        .iter()
+        .map(|l| {
+            let line = Line::parse(l, comments, in_block);
+            in_block = comments.in_block_after(l, in_block);
+            line
+        })
+        .collect::<Vec<Line>>();
+    let mut result = Vec::new();
+    let mut i = 0;
+    while i < lines.len() {
+        if lines[i].is_blank() {
+            result.push(format!("{}{}", lines[i].prefix, lines[i].body).trim_end().to_string());
+            i += 1;
+            continue;
+        }
+        let first = &lines[i];
+        let mut j = i + 1;
+        while j < lines.len()
+            && !lines[j].is_blank()
+            && lines[j].key() == first.key()
+            && bullet(&lines[j].body).is_none()
+        {
+            j += 1;
+        }
+
+        let (first_prefix, prefix) = match bullet(&first.body) {
+            Some(b) => (
+                format!("{}{}", first.prefix, b),
+                format!("{}{}", first.prefix, " ".repeat(display_width(b, tab_width))),
+            ),
+            None => {
+                let body_indent = indent::leading_whitespace(&first.body);
+                let p = format!("{}{}", first.prefix, body_indent);
+                (p.clone(), p)
+            }
+        };
+        let first_body = &first.body[bullet(&first.body).map_or(0, |b| b.len())..];
+        let words = first_body
+            .split_whitespace()
+            .chain(lines[i + 1..j].iter().flat_map(|l| l.body.split_whitespace()))
+            .collect::<Vec<&str>>();
+        result.extend(fill(&words, &first_prefix, &prefix, width, tab_width));
+        i = j;
+    }
+    result
+}
+
+impl Buffer {
+    fn comments(&self) -> Comments<'static> {
+        match language::get(&self.language) {
+            Some(l) => Comments {
+                line: l.line_comments.iter().map(|m| m.as_str()).collect(),
+                block: l.block_comment.as_ref().map(|(open, close)| (open.as_str(), close.as_str())),
+            },
+            None => Comments::default(),
+        }
+    }
+
+    // Whether each line up to `y` starts inside a block comment
+    fn block_comment_starts(&self, comments: &Comments, y: usize) -> Vec<bool> {
+        let mut in_block = false;
+        let mut starts = Vec::new();
+        for line in &self.contents[..=y] {
+            starts.push(in_block);
+            in_block = comments.in_block_after(line, in_block);
+        }
+        starts
+    }
+
+    // The lines of the paragraph around the cursor: neighbouring non-blank lines with the
+    // same comment marker, stopping at list bullets.
+    fn paragraph_at(&self, y: usize) -> Option<(usize, usize)> {
+        let comments = &self.comments();
+        let in_block = self.block_comment_starts(comments, self.len() - 1);
+        let parse = |y: usize| Line::parse(&self.contents[y], comments, in_block[y]);
+        let line = parse(y);
+        if line.is_blank() {
+            return None;
+        }
+        let same_paragraph = |other: &Line| !other.is_blank() && other.key() == line.key();
+        let mut y1 = y;
+        while y1 > 0 && bullet(&parse(y1).body).is_none() {
+            let prev = parse(y1 - 1);
+            if !same_paragraph(&prev) {
+                break;
+            }
+            y1 -= 1;
+        }
+        let mut y2 = y;
+        while y2 + 1 < self.len() {
+            let next = parse(y2 + 1);
+            if !same_paragraph(&next) || bullet(&next.body).is_some() {
+                break;
+            }
+            y2 += 1;
+        }
+        Some((y1, y2))
+    }
+
+    // Rewrap the selected lines, or the paragraph at the cursor, to `width` columns
+    pub fn fill_paragraph(&mut self, width: usize) {
+        let (x1, y1, x2, y2) = self.get_selection();
+        let had_selection = x1 != x2 || y1 != y2;
+        let (y1, y2) = if had_selection {
+            self.selected_lines()
+        } else {
+            match self.paragraph_at(self.cursor_y) {
+                Some(range) => range,
+                None => return,
+            }
+        };
+
+        let comments = self.comments();
+        let in_block = self.block_comment_starts(&comments, y1)[y1];
+        let lines = reflow(&self.contents[y1..=y2], width.max(self.tab_width), self.tab_width, &comments, in_block);
+        let new_y2 = y1 + lines.len() - 1;
+        if lines[..] != self.contents[y1..=y2] {
+            let x2 = self.line_len(y2);
+            self.replace_text(0, y1, x2, y2, lines.join("\n"));
+        }
+
+        self.cursor_y = new_y2;
+        self.cursor_x = self.line_len(new_y2);
+        self.max_cursor_x = self.cursor_x;
+        if had_selection {
+            self.sel_x = 0;
+            self.sel_y = y1;
+        } else {
+            self.set_selection(false);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(lines: &[&str]) -> Vec<String> {
+        lines.iter().map(|l| l.to_string()).collect()
+    }
+
+    #[test]
+    fn keeps_line_comment_markers() {
+        let comments = Comments { line: vec!["///", "//"], block: None };
+        let result = reflow(&lines(&["    /// aaa bbb ccc", "    /// ddd", "    ///", "    /// - eee fff ggg hhh", "    ///   iii"]), 21, 4, &comments, false);
+        assert_eq!(result, lines(&["    /// aaa bbb ccc", "    /// ddd", "    ///", "    /// - eee fff ggg", "    ///   hhh iii"]));
+    }
+
+    #[test]
+    fn keeps_indentation() {
+        let result = reflow(&lines(&["  a b", "  c d e f g h i j k"]), 10, 4, &Comments::default(), false);
+        assert_eq!(result, lines(&["  a b c d", "  e f g h", "  i j k"]));
+    }
+
+    #[test]
+    fn nested_bullet_gets_hanging_indent() {
+        let result = reflow(&lines(&["- top item", "  * nested bullet with quite a few words in it"]), 20, 4, &Comments::default(), false);
+        assert_eq!(result, lines(&["- top item", "  * nested bullet", "    with quite a few", "    words in it"]));
+    }
+
+    #[test]
+    fn star_leads_block_comment_lines() {
+        let comments = Comments { line: vec!["//"], block: Some(("/*", "*/")) };
+        let result = reflow(&lines(&["/*", " * aaa bbb ccc ddd", " * eee", " */"]), 12, 4, &comments, false);
+        assert_eq!(result, lines(&["/*", " * aaa bbb", " * ccc ddd", " * eee", " */"]));
+        // Starting partway through the comment
+        let result = reflow(&lines(&[" * aaa bbb ccc ddd eee"]), 12, 4, &comments, true);
+        assert_eq!(result, lines(&[" * aaa bbb", " * ccc ddd", " * eee"]));
+    }
+
+    #[test]
+    fn tracks_block_comments() {
+        let comments = Comments { line: Vec::new(), block: Some(("/*", "*/")) };
+        assert!(comments.in_block_after("x /* a", false));
+        assert!(!comments.in_block_after("x /* a */ b", false));
+        assert!(!comments.in_block_after("a */", true));
+        assert!(comments.in_block_after("a */ b /* c", true));
+        assert!(!Comments::default().in_block_after("/*", false));
+    }
+
+    #[test]
+    fn fills_paragraph_at_cursor() {
+        let mut buffer = Buffer::from_lines(["x", "", "one two", "three four five", "", "y"]);
+        buffer.cursor_y = 2;
+        buffer.set_selection(false);
+        buffer.fill_paragraph(9);
+        assert_eq!(buffer.contents, lines(&["x", "", "one two", "three", "four five", "", "y"]));
+        assert_eq!((buffer.cursor_x, buffer.cursor_y), (9, 4));
+        buffer.undo();
+        assert_eq!(buffer.contents, lines(&["x", "", "one two", "three four five", "", "y"]));
+    }
+}
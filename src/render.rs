@@ -0,0 +1,246 @@
+use pgfx::{Color, Engine, Rect};
+use unicode_segmentation::UnicodeSegmentation;
+
+// What panes draw with. The window is one renderer; Headless draws nowhere and keeps
+// a list of what was drawn instead.
+pub trait Renderer {
+    fn clear(&mut self, color: Color);
+    fn draw_rect(&mut self, rect: Rect, color: Color);
+    fn draw_text(&mut self, text: &str, x: f32, y: f32, size: f32, color: Color);
+    // The text cursor at the top left of a character cell
+    fn draw_cursor(&mut self, x: f32, y: f32, color: Color) {
+        let rect = Rect::new(x, y, 2.0, self.font_size());
+        self.draw_rect(rect, color);
+    }
+    // Text is monospaced, so every character is this wide
+    fn char_width(&self) -> f32;
+    fn font_size(&self) -> f32;
+}
+
+impl Renderer for Engine {
+    fn clear(&mut self, color: Color) {
+        Engine::clear(self, color);
+    }
+
+    fn draw_rect(&mut self, rect: Rect, color: Color) {
+        Engine::draw_rect(self, rect, color);
+    }
+
+    fn draw_text(&mut self, text: &str, x: f32, y: f32, size: f32, color: Color) {
+        Engine::draw_text(self, text, x, y, size, color);
+    }
+
+    fn char_width(&self) -> f32 {
+        self.char_width
+    }
+
+    fn font_size(&self) -> f32 {
+        self.font_size
+    }
+}
+
+pub enum DrawCommand {
+    Clear(Color),
+    Rect(Rect, Color),
+    Text(String, f32, f32, Color),
+    Cursor(f32, f32, Color),
+}
+
+// One character cell of a rasterised frame
+#[derive(Clone)]
+pub struct Cell {
+    pub text: String,
+    pub fg: Color,
+    pub bg: Color,
+}
+
+// A renderer with no window. Everything drawn since the last clear is kept in a display
+// list, which can be looked at directly or rasterised into a grid of character cells.
+pub struct Headless {
+    pub char_width: f32,
+    pub font_size: f32,
+    pub commands: Vec<DrawCommand>,
+}
+
+impl Headless {
+    pub fn new(char_width: f32, font_size: f32) -> Self {
+        Headless {
+            char_width,
+            font_size,
+            commands: Vec::new(),
+        }
+    }
+
+    fn cell_at(&self, x: f32, y: f32) -> (usize, usize) {
+        ((x / self.char_width).round().max(0.0) as usize, (y / self.font_size).round().max(0.0) as usize)
+    }
+
+    // The frame as `columns` by `rows` cells, each one char_width by font_size. A
+    // rectangle fills the cells it covers at least half of, text takes a cell per
+    // character, and the cursor is a block.
+    pub fn cells(&self, columns: usize, rows: usize) -> Vec<Vec<Cell>> {
+        let blank = Cell { text: " ".to_string(), fg: Color::new(255, 255, 255), bg: Color::new(0, 0, 0) };
+        let mut cells = vec![vec![blank; columns]; rows];
+        for command in &self.commands {
+            match command {
+                DrawCommand::Clear(color) => {
+                    for cell in cells.iter_mut().flatten() {
+                        cell.bg = *color;
+                    }
+                }
+                DrawCommand::Rect(rect, color) => {
+                    let (x1, y1) = self.cell_at(rect.x, rect.y);
+                    let (x2, y2) = self.cell_at(rect.x + rect.width, rect.y + rect.height);
+                    for row in cells.iter_mut().take(y2.min(rows)).skip(y1) {
+                        for cell in row.iter_mut().take(x2.min(columns)).skip(x1) {
+                            cell.text = " ".to_string();
+                            cell.bg = *color;
+                        }
+                    }
+                }
+                DrawCommand::Text(text, x, y, color) => {
+                    let (x, y) = self.cell_at(*x, *y);
+                    for (i, g) in text.graphemes(true).enumerate() {
+                        if let Some(cell) = cells.get_mut(y).and_then(|row| row.get_mut(x + i)) {
+                            cell.text = g.to_string();
+                            cell.fg = *color;
+                        }
+                    }
+                }
+                DrawCommand::Cursor(x, y, color) => {
+                    let (x, y) = self.cell_at(*x, *y);
+                    if let Some(cell) = cells.get_mut(y).and_then(|row| row.get_mut(x)) {
+                        cell.fg = cell.bg;
+                        cell.bg = *color;
+                    }
+                }
+            }
+        }
+        cells
+    }
+
+    // Just the characters of the frame, one string per row
+    pub fn text(&self, columns: usize, rows: usize) -> Vec<String> {
+        self.cells(columns, rows)
+            .iter()
+            .map(|row| row.iter().map(|c| c.text.as_str()).collect::<String>().trim_end().to_string())
+            .collect()
+    }
+}
+
+impl Renderer for Headless {
+    fn clear(&mut self, color: Color) {
+        self.commands.clear();
+        self.commands.push(DrawCommand::Clear(color));
+    }
+
+    fn draw_rect(&mut self, rect: Rect, color: Color) {
+        self.commands.push(DrawCommand::Rect(rect, color));
+    }
+
+    fn draw_text(&mut self, text: &str, x: f32, y: f32, _size: f32, color: Color) {
+        self.commands.push(DrawCommand::Text(text.to_string(), x, y, color));
+    }
+
+    fn draw_cursor(&mut self, x: f32, y: f32, color: Color) {
+        self.commands.push(DrawCommand::Cursor(x, y, color));
+    }
+
+    fn char_width(&self) -> f32 {
+        self.char_width
+    }
+
+    fn font_size(&self) -> f32 {
+        self.font_size
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::buffer::Buffer;
+    use crate::gutter::{LineNumbers, Slot};
+    use crate::pane::{Pane, PaneType};
+
+    fn same(a: Color, b: Color) -> bool {
+        (a.r, a.g, a.b) == (b.r, b.g, b.b)
+    }
+
+    // A pane of 20 by 6 cells with no padding, drawn into a Headless with one pixel cells
+    fn draw(pane: &mut Pane, buffer: &Buffer) -> Headless {
+        let mut headless = Headless::new(1.0, 1.0);
+        headless.clear(Color::new(0, 0, 0));
+        pane.draw(&mut headless, buffer, true, "");
+        headless
+    }
+
+    fn pane() -> Pane {
+        let mut pane = Pane::new(PaneType::Buffer, 0, 1.0);
+        pane.padding = 0.0;
+        pane.rect = Rect::new(0.0, 0.0, 20.0, 6.0);
+        pane.status_left.clear();
+        pane.status_right.clear();
+        pane
+    }
+
+    #[test]
+    fn spreads_text_over_cells() {
+        let mut headless = Headless::new(1.0, 1.0);
+        headless.clear(Color::new(0, 0, 0));
+        headless.draw_text("héllo", 2.0, 0.0, 1.0, Color::new(1, 2, 3));
+        let cells = headless.cells(10, 1);
+        assert_eq!(cells[0][3].text, "é");
+        assert!(same(cells[0][3].fg, Color::new(1, 2, 3)));
+        assert_eq!(headless.text(10, 1), vec!["  héllo"]);
+    }
+
+    #[test]
+    fn draws_pane_contents() {
+        let mut pane = pane();
+        pane.gutter.line_numbers = LineNumbers::Off;
+        let buffer = Buffer::from_lines(["fn main() {", "    x", "}"]);
+        let text = draw(&mut pane, &buffer).text(20, 6);
+        assert_eq!(&text[1..5], &["fn main() {", "    x", "}", ""]);
+
+        pane.gutter.line_numbers = LineNumbers::Absolute;
+        let text = draw(&mut pane, &buffer).text(20, 6);
+        assert_eq!(&text[1..4], &[" 1 fn main() {", " 2     x", " 3 }"]);
+    }
+
+    #[test]
+    fn reserved_slots_move_the_text_over_and_show_their_marks() {
+        let mut pane = pane();
+        let mut buffer = Buffer::from_lines(["fn main() {", "    x", "}"]);
+        pane.gutter.reserve(Slot::Fold);
+        pane.gutter.reserve(Slot::Git);
+        let text = draw(&mut pane, &buffer).text(20, 6);
+        assert_eq!(&text[1..4], &["  1  fn main() {", "  2      x", "  3  }"]);
+
+        // Bookmarks add their own slot, between git markers and the numbers
+        buffer.toggle_bookmark(1);
+        let headless = draw(&mut pane, &buffer);
+        assert_eq!(&headless.text(20, 6)[1..4], &["   1  fn main() {", " • 2      x", "   3  }"]);
+        let cells = headless.cells(20, 6);
+        assert!(same(cells[2][1].fg, pane.theme.gutter_current_fg));
+    }
+
+    #[test]
+    fn draws_selection_and_cursor() {
+        let mut pane = pane();
+        pane.gutter.line_numbers = LineNumbers::Off;
+        let mut buffer = Buffer::from_lines(["fn main() {", "    x", "}"]);
+        buffer.sel_x = 0;
+        buffer.sel_y = 1;
+        buffer.cursor_x = 4;
+        buffer.cursor_y = 1;
+        let headless = draw(&mut pane, &buffer);
+        let cells = headless.cells(20, 6);
+        let theme = &pane.theme;
+        // The indentation before the cursor is selected, and the cursor is a block on the x
+        assert!((0..4).all(|x| same(cells[2][x].bg, theme.selection)));
+        assert!(same(cells[1][1].bg, theme.bg));
+        assert!(same(cells[2][4].bg, theme.cursor));
+        assert_eq!(cells[2][4].text, "x");
+        assert!(headless.commands.iter().any(|c| matches!(c, DrawCommand::Cursor(x, y, _) if *x == 4.0 && *y == 2.0)));
+    }
+}
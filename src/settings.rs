@@ -0,0 +1,217 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::completion;
+use crate::config;
+use crate::gutter::{LineNumbers, Slot};
+use crate::indent;
+use crate::status::{self, Item};
+use crate::theme;
+
+// Every setting, for the "did you mean" hint on typos
+const NAMES: &[&str] = &[
+    "font",
+    "font_size",
+    "padding",
+    "scroll_lines",
+    "page_lines",
+    "scroll_past_end",
+    "scrolloff_top",
+    "scrolloff_bottom",
+    "wrap",
+    "tab_width",
+    "fill_column",
+    "auto_pairs",
+    "completion_min_length",
+    "line_numbers",
+    "gutter_slots",
+    "status_left",
+    "status_right",
+    "theme",
+];
+
+#[derive(Clone, PartialEq)]
+pub struct Settings {
+    pub font: PathBuf,
+    pub font_size: f32,
+    // Space around and inside panes, in pixels
+    pub padding: f32,
+    // Lines scrolled per mouse wheel step
+    pub scroll_lines: f32,
+    // Lines scrolled by page up and page down
+    pub page_lines: f32,
+    // Lines that can be scrolled past the end of the buffer
+    pub scroll_past_end: f32,
+    // Lines kept visible above and below the cursor
+    pub scrolloff_top: usize,
+    pub scrolloff_bottom: usize,
+    // Wrap long lines, rather than scrolling sideways
+    pub wrap: bool,
+    pub tab_width: usize,
+    pub fill_column: usize,
+    pub auto_pairs: bool,
+    // How much of a word has to be typed before completions show up by themselves
+    pub completion_min_length: usize,
+    // off, absolute, relative or hybrid
+    pub line_numbers: LineNumbers,
+    // Gutter columns kept for marks even when there are none
+    pub gutter_slots: Vec<Slot>,
+    // What pane bars show, see status.rs
+    pub status_left: Vec<Item>,
+    pub status_right: Vec<Item>,
+    // A theme file's name without the extension
+    pub theme: String,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            font: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("fonts/monospace.ttf"),
+            font_size: 32.0,
+            padding: 5.0,
+            scroll_lines: 5.0,
+            page_lines: 40.0,
+            scroll_past_end: 5.0,
+            scrolloff_top: 3,
+            scrolloff_bottom: 3,
+            wrap: true,
+            tab_width: indent::DEFAULT_TAB_WIDTH,
+            fill_column: 80,
+            auto_pairs: true,
+            completion_min_length: completion::AUTO_TRIGGER_LENGTH,
+            line_numbers: LineNumbers::Absolute,
+            gutter_slots: Vec::new(),
+            status_left: status::default_left(),
+            status_right: status::default_right(),
+            theme: theme::DEFAULT_THEME.to_string(),
+        }
+    }
+}
+
+// The user's settings file, then the project's, which wins
+pub fn settings_paths() -> Vec<PathBuf> {
+    let mut paths = Vec::new();
+    if let Some(dir) = config::config_dir() {
+        paths.push(dir.join("settings"));
+    }
+    paths.push(config::project_dir().join(".sdleditor"));
+    paths
+}
+
+fn parse_number(value: &str, min: f32, max: f32) -> Result<f32, String> {
+    let number = value.parse::<f32>().map_err(|_| format!("expected a number, not {}", value))?;
+    if number < min || number > max {
+        return Err(format!("{} is out of range, it should be between {} and {}", number, min, max));
+    }
+    Ok(number)
+}
+
+pub fn parse_count(value: &str, min: usize, max: usize) -> Result<usize, String> {
+    let number = value.parse::<usize>().map_err(|_| format!("expected a whole number, not {}", value))?;
+    if number < min || number > max {
+        return Err(format!("{} is out of range, it should be between {} and {}", number, min, max));
+    }
+    Ok(number)
+}
+
+fn parse_bool(value: &str) -> Result<bool, String> {
+    match value {
+        "true" | "yes" | "on" => Ok(true),
+        "false" | "no" | "off" => Ok(false),
+        _ => Err(format!("expected true or false, not {}", value)),
+    }
+}
+
+fn unknown_setting(name: &str) -> String {
+    let closest = NAMES
+        .iter()
+        .filter_map(|n| completion::fuzzy_score(name, n).or_else(|| completion::fuzzy_score(n, name)).map(|s| (s, n)))
+        .max();
+    match closest {
+        Some((_, n)) => format!("unknown setting {}, did you mean {}?", name, n),
+        None => format!("unknown setting {}", name),
+    }
+}
+
+impl Settings {
+    pub fn load(paths: &[PathBuf]) -> Self {
+        let mut settings = Self::default();
+        for path in paths {
+            if let Ok(contents) = fs::read_to_string(path) {
+                let dir = path.parent().unwrap_or_else(|| Path::new("."));
+                for problem in settings.apply_file(&contents, dir) {
+                    eprintln!("Settings: {}: {}", path.display(), problem);
+                }
+            }
+        }
+        settings
+    }
+
+    // Settings files are `name = value` lines, with # for comments. Paths are relative
+    // to the file. Bad lines are skipped and returned as problems.
+    pub fn apply_file(&mut self, contents: &str, dir: &Path) -> Vec<String> {
+        let mut problems = Vec::new();
+        for (i, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let result = match line.find('=') {
+                Some(eq) => {
+                    let name = line[..eq].trim();
+                    let value = line[eq + 1..].trim().trim_matches('"');
+                    self.set(name, value, dir)
+                }
+                None => Err("expected name = value".to_string()),
+            };
+            if let Err(e) = result {
+                problems.push(format!("line {}: {}", i + 1, e));
+            }
+        }
+        problems
+    }
+
+    fn set(&mut self, name: &str, value: &str, dir: &Path) -> Result<(), String> {
+        match name {
+            "font" => {
+                let font = dir.join(value);
+                if !font.is_file() {
+                    return Err(format!("can't find the font {}", font.display()));
+                }
+                self.font = font;
+            }
+            "font_size" => self.font_size = parse_number(value, 4.0, 200.0)?,
+            "padding" => self.padding = parse_number(value, 0.0, 100.0)?,
+            "scroll_lines" => self.scroll_lines = parse_number(value, 0.1, 100.0)?,
+            "page_lines" => self.page_lines = parse_number(value, 1.0, 1000.0)?,
+            "scroll_past_end" => self.scroll_past_end = parse_number(value, 0.0, 1000.0)?,
+            "scrolloff_top" => self.scrolloff_top = parse_count(value, 0, 1000)?,
+            "scrolloff_bottom" => self.scrolloff_bottom = parse_count(value, 0, 1000)?,
+            "wrap" => self.wrap = parse_bool(value)?,
+            "tab_width" => self.tab_width = parse_count(value, 1, 16)?,
+            "fill_column" => self.fill_column = parse_count(value, 10, 1000)?,
+            "auto_pairs" => self.auto_pairs = parse_bool(value)?,
+            "completion_min_length" => self.completion_min_length = parse_count(value, 1, 100)?,
+            "line_numbers" => {
+                self.line_numbers = LineNumbers::parse(value).ok_or_else(|| format!("expected off, absolute, relative or hybrid, not {}", value))?;
+            }
+            "gutter_slots" => {
+                self.gutter_slots = value
+                    .split_whitespace()
+                    .map(|name| Slot::parse(name).ok_or_else(|| format!("expected git, bookmark, diagnostic or fold, not {}", name)))
+                    .collect::<Result<Vec<Slot>, String>>()?;
+            }
+            "status_left" => self.status_left = status::parse_items(value)?,
+            "status_right" => self.status_right = status::parse_items(value)?,
+            "theme" => {
+                let names = theme::theme_names();
+                if !names.iter().any(|n| n.eq_ignore_ascii_case(value)) {
+                    return Err(format!("there's no theme called {}, the themes are {}", value, names.join(", ")));
+                }
+                self.theme = value.to_string();
+            }
+            _ => return Err(unknown_setting(name)),
+        }
+        Ok(())
+    }
+}
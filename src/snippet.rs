@@ -0,0 +1,656 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+use crate::change::{Position, Range};
+use crate::indent;
+
+pub struct Snippet {
+    pub trigger: String,
+    pub body: String,
+}
+
+// Snippet files look like this, with any number of snippets per file:
+//
+//     snippet fn "function"
+//     fn ${1:name}(${2}) {
+//         $0
+//     }
+//     endsnippet
+//
+// The quoted description after the trigger is for whoever reads the file. Lines
+// outside of a snippet starting with # are comments.
+pub fn parse_snippet_file(contents: &str) -> Vec<Snippet> {
+    let mut snippets = Vec::new();
+    let mut current: Option<(String, Vec<&str>)> = None;
+    for line in contents.lines() {
+        match current.as_mut() {
+            Some((_, body)) => {
+                if line.trim_end() == "endsnippet" {
+                    let (trigger, body) = current.take().unwrap();
+                    snippets.push(Snippet {
+                        trigger,
+                        body: body.join("\n"),
+                    });
+                } else {
+                    body.push(line);
+                }
+            }
+            None => {
+                if let Some(header) = line.strip_prefix("snippet ") {
+                    let trigger = header.split_whitespace().next().unwrap_or("");
+                    current = Some((trigger.to_string(), Vec::new()));
+                }
+            }
+        }
+    }
+    snippets
+}
+
+// Snippets for each language, loaded from data/snippets/<language>.snippets the first
+// time they're needed
+pub struct SnippetLibrary {
+    dir: PathBuf,
+    by_language: HashMap<String, Vec<Snippet>>,
+}
+
+impl SnippetLibrary {
+    pub fn new() -> Self {
+        Self {
+            dir: PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data/snippets"),
+            by_language: HashMap::new(),
+        }
+    }
+
+    pub fn find(&mut self, language: &str, trigger: &str) -> Option<&Snippet> {
+        let dir = &self.dir;
+        let snippets = self.by_language.entry(language.to_string()).or_insert_with(|| {
+            let path = dir.join(format!("{}.snippets", language));
+            fs::read_to_string(path).map(|s| parse_snippet_file(&s)).unwrap_or_default()
+        });
+        snippets.iter().find(|s| s.trigger == trigger)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Part {
+    Text(String),
+    TabStop { index: usize, default: Vec<Part> },
+    Choice { index: usize, options: Vec<String> },
+    Variable { name: String, default: Vec<Part> },
+}
+
+fn parse_number(chars: &[char], pos: &mut usize) -> Option<usize> {
+    let start = *pos;
+    while *pos < chars.len() && chars[*pos].is_ascii_digit() {
+        *pos += 1;
+    }
+    chars[start..*pos].iter().collect::<String>().parse().ok()
+}
+
+fn parse_name(chars: &[char], pos: &mut usize) -> String {
+    let start = *pos;
+    while *pos < chars.len() && (chars[*pos].is_alphanumeric() || chars[*pos] == '_') {
+        *pos += 1;
+    }
+    chars[start..*pos].iter().collect()
+}
+
+// Parse the `|one,two|}` part of a choice, with pos just after the opening `|`
+fn parse_choices(chars: &[char], pos: &mut usize) -> Option<Vec<String>> {
+    let mut options = vec![String::new()];
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        *pos += 1;
+        match c {
+            '\\' if *pos < chars.len() => {
+                options.last_mut().unwrap().push(chars[*pos]);
+                *pos += 1;
+            }
+            ',' => options.push(String::new()),
+            '|' if chars.get(*pos) == Some(&'}') => {
+                *pos += 1;
+                return Some(options);
+            }
+            _ => options.last_mut().unwrap().push(c),
+        }
+    }
+    None
+}
+
+// Try to parse a tab stop or variable starting at the `$` at chars[pos]
+fn parse_dollar(chars: &[char], pos: &mut usize) -> Option<Part> {
+    let mut p = *pos + 1;
+    let c = *chars.get(p)?;
+    let part = if c.is_ascii_digit() {
+        let index = parse_number(chars, &mut p)?;
+        Part::TabStop { index, default: Vec::new() }
+    } else if c.is_alphabetic() || c == '_' {
+        Part::Variable { name: parse_name(chars, &mut p), default: Vec::new() }
+    } else if c == '{' {
+        p += 1;
+        let next = *chars.get(p)?;
+        if next.is_ascii_digit() {
+            let index = parse_number(chars, &mut p)?;
+            match chars.get(p)? {
+                '}' => {
+                    p += 1;
+                    Part::TabStop { index, default: Vec::new() }
+                }
+                ':' => {
+                    p += 1;
+                    let default = parse_parts(chars, &mut p, true);
+                    if chars.get(p) != Some(&'}') {
+                        return None;
+                    }
+                    p += 1;
+                    Part::TabStop { index, default }
+                }
+                '|' => {
+                    p += 1;
+                    Part::Choice { index, options: parse_choices(chars, &mut p)? }
+                }
+                _ => return None,
+            }
+        } else if next.is_alphabetic() || next == '_' {
+            let name = parse_name(chars, &mut p);
+            let default = if chars.get(p) == Some(&':') {
+                p += 1;
+                parse_parts(chars, &mut p, true)
+            } else {
+                Vec::new()
+            };
+            if chars.get(p) != Some(&'}') {
+                return None;
+            }
+            p += 1;
+            Part::Variable { name, default }
+        } else {
+            return None;
+        }
+    } else {
+        return None;
+    };
+    *pos = p;
+    Some(part)
+}
+
+fn parse_parts(chars: &[char], pos: &mut usize, in_placeholder: bool) -> Vec<Part> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    while *pos < chars.len() {
+        let c = chars[*pos];
+        if c == '\\' && *pos + 1 < chars.len() && "$}\\".contains(chars[*pos + 1]) {
+            text.push(chars[*pos + 1]);
+            *pos += 2;
+        } else if c == '}' && in_placeholder {
+            break;
+        } else if c == '$' {
+            match parse_dollar(chars, pos) {
+                Some(part) => {
+                    if !text.is_empty() {
+                        parts.push(Part::Text(text.clone()));
+                        text.clear();
+                    }
+                    parts.push(part);
+                }
+                None => {
+                    text.push(c);
+                    *pos += 1;
+                }
+            }
+        } else {
+            text.push(c);
+            *pos += 1;
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    parts
+}
+
+fn parse_body(body: &str) -> Vec<Part> {
+    let chars = body.chars().collect::<Vec<char>>();
+    parse_parts(&chars, &mut 0, false)
+}
+
+// Convert days since 1970-01-01 to a (year, month, day) date
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let z = days + 719468;
+    let era = if z >= 0 { z } else { z - 146096 } / 146097;
+    let doe = z - era * 146097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+// The values of the variables a snippet can use. Times are in UTC.
+fn variables(buffer: &Buffer, selected_text: &str) -> HashMap<&'static str, String> {
+    let path = Path::new(&buffer.name);
+    let file_name = |p: Option<&std::ffi::OsStr>| p.map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let line = &buffer.contents[buffer.cursor_y];
+    let (w1, w2) = buffer.word_at(buffer.cursor_x, buffer.cursor_y);
+
+    let secs = SystemTime::now().duration_since(UNIX_EPOCH).map(|d| d.as_secs() as i64).unwrap_or(0);
+    let (year, month, day) = civil_from_days(secs.div_euclid(86400));
+    let time = secs.rem_euclid(86400);
+
+    let mut vars = HashMap::new();
+    vars.insert("TM_FILENAME", file_name(path.file_name()));
+    vars.insert("TM_FILENAME_BASE", file_name(path.file_stem()));
+    vars.insert("TM_DIRECTORY", path.parent().map(|p| p.to_string_lossy().into_owned()).unwrap_or_default());
+    vars.insert("TM_FILEPATH", buffer.name.clone());
+    vars.insert("TM_SELECTED_TEXT", selected_text.to_string());
+    vars.insert("TM_CURRENT_LINE", line.clone());
+    vars.insert("TM_CURRENT_WORD", buffer.line_graphemes(buffer.cursor_y)[w1..w2].concat());
+    vars.insert("TM_LINE_INDEX", buffer.cursor_y.to_string());
+    vars.insert("TM_LINE_NUMBER", (buffer.cursor_y + 1).to_string());
+    vars.insert("CURRENT_YEAR", year.to_string());
+    vars.insert("CURRENT_YEAR_SHORT", format!("{:02}", year % 100));
+    vars.insert("CURRENT_MONTH", format!("{:02}", month));
+    vars.insert("CURRENT_DATE", format!("{:02}", day));
+    vars.insert("CURRENT_HOUR", format!("{:02}", time / 3600));
+    vars.insert("CURRENT_MINUTE", format!("{:02}", time / 60 % 60));
+    vars.insert("CURRENT_SECOND", format!("{:02}", time % 60));
+    vars
+}
+
+struct Field {
+    index: usize,
+    // The first range is the one being edited, the rest mirror it
+    ranges: Vec<Range>,
+    choices: Vec<String>,
+}
+
+// Builds the text of an expanded snippet, keeping track of where each field ends up
+// relative to the start of the snippet
+struct Expansion {
+    text: String,
+    x: usize,
+    y: usize,
+    indent: String,
+    unit: String,
+    at_line_start: bool,
+    vars: HashMap<&'static str, String>,
+    defaults: HashMap<usize, String>,
+    fields: BTreeMap<usize, Field>,
+    // Tab stops whose placeholder has been rendered, so later copies become mirrors
+    primaries: HashSet<usize>,
+}
+
+impl Expansion {
+    fn push_str(&mut self, s: &str) {
+        for g in s.graphemes(true) {
+            if g == "\n" || g == "\r\n" {
+                self.text.push('\n');
+                self.text.push_str(&self.indent);
+                self.y += 1;
+                self.x = self.indent.graphemes(true).count();
+                self.at_line_start = true;
+            } else if g == "\t" && self.at_line_start {
+                // Snippet files indent with tabs, which become the buffer's indentation
+                self.text.push_str(&self.unit);
+                self.x += self.unit.graphemes(true).count();
+            } else {
+                self.text.push_str(g);
+                self.x += 1;
+                self.at_line_start = false;
+            }
+        }
+    }
+
+    fn pos(&self) -> Position {
+        Position::new(self.x, self.y)
+    }
+
+    // Work out the text of every tab stop, so mirrors that come before the
+    // placeholder they copy still get the right text
+    fn collect_defaults(&mut self, parts: &[Part]) {
+        for part in parts {
+            match part {
+                Part::TabStop { index, default } if !default.is_empty() => {
+                    if !self.defaults.contains_key(index) {
+                        let text = self.plain_text(default);
+                        self.defaults.insert(*index, text);
+                    }
+                    self.collect_defaults(default);
+                }
+                Part::Choice { index, options } => {
+                    self.defaults.entry(*index).or_insert_with(|| options[0].clone());
+                }
+                _ => {}
+            }
+        }
+    }
+
+    fn plain_text(&self, parts: &[Part]) -> String {
+        let mut s = String::new();
+        for part in parts {
+            match part {
+                Part::Text(t) => s.push_str(t),
+                Part::TabStop { default, .. } => s.push_str(&self.plain_text(default)),
+                Part::Choice { options, .. } => s.push_str(&options[0]),
+                Part::Variable { name, default } => match self.vars.get(name.as_str()) {
+                    Some(value) => s.push_str(value),
+                    None => s.push_str(&self.plain_text(default)),
+                },
+            }
+        }
+        s
+    }
+
+    fn add_range(&mut self, index: usize, range: Range, is_primary: bool, choices: Vec<String>) {
+        let field = self.fields.entry(index).or_insert_with(|| Field {
+            index,
+            ranges: Vec::new(),
+            choices: Vec::new(),
+        });
+        if is_primary {
+            field.ranges.insert(0, range);
+            field.choices = choices;
+        } else {
+            field.ranges.push(range);
+        }
+    }
+
+    fn render(&mut self, parts: &[Part]) {
+        for part in parts {
+            match part {
+                Part::Text(t) => self.push_str(t),
+                Part::TabStop { index, default } => {
+                    let start = self.pos();
+                    let is_primary = !default.is_empty() && self.primaries.insert(*index);
+                    if is_primary {
+                        self.render(default);
+                    } else {
+                        let text = self.defaults.get(index).cloned().unwrap_or_default();
+                        self.push_str(&text);
+                    }
+                    let end = self.pos();
+                    self.add_range(*index, Range { start, end }, is_primary, Vec::new());
+                }
+                Part::Choice { index, options } => {
+                    let start = self.pos();
+                    self.push_str(&options[0]);
+                    let end = self.pos();
+                    let is_primary = self.primaries.insert(*index);
+                    self.add_range(*index, Range { start, end }, is_primary, options.clone());
+                }
+                Part::Variable { name, default } => match self.vars.get(name.as_str()).cloned() {
+                    Some(value) => self.push_str(&value),
+                    None => self.render(default),
+                },
+            }
+        }
+    }
+}
+
+pub struct SnippetSession {
+    fields: Vec<Field>,
+    current: usize,
+    version: u64,
+}
+
+impl SnippetSession {
+    // Replace x1..x2 on the cursor line with the expanded snippet and start a session
+    // on its first field. Returns None if the snippet has no fields to visit.
+    pub fn expand(buffer: &mut Buffer, x1: usize, x2: usize, body: &str, selected_text: &str) -> Option<Self> {
+        let y = buffer.cursor_y;
+        let parts = parse_body(body);
+        let mut expansion = Expansion {
+            text: String::new(),
+            x: 0,
+            y: 0,
+            indent: indent::leading_whitespace(&buffer.contents[y]).to_string(),
+            unit: buffer.indent_style.unit(),
+            at_line_start: false,
+            vars: variables(buffer, selected_text),
+            defaults: HashMap::new(),
+            fields: BTreeMap::new(),
+            primaries: HashSet::new(),
+        };
+        expansion.collect_defaults(&parts);
+        expansion.render(&parts);
+
+        let to_buffer = |p: Position| {
+            if p.y == 0 {
+                Position::new(x1 + p.x, y)
+            } else {
+                Position::new(p.x, y + p.y)
+            }
+        };
+        let end = to_buffer(expansion.pos());
+        // $0 is where the cursor ends up, and it's always visited last
+        let final_field = expansion.fields.remove(&0).map(|mut f| {
+            f.ranges = f.ranges.iter().map(|r| Range { start: to_buffer(r.start), end: to_buffer(r.end) }).collect();
+            f
+        });
+        let mut fields = expansion
+            .fields
+            .into_values()
+            .map(|mut f| {
+                f.ranges = f.ranges.iter().map(|r| Range { start: to_buffer(r.start), end: to_buffer(r.end) }).collect();
+                f
+            })
+            .collect::<Vec<Field>>();
+        fields.push(final_field.unwrap_or(Field {
+            index: 0,
+            ranges: vec![Range { start: end, end }],
+            choices: Vec::new(),
+        }));
+
+        buffer.replace_text(x1, y, x2, y, expansion.text);
+        let session = SnippetSession {
+            fields,
+            current: 0,
+            version: buffer.version(),
+        };
+        session.select_current(buffer);
+        if session.fields.len() == 1 {
+            return None;
+        }
+        Some(session)
+    }
+
+    fn select_current(&self, buffer: &mut Buffer) {
+        let range = self.fields[self.current].ranges[0];
+        buffer.sel_x = range.start.x;
+        buffer.sel_y = range.start.y;
+        buffer.cursor_x = range.end.x;
+        buffer.cursor_y = range.end.y;
+        buffer.max_cursor_x = buffer.cursor_x;
+    }
+
+    // Move to the next field. Returns false once the final field has been reached
+    // and the session is over.
+    pub fn next_field(&mut self, buffer: &mut Buffer) -> bool {
+        self.current = (self.current + 1).min(self.fields.len() - 1);
+        self.select_current(buffer);
+        if self.fields[self.current].index == 0 {
+            buffer.set_selection(false);
+            return false;
+        }
+        true
+    }
+
+    pub fn prev_field(&mut self, buffer: &mut Buffer) {
+        self.current = self.current.saturating_sub(1);
+        self.select_current(buffer);
+    }
+
+    pub fn has_choices(&self) -> bool {
+        !self.fields[self.current].choices.is_empty()
+    }
+
+    // Replace the current field with the next (or previous) of its choices
+    pub fn cycle_choice(&mut self, buffer: &mut Buffer, forward: bool) {
+        let field = &self.fields[self.current];
+        let range = field.ranges[0];
+        let text = buffer.text_range(range.start.x, range.start.y, range.end.x, range.end.y);
+        let n = field.choices.len();
+        let i = field.choices.iter().position(|c| *c == text).unwrap_or(0);
+        let i = if forward { (i + 1) % n } else { (i + n - 1) % n };
+        let choice = field.choices[i].clone();
+        let (x, y) = buffer.replace_text(range.start.x, range.start.y, range.end.x, range.end.y, choice);
+        self.track_changes(buffer);
+        self.fields[self.current].ranges[0] = Range { start: range.start, end: Position::new(x, y) };
+        self.sync(buffer);
+        self.select_current(buffer);
+    }
+
+    // Move every field along with the edits made since we last looked. The field being
+    // edited grows with text typed at its end, the others get pushed along by it.
+    fn track_changes(&mut self, buffer: &Buffer) -> bool {
+        let changes = match buffer.changes_since(self.version) {
+            Some(changes) => changes,
+            None => return false,
+        };
+        for change in &changes {
+            for (i, field) in self.fields.iter_mut().enumerate() {
+                let is_current = i == self.current;
+                for (j, range) in field.ranges.iter_mut().enumerate() {
+                    range.start = change.adjust(range.start, !is_current || j > 0);
+                    range.end = change.adjust(range.end, is_current).max(range.start);
+                }
+            }
+        }
+        self.version = buffer.version();
+        true
+    }
+
+    // Bring the session up to date after the buffer was edited, copying the current
+    // field's text into its mirrors. Returns false if the session should end because
+    // the cursor left the snippet or its fields can no longer be tracked.
+    pub fn sync(&mut self, buffer: &mut Buffer) -> bool {
+        if !self.track_changes(buffer) {
+            return false;
+        }
+        let primary = self.fields[self.current].ranges[0];
+        let text = buffer.text_range(primary.start.x, primary.start.y, primary.end.x, primary.end.y);
+        buffer.continue_undo_group();
+        for i in 1..self.fields[self.current].ranges.len() {
+            let mirror = self.fields[self.current].ranges[i];
+            if buffer.text_range(mirror.start.x, mirror.start.y, mirror.end.x, mirror.end.y) == text {
+                continue;
+            }
+            let (x, y) = buffer.replace_text(mirror.start.x, mirror.start.y, mirror.end.x, mirror.end.y, text.clone());
+            self.track_changes(buffer);
+            self.fields[self.current].ranges[i] = Range { start: mirror.start, end: Position::new(x, y) };
+        }
+        buffer.end_undo_group();
+        self.version = buffer.version();
+
+        let cursor = Position::new(buffer.cursor_x, buffer.cursor_y);
+        self.fields
+            .iter()
+            .flat_map(|f| f.ranges.iter())
+            .any(|r| r.start <= cursor && cursor <= r.end)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn buffer(lines: &[&str], x: usize, y: usize) -> Buffer {
+        let mut buffer = Buffer::from_lines(lines.iter().copied());
+        buffer.cursor_x = x;
+        buffer.cursor_y = y;
+        buffer.set_selection(false);
+        buffer
+    }
+
+    #[test]
+    fn parses_snippet_files() {
+        let snippets = parse_snippet_file("# comment\nsnippet fn \"function\"\nfn ${1:name}() {\n\t$0\n}\nendsnippet\nsnippet p\nprint($1)\nendsnippet\n");
+        assert_eq!(snippets.len(), 2);
+        assert_eq!(snippets[0].trigger, "fn");
+        assert_eq!(snippets[0].body, "fn ${1:name}() {\n\t$0\n}");
+        assert_eq!(snippets[1].trigger, "p");
+        assert_eq!(snippets[1].body, "print($1)");
+    }
+
+    #[test]
+    fn expands_with_indentation_and_placeholders() {
+        let mut buffer = buffer(&["    for"], 7, 0);
+        let session = SnippetSession::expand(&mut buffer, 4, 7, "for ${1:i} in ${2:items} {\n\t$0\n}", "");
+        assert!(session.is_some());
+        assert_eq!(buffer.contents, vec!["    for i in items {", "        ", "    }"]);
+        // The first field is selected
+        assert_eq!((buffer.sel_x, buffer.cursor_x, buffer.cursor_y), (8, 9, 0));
+    }
+
+    #[test]
+    fn visits_fields_in_order_and_ends_on_the_final_one() {
+        let mut buffer = buffer(&["f"], 1, 0);
+        let mut session = SnippetSession::expand(&mut buffer, 0, 1, "${2:b}(${1:a})$0;", "").unwrap();
+        assert_eq!(buffer.contents[0], "b(a);");
+        assert_eq!((buffer.sel_x, buffer.cursor_x), (2, 3));
+        assert!(session.next_field(&mut buffer));
+        assert_eq!((buffer.sel_x, buffer.cursor_x), (0, 1));
+        session.prev_field(&mut buffer);
+        assert_eq!((buffer.sel_x, buffer.cursor_x), (2, 3));
+        session.next_field(&mut buffer);
+        assert!(!session.next_field(&mut buffer));
+        assert_eq!(buffer.cursor_x, 4);
+    }
+
+    #[test]
+    fn mirrors_follow_the_field_being_typed() {
+        let mut buffer = buffer(&[""], 0, 0);
+        let mut session = SnippetSession::expand(&mut buffer, 0, 0, "let ${1:x} = $1 + $1;", "").unwrap();
+        assert_eq!(buffer.contents[0], "let x = x + x;");
+        buffer.action_type_text("ab".to_string());
+        assert!(session.sync(&mut buffer));
+        assert_eq!(buffer.contents[0], "let ab = ab + ab;");
+    }
+
+    #[test]
+    fn mirror_updates_are_undone_with_the_edit_that_caused_them() {
+        let mut buffer = buffer(&[""], 0, 0);
+        let mut session = SnippetSession::expand(&mut buffer, 0, 0, "${1:x} = $1", "").unwrap();
+        buffer.action_type_text("ab".to_string());
+        assert!(session.sync(&mut buffer));
+        assert_eq!(buffer.contents[0], "ab = ab");
+        buffer.undo();
+        assert_eq!(buffer.contents[0], "x = x");
+    }
+
+    #[test]
+    fn directory_is_the_whole_parent_path() {
+        let mut buffer = buffer(&["d"], 1, 0);
+        buffer.name = "/home/me/src/main.rs".to_string();
+        SnippetSession::expand(&mut buffer, 0, 1, "$TM_DIRECTORY $TM_FILENAME_BASE", "");
+        assert_eq!(buffer.contents[0], "/home/me/src main");
+    }
+
+    #[test]
+    fn expands_choices_variables_and_escapes() {
+        let mut buffer = buffer(&["d"], 1, 0);
+        let mut session =
+            SnippetSession::expand(&mut buffer, 0, 1, "${1|Debug,Clone|} ${FOO:bar} $TM_SELECTED_TEXT \\$1", "sel").unwrap();
+        assert_eq!(buffer.contents[0], "Debug bar sel $1");
+        assert!(session.has_choices());
+        session.cycle_choice(&mut buffer, true);
+        assert_eq!(buffer.contents[0], "Clone bar sel $1");
+        session.cycle_choice(&mut buffer, false);
+        assert_eq!(buffer.contents[0], "Debug bar sel $1");
+    }
+
+    #[test]
+    fn snippets_without_fields_just_insert_text() {
+        let mut buffer = buffer(&["x"], 1, 0);
+        assert!(SnippetSession::expand(&mut buffer, 0, 1, "plain text", "").is_none());
+        assert_eq!(buffer.contents[0], "plain text");
+        assert_eq!(buffer.cursor_x, 10);
+    }
+}
@@ -0,0 +1,119 @@
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::buffer::Buffer;
+use crate::indent::IndentStyle;
+
+// Things the bar at the top of a pane can show
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Item {
+    Name,
+    // * when there are unsaved changes
+    Dirty,
+    ReadOnly,
+    // Line and column of the cursor
+    Position,
+    // How much is selected, when anything is
+    Selection,
+    Language,
+    Encoding,
+    LineEnding,
+    Indent,
+    // What the input mode has to say, like NORMAL or a half-typed chord
+    Mode,
+    // How far down the file the view is
+    Percent,
+}
+
+const NAMES: &[(&str, Item)] = &[
+    ("name", Item::Name),
+    ("dirty", Item::Dirty),
+    ("read_only", Item::ReadOnly),
+    ("position", Item::Position),
+    ("selection", Item::Selection),
+    ("language", Item::Language),
+    ("encoding", Item::Encoding),
+    ("line_ending", Item::LineEnding),
+    ("indent", Item::Indent),
+    ("mode", Item::Mode),
+    ("percent", Item::Percent),
+];
+
+pub fn default_left() -> Vec<Item> {
+    vec![Item::Dirty, Item::Name, Item::ReadOnly]
+}
+
+pub fn default_right() -> Vec<Item> {
+    vec![
+        Item::Mode,
+        Item::Selection,
+        Item::Position,
+        Item::Language,
+        Item::Indent,
+        Item::Encoding,
+        Item::LineEnding,
+        Item::Percent,
+    ]
+}
+
+// A list of item names separated by spaces, like "dirty name read_only"
+pub fn parse_items(value: &str) -> Result<Vec<Item>, String> {
+    value
+        .split_whitespace()
+        .map(|name| match NAMES.iter().find(|(n, _)| *n == name) {
+            Some((_, item)) => Ok(*item),
+            None => {
+                let names = NAMES.iter().map(|(n, _)| *n).collect::<Vec<&str>>();
+                Err(format!("there's no status item called {}, the items are {}", name, names.join(", ")))
+            }
+        })
+        .collect()
+}
+
+// What the view shows of the file: all of it, the top, the bottom, or how far the top of
+// the view is between those
+pub fn view_position(top: f32, visible: f32, total: f32) -> String {
+    if total <= visible {
+        "All".to_string()
+    } else if top <= 0.0 {
+        "Top".to_string()
+    } else if top + visible >= total {
+        "Bot".to_string()
+    } else {
+        format!("{}%", (top / (total - visible) * 100.0).round() as usize)
+    }
+}
+
+// An item's text, empty when it has nothing to show
+pub fn item_text(item: Item, buffer: &Buffer, mode: &str, view: &str) -> String {
+    match item {
+        Item::Name => buffer.name.clone(),
+        Item::Dirty => if buffer.is_dirty { "*".to_string() } else { String::new() },
+        Item::ReadOnly => if buffer.read_only { "[RO]".to_string() } else { String::new() },
+        Item::Position => format!("{}:{}", buffer.cursor_y + 1, buffer.cursor_x + 1),
+        Item::Selection => {
+            let (x1, y1, x2, y2) = buffer.get_selection();
+            if (x1, y1) == (x2, y2) {
+                return String::new();
+            }
+            let chars = buffer.text_range(x1, y1, x2, y2).graphemes(true).count();
+            match y2 - y1 {
+                0 => format!("{} selected", chars),
+                lines => format!("{} selected, {} lines", chars, lines + 1),
+            }
+        }
+        Item::Language => buffer.language.clone(),
+        Item::Encoding => buffer.encoding.name().to_string(),
+        Item::LineEnding => buffer.line_ending.name().to_string(),
+        Item::Indent => match buffer.indent_style {
+            IndentStyle::Tabs => "Tabs".to_string(),
+            IndentStyle::Spaces(n) => format!("Spaces: {}", n),
+        },
+        Item::Mode => mode.to_string(),
+        Item::Percent => view.to_string(),
+    }
+}
+
+// The texts of the items that have something to show
+pub fn texts(items: &[Item], buffer: &Buffer, mode: &str, view: &str) -> Vec<String> {
+    items.iter().map(|i| item_text(*i, buffer, mode, view)).filter(|t| !t.is_empty()).collect()
+}
@@ -0,0 +1,390 @@
+use std::env;
+use std::io::{self, Read, Write};
+use std::process::{Command, Stdio};
+use std::sync::mpsc::{self, Receiver};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use pgfx::Color;
+
+use crate::render::{Cell, Headless};
+
+// How often to ask the terminal for its size, since there's no resize event to wait for
+const SIZE_CHECK_INTERVAL: Duration = Duration::from_millis(250);
+// Presses closer together than this on the same cell count as a double click
+const DOUBLE_CLICK_TIME: Duration = Duration::from_millis(400);
+
+// What the terminal sends, in the editor's terms. Typing a character gives a key and
+// its text, the way the window does. Mouse positions are cells from the top left.
+#[derive(Debug, PartialEq)]
+pub enum Input {
+    Key(String),
+    Text(String),
+    MouseDown { x: usize, y: usize, clicks: usize },
+    MouseDrag { x: usize, y: usize },
+    MouseUp,
+    // Lines to scroll, up being positive like the window's scroll wheel
+    Scroll(f32),
+}
+
+// "c-", "s-" and "a-" for xterm's modifier parameter, which is one more than a bitmask
+// of shift, alt and control
+fn modifiers(param: u32) -> String {
+    let bits = param.saturating_sub(1);
+    let mut result = String::new();
+    if bits & 4 != 0 {
+        result.push_str("c-");
+    }
+    if bits & 1 != 0 {
+        result.push_str("s-");
+    }
+    if bits & 2 != 0 {
+        result.push_str("a-");
+    }
+    result
+}
+
+// The key for a control character, or None if it isn't one
+fn control_key(byte: u8) -> Option<String> {
+    let name = match byte {
+        b'\r' | b'\n' => "return".to_string(),
+        b'\t' => "tab".to_string(),
+        0x7f | 0x08 => "backspace".to_string(),
+        0x1b => "escape".to_string(),
+        0 => "c-space".to_string(),
+        1..=26 => format!("c-{}", (b'a' + byte - 1) as char),
+        28..=31 => format!("c-{}", (b'\\' + byte - 28) as char),
+        _ => return None,
+    };
+    Some(name)
+}
+
+// The key for a typed character: shifted letters get "s-", like the window gives
+fn char_key(c: char) -> String {
+    match c {
+        ' ' => "space".to_string(),
+        c if c.is_uppercase() => format!("s-{}", c.to_lowercase()),
+        c => c.to_string(),
+    }
+}
+
+// The key for the end of a CSI or SS3 sequence, like the "A" of ESC [ A
+fn sequence_key(params: &[u32], end: char) -> Option<String> {
+    let name = match (end, params.first().copied().unwrap_or(1)) {
+        ('A', _) => "up",
+        ('B', _) => "down",
+        ('C', _) => "right",
+        ('D', _) => "left",
+        ('H', _) => "home",
+        ('F', _) => "end",
+        ('P', _) => "f1",
+        ('Q', _) => "f2",
+        ('R', _) => "f3",
+        ('S', _) => "f4",
+        ('Z', _) => return Some("s-tab".to_string()),
+        ('~', 1) | ('~', 7) => "home",
+        ('~', 2) => "insert",
+        ('~', 3) => "delete",
+        ('~', 4) | ('~', 8) => "end",
+        ('~', 5) => "pageup",
+        ('~', 6) => "pagedown",
+        ('~', n @ 11..=15) => return Some(format!("{}f{}", modifiers(params.get(1).copied().unwrap_or(1)), n - 10)),
+        ('~', n @ 17..=21) => return Some(format!("{}f{}", modifiers(params.get(1).copied().unwrap_or(1)), n - 11)),
+        ('~', n @ 23..=24) => return Some(format!("{}f{}", modifiers(params.get(1).copied().unwrap_or(1)), n - 12)),
+        _ => return None,
+    };
+    Some(format!("{}{}", modifiers(params.get(1).copied().unwrap_or(1)), name))
+}
+
+// An SGR mouse report: ESC [ < button ; column ; row, then M for a press or m for a release
+fn mouse_input(params: &[u32], end: char) -> Option<Input> {
+    let (button, x, y) = match params {
+        [button, x, y] => (*button, x.saturating_sub(1) as usize, y.saturating_sub(1) as usize),
+        _ => return None,
+    };
+    if button & 64 != 0 {
+        return Some(Input::Scroll(if button & 1 == 0 { 1.0 } else { -1.0 }));
+    }
+    match (end, button & 3, button & 32 != 0) {
+        ('m', _, _) => Some(Input::MouseUp),
+        ('M', 0, true) => Some(Input::MouseDrag { x, y }),
+        ('M', 0, false) => Some(Input::MouseDown { x, y, clicks: 1 }),
+        _ => None,
+    }
+}
+
+// The character starting at bytes[i] and how many bytes it takes
+fn char_at(bytes: &[u8], i: usize) -> Option<(char, usize)> {
+    let len = match bytes[i] {
+        0xf0..=0xff => 4,
+        0xe0..=0xef => 3,
+        0xc0..=0xdf => 2,
+        _ => 1,
+    };
+    let c = std::str::from_utf8(bytes.get(i..i + len)?).ok()?.chars().next()?;
+    Some((c, len))
+}
+
+// Split what was read from the terminal into keys, text and mouse events. A lone escape
+// is the escape key; followed by another key it means that key with alt held.
+pub fn parse_input(bytes: &[u8]) -> Vec<Input> {
+    let mut inputs = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let byte = bytes[i];
+        if byte == 0x1b && i + 1 < bytes.len() && (bytes[i + 1] == b'[' || bytes[i + 1] == b'O') {
+            // ESC [ or ESC O, numbers separated by semicolons, then a letter or ~
+            let mouse = bytes.get(i + 2) == Some(&b'<');
+            let mut j = if mouse { i + 3 } else { i + 2 };
+            let mut params = Vec::new();
+            let mut number = None;
+            while j < bytes.len() && (bytes[j].is_ascii_digit() || bytes[j] == b';') {
+                if bytes[j] == b';' {
+                    params.push(number.take().unwrap_or(1));
+                } else {
+                    number = Some(number.unwrap_or(0) * 10 + (bytes[j] - b'0') as u32);
+                }
+                j += 1;
+            }
+            params.extend(number);
+            if let Some(&end) = bytes.get(j) {
+                let end = end as char;
+                if mouse {
+                    inputs.extend(mouse_input(&params, end));
+                } else {
+                    inputs.extend(sequence_key(&params, end).map(Input::Key));
+                }
+                i = j + 1;
+                continue;
+            }
+            // Cut off, so there's nothing to make of it
+            break;
+        }
+        if byte == 0x1b && i + 1 < bytes.len() {
+            // Alt and a key
+            let next = char_at(bytes, i + 1);
+            let key = control_key(bytes[i + 1]).or_else(|| next.map(|(c, _)| char_key(c)));
+            if let Some(key) = key {
+                let len = next.map_or(1, |(_, len)| len);
+                // Alt goes after control and shift, like the other modifiers
+                let name = key.trim_start_matches("c-").trim_start_matches("s-");
+                inputs.push(Input::Key(format!("{}a-{}", &key[..key.len() - name.len()], name)));
+                i += 1 + len;
+                continue;
+            }
+        }
+        if let Some(key) = control_key(byte) {
+            inputs.push(Input::Key(key));
+            i += 1;
+            continue;
+        }
+        // A character, which can be several bytes
+        match char_at(bytes, i) {
+            Some((c, len)) => {
+                inputs.push(Input::Key(char_key(c)));
+                inputs.push(Input::Text(c.to_string()));
+                i += len;
+            }
+            None => i += 1,
+        }
+    }
+    inputs
+}
+
+// The nearest of the 256 xterm colours: the 6x6x6 cube or the grey ramp
+pub fn ansi_256(color: Color) -> u8 {
+    let level = |v: u8| if v < 48 { 0 } else if v < 115 { 1 } else { (v - 35) / 40 };
+    let value = |l: u8| if l == 0 { 0 } else { l as i32 * 40 + 55 };
+    let (r, g, b) = (level(color.r), level(color.g), level(color.b));
+    let distance = |x: i32, y: i32, z: i32| {
+        (x - color.r as i32).pow(2) + (y - color.g as i32).pow(2) + (z - color.b as i32).pow(2)
+    };
+    let cube = 16 + 36 * r + 6 * g + b;
+    let cube_distance = distance(value(r), value(g), value(b));
+    let average = (color.r as i32 + color.g as i32 + color.b as i32) / 3;
+    let grey = ((average - 8).max(0) / 10).min(23);
+    let grey_value = grey * 10 + 8;
+    if distance(grey_value, grey_value, grey_value) < cube_distance {
+        232 + grey as u8
+    } else {
+        cube
+    }
+}
+
+fn same_color(a: Color, b: Color) -> bool {
+    (a.r, a.g, a.b) == (b.r, b.g, b.b)
+}
+
+fn same_cell(a: &Cell, b: &Cell) -> bool {
+    a.text == b.text && same_color(a.fg, b.fg) && same_color(a.bg, b.bg)
+}
+
+// Run stty on the terminal, for what it prints
+fn stty(args: &[&str]) -> Option<String> {
+    let output = Command::new("stty").args(args).stdin(Stdio::inherit()).output().ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8_lossy(&output.stdout).trim().to_string())
+}
+
+// Rows and columns, from stty
+fn terminal_size() -> Option<(usize, usize)> {
+    let size = stty(&["size"])?;
+    let mut parts = size.split_whitespace().map(|p| p.parse::<usize>().ok());
+    match (parts.next()??, parts.next()??) {
+        (rows, columns) if rows > 0 && columns > 0 => Some((columns, rows)),
+        _ => None,
+    }
+}
+
+// The terminal as a screen of character cells. Frames are drawn into `screen` like any
+// other renderer, with every cell one unit square, and `present` writes out the cells
+// that changed since the last frame. The terminal goes back how it was when this drops.
+pub struct Terminal {
+    pub screen: Headless,
+    pub columns: usize,
+    pub rows: usize,
+    truecolor: bool,
+    // What's on the terminal now, empty when it needs drawing from scratch
+    shown: Vec<Vec<Cell>>,
+    saved_mode: String,
+    input: Receiver<Vec<u8>>,
+    last_size_check: Instant,
+    // Where and when the mouse was last pressed, and how many presses in a row
+    last_click: Option<(usize, usize, Instant, usize)>,
+}
+
+impl Terminal {
+    pub fn new() -> Result<Terminal, String> {
+        let saved_mode = stty(&["-g"]).ok_or("standard input isn't a terminal")?;
+        let (columns, rows) = terminal_size().ok_or("couldn't get the terminal's size")?;
+        stty(&["raw", "-echo"]).ok_or("couldn't put the terminal in raw mode")?;
+
+        // Keystrokes come in on their own thread so reading can time out
+        let (sender, input) = mpsc::channel();
+        thread::spawn(move || {
+            let mut stdin = io::stdin();
+            let mut buf = [0; 1024];
+            while let Ok(n) = stdin.read(&mut buf) {
+                if n == 0 || sender.send(buf[..n].to_vec()).is_err() {
+                    break;
+                }
+            }
+        });
+
+        let colorterm = env::var("COLORTERM").unwrap_or_default();
+        let terminal = Terminal {
+            screen: Headless::new(1.0, 1.0),
+            columns,
+            rows,
+            truecolor: colorterm == "truecolor" || colorterm == "24bit",
+            shown: Vec::new(),
+            saved_mode,
+            input,
+            last_size_check: Instant::now(),
+            last_click: None,
+        };
+        // The alternate screen, no cursor, and mouse presses and drags in SGR form
+        terminal.write("\x1b[?1049h\x1b[?25l\x1b[?1002h\x1b[?1006h");
+        Ok(terminal)
+    }
+
+    fn write(&self, text: &str) {
+        let mut stdout = io::stdout();
+        let _ = stdout.write_all(text.as_bytes());
+        let _ = stdout.flush();
+    }
+
+    // Whether the terminal has changed size since this was last asked
+    pub fn resized(&mut self) -> bool {
+        if self.last_size_check.elapsed() < SIZE_CHECK_INTERVAL {
+            return false;
+        }
+        self.last_size_check = Instant::now();
+        match terminal_size() {
+            Some((columns, rows)) if (columns, rows) != (self.columns, self.rows) => {
+                self.columns = columns;
+                self.rows = rows;
+                self.shown.clear();
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Whatever's been typed, waiting up to `timeout` for something
+    pub fn read(&mut self, timeout: Duration) -> Vec<Input> {
+        let mut bytes = match self.input.recv_timeout(timeout) {
+            Ok(bytes) => bytes,
+            Err(_) => return Vec::new(),
+        };
+        while let Ok(more) = self.input.try_recv() {
+            bytes.extend(more);
+        }
+        let mut inputs = parse_input(&bytes);
+        for input in &mut inputs {
+            if let Input::MouseDown { x, y, clicks } = input {
+                *clicks = match self.last_click {
+                    Some((last_x, last_y, time, n)) if (last_x, last_y) == (*x, *y) && time.elapsed() < DOUBLE_CLICK_TIME => n + 1,
+                    _ => 1,
+                };
+                self.last_click = Some((*x, *y, Instant::now(), *clicks));
+            }
+        }
+        inputs
+    }
+
+    fn color_code(&self, color: Color, background: bool) -> String {
+        let layer = if background { 48 } else { 38 };
+        if self.truecolor {
+            format!("\x1b[{};2;{};{};{}m", layer, color.r, color.g, color.b)
+        } else {
+            format!("\x1b[{};5;{}m", layer, ansi_256(color))
+        }
+    }
+
+    // Write out what's been drawn to the screen since the last frame
+    pub fn present(&mut self) {
+        let cells = self.screen.cells(self.columns, self.rows);
+        let mut out = String::new();
+        if self.shown.is_empty() {
+            out.push_str("\x1b[2J");
+        }
+        let mut colors: Option<(Color, Color)> = None;
+        // Where the terminal's cursor is after the last write, to skip moving it
+        let mut position = None;
+        for (y, row) in cells.iter().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                let shown = self.shown.get(y).and_then(|r| r.get(x));
+                if shown.is_some_and(|s| same_cell(s, cell)) {
+                    continue;
+                }
+                if position != Some((x, y)) {
+                    out.push_str(&format!("\x1b[{};{}H", y + 1, x + 1));
+                }
+                if colors.is_none_or(|(fg, _)| !same_color(fg, cell.fg)) {
+                    out.push_str(&self.color_code(cell.fg, false));
+                }
+                if colors.is_none_or(|(_, bg)| !same_color(bg, cell.bg)) {
+                    out.push_str(&self.color_code(cell.bg, true));
+                }
+                colors = Some((cell.fg, cell.bg));
+                out.push_str(&cell.text);
+                position = Some((x + 1, y));
+            }
+        }
+        if !out.is_empty() {
+            out.push_str("\x1b[0m");
+            self.write(&out);
+        }
+        self.shown = cells;
+    }
+}
+
+impl Drop for Terminal {
+    fn drop(&mut self) {
+        self.write("\x1b[?1006l\x1b[?1002l\x1b[?25h\x1b[0m\x1b[?1049l");
+        stty(&[&self.saved_mode]);
+    }
+}
@@ -0,0 +1,411 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use pgfx::Color;
+
+use crate::config;
+use crate::json::{self, Value};
+use crate::plist;
+
+pub const DEFAULT_THEME: &str = "gruvbox-dark";
+
+#[derive(Clone)]
+pub struct Theme {
+    // The file name without the extension, which is how settings and commands refer to it
+    pub name: String,
+    pub dark: bool,
+    pub fg: Color,
+    pub bg: Color,
+    pub ui_fg: Color,
+    pub ui_bg: Color,
+    pub ui_inactive_fg: Color,
+    pub ui_inactive_bg: Color,
+    pub selection: Color,
+    pub cursor: Color,
+    // Line numbers, and the current line's number
+    pub gutter_fg: Color,
+    pub gutter_current_fg: Color,
+    // Colours for syntax tokens by TextMate scope, like "comment" or "string.quoted"
+    pub scopes: Vec<(String, Color)>,
+}
+
+// Parse #rgb, #rrggbb or #rrggbbaa. Colours with alpha are mixed into `under` since
+// nothing is drawn translucent.
+fn parse_color(text: &str, under: Color) -> Option<Color> {
+    let hex = text.trim().strip_prefix('#')?;
+    let digits = hex.chars().map(|c| c.to_digit(16).map(|d| d as u8)).collect::<Option<Vec<u8>>>()?;
+    let (r, g, b, a) = match digits.len() {
+        3 | 4 => (digits[0] * 17, digits[1] * 17, digits[2] * 17, digits.get(3).map_or(255, |a| a * 17)),
+        6 | 8 => (
+            digits[0] * 16 + digits[1],
+            digits[2] * 16 + digits[3],
+            digits[4] * 16 + digits[5],
+            if digits.len() == 8 { digits[6] * 16 + digits[7] } else { 255 },
+        ),
+        _ => return None,
+    };
+    Some(mix(Color::new(r, g, b), under, a as f32 / 255.0))
+}
+
+// `amount` of `a` and the rest of `b`
+fn mix(a: Color, b: Color, amount: f32) -> Color {
+    let channel = |x: u8, y: u8| (x as f32 * amount + y as f32 * (1.0 - amount)).round() as u8;
+    Color::new(channel(a.r, b.r), channel(a.g, b.g), channel(a.b, b.b))
+}
+
+fn is_dark(c: Color) -> bool {
+    (c.r as u32 * 299 + c.g as u32 * 587 + c.b as u32 * 114) / 1000 < 128
+}
+
+// Turn a TextMate scope selector like "source.rust comment, string - string.regexp" into
+// the scopes it's about. Only the last part of a descendant selector is kept and
+// exclusions are dropped, which is close enough for colouring.
+fn selector_scopes(selector: &str) -> Vec<String> {
+    selector
+        .split(',')
+        .filter_map(|part| {
+            let part = part.split(" -").next().unwrap_or("");
+            part.split_whitespace().last().map(|s| s.to_string())
+        })
+        .collect()
+}
+
+impl Default for Theme {
+    // Gruvbox dark, for when no theme file can be read
+    fn default() -> Self {
+        Theme {
+            name: DEFAULT_THEME.to_string(),
+            dark: true,
+            fg: Color::new(253, 244, 193),
+            bg: Color::new(40, 40, 40),
+            ui_fg: Color::new(253, 244, 193),
+            ui_bg: Color::new(80, 73, 69),
+            ui_inactive_fg: Color::new(189, 174, 147),
+            ui_inactive_bg: Color::new(60, 56, 54),
+            selection: Color::new(168, 153, 132),
+            cursor: Color::new(253, 244, 193),
+            gutter_fg: Color::new(124, 111, 100),
+            gutter_current_fg: Color::new(250, 189, 47),
+            scopes: vec![("comment".to_string(), Color::new(146, 131, 116))],
+        }
+    }
+}
+
+impl Theme {
+    // The colour for a token's scope, from the most specific theme scope that covers it.
+    // "comment" covers "comment.line.double-slash" but not "commentary".
+    pub fn scope_color(&self, scope: &str) -> Option<Color> {
+        self.scopes
+            .iter()
+            .filter(|(s, _)| scope == s || (scope.starts_with(s.as_str()) && scope[s.len()..].starts_with('.')))
+            .max_by_key(|(s, _)| s.len())
+            .map(|(_, c)| *c)
+    }
+
+    // The colour for a token, from its innermost scope that the theme colours
+    pub fn color_for_scopes(&self, scopes: &[String]) -> Color {
+        scopes.iter().rev().find_map(|s| self.scope_color(s)).unwrap_or(self.fg)
+    }
+
+    // Bar colours for themes that only describe the text area
+    fn derive_ui(&mut self) {
+        self.ui_fg = self.fg;
+        self.ui_bg = mix(self.fg, self.bg, 0.2);
+        self.ui_inactive_fg = mix(self.fg, self.bg, 0.7);
+        self.ui_inactive_bg = mix(self.fg, self.bg, 0.1);
+        self.gutter_fg = mix(self.fg, self.bg, 0.4);
+        self.gutter_current_fg = self.fg;
+    }
+
+    pub fn load(path: &Path) -> Result<Theme, String> {
+        let text = fs::read_to_string(path).map_err(|e| e.to_string())?;
+        let mut theme = match path.extension().and_then(|e| e.to_str()).unwrap_or("") {
+            "tmTheme" => Self::from_tm_theme(&plist::parse(&text)?)?,
+            "json" => Self::from_vscode(&json::parse(&text)?)?,
+            _ => Self::parse(&text)?,
+        };
+        theme.name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+        Ok(theme)
+    }
+
+    // Our own theme files are `name = #colour` lines under a [ui] and a [syntax] section:
+    //
+    //     [ui]
+    //     background = #282828
+    //     [syntax]
+    //     comment = #928374
+    //
+    // Colours that aren't given are worked out from the text colours.
+    pub fn parse(text: &str) -> Result<Theme, String> {
+        let mut theme = Theme::default();
+        theme.scopes.clear();
+        let mut colors = Vec::new();
+        let mut section = String::new();
+        for (i, line) in text.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') && line.ends_with(']') {
+                section = line[1..line.len() - 1].trim().to_string();
+                continue;
+            }
+            let (name, value) = match line.find('=') {
+                Some(eq) => (line[..eq].trim(), line[eq + 1..].trim()),
+                None => return Err(format!("line {}: expected name = value", i + 1)),
+            };
+            match section.as_str() {
+                "" if name == "variant" => theme.dark = value != "light",
+                "" if name == "name" => {}
+                "ui" | "syntax" => colors.push((section == "ui", name, value, i + 1)),
+                _ => return Err(format!("line {}: unknown setting {}", i + 1, name)),
+            }
+        }
+        // Translucent colours are mixed into the background, so that has to come first
+        let background = colors.iter().position(|(is_ui, name, _, _)| *is_ui && *name == "background");
+        if let Some(i) = background {
+            let color = colors.remove(i);
+            colors.insert(0, color);
+        }
+        let mut ui = Vec::new();
+        let mut bg = theme.bg;
+        for (is_ui, name, value, line) in colors {
+            let color = parse_color(value, bg).ok_or_else(|| format!("line {}: bad colour {}", line, value))?;
+            if !is_ui {
+                theme.scopes.push((name.to_string(), color));
+                continue;
+            }
+            if name == "background" {
+                bg = color;
+            }
+            ui.push((name.to_string(), color, line));
+        }
+        // Anything not given is worked out from the text colours
+        let given = |n: &str| ui.iter().any(|(name, _, _)| name == n);
+        let fg = ui.iter().find(|(n, _, _)| n == "foreground").map_or(theme.fg, |(_, c, _)| *c);
+        if given("foreground") || given("background") {
+            theme.fg = fg;
+            theme.bg = bg;
+            theme.cursor = fg;
+            theme.selection = mix(fg, bg, 0.3);
+            theme.derive_ui();
+        }
+        for (name, color, line) in &ui {
+            match name.as_str() {
+                "foreground" | "background" => {}
+                "selection" => theme.selection = *color,
+                "cursor" => theme.cursor = *color,
+                "bar.foreground" => theme.ui_fg = *color,
+                "bar.background" => theme.ui_bg = *color,
+                "bar.inactive_foreground" => theme.ui_inactive_fg = *color,
+                "bar.inactive_background" => theme.ui_inactive_bg = *color,
+                "gutter.foreground" => theme.gutter_fg = *color,
+                "gutter.current_foreground" => theme.gutter_current_fg = *color,
+                _ => return Err(format!("line {}: unknown colour {}", line, name)),
+            }
+        }
+        Ok(theme)
+    }
+
+    // Import a TextMate .tmTheme, as used by TextMate, Sublime Text and bat
+    pub fn from_tm_theme(plist: &Value) -> Result<Theme, String> {
+        let rules = plist.get("settings").map(|s| s.as_array()).unwrap_or(&[]);
+        let globals = rules
+            .iter()
+            .find(|r| r.get("scope").is_none())
+            .and_then(|r| r.get("settings"))
+            .ok_or("the theme has no global settings")?;
+        let mut theme = Theme::default();
+        let color = |value: &Value, key: &str, under: Color| value.get(key).and_then(|v| v.as_str()).and_then(|c| parse_color(c, under));
+        theme.bg = color(globals, "background", theme.bg).ok_or("the theme has no background colour")?;
+        theme.fg = color(globals, "foreground", theme.bg).ok_or("the theme has no foreground colour")?;
+        theme.cursor = color(globals, "caret", theme.bg).unwrap_or(theme.fg);
+        theme.selection = color(globals, "selection", theme.bg).unwrap_or_else(|| mix(theme.fg, theme.bg, 0.3));
+        theme.dark = is_dark(theme.bg);
+        theme.derive_ui();
+        if let Some(c) = color(globals, "gutterForeground", theme.bg) {
+            theme.gutter_fg = c;
+        }
+        theme.scopes.clear();
+        for rule in rules {
+            let (scope, settings) = match (rule.get("scope").and_then(|s| s.as_str()), rule.get("settings")) {
+                (Some(scope), Some(settings)) => (scope, settings),
+                _ => continue,
+            };
+            if let Some(fg) = color(settings, "foreground", theme.bg) {
+                for scope in selector_scopes(scope) {
+                    theme.scopes.push((scope, fg));
+                }
+            }
+        }
+        Ok(theme)
+    }
+
+    // Import a VS Code colour theme (the JSON files in an extension's themes folder).
+    // Themes that "include" another one need that merged in by hand.
+    pub fn from_vscode(json: &Value) -> Result<Theme, String> {
+        let colors = json.get("colors").ok_or("the theme has no colors")?;
+        let mut theme = Theme::default();
+        let color = |key: &str, under: Color| colors.get(key).and_then(|v| v.as_str()).and_then(|c| parse_color(c, under));
+        theme.dark = json.get("type").and_then(|t| t.as_str()) != Some("light");
+        theme.bg = color("editor.background", theme.bg).ok_or("the theme has no editor.background")?;
+        theme.fg = color("editor.foreground", theme.bg).or_else(|| color("foreground", theme.bg)).ok_or("the theme has no editor.foreground")?;
+        theme.derive_ui();
+        theme.cursor = color("editorCursor.foreground", theme.bg).unwrap_or(theme.fg);
+        theme.selection = color("editor.selectionBackground", theme.bg).unwrap_or_else(|| mix(theme.fg, theme.bg, 0.3));
+        if let Some(c) = color("tab.activeBackground", theme.bg).or_else(|| color("statusBar.background", theme.bg)) {
+            theme.ui_bg = c;
+        }
+        if let Some(c) = color("tab.activeForeground", theme.ui_bg).or_else(|| color("statusBar.foreground", theme.ui_bg)) {
+            theme.ui_fg = c;
+        }
+        if let Some(c) = color("tab.inactiveBackground", theme.bg) {
+            theme.ui_inactive_bg = c;
+        }
+        if let Some(c) = color("tab.inactiveForeground", theme.ui_inactive_bg) {
+            theme.ui_inactive_fg = c;
+        }
+        if let Some(c) = color("editorLineNumber.foreground", theme.bg) {
+            theme.gutter_fg = c;
+        }
+        if let Some(c) = color("editorLineNumber.activeForeground", theme.bg) {
+            theme.gutter_current_fg = c;
+        }
+        theme.scopes.clear();
+        for rule in json.get("tokenColors").map(|t| t.as_array()).unwrap_or(&[]) {
+            let fg = match rule.get("settings").and_then(|s| s.get("foreground")).and_then(|c| c.as_str()) {
+                Some(c) => match parse_color(c, theme.bg) {
+                    Some(c) => c,
+                    None => continue,
+                },
+                None => continue,
+            };
+            let selectors = match rule.get("scope") {
+                Some(Value::Str(s)) => vec![s.clone()],
+                Some(Value::Array(items)) => items.iter().filter_map(|i| i.as_str().map(|s| s.to_string())).collect(),
+                _ => Vec::new(),
+            };
+            for selector in selectors {
+                for scope in selector_scopes(&selector) {
+                    theme.scopes.push((scope, fg));
+                }
+            }
+        }
+        Ok(theme)
+    }
+}
+
+// Theme files, from data/themes and the user's themes directory, sorted by name. A user
+// theme with the same name as a bundled one replaces it.
+pub fn theme_files() -> Vec<PathBuf> {
+    let mut dirs = vec![PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("data/themes")];
+    if let Some(dir) = config::config_dir() {
+        dirs.push(dir.join("themes"));
+    }
+    let mut files: Vec<(String, PathBuf)> = Vec::new();
+    for dir in dirs {
+        let entries = match fs::read_dir(dir) {
+            Ok(entries) => entries,
+            Err(_) => continue,
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+            if !["theme", "tmTheme", "json"].contains(&ext) {
+                continue;
+            }
+            let name = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+            files.retain(|(n, _)| *n != name);
+            files.push((name, path));
+        }
+    }
+    files.sort();
+    files.into_iter().map(|(_, path)| path).collect()
+}
+
+pub fn theme_names() -> Vec<String> {
+    theme_files()
+        .iter()
+        .filter_map(|p| p.file_stem().map(|s| s.to_string_lossy().into_owned()))
+        .collect()
+}
+
+pub fn load_named(name: &str) -> Result<Theme, String> {
+    let path = theme_files()
+        .into_iter()
+        .find(|p| p.file_stem().is_some_and(|s| s.to_string_lossy().eq_ignore_ascii_case(name)))
+        .ok_or_else(|| format!("there's no theme called {}", name))?;
+    Theme::load(&path).map_err(|e| format!("{}: {}", path.display(), e))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rgb(c: Color) -> (u8, u8, u8) {
+        (c.r, c.g, c.b)
+    }
+
+    #[test]
+    fn parses_theme_files() {
+        let theme = Theme::parse("# comment\nvariant = light\n[ui]\nforeground = #000\nbackground = #ffffff\ncursor = #ff000080\n[syntax]\ncomment = #123456\n").unwrap();
+        assert!(!theme.dark);
+        assert_eq!((rgb(theme.fg), rgb(theme.bg)), ((0, 0, 0), (255, 255, 255)));
+        // Half transparent red over the white background
+        assert_eq!(rgb(theme.cursor), (255, 127, 127));
+        assert_eq!(rgb(theme.selection), rgb(mix(theme.fg, theme.bg, 0.3)));
+        assert_eq!(theme.scopes.len(), 1);
+        assert_eq!(theme.scope_color("comment.line.double-slash").map(rgb), Some((0x12, 0x34, 0x56)));
+        assert_eq!(theme.scope_color("commentary"), None);
+    }
+
+    #[test]
+    fn rejects_bad_theme_files() {
+        assert_eq!(Theme::parse("[ui]\nforeground #000").err(), Some("line 2: expected name = value".to_string()));
+        assert_eq!(Theme::parse("[ui]\nforeground = #00g").err(), Some("line 2: bad colour #00g".to_string()));
+        assert_eq!(Theme::parse("[ui]\nshadow = #000").err(), Some("line 2: unknown colour shadow".to_string()));
+        assert_eq!(Theme::parse("[misc]\nfoo = 1").err(), Some("line 2: unknown setting foo".to_string()));
+    }
+
+    #[test]
+    fn imports_tm_themes() {
+        let plist = plist::parse(
+            "<plist><dict><key>settings</key><array>
+                <dict><key>settings</key><dict>
+                    <key>background</key><string>#202020</string>
+                    <key>foreground</key><string>#e0e0e0</string>
+                </dict></dict>
+                <dict><key>scope</key><string>source.rust comment, string - string.regexp</string>
+                    <key>settings</key><dict><key>foreground</key><string>#ff0000</string></dict></dict>
+            </array></dict></plist>",
+        )
+        .unwrap();
+        let theme = Theme::from_tm_theme(&plist).unwrap();
+        assert!(theme.dark);
+        assert_eq!(rgb(theme.bg), (0x20, 0x20, 0x20));
+        let scopes = theme.scopes.iter().map(|(s, _)| s.as_str()).collect::<Vec<&str>>();
+        assert_eq!(scopes, vec!["comment", "string"]);
+        assert!(Theme::from_tm_theme(&plist::parse("<dict><key>settings</key><array/></dict>").unwrap()).is_err());
+    }
+
+    #[test]
+    fn imports_vscode_themes() {
+        let json = json::parse(
+            r##"{
+                "type": "light",
+                "colors": {"editor.background": "#ffffff", "editor.foreground": "#333333", "editorLineNumber.foreground": "#999"},
+                "tokenColors": [
+                    {"scope": ["keyword", "storage.type"], "settings": {"foreground": "#0000ff"}},
+                    {"scope": "comment", "settings": {"fontStyle": "italic"}},
+                ]
+            }"##,
+        )
+        .unwrap();
+        let theme = Theme::from_vscode(&json).unwrap();
+        assert!(!theme.dark);
+        assert_eq!(rgb(theme.gutter_fg), (0x99, 0x99, 0x99));
+        assert_eq!(theme.color_for_scopes(&["source".to_string(), "storage.type.rust".to_string()]).b, 255);
+        assert_eq!(theme.scopes.len(), 2);
+        assert!(Theme::from_vscode(&json::parse(r#"{"colors": {}}"#).unwrap()).is_err());
+    }
+}
@@ -0,0 +1,1802 @@
+use std::cell::RefCell;
+use std::cmp::{max, min};
+use std::collections::HashMap;
+use std::rc::Rc;
+
+use clipboard::{ClipboardContext, ClipboardProvider};
+use unicode_segmentation::UnicodeSegmentation;
+
+use crate::application::{Mode, MouseEvent};
+use crate::buffer::Buffer;
+use crate::change::SubscriptionId;
+use crate::indent::{self, leading_whitespace};
+use crate::pane::{Highlight, Pane};
+
+const MOTIONS: &str = "hjklwWbBeE0^$G{}%;,|+-_nN*#";
+const OBJECTS: &str = "wWp()b{}B[]<>\"'`";
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum State {
+    Normal,
+    Insert,
+    Visual,
+    VisualLine,
+    VisualBlock,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum Kind {
+    Charwise,
+    Linewise,
+    Blockwise,
+}
+
+#[derive(Clone, Debug)]
+struct Register {
+    text: String,
+    kind: Kind,
+}
+
+// Part of the buffer for an operator to work on. The end is exclusive, and for
+// linewise regions only the lines matter.
+#[derive(Clone, Copy, Debug)]
+struct Region {
+    x1: usize,
+    y1: usize,
+    x2: usize,
+    y2: usize,
+    kind: Kind,
+}
+
+// Where a motion ends up
+struct Target {
+    x: usize,
+    y: usize,
+    linewise: bool,
+    inclusive: bool,
+}
+
+#[derive(Clone, Debug)]
+struct Command {
+    register: Option<char>,
+    count: Option<usize>,
+    // An operator like "d" or "gU"
+    op: Option<String>,
+    // A motion, text object or other command, like "w", "fx", "iw" or "p"
+    action: String,
+}
+
+enum Parse {
+    Incomplete,
+    Invalid,
+    Done(Command),
+}
+
+#[derive(Clone)]
+enum InsertEvent {
+    Key(String),
+    Text(String),
+}
+
+// How the text typed in insert mode is repeated when leaving it
+enum Repeat {
+    Text,
+    Line { below: bool },
+    Block { x: usize, y1: usize, y2: usize, append: bool },
+}
+
+struct InsertSession {
+    // The command that started it, for "."
+    command: Option<Command>,
+    count: usize,
+    repeat: Repeat,
+    events: Vec<InsertEvent>,
+    undo_start: usize,
+    // The text the session adds to the buffer, for the . register
+    inserted: Rc<RefCell<String>>,
+    subscription: SubscriptionId,
+}
+
+#[derive(Clone)]
+struct Change {
+    command: Command,
+    events: Vec<InsertEvent>,
+}
+
+pub struct Vim {
+    state: State,
+    pending: String,
+    registers: HashMap<char, Register>,
+    // The other end of a visual selection
+    anchor: (usize, usize),
+    insert: Option<InsertSession>,
+    last_change: Option<Change>,
+    last_find: Option<(char, char)>,
+    // The word searched for with * or #, and whether that went forward
+    search: Option<(String, bool)>,
+    replaying: bool,
+    undo_start: usize,
+}
+
+fn is_word_char(c: char) -> bool {
+    c.is_alphanumeric() || c == '_'
+}
+
+// 0 for blanks, 1 for punctuation and 2 for word characters. With `big` everything
+// that isn't blank is the same class.
+fn class_of(g: &str, big: bool) -> u8 {
+    let c = g.chars().next().unwrap_or(' ');
+    if c.is_whitespace() {
+        0
+    } else if big || !is_word_char(c) {
+        1
+    } else {
+        2
+    }
+}
+
+fn first_nonblank(buffer: &Buffer, y: usize) -> usize {
+    leading_whitespace(&buffer.contents[y]).graphemes(true).count()
+}
+
+fn toggle_case(s: &str) -> String {
+    s.chars()
+        .map(|c| {
+            if c.is_uppercase() {
+                c.to_lowercase().collect::<String>()
+            } else {
+                c.to_uppercase().collect::<String>()
+            }
+        })
+        .collect()
+}
+
+fn multiply(a: Option<usize>, b: Option<usize>) -> Option<usize> {
+    match (a, b) {
+        (None, None) => None,
+        _ => Some(a.unwrap_or(1).saturating_mul(b.unwrap_or(1))),
+    }
+}
+
+// Steps through a buffer a grapheme at a time. x == line length is the line break.
+struct Walker<'a> {
+    buffer: &'a Buffer,
+    x: usize,
+    y: usize,
+    line: Vec<&'a str>,
+}
+
+impl<'a> Walker<'a> {
+    fn new(buffer: &'a Buffer, x: usize, y: usize) -> Self {
+        let line = buffer.line_graphemes(y);
+        Self { buffer, x: min(x, line.len()), y, line }
+    }
+
+    fn pos(&self) -> (usize, usize) {
+        (self.x, self.y)
+    }
+
+    fn set(&mut self, (x, y): (usize, usize)) {
+        if y != self.y {
+            self.line = self.buffer.line_graphemes(y);
+            self.y = y;
+        }
+        self.x = x;
+    }
+
+    fn class(&self, big: bool) -> u8 {
+        self.line.get(self.x).map_or(0, |g| class_of(g, big))
+    }
+
+    fn char(&self) -> Option<char> {
+        self.line.get(self.x).and_then(|g| g.chars().next())
+    }
+
+    fn is_empty_line(&self) -> bool {
+        self.line.is_empty()
+    }
+
+    fn next(&mut self) -> bool {
+        if self.x < self.line.len() {
+            self.x += 1;
+            true
+        } else if self.y + 1 < self.buffer.len() {
+            self.set((0, self.y + 1));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn prev(&mut self) -> bool {
+        if self.x > 0 {
+            self.x -= 1;
+            true
+        } else if self.y > 0 {
+            let len = self.buffer.line_len(self.y - 1);
+            self.set((len, self.y - 1));
+            true
+        } else {
+            false
+        }
+    }
+}
+
+// w and W: to the start of the next word, stopping at empty lines
+fn word_forward(w: &mut Walker, big: bool) {
+    let start = w.pos();
+    let class = w.class(big);
+    if class != 0 {
+        while w.class(big) == class {
+            if !w.next() {
+                return;
+            }
+        }
+    }
+    while w.class(big) == 0 {
+        if w.is_empty_line() && w.pos() != start {
+            break;
+        }
+        if !w.next() {
+            break;
+        }
+    }
+}
+
+// e and E: to the end of this or the next word
+fn word_end(w: &mut Walker, big: bool) {
+    if !w.next() {
+        return;
+    }
+    while w.class(big) == 0 {
+        if !w.next() {
+            return;
+        }
+    }
+    let class = w.class(big);
+    loop {
+        let pos = w.pos();
+        if !w.next() || w.class(big) != class {
+            w.set(pos);
+            break;
+        }
+    }
+}
+
+// b and B: to the start of this or the previous word
+fn word_back(w: &mut Walker, big: bool) {
+    if !w.prev() {
+        return;
+    }
+    while w.class(big) == 0 {
+        if w.is_empty_line() || !w.prev() {
+            return;
+        }
+    }
+    let class = w.class(big);
+    loop {
+        let pos = w.pos();
+        if !w.prev() {
+            break;
+        }
+        if w.class(big) != class {
+            w.set(pos);
+            break;
+        }
+    }
+}
+
+// ge and gE: to the end of the previous word
+fn word_end_back(w: &mut Walker, big: bool) {
+    let start = w.pos();
+    let class = w.class(big);
+    if class != 0 {
+        while w.class(big) == class {
+            if !w.prev() {
+                return;
+            }
+        }
+    }
+    while w.class(big) == 0 {
+        if w.is_empty_line() && w.pos() != start {
+            break;
+        }
+        if !w.prev() {
+            break;
+        }
+    }
+}
+
+// The first `open` before (x, y) that isn't closed again before it. A bracket right
+// at (x, y) counts as enclosing it.
+fn find_open(buffer: &Buffer, x: usize, y: usize, open: char, close: char) -> Option<(usize, usize)> {
+    let mut w = Walker::new(buffer, x, y);
+    let mut depth = 0;
+    loop {
+        let c = w.char();
+        if c == Some(close) && w.pos() != (x, y) {
+            depth += 1;
+        } else if c == Some(open) {
+            if depth == 0 {
+                return Some(w.pos());
+            }
+            depth -= 1;
+        }
+        if !w.prev() {
+            return None;
+        }
+    }
+}
+
+fn find_close(buffer: &Buffer, x: usize, y: usize, open: char, close: char) -> Option<(usize, usize)> {
+    let mut w = Walker::new(buffer, x, y);
+    let mut depth = 0;
+    while w.next() {
+        let c = w.char();
+        if c == Some(open) {
+            depth += 1;
+        } else if c == Some(close) {
+            if depth == 0 {
+                return Some(w.pos());
+            }
+            depth -= 1;
+        }
+    }
+    None
+}
+
+// Whole-word matches of `word` after (or before) (x, y), wrapping around the buffer
+fn search_word(buffer: &Buffer, word: &str, x: usize, y: usize, forward: bool) -> Option<(usize, usize)> {
+    let matches_in = |ly: usize| -> Vec<usize> {
+        let line = &buffer.contents[ly];
+        line.match_indices(word)
+            .filter(|(i, _)| {
+                let before = line[..*i].chars().last();
+                let after = line[i + word.len()..].chars().next();
+                !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+            })
+            .map(|(i, _)| line[..i].graphemes(true).count())
+            .collect()
+    };
+    let lines = buffer.len();
+    for k in 0..=lines {
+        if forward {
+            let ly = (y + k) % lines;
+            let mut found = matches_in(ly).into_iter();
+            let mx = match k {
+                0 => found.find(|mx| *mx > x),
+                k if k == lines => found.find(|mx| *mx <= x),
+                _ => found.next(),
+            };
+            if let Some(mx) = mx {
+                return Some((mx, ly));
+            }
+        } else {
+            let ly = (y + lines - k % lines) % lines;
+            let mut found = matches_in(ly).into_iter().rev();
+            let mx = match k {
+                0 => found.find(|mx| *mx < x),
+                k if k == lines => found.find(|mx| *mx >= x),
+                _ => found.next(),
+            };
+            if let Some(mx) = mx {
+                return Some((mx, ly));
+            }
+        }
+    }
+    None
+}
+
+fn read_count(keys: &[char], mut i: usize) -> (Option<usize>, usize) {
+    if i >= keys.len() || !('1'..='9').contains(&keys[i]) {
+        return (None, i);
+    }
+    let mut n: usize = 0;
+    while i < keys.len() && keys[i].is_ascii_digit() {
+        n = n.saturating_mul(10).saturating_add(keys[i] as usize - '0' as usize);
+        i += 1;
+    }
+    (Some(n), i)
+}
+
+// Ok(None) means more keys are needed
+fn parse_motion(keys: &[char]) -> Result<Option<String>, ()> {
+    let len = match keys.first() {
+        None => return Ok(None),
+        Some('f') | Some('F') | Some('t') | Some('T') => 2,
+        Some('g') => match keys.get(1) {
+            None => return Ok(None),
+            Some('g') | Some('e') | Some('E') | Some('_') => 2,
+            _ => return Err(()),
+        },
+        Some(c) if MOTIONS.contains(*c) => 1,
+        _ => return Err(()),
+    };
+    if keys.len() < len {
+        Ok(None)
+    } else if keys.len() > len {
+        Err(())
+    } else {
+        Ok(Some(keys.iter().collect()))
+    }
+}
+
+fn parse_object(keys: &[char]) -> Result<Option<String>, ()> {
+    match keys.len() {
+        1 => Ok(None),
+        2 if OBJECTS.contains(keys[1]) => Ok(Some(keys.iter().collect())),
+        _ => Err(()),
+    }
+}
+
+// Parse the keys typed so far in normal or visual mode:
+// ["x] [count] (operator [count] (motion | text object | operator again) | command | motion)
+fn parse(keys: &[char], visual: bool) -> Parse {
+    let mut i = 0;
+    let mut register = None;
+    if keys.first() == Some(&'"') {
+        match keys.get(1) {
+            Some(c) => {
+                register = Some(*c);
+                i = 2;
+            }
+            None => return Parse::Incomplete,
+        }
+    }
+    let (count, i) = read_count(keys, i);
+    let rest = &keys[i..];
+    let done = |count, op, action| match action {
+        Err(()) => Parse::Invalid,
+        Ok(None) => Parse::Incomplete,
+        Ok(Some(action)) => Parse::Done(Command { register, count, op, action }),
+    };
+    if rest.is_empty() || rest == ['g'] {
+        return Parse::Incomplete;
+    }
+
+    let op = match rest {
+        ['g', c, ..] if "~uU".contains(*c) => Some(format!("g{}", c)),
+        [c, ..] if "dcy<>".contains(*c) => Some(c.to_string()),
+        _ => None,
+    };
+    if let Some(op) = op {
+        let rest = &rest[op.chars().count()..];
+        if visual {
+            return done(count, Some(op), if rest.is_empty() { Ok(Some(String::new())) } else { Err(()) });
+        }
+        let (count2, j) = read_count(rest, 0);
+        let rest = &rest[j..];
+        let last = op.chars().last().unwrap();
+        let is_g = op.starts_with('g');
+        let action = match rest {
+            [] => Ok(None),
+            // Doubled operators like dd work on whole lines
+            [c] if *c == last => Ok(Some("_".to_string())),
+            ['g'] if is_g => Ok(None),
+            ['g', c] if is_g && *c == last => Ok(Some("_".to_string())),
+            ['i', ..] | ['a', ..] => parse_object(rest),
+            _ => parse_motion(rest),
+        };
+        return done(multiply(count, count2), Some(op), action);
+    }
+
+    let commands = if visual { "xXDYCSRsuU~JpPIAoOvV\x16" } else { "xXDCsSYpPuJ~.iaIAoOvV\x12\x16" };
+    let action = match rest {
+        ['r'] => Ok(None),
+        ['r', _] => Ok(Some(rest.iter().collect())),
+        // zz, zt and zb scroll the view around the cursor
+        ['z'] => Ok(None),
+        ['z', c] if "ztb".contains(*c) => Ok(Some(rest.iter().collect())),
+        ['i', ..] | ['a', ..] if visual => parse_object(rest),
+        [c] if commands.contains(*c) => Ok(Some(c.to_string())),
+        _ => parse_motion(rest),
+    };
+    done(count, None, action)
+}
+
+// Commands that are short for an operator and a motion
+fn expand_alias(cmd: Command, visual: bool) -> Command {
+    let (op, action) = match (visual, cmd.op.as_deref(), cmd.action.as_str()) {
+        (false, None, "x") => ("d", "l"),
+        (false, None, "X") => ("d", "h"),
+        (false, None, "D") => ("d", "$"),
+        (false, None, "C") => ("c", "$"),
+        (false, None, "s") => ("c", "l"),
+        (false, None, "S") => ("c", "_"),
+        (false, None, "Y") => ("y", "_"),
+        (true, None, "x") => ("d", ""),
+        (true, None, "s") => ("c", ""),
+        (true, None, "u") => ("gu", ""),
+        (true, None, "U") => ("gU", ""),
+        (true, None, "~") => ("g~", ""),
+        _ => return cmd,
+    };
+    Command {
+        op: Some(op.to_string()),
+        action: action.to_string(),
+        ..cmd
+    }
+}
+
+// zz, zt or zb
+fn scroll_view(pane: &mut Pane, buffer: &mut Buffer, action: &str) {
+    let command = match action {
+        "zt" => "view.cursor_to_top",
+        "zb" => "view.cursor_to_bottom",
+        _ => "view.center_on_cursor",
+    };
+    pane.run_command(buffer, command);
+}
+
+fn get_clipboard() -> Option<String> {
+    let ctx: Result<ClipboardContext, _> = ClipboardProvider::new();
+    ctx.ok()?.get_contents().ok()
+}
+
+fn set_clipboard(text: String) {
+    let ctx: Result<ClipboardContext, _> = ClipboardProvider::new();
+    if let Ok(mut ctx) = ctx {
+        let _ = ctx.set_contents(text);
+    }
+}
+
+impl Vim {
+    pub fn new() -> Self {
+        Self {
+            state: State::Normal,
+            pending: String::new(),
+            registers: HashMap::new(),
+            anchor: (0, 0),
+            insert: None,
+            last_change: None,
+            last_find: None,
+            search: None,
+            replaying: false,
+            undo_start: 0,
+        }
+    }
+
+    fn is_visual(&self) -> bool {
+        matches!(self.state, State::Visual | State::VisualLine | State::VisualBlock)
+    }
+
+    // Registers
+    //========================================
+
+    fn store(&mut self, register: Option<char>, reg: Register, is_delete: bool) {
+        match register {
+            Some('_') => return,
+            Some(c) if c.is_ascii_uppercase() => {
+                let lower = c.to_ascii_lowercase();
+                let combined = match self.registers.get(&lower) {
+                    Some(old) if old.kind == Kind::Linewise || reg.kind == Kind::Linewise => Register {
+                        text: format!("{}\n{}", old.text, reg.text),
+                        kind: Kind::Linewise,
+                    },
+                    Some(old) => Register {
+                        text: format!("{}{}", old.text, reg.text),
+                        kind: old.kind,
+                    },
+                    None => reg.clone(),
+                };
+                self.registers.insert(lower, combined);
+            }
+            Some('+') | Some('*') => {
+                let text = if reg.kind == Kind::Linewise { format!("{}\n", reg.text) } else { reg.text.clone() };
+                set_clipboard(text);
+            }
+            Some(c) => {
+                self.registers.insert(c, reg.clone());
+            }
+            None if !is_delete => {
+                self.registers.insert('0', reg.clone());
+            }
+            None if reg.kind == Kind::Charwise && !reg.text.contains('\n') => {
+                self.registers.insert('-', reg.clone());
+            }
+            None => {
+                // Bigger deletes shift through the numbered registers
+                for d in (b'1'..b'9').rev() {
+                    if let Some(r) = self.registers.remove(&(d as char)) {
+                        self.registers.insert((d + 1) as char, r);
+                    }
+                }
+                self.registers.insert('1', reg.clone());
+            }
+        }
+        self.registers.insert('"', reg);
+    }
+
+    fn read_register(&self, register: Option<char>) -> Option<Register> {
+        match register.unwrap_or('"') {
+            '+' | '*' => {
+                let text = get_clipboard()?;
+                match text.strip_suffix('\n') {
+                    Some(text) => Some(Register { text: text.to_string(), kind: Kind::Linewise }),
+                    None => Some(Register { text, kind: Kind::Charwise }),
+                }
+            }
+            c => self.registers.get(&c.to_ascii_lowercase()).cloned(),
+        }
+    }
+
+    // Motions and text objects
+    //========================================
+
+    fn motion(&mut self, buffer: &Buffer, action: &str, count: Option<usize>, for_op: bool) -> Option<Target> {
+        let (x, y) = (buffer.cursor_x, buffer.cursor_y);
+        let n = count.unwrap_or(1);
+        let len = buffer.line_len(y);
+        let last = buffer.len() - 1;
+        let chars = action.chars().collect::<Vec<char>>();
+        let exclusive = |x, y| Some(Target { x, y, linewise: false, inclusive: false });
+        let inclusive = |x, y| Some(Target { x, y, linewise: false, inclusive: true });
+        let linewise = |x, y| Some(Target { x, y, linewise: true, inclusive: false });
+        let column = |y| min(buffer.max_cursor_x, buffer.line_len(y).saturating_sub(1));
+        let big = chars[0].is_uppercase();
+
+        match chars[0] {
+            'h' if x > 0 => exclusive(x.saturating_sub(n), y),
+            'l' => {
+                let limit = if for_op { len } else { len.saturating_sub(1) };
+                if x < limit {
+                    exclusive(min(x + n, limit), y)
+                } else {
+                    None
+                }
+            }
+            'j' if y < last => linewise(column(min(y + n, last)), min(y + n, last)),
+            'k' if y > 0 => linewise(column(y.saturating_sub(n)), y.saturating_sub(n)),
+            '+' if y < last => linewise(first_nonblank(buffer, min(y + n, last)), min(y + n, last)),
+            '-' if y > 0 => linewise(first_nonblank(buffer, y.saturating_sub(n)), y.saturating_sub(n)),
+            '_' => {
+                let ty = min(y + n - 1, last);
+                linewise(first_nonblank(buffer, ty), ty)
+            }
+            '0' => exclusive(0, y),
+            '^' => exclusive(first_nonblank(buffer, y), y),
+            '$' => {
+                let ty = min(y + n - 1, last);
+                inclusive(buffer.line_len(ty).saturating_sub(1), ty)
+            }
+            '|' => exclusive(min(n - 1, len.saturating_sub(1)), y),
+            'G' => {
+                let ty = count.map_or(last, |n| min(n.max(1) - 1, last));
+                linewise(first_nonblank(buffer, ty), ty)
+            }
+            'g' => match chars[1] {
+                'g' => {
+                    let ty = count.map_or(0, |n| min(n.max(1) - 1, last));
+                    linewise(first_nonblank(buffer, ty), ty)
+                }
+                'e' | 'E' => {
+                    let mut w = Walker::new(buffer, x, y);
+                    for _ in 0..n {
+                        word_end_back(&mut w, chars[1] == 'E');
+                    }
+                    let (tx, ty) = w.pos();
+                    inclusive(tx, ty)
+                }
+                _ => {
+                    let ty = min(y + n - 1, last);
+                    let line = buffer.contents[ty].trim_end();
+                    inclusive(line.graphemes(true).count().saturating_sub(1), ty)
+                }
+            },
+            'w' | 'W' | 'b' | 'B' | 'e' | 'E' => {
+                let mut w = Walker::new(buffer, x, y);
+                for _ in 0..n {
+                    match chars[0] {
+                        'w' | 'W' => word_forward(&mut w, big),
+                        'b' | 'B' => word_back(&mut w, big),
+                        _ => word_end(&mut w, big),
+                    }
+                }
+                let (tx, ty) = w.pos();
+                if chars[0] == 'e' || chars[0] == 'E' {
+                    inclusive(tx, ty)
+                } else {
+                    exclusive(tx, ty)
+                }
+            }
+            'f' | 'F' | 't' | 'T' => {
+                self.last_find = Some((chars[0], chars[1]));
+                self.find_char(buffer, chars[0], chars[1], n)
+            }
+            ';' | ',' => {
+                let (kind, c) = self.last_find?;
+                let kind = if chars[0] == ',' {
+                    match kind {
+                        'f' => 'F',
+                        'F' => 'f',
+                        't' => 'T',
+                        _ => 't',
+                    }
+                } else {
+                    kind
+                };
+                self.find_char(buffer, kind, c, n)
+            }
+            '%' => {
+                let line = buffer.line_graphemes(y);
+                let (bx, c) = (x..line.len()).find_map(|i| {
+                    let c = line[i].chars().next()?;
+                    if "()[]{}".contains(c) {
+                        Some((i, c))
+                    } else {
+                        None
+                    }
+                })?;
+                let (tx, ty) = if indent::is_open_bracket(c) {
+                    find_close(buffer, bx, y, c, indent::matching_bracket(c))?
+                } else {
+                    find_open(buffer, bx, y, indent::matching_bracket(c), c)?
+                };
+                inclusive(tx, ty)
+            }
+            '}' => {
+                let blank = |y: usize| buffer.contents[y].trim().is_empty();
+                let mut ty = y;
+                for _ in 0..n {
+                    while ty < last && blank(ty) {
+                        ty += 1;
+                    }
+                    while ty < last && !blank(ty) {
+                        ty += 1;
+                    }
+                }
+                if ty == last && !blank(ty) {
+                    exclusive(buffer.line_len(ty), ty)
+                } else {
+                    exclusive(0, ty)
+                }
+            }
+            '{' => {
+                let blank = |y: usize| buffer.contents[y].trim().is_empty();
+                let mut ty = y;
+                for _ in 0..n {
+                    while ty > 0 && blank(ty) {
+                        ty -= 1;
+                    }
+                    while ty > 0 && !blank(ty) {
+                        ty -= 1;
+                    }
+                }
+                exclusive(0, ty)
+            }
+            '*' | '#' => {
+                let (x1, x2) = buffer.word_at(x, y);
+                if x1 == x2 {
+                    return None;
+                }
+                let word = buffer.text_range(x1, y, x2, y);
+                let forward = chars[0] == '*';
+                let (tx, ty) = search_word(buffer, &word, x1, y, forward)?;
+                self.search = Some((word, forward));
+                exclusive(tx, ty)
+            }
+            'n' | 'N' => {
+                let (word, forward) = self.search.clone()?;
+                let forward = forward == (chars[0] == 'n');
+                let mut pos = (x, y);
+                for _ in 0..n {
+                    pos = search_word(buffer, &word, pos.0, pos.1, forward)?;
+                }
+                exclusive(pos.0, pos.1)
+            }
+            _ => None,
+        }
+    }
+
+    fn find_char(&self, buffer: &Buffer, kind: char, c: char, n: usize) -> Option<Target> {
+        let (x, y) = (buffer.cursor_x, buffer.cursor_y);
+        let line = buffer.line_graphemes(y);
+        let is_c = |i: usize| line[i].starts_with(c);
+        let mut pos = x;
+        match kind {
+            'f' | 't' => {
+                // Repeating t shouldn't get stuck right before the character
+                if kind == 't' && pos + 1 < line.len() && is_c(pos + 1) {
+                    pos += 1;
+                }
+                for _ in 0..n {
+                    pos = (pos + 1..line.len()).find(|i| is_c(*i))?;
+                }
+                let x = if kind == 't' { pos - 1 } else { pos };
+                Some(Target { x, y, linewise: false, inclusive: true })
+            }
+            _ => {
+                if kind == 'T' && pos > 1 && is_c(pos - 1) {
+                    pos -= 1;
+                }
+                for _ in 0..n {
+                    pos = (0..pos).rev().find(|i| is_c(*i))?;
+                }
+                let x = if kind == 'T' { pos + 1 } else { pos };
+                Some(Target { x, y, linewise: false, inclusive: false })
+            }
+        }
+    }
+
+    fn object(&self, buffer: &Buffer, action: &str) -> Option<Region> {
+        let chars = action.chars().collect::<Vec<char>>();
+        let inner = chars[0] == 'i';
+        let (x, y) = (buffer.cursor_x, buffer.cursor_y);
+        match chars[1] {
+            'w' | 'W' => {
+                let line = buffer.line_graphemes(y);
+                if line.is_empty() {
+                    return None;
+                }
+                let big = chars[1] == 'W';
+                let class = |i: usize| class_of(line[i], big);
+                let x = min(x, line.len() - 1);
+                let mut x1 = x;
+                while x1 > 0 && class(x1 - 1) == class(x) {
+                    x1 -= 1;
+                }
+                let mut x2 = x + 1;
+                while x2 < line.len() && class(x2) == class(x) {
+                    x2 += 1;
+                }
+                if !inner {
+                    if class(x) == 0 {
+                        // Blanks take the word after them along
+                        if x2 < line.len() {
+                            let c = class(x2);
+                            while x2 < line.len() && class(x2) == c {
+                                x2 += 1;
+                            }
+                        }
+                    } else {
+                        // Words take the blanks after them, or before them at the end of a line
+                        let end = x2;
+                        while x2 < line.len() && class(x2) == 0 {
+                            x2 += 1;
+                        }
+                        if x2 == end {
+                            while x1 > 0 && class(x1 - 1) == 0 {
+                                x1 -= 1;
+                            }
+                        }
+                    }
+                }
+                Some(Region { x1, y1: y, x2, y2: y, kind: Kind::Charwise })
+            }
+            'p' => {
+                let blank = |y: usize| buffer.contents[y].trim().is_empty();
+                let is_blank = blank(y);
+                let mut y1 = y;
+                while y1 > 0 && blank(y1 - 1) == is_blank {
+                    y1 -= 1;
+                }
+                let mut y2 = y;
+                while y2 + 1 < buffer.len() && blank(y2 + 1) == is_blank {
+                    y2 += 1;
+                }
+                if !inner && !is_blank {
+                    while y2 + 1 < buffer.len() && blank(y2 + 1) {
+                        y2 += 1;
+                    }
+                }
+                Some(Region { x1: 0, y1, x2: 0, y2, kind: Kind::Linewise })
+            }
+            '"' | '\'' | '`' => {
+                let line = buffer.line_graphemes(y);
+                let mut quotes = Vec::new();
+                let mut escaped = false;
+                for (i, g) in line.iter().enumerate() {
+                    if !escaped && g.starts_with(chars[1]) {
+                        quotes.push(i);
+                    }
+                    escaped = !escaped && *g == "\\";
+                }
+                let pair = quotes
+                    .chunks(2)
+                    .filter(|p| p.len() == 2)
+                    .find(|p| p[0] <= x && x <= p[1])
+                    .or_else(|| quotes.chunks(2).filter(|p| p.len() == 2).find(|p| p[0] > x))?;
+                let (x1, mut x2) = if inner { (pair[0] + 1, pair[1]) } else { (pair[0], pair[1] + 1) };
+                if !inner {
+                    while x2 < line.len() && line[x2].trim().is_empty() {
+                        x2 += 1;
+                    }
+                }
+                Some(Region { x1, y1: y, x2, y2: y, kind: Kind::Charwise })
+            }
+            c => {
+                let (open, close) = match c {
+                    '(' | ')' | 'b' => ('(', ')'),
+                    '{' | '}' | 'B' => ('{', '}'),
+                    '[' | ']' => ('[', ']'),
+                    _ => ('<', '>'),
+                };
+                let (ox, oy) = find_open(buffer, x, y, open, close)?;
+                let (cx, cy) = find_close(buffer, ox, oy, open, close)?;
+                if !inner {
+                    return Some(Region { x1: ox, y1: oy, x2: cx + 1, y2: cy, kind: Kind::Charwise });
+                }
+                // Brackets on lines of their own select the lines in between
+                let close_line = buffer.line_graphemes(cy);
+                if ox + 1 == buffer.line_len(oy) && cy > oy && close_line[..cx].iter().all(|g| g.trim().is_empty()) {
+                    if cy == oy + 1 {
+                        return None;
+                    }
+                    return Some(Region { x1: 0, y1: oy + 1, x2: 0, y2: cy - 1, kind: Kind::Linewise });
+                }
+                Some(Region { x1: ox + 1, y1: oy, x2: cx, y2: cy, kind: Kind::Charwise })
+            }
+        }
+    }
+
+    // The region between the cursor and where a motion ends up
+    fn motion_region(&self, buffer: &Buffer, target: &Target) -> Option<Region> {
+        let (cx, cy) = (buffer.cursor_x, buffer.cursor_y);
+        if target.linewise {
+            return Some(Region { x1: 0, y1: min(cy, target.y), x2: 0, y2: max(cy, target.y), kind: Kind::Linewise });
+        }
+        let ((x1, y1), (mut x2, mut y2)) = if (target.y, target.x) < (cy, cx) {
+            ((target.x, target.y), (cx, cy))
+        } else {
+            ((cx, cy), (target.x, target.y))
+        };
+        if target.inclusive {
+            x2 = min(x2 + 1, buffer.line_len(y2));
+        } else if y2 > y1 && x2 == 0 {
+            // An exclusive motion to the start of a line stops at the end of the line before
+            y2 -= 1;
+            x2 = buffer.line_len(y2);
+        }
+        if (x1, y1) == (x2, y2) {
+            return None;
+        }
+        Some(Region { x1, y1, x2, y2, kind: Kind::Charwise })
+    }
+
+    fn visual_region(&self, buffer: &Buffer) -> Region {
+        let (ax, ay) = self.anchor;
+        let (cx, cy) = (buffer.cursor_x, buffer.cursor_y);
+        let (y1, y2) = (min(ay, cy), max(ay, cy));
+        match self.state {
+            State::VisualLine => Region { x1: 0, y1, x2: 0, y2, kind: Kind::Linewise },
+            State::VisualBlock => Region { x1: min(ax, cx), y1, x2: max(ax, cx) + 1, y2, kind: Kind::Blockwise },
+            _ => {
+                let ((x1, y1), (x2, y2)) = if (cy, cx) < (ay, ax) { ((cx, cy), (ax, ay)) } else { ((ax, ay), (cx, cy)) };
+                let len = buffer.line_len(y2);
+                // Selecting the end of a line takes the line break along
+                let (x2, y2) = if x2 < len {
+                    (x2 + 1, y2)
+                } else if len == 0 && y2 + 1 < buffer.len() {
+                    (0, y2 + 1)
+                } else {
+                    (len, y2)
+                };
+                Region { x1, y1, x2, y2, kind: Kind::Charwise }
+            }
+        }
+    }
+
+    // Operators
+    //========================================
+
+    fn region_text(&self, buffer: &Buffer, r: &Region) -> String {
+        match r.kind {
+            Kind::Charwise => buffer.text_range(r.x1, r.y1, r.x2, r.y2),
+            Kind::Linewise => buffer.contents[r.y1..=r.y2].join("\n"),
+            Kind::Blockwise => (r.y1..=r.y2)
+                .map(|y| {
+                    let line = buffer.line_graphemes(y);
+                    line[min(r.x1, line.len())..min(r.x2, line.len())].concat()
+                })
+                .collect::<Vec<String>>()
+                .join("\n"),
+        }
+    }
+
+    fn delete_region(&self, buffer: &mut Buffer, r: &Region) {
+        match r.kind {
+            Kind::Charwise => {
+                buffer.delete_text(r.x1, r.y1, r.x2, r.y2);
+                buffer.cursor_x = r.x1;
+                buffer.cursor_y = r.y1;
+            }
+            Kind::Linewise => {
+                let last = buffer.len() - 1;
+                if r.y2 < last {
+                    buffer.delete_text(0, r.y1, 0, r.y2 + 1);
+                } else if r.y1 > 0 {
+                    buffer.delete_text(buffer.line_len(r.y1 - 1), r.y1 - 1, buffer.line_len(last), last);
+                } else {
+                    buffer.delete_text(0, 0, buffer.line_len(last), last);
+                }
+                buffer.cursor_y = min(r.y1, buffer.len() - 1);
+                buffer.cursor_x = first_nonblank(buffer, buffer.cursor_y);
+            }
+            Kind::Blockwise => {
+                for y in r.y1..=r.y2 {
+                    let len = buffer.line_len(y);
+                    if r.x1 < len {
+                        buffer.delete_text(r.x1, y, min(r.x2, len), y);
+                    }
+                }
+                buffer.cursor_x = r.x1;
+                buffer.cursor_y = r.y1;
+            }
+        }
+    }
+
+    fn transform_region(&self, buffer: &mut Buffer, r: &Region, f: fn(&str) -> String) {
+        match r.kind {
+            Kind::Charwise => {
+                let text = buffer.text_range(r.x1, r.y1, r.x2, r.y2);
+                buffer.replace_text(r.x1, r.y1, r.x2, r.y2, f(&text));
+            }
+            Kind::Linewise => {
+                let text = buffer.contents[r.y1..=r.y2].join("\n");
+                let x2 = buffer.line_len(r.y2);
+                buffer.replace_text(0, r.y1, x2, r.y2, f(&text));
+            }
+            Kind::Blockwise => {
+                for y in r.y1..=r.y2 {
+                    let len = buffer.line_len(y);
+                    let (x1, x2) = (min(r.x1, len), min(r.x2, len));
+                    let text = buffer.text_range(x1, y, x2, y);
+                    buffer.replace_text(x1, y, x2, y, f(&text));
+                }
+            }
+        }
+        buffer.cursor_x = r.x1;
+        buffer.cursor_y = r.y1;
+    }
+
+    fn apply_operator(&mut self, buffer: &mut Buffer, op: &str, r: Region, register: Option<char>, command: Option<Command>, levels: usize) {
+        match op {
+            "y" => {
+                let text = self.region_text(buffer, &r);
+                self.store(register, Register { text, kind: r.kind }, false);
+                if r.kind == Kind::Linewise {
+                    buffer.cursor_y = r.y1;
+                } else {
+                    buffer.cursor_x = r.x1;
+                    buffer.cursor_y = r.y1;
+                }
+            }
+            "d" => {
+                let text = self.region_text(buffer, &r);
+                self.store(register, Register { text, kind: r.kind }, true);
+                self.delete_region(buffer, &r);
+            }
+            "c" => {
+                let text = self.region_text(buffer, &r);
+                self.store(register, Register { text, kind: r.kind }, true);
+                let repeat = match r.kind {
+                    Kind::Linewise => {
+                        // Keep one line with the indentation of the first
+                        let indentation = leading_whitespace(&buffer.contents[r.y1]).to_string();
+                        let x2 = buffer.line_len(r.y2);
+                        let (x, y) = buffer.replace_text(0, r.y1, x2, r.y2, indentation);
+                        buffer.cursor_x = x;
+                        buffer.cursor_y = y;
+                        Repeat::Text
+                    }
+                    Kind::Blockwise => {
+                        self.delete_region(buffer, &r);
+                        Repeat::Block { x: r.x1, y1: r.y1, y2: r.y2, append: false }
+                    }
+                    Kind::Charwise => {
+                        self.delete_region(buffer, &r);
+                        Repeat::Text
+                    }
+                };
+                self.start_insert(buffer, command, 1, repeat);
+            }
+            ">" | "<" => {
+                buffer.sel_x = 0;
+                buffer.sel_y = r.y1;
+                buffer.cursor_x = buffer.line_len(r.y2);
+                buffer.cursor_y = r.y2;
+                for _ in 0..levels {
+                    if op == ">" {
+                        buffer.indent_selection();
+                    } else {
+                        buffer.dedent_selection();
+                    }
+                }
+                buffer.cursor_y = r.y1;
+                buffer.cursor_x = first_nonblank(buffer, r.y1);
+            }
+            "gu" => self.transform_region(buffer, &r, |s| s.to_lowercase()),
+            "gU" => self.transform_region(buffer, &r, |s| s.to_uppercase()),
+            _ => self.transform_region(buffer, &r, toggle_case),
+        }
+        buffer.max_cursor_x = buffer.cursor_x;
+        buffer.set_selection(false);
+    }
+
+    fn put(&mut self, buffer: &mut Buffer, reg: Register, count: usize, before: bool) {
+        let (x, y) = (buffer.cursor_x, buffer.cursor_y);
+        let len = buffer.line_len(y);
+        match reg.kind {
+            Kind::Linewise => {
+                let text = vec![reg.text; count].join("\n");
+                let y = if before { y } else { y + 1 };
+                buffer.insert_line(y, text);
+                buffer.cursor_y = y;
+                buffer.cursor_x = first_nonblank(buffer, y);
+            }
+            Kind::Charwise => {
+                let text = reg.text.repeat(count);
+                let x = if before || len == 0 { x } else { min(x + 1, len) };
+                let (end_x, end_y) = buffer.insert_text(x, y, text.clone());
+                if text.contains('\n') {
+                    buffer.cursor_x = x;
+                    buffer.cursor_y = y;
+                } else {
+                    buffer.cursor_x = end_x.saturating_sub(1);
+                    buffer.cursor_y = end_y;
+                }
+            }
+            Kind::Blockwise => {
+                let x = if before || len == 0 { x } else { min(x + 1, len) };
+                for (i, piece) in reg.text.split('\n').enumerate() {
+                    let line_y = y + i;
+                    if line_y >= buffer.len() {
+                        let last = buffer.len() - 1;
+                        buffer.insert_text(buffer.line_len(last), last, "\n".to_string());
+                    }
+                    let line_len = buffer.line_len(line_y);
+                    if line_len < x {
+                        buffer.insert_text(line_len, line_y, " ".repeat(x - line_len));
+                    }
+                    buffer.insert_text(x, line_y, piece.repeat(count));
+                }
+                buffer.cursor_x = x;
+                buffer.cursor_y = y;
+            }
+        }
+        buffer.max_cursor_x = buffer.cursor_x;
+        buffer.set_selection(false);
+    }
+
+    // Insert mode
+    //========================================
+
+    fn start_insert(&mut self, buffer: &mut Buffer, command: Option<Command>, count: usize, repeat: Repeat) {
+        self.state = State::Insert;
+        let inserted = Rc::new(RefCell::new(String::new()));
+        let record = inserted.clone();
+        let subscription = buffer.subscribe(move |change| {
+            let mut inserted = record.borrow_mut();
+            // Backspacing over what was typed takes it back out
+            if let Some(kept) = inserted.strip_suffix(change.old_text.as_str()).map(|s| s.len()) {
+                inserted.truncate(kept);
+            }
+            inserted.push_str(&change.text);
+        });
+        self.insert = Some(InsertSession {
+            command,
+            count,
+            repeat,
+            events: Vec::new(),
+            undo_start: self.undo_start,
+            inserted,
+            subscription,
+        });
+    }
+
+    fn open_line(&self, buffer: &mut Buffer, below: bool) {
+        let y = buffer.cursor_y;
+        if below {
+            buffer.cursor_x = buffer.line_len(y);
+            buffer.set_selection(false);
+            buffer.break_line_with_auto_indent();
+        } else {
+            let indentation = leading_whitespace(&buffer.contents[y]).to_string();
+            let x = indentation.graphemes(true).count();
+            buffer.insert_line(y, indentation);
+            buffer.cursor_x = x;
+            buffer.cursor_y = y;
+        }
+        buffer.max_cursor_x = buffer.cursor_x;
+        buffer.set_selection(false);
+    }
+
+    fn replay_insert(&self, pane: &mut Pane, buffer: &mut Buffer, events: &[InsertEvent]) {
+        for event in events {
+            match event {
+                InsertEvent::Key(kstr) => {
+                    pane.handle_keystroke(buffer, kstr);
+                }
+                InsertEvent::Text(text) => pane.type_text(buffer, text),
+            }
+        }
+    }
+
+    fn leave_insert(&mut self, pane: &mut Pane, buffer: &mut Buffer) {
+        self.state = State::Normal;
+        let session = match self.insert.take() {
+            Some(session) => session,
+            None => return,
+        };
+        buffer.unsubscribe(session.subscription);
+        let text = session.inserted.take();
+        self.registers.insert('.', Register { text, kind: Kind::Charwise });
+        let mut is_block = false;
+        if !session.events.is_empty() {
+            match session.repeat {
+                Repeat::Text => {
+                    for _ in 1..session.count {
+                        self.replay_insert(pane, buffer, &session.events);
+                    }
+                }
+                Repeat::Line { below } => {
+                    for _ in 1..session.count {
+                        self.open_line(buffer, below);
+                        self.replay_insert(pane, buffer, &session.events);
+                    }
+                }
+                Repeat::Block { x, y1, y2, append } => {
+                    is_block = true;
+                    for y in y1 + 1..=min(y2, buffer.len() - 1) {
+                        let len = buffer.line_len(y);
+                        if len < x {
+                            if !append {
+                                continue;
+                            }
+                            buffer.insert_text(len, y, " ".repeat(x - len));
+                        }
+                        buffer.cursor_x = x;
+                        buffer.cursor_y = y;
+                        buffer.max_cursor_x = x;
+                        buffer.set_selection(false);
+                        self.replay_insert(pane, buffer, &session.events);
+                    }
+                    buffer.cursor_x = x;
+                    buffer.cursor_y = y1;
+                }
+            }
+        }
+
+        // The whole insert is undone in one go
+        let start = session.undo_start;
+        if buffer.undo_stack.len() > start + 1 {
+            let merged = buffer.undo_stack.drain(start..).flatten().collect();
+            buffer.undo_stack.push(merged);
+        }
+
+        if let Some(command) = session.command {
+            if !self.replaying {
+                self.last_change = Some(Change { command, events: session.events });
+            }
+        }
+        if !is_block && buffer.cursor_x > 0 {
+            buffer.cursor_x -= 1;
+        }
+        buffer.max_cursor_x = buffer.cursor_x;
+        buffer.set_selection(false);
+    }
+
+    // Running commands
+    //========================================
+
+    fn remember(&mut self, command: Command) {
+        if !self.replaying {
+            self.last_change = Some(Change { command, events: Vec::new() });
+        }
+    }
+
+    fn move_to(&self, buffer: &mut Buffer, action: &str, target: Target) {
+        buffer.cursor_x = target.x;
+        buffer.cursor_y = target.y;
+        match action {
+            "j" | "k" => {}
+            "$" => buffer.max_cursor_x = usize::MAX,
+            _ => buffer.max_cursor_x = target.x,
+        }
+    }
+
+    fn execute(&mut self, pane: &mut Pane, buffer: &mut Buffer, command: Command) {
+        self.undo_start = buffer.undo_stack.len();
+        let command = expand_alias(command, self.is_visual());
+        // Undo and redo work on the undo stack themselves, so they can't be grouped
+        let is_undo = command.op.is_none() && (command.action == "u" || command.action == "\x12") && !self.is_visual();
+        if !is_undo {
+            buffer.begin_undo_group();
+        }
+        if self.is_visual() {
+            self.execute_visual(pane, buffer, command);
+        } else {
+            self.execute_normal(pane, buffer, command);
+        }
+        if !is_undo {
+            buffer.end_undo_group();
+        }
+    }
+
+    fn execute_normal(&mut self, pane: &mut Pane, buffer: &mut Buffer, command: Command) {
+        let count = command.count;
+        let n = count.unwrap_or(1);
+        let action = command.action.as_str();
+
+        if let Some(op) = command.op.clone() {
+            let region = if action.len() == 2 && (action.starts_with('i') || action.starts_with('a')) {
+                self.object(buffer, action)
+            } else {
+                // cw on a word only changes to the end of the word
+                let on_word = buffer.line_graphemes(buffer.cursor_y).get(buffer.cursor_x).is_some_and(|g| class_of(g, false) != 0);
+                let action = match action {
+                    "w" if op == "c" && on_word => "e",
+                    "W" if op == "c" && on_word => "E",
+                    _ => action,
+                };
+                self.motion(buffer, action, count, true).and_then(|target| self.motion_region(buffer, &target))
+            };
+            if let Some(region) = region {
+                let keep = if op == "c" { Some(command.clone()) } else { None };
+                self.apply_operator(buffer, &op, region, command.register, keep, 1);
+                if op != "y" && op != "c" {
+                    self.remember(command);
+                }
+            }
+            return;
+        }
+
+        let (x, y) = (buffer.cursor_x, buffer.cursor_y);
+        let len = buffer.line_len(y);
+        match action.chars().next().unwrap_or(' ') {
+            'p' | 'P' => {
+                if let Some(reg) = self.read_register(command.register) {
+                    self.put(buffer, reg, n, action == "P");
+                    self.remember(command);
+                }
+            }
+            'u' => {
+                for _ in 0..n {
+                    buffer.undo();
+                }
+            }
+            '\x12' => {
+                for _ in 0..n {
+                    buffer.redo();
+                }
+            }
+            'J' => {
+                let y2 = min(y + max(n, 2) - 1, buffer.len() - 1);
+                if y2 > y {
+                    let join_x = buffer.contents[y].trim_end().graphemes(true).count();
+                    buffer.sel_x = 0;
+                    buffer.sel_y = y;
+                    buffer.cursor_x = 0;
+                    buffer.cursor_y = y2;
+                    buffer.join_lines();
+                    buffer.cursor_x = min(join_x, buffer.line_len(y));
+                    buffer.cursor_y = y;
+                    self.remember(command);
+                }
+            }
+            '~' if len > 0 => {
+                let x2 = min(x + n, len);
+                self.transform_region(buffer, &Region { x1: x, y1: y, x2, y2: y, kind: Kind::Charwise }, toggle_case);
+                buffer.cursor_x = min(x2, len - 1);
+                self.remember(command);
+            }
+            'r' if x + n <= len => {
+                let c = action.chars().nth(1).unwrap();
+                buffer.replace_text(x, y, x + n, y, c.to_string().repeat(n));
+                buffer.cursor_x = x + n - 1;
+                self.remember(command);
+            }
+            '.' => {
+                if let Some(change) = self.last_change.clone() {
+                    let mut command = change.command.clone();
+                    if count.is_some() {
+                        command.count = count;
+                    }
+                    self.replaying = true;
+                    self.execute_normal(pane, buffer, command);
+                    if self.state == State::Insert {
+                        self.replay_insert(pane, buffer, &change.events);
+                        if let Some(session) = self.insert.as_mut() {
+                            session.events = change.events.clone();
+                        }
+                        self.leave_insert(pane, buffer);
+                    }
+                    self.replaying = false;
+                }
+            }
+            'i' | 'a' | 'I' | 'A' => {
+                buffer.cursor_x = match action {
+                    "a" => min(x + 1, len),
+                    "I" => first_nonblank(buffer, y),
+                    "A" => len,
+                    _ => x,
+                };
+                self.start_insert(buffer, Some(command), n, Repeat::Text);
+            }
+            'o' | 'O' => {
+                let below = action == "o";
+                self.open_line(buffer, below);
+                self.start_insert(buffer, Some(command), n, Repeat::Line { below });
+            }
+            'z' => scroll_view(pane, buffer, action),
+            'v' => self.enter_visual(buffer, State::Visual),
+            'V' => self.enter_visual(buffer, State::VisualLine),
+            '\x16' => self.enter_visual(buffer, State::VisualBlock),
+            _ => {
+                if let Some(target) = self.motion(buffer, action, count, false) {
+                    self.move_to(buffer, action, target);
+                }
+            }
+        }
+        buffer.set_selection(false);
+    }
+
+    fn enter_visual(&mut self, buffer: &Buffer, state: State) {
+        self.anchor = (buffer.cursor_x, buffer.cursor_y);
+        self.state = state;
+    }
+
+    fn execute_visual(&mut self, pane: &mut Pane, buffer: &mut Buffer, command: Command) {
+        let n = command.count.unwrap_or(1);
+        let action = command.action.as_str();
+
+        if let Some(op) = command.op.clone() {
+            let region = self.visual_region(buffer);
+            self.state = State::Normal;
+            self.apply_operator(buffer, &op, region, command.register, None, n);
+            return;
+        }
+
+        match action.chars().next().unwrap_or(' ') {
+            'z' => scroll_view(pane, buffer, action),
+            'o' => {
+                let (x, y) = self.anchor;
+                self.anchor = (buffer.cursor_x, buffer.cursor_y);
+                buffer.cursor_x = x;
+                buffer.cursor_y = y;
+            }
+            'v' | 'V' | '\x16' => {
+                let state = match action {
+                    "v" => State::Visual,
+                    "V" => State::VisualLine,
+                    _ => State::VisualBlock,
+                };
+                self.state = if state == self.state { State::Normal } else { state };
+            }
+            // The uppercase versions always work on whole lines
+            'X' | 'D' | 'Y' | 'C' | 'S' | 'R' => {
+                self.state = State::VisualLine;
+                let op = match action {
+                    "Y" => "y",
+                    "X" | "D" => "d",
+                    _ => "c",
+                };
+                let command = Command { op: Some(op.to_string()), ..command };
+                self.execute_visual(pane, buffer, command);
+            }
+            'J' => {
+                let r = self.visual_region(buffer);
+                let y2 = if r.y2 > r.y1 { r.y2 } else { min(r.y1 + 1, buffer.len() - 1) };
+                self.state = State::Normal;
+                if y2 > r.y1 {
+                    buffer.sel_x = 0;
+                    buffer.sel_y = r.y1;
+                    buffer.cursor_x = 0;
+                    buffer.cursor_y = y2;
+                    buffer.join_lines();
+                    buffer.cursor_y = r.y1;
+                }
+            }
+            'r' => {
+                let c = action.chars().nth(1).unwrap();
+                let r = self.visual_region(buffer);
+                self.state = State::Normal;
+                let text = self.region_text(buffer, &r);
+                let replaced = text.split('\n').map(|line| c.to_string().repeat(line.graphemes(true).count())).collect::<Vec<String>>();
+                match r.kind {
+                    Kind::Blockwise => {
+                        for (i, line) in replaced.iter().enumerate() {
+                            let y = r.y1 + i;
+                            let len = buffer.line_len(y);
+                            let (x1, x2) = (min(r.x1, len), min(r.x2, len));
+                            buffer.replace_text(x1, y, x2, y, line.clone());
+                        }
+                    }
+                    Kind::Linewise => {
+                        let x2 = buffer.line_len(r.y2);
+                        buffer.replace_text(0, r.y1, x2, r.y2, replaced.join("\n"));
+                    }
+                    Kind::Charwise => {
+                        buffer.replace_text(r.x1, r.y1, r.x2, r.y2, replaced.join("\n"));
+                    }
+                }
+                buffer.cursor_x = r.x1;
+                buffer.cursor_y = r.y1;
+            }
+            'p' | 'P' => {
+                // Replace the selection with the register, keeping what was there
+                // in the unnamed register
+                if let Some(reg) = self.read_register(command.register) {
+                    let r = self.visual_region(buffer);
+                    self.state = State::Normal;
+                    let at_end = r.kind == Kind::Linewise && r.y2 + 1 >= buffer.len() && r.y1 > 0;
+                    self.apply_operator(buffer, "d", r, None, None, 1);
+                    if r.kind == Kind::Charwise && reg.kind == Kind::Linewise {
+                        buffer.insert_text(r.x1, r.y1, "\n\n".to_string());
+                        buffer.cursor_x = 0;
+                        buffer.cursor_y = r.y1 + 1;
+                        let text = reg.text.clone();
+                        buffer.insert_text(0, r.y1 + 1, text);
+                    } else {
+                        self.put(buffer, reg, n, !at_end);
+                    }
+                }
+            }
+            'I' | 'A' => {
+                let r = self.visual_region(buffer);
+                self.state = State::Normal;
+                let append = action == "A";
+                match r.kind {
+                    Kind::Blockwise => {
+                        let x = if append { r.x2 } else { r.x1 };
+                        let len = buffer.line_len(r.y1);
+                        if len < x {
+                            buffer.insert_text(len, r.y1, " ".repeat(x - len));
+                        }
+                        buffer.cursor_x = x;
+                        buffer.cursor_y = r.y1;
+                        self.start_insert(buffer, None, 1, Repeat::Block { x, y1: r.y1, y2: r.y2, append });
+                    }
+                    Kind::Linewise => {
+                        buffer.cursor_y = if append { r.y2 } else { r.y1 };
+                        buffer.cursor_x = if append { buffer.line_len(r.y2) } else { first_nonblank(buffer, r.y1) };
+                        self.start_insert(buffer, None, 1, Repeat::Text);
+                    }
+                    Kind::Charwise => {
+                        buffer.cursor_x = if append { r.x2 } else { r.x1 };
+                        buffer.cursor_y = if append { r.y2 } else { r.y1 };
+                        self.start_insert(buffer, None, 1, Repeat::Text);
+                    }
+                }
+            }
+            'i' | 'a' => {
+                if let Some(r) = self.object(buffer, action) {
+                    if r.kind == Kind::Linewise {
+                        self.state = State::VisualLine;
+                        self.anchor = (0, r.y1);
+                        buffer.cursor_x = 0;
+                        buffer.cursor_y = r.y2;
+                    } else {
+                        self.state = State::Visual;
+                        self.anchor = (r.x1, r.y1);
+                        let (x, y) = buffer.prev_char(r.x2, r.y2);
+                        buffer.cursor_x = x;
+                        buffer.cursor_y = y;
+                    }
+                }
+            }
+            _ => {
+                if let Some(target) = self.motion(buffer, action, command.count, false) {
+                    self.move_to(buffer, action, target);
+                }
+            }
+        }
+        buffer.set_selection(false);
+    }
+
+    fn handle_char(&mut self, pane: &mut Pane, buffer: &mut Buffer, c: char) {
+        // A selection made with the mouse carries on as a visual selection
+        if self.state == State::Normal && (buffer.sel_x, buffer.sel_y) != (buffer.cursor_x, buffer.cursor_y) {
+            self.anchor = (buffer.sel_x, buffer.sel_y);
+            if (buffer.cursor_y, buffer.cursor_x) > (buffer.sel_y, buffer.sel_x) {
+                let (x, y) = buffer.prev_char(buffer.cursor_x, buffer.cursor_y);
+                buffer.cursor_x = x;
+                buffer.cursor_y = y;
+            }
+            self.state = State::Visual;
+            buffer.set_selection(false);
+        }
+
+        self.pending.push(c);
+        let keys = self.pending.chars().collect::<Vec<char>>();
+        match parse(&keys, self.is_visual()) {
+            Parse::Incomplete => {}
+            Parse::Invalid => self.pending.clear(),
+            Parse::Done(command) => {
+                self.pending.clear();
+                self.execute(pane, buffer, command);
+            }
+        }
+        self.update(pane, buffer);
+    }
+
+    // Keep the cursor on a character and show the visual selection
+    fn update(&self, pane: &mut Pane, buffer: &mut Buffer) {
+        if self.state != State::Insert {
+            let len = buffer.line_len(buffer.cursor_y);
+            if buffer.cursor_x >= len {
+                buffer.cursor_x = len.saturating_sub(1);
+            }
+            buffer.set_selection(false);
+        }
+        pane.highlight = match self.state {
+            State::Visual => {
+                let r = self.visual_region(buffer);
+                Some(Highlight::Range(r.x1, r.y1, r.x2, r.y2))
+            }
+            State::VisualLine => {
+                let r = self.visual_region(buffer);
+                Some(Highlight::Range(0, r.y1, 0, r.y2 + 1))
+            }
+            State::VisualBlock => {
+                let r = self.visual_region(buffer);
+                Some(Highlight::Block(r.x1, r.y1, r.x2, r.y2))
+            }
+            _ => None,
+        };
+    }
+
+    fn escape(&mut self, pane: &mut Pane, buffer: &mut Buffer) {
+        self.pending.clear();
+        if self.state == State::Insert {
+            self.leave_insert(pane, buffer);
+        }
+        self.state = State::Normal;
+        self.update(pane, buffer);
+    }
+}
+
+impl Mode for Vim {
+    fn name(&self) -> &'static str {
+        "vim"
+    }
+
+    fn status(&self) -> String {
+        match self.state {
+            State::Normal if self.pending.is_empty() => "NORMAL".to_string(),
+            State::Normal => self.pending.replace('\x12', "^R").replace('\x16', "^V"),
+            State::Insert => "-- INSERT --".to_string(),
+            State::Visual => "-- VISUAL --".to_string(),
+            State::VisualLine => "-- VISUAL LINE --".to_string(),
+            State::VisualBlock => "-- VISUAL BLOCK --".to_string(),
+        }
+    }
+
+    fn handle_key(&mut self, pane: &mut Pane, buffer: &mut Buffer, kstr: &str) -> bool {
+        if kstr == "escape" {
+            self.escape(pane, buffer);
+            return true;
+        }
+        if self.state == State::Insert {
+            if !self.replaying {
+                if let Some(session) = self.insert.as_mut() {
+                    session.events.push(InsertEvent::Key(kstr.to_string()));
+                }
+            }
+            return false;
+        }
+        let c = match kstr {
+            "up" => 'k',
+            "down" => 'j',
+            "left" | "backspace" => 'h',
+            "right" => 'l',
+            "return" => '+',
+            "home" => '0',
+            "end" => '$',
+            "delete" => 'x',
+            "c-r" => '\x12',
+            "c-v" => '\x16',
+            _ => {
+                // Printable keys come through as text. Anything with a modifier or a
+                // function key goes to the pane as usual.
+                let key = kstr.trim_start_matches("s-");
+                let is_function_key = key.len() > 1 && key.starts_with('f') && key[1..].chars().all(|c| c.is_ascii_digit());
+                return !(key.starts_with("c-") || key.starts_with("a-") || is_function_key);
+            }
+        };
+        self.handle_char(pane, buffer, c);
+        true
+    }
+
+    fn handle_text(&mut self, pane: &mut Pane, buffer: &mut Buffer, text: &str) -> bool {
+        if self.state == State::Insert {
+            if !self.replaying {
+                if let Some(session) = self.insert.as_mut() {
+                    session.events.push(InsertEvent::Text(text.to_string()));
+                }
+            }
+            return false;
+        }
+        for (i, c) in text.char_indices() {
+            if self.state == State::Insert {
+                // Whatever comes after the command that started insert mode is typed
+                let rest = &text[i..];
+                if let Some(session) = self.insert.as_mut() {
+                    session.events.push(InsertEvent::Text(rest.to_string()));
+                }
+                pane.type_text(buffer, rest);
+                break;
+            }
+            self.handle_char(pane, buffer, c);
+        }
+        true
+    }
+
+    fn handle_mouse(&mut self, pane: &mut Pane, buffer: &mut Buffer, event: &MouseEvent) -> bool {
+        if let MouseEvent::Down = event {
+            self.pending.clear();
+            if self.is_visual() {
+                self.state = State::Normal;
+                self.update(pane, buffer);
+            }
+        }
+        false
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pane::PaneType;
+
+    // Run `keys` through vim the way the editor does: whatever vim doesn't take
+    // in insert mode gets typed into the pane. "\x1b" stands for escape.
+    fn run(lines: &[&str], x: usize, y: usize, keys: &str) -> (Vim, Buffer) {
+        let mut vim = Vim::new();
+        let mut pane = Pane::new(PaneType::Buffer, 0, 1.0);
+        let mut buffer = Buffer::from_lines(lines.iter().copied());
+        buffer.cursor_x = x;
+        buffer.cursor_y = y;
+        buffer.set_selection(false);
+        for c in keys.chars() {
+            if c == '\x1b' {
+                vim.handle_key(&mut pane, &mut buffer, "escape");
+            } else if !vim.handle_text(&mut pane, &mut buffer, &c.to_string()) {
+                pane.type_text(&mut buffer, &c.to_string());
+            }
+        }
+        (vim, buffer)
+    }
+
+    #[test]
+    fn operators_take_a_count() {
+        let (_, buffer) = run(&["one two three four five"], 0, 0, "d3w");
+        assert_eq!(buffer.contents, vec!["four five"]);
+    }
+
+    #[test]
+    fn changes_inside_parentheses() {
+        let (vim, buffer) = run(&["call(a, b) x"], 6, 0, "ci(z\x1b");
+        assert_eq!(buffer.contents, vec!["call(z) x"]);
+        assert_eq!(vim.state, State::Normal);
+    }
+
+    #[test]
+    fn deleted_lines_can_be_put_back() {
+        let (_, buffer) = run(&["a", "b", "c"], 0, 0, "ddp");
+        assert_eq!(buffer.contents, vec!["b", "a", "c"]);
+        assert_eq!(buffer.cursor_y, 1);
+    }
+
+    #[test]
+    fn yanks_into_and_puts_from_named_registers() {
+        let (vim, buffer) = run(&["a", "b"], 0, 0, "\"ayyjdd\"ap");
+        assert_eq!(buffer.contents, vec!["a", "a"]);
+        assert_eq!(vim.registers[&'a'].text, "a");
+        assert_eq!(vim.registers[&'"'].text, "b");
+    }
+
+    #[test]
+    fn dot_repeats_a_change() {
+        let (vim, buffer) = run(&["foo bar baz"], 0, 0, "cwX\x1bw.");
+        assert_eq!(buffer.contents, vec!["X X baz"]);
+        assert_eq!(vim.registers[&'.'].text, "X");
+    }
+
+    #[test]
+    fn visual_line_shifts_every_selected_line() {
+        let (vim, buffer) = run(&["a", "b", "c"], 0, 0, "Vj>");
+        assert_eq!(buffer.contents, vec!["    a", "    b", "c"]);
+        assert_eq!(vim.state, State::Normal);
+    }
+}
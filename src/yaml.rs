@@ -0,0 +1,415 @@
+use crate::json::Value;
+
+// The subset of YAML that .sublime-syntax files use: block mappings and sequences,
+// [flow, sequences] and {flow: mappings}, plain, 'single' and "double" quoted scalars,
+// and | and > block scalars. Anchors, tags and multiple documents aren't supported.
+
+struct Line {
+    number: usize,
+    indent: usize,
+    text: String,
+}
+
+// Cut a # comment off the end of a line, leaving ones inside quotes alone
+fn strip_comment(text: &str) -> &str {
+    let mut quote = None;
+    let mut prev = ' ';
+    for (i, c) in text.char_indices() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None if c == '\'' || c == '"' => quote = Some(c),
+            None if c == '#' && prev.is_whitespace() => return &text[..i],
+            None => {}
+        }
+        prev = c;
+    }
+    text
+}
+
+// Where a `key: value` line's colon is, if it is one
+fn find_colon(text: &str) -> Option<usize> {
+    let mut quote = None;
+    let mut depth = 0;
+    let chars = text.char_indices().collect::<Vec<(usize, char)>>();
+    for (n, &(i, c)) in chars.iter().enumerate() {
+        match quote {
+            Some(q) if c == q => quote = None,
+            Some(_) => {}
+            None => match c {
+                '\'' | '"' if n == 0 => quote = Some(c),
+                '[' | '{' => depth += 1,
+                ']' | '}' => depth -= 1,
+                ':' if depth == 0 && chars.get(n + 1).is_none_or(|(_, c)| c.is_whitespace()) => return Some(i),
+                _ => {}
+            },
+        }
+    }
+    None
+}
+
+fn scalar(text: &str) -> Value {
+    match text {
+        "" | "~" | "null" => Value::Null,
+        "true" => Value::Bool(true),
+        "false" => Value::Bool(false),
+        _ => match text.parse::<f64>() {
+            Ok(n) if !text.starts_with('.') && !text.ends_with('.') => Value::Number(n),
+            _ => Value::Str(text.to_string()),
+        },
+    }
+}
+
+fn single_quoted(text: &str) -> Result<(String, usize), String> {
+    let mut s = String::new();
+    let mut chars = text.char_indices().skip(1).peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\'' {
+            if chars.peek().map(|(_, c)| *c) == Some('\'') {
+                chars.next();
+                s.push('\'');
+            } else {
+                return Ok((s, i + 1));
+            }
+        } else {
+            s.push(c);
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+fn double_quoted(text: &str) -> Result<(String, usize), String> {
+    let mut s = String::new();
+    let mut chars = text.char_indices().skip(1);
+    while let Some((i, c)) = chars.next() {
+        match c {
+            '"' => return Ok((s, i + 1)),
+            '\\' => match chars.next().map(|(_, c)| c) {
+                Some('n') => s.push('\n'),
+                Some('t') => s.push('\t'),
+                Some('r') => s.push('\r'),
+                Some('0') => s.push('\0'),
+                Some(c @ ('x' | 'u' | 'U')) => {
+                    let len = match c {
+                        'x' => 2,
+                        'u' => 4,
+                        _ => 8,
+                    };
+                    let hex = (0..len).filter_map(|_| chars.next().map(|(_, c)| c)).collect::<String>();
+                    let code = u32::from_str_radix(&hex, 16).map_err(|_| "bad escape".to_string())?;
+                    s.push(char::from_u32(code).unwrap_or('\u{fffd}'));
+                }
+                Some(c) => s.push(c),
+                None => break,
+            },
+            _ => s.push(c),
+        }
+    }
+    Err("unterminated string".to_string())
+}
+
+// A [flow, sequence], {flow: mapping} or scalar at the start of `text`, and how much of
+// the text it took up
+fn flow(text: &str, in_collection: bool) -> Result<(Value, usize), String> {
+    let trimmed = text.trim_start();
+    let skipped = text.len() - trimmed.len();
+    let (value, len) = match trimmed.chars().next() {
+        Some('\'') => {
+            let (s, len) = single_quoted(trimmed)?;
+            (Value::Str(s), len)
+        }
+        Some('"') => {
+            let (s, len) = double_quoted(trimmed)?;
+            (Value::Str(s), len)
+        }
+        Some(open @ ('[' | '{')) => {
+            let close = if open == '[' { ']' } else { '}' };
+            let mut items = Vec::new();
+            let mut entries = Vec::new();
+            let mut pos = 1;
+            loop {
+                let rest = &trimmed[pos..];
+                let after = rest.trim_start();
+                pos += rest.len() - after.len();
+                if after.starts_with(close) {
+                    pos += 1;
+                    break;
+                }
+                if after.is_empty() {
+                    return Err(format!("expected {}", close));
+                }
+                let (item, len) = flow(after, true)?;
+                pos += len;
+                if open == '{' {
+                    let key = match item {
+                        Value::Str(s) => s,
+                        Value::Null => String::new(),
+                        other => format!("{:?}", other),
+                    };
+                    let rest = trimmed[pos..].trim_start();
+                    let value = match rest.strip_prefix(':') {
+                        Some(after_colon) => {
+                            pos = trimmed.len() - after_colon.len();
+                            let (value, len) = flow(after_colon, true)?;
+                            pos += len;
+                            value
+                        }
+                        None => Value::Null,
+                    };
+                    entries.push((key, value));
+                } else {
+                    items.push(item);
+                }
+                let rest = &trimmed[pos..];
+                let after = rest.trim_start();
+                pos += rest.len() - after.len();
+                if after.starts_with(',') {
+                    pos += 1;
+                }
+            }
+            let value = if open == '[' { Value::Array(items) } else { Value::Object(entries) };
+            (value, pos)
+        }
+        _ => {
+            // Inside a collection, plain scalars end at , ] } or a key's colon
+            let end = if in_collection {
+                trimmed
+                    .char_indices()
+                    .find(|&(i, c)| ",]}".contains(c) || (c == ':' && trimmed[i + 1..].starts_with(|c: char| c.is_whitespace() || ",]}".contains(c))))
+                    .map_or(trimmed.len(), |(i, _)| i)
+            } else {
+                trimmed.len()
+            };
+            (scalar(trimmed[..end].trim()), end)
+        }
+    };
+    Ok((value, skipped + len))
+}
+
+struct Parser {
+    lines: Vec<Line>,
+    pos: usize,
+}
+
+impl Parser {
+    fn error<T>(&self, message: &str) -> Result<T, String> {
+        let number = self.lines.get(self.pos).or_else(|| self.lines.last()).map_or(0, |l| l.number);
+        Err(format!("line {}: {}", number, message))
+    }
+
+    fn block(&mut self, indent: usize) -> Result<Value, String> {
+        let line = match self.lines.get(self.pos) {
+            Some(line) if line.indent >= indent => line,
+            _ => return Ok(Value::Null),
+        };
+        let indent = line.indent;
+        if line.text == "-" || line.text.starts_with("- ") {
+            self.sequence(indent)
+        } else if find_colon(&line.text).is_some() {
+            self.mapping(indent)
+        } else {
+            let text = line.text.clone();
+            self.pos += 1;
+            self.scalar_value(&text, indent)
+        }
+    }
+
+    fn sequence(&mut self, indent: usize) -> Result<Value, String> {
+        let mut items = Vec::new();
+        while let Some(line) = self.lines.get_mut(self.pos) {
+            if line.indent != indent || !(line.text == "-" || line.text.starts_with("- ")) {
+                break;
+            }
+            let rest = line.text[1..].trim_start().to_string();
+            if rest.is_empty() {
+                self.pos += 1;
+                items.push(self.block(indent + 1)?);
+            } else {
+                // Treat what follows the dash as a line of its own, indented to where it starts
+                line.indent += line.text.len() - rest.len();
+                line.text = rest;
+                let inner = line.indent;
+                items.push(self.block(inner)?);
+            }
+        }
+        Ok(Value::Array(items))
+    }
+
+    fn mapping(&mut self, indent: usize) -> Result<Value, String> {
+        let mut entries = Vec::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent < indent {
+                break;
+            }
+            if line.indent > indent {
+                return self.error("unexpected indentation");
+            }
+            let colon = match find_colon(&line.text) {
+                Some(colon) => colon,
+                None => return self.error("expected key: value"),
+            };
+            let key = match flow(&line.text[..colon], false)?.0 {
+                Value::Str(s) => s,
+                Value::Number(n) => n.to_string(),
+                Value::Bool(b) => b.to_string(),
+                _ => String::new(),
+            };
+            let rest = line.text[colon + 1..].trim().to_string();
+            self.pos += 1;
+            let value = if rest.is_empty() {
+                // A sequence under a key may sit at the key's own indentation
+                match self.lines.get(self.pos) {
+                    Some(next) if next.indent == indent && (next.text == "-" || next.text.starts_with("- ")) => self.sequence(indent)?,
+                    _ => self.block(indent + 1)?,
+                }
+            } else {
+                self.scalar_value(&rest, indent)?
+            };
+            entries.push((key, value));
+        }
+        Ok(Value::Object(entries))
+    }
+
+    // The value after a key or dash, which may carry on over the following lines
+    fn scalar_value(&mut self, text: &str, indent: usize) -> Result<Value, String> {
+        if text.starts_with('|') || text.starts_with('>') {
+            return Ok(Value::Str(self.block_scalar(text, indent)));
+        }
+        // Quoted strings and flow collections can span lines
+        let mut text = text.to_string();
+        loop {
+            match flow(&text, false) {
+                Ok((value, len)) if text[len..].trim().is_empty() => return Ok(value),
+                Ok(_) if !text.starts_with(['\'', '"', '[', '{']) => return Ok(Value::Str(text)),
+                Ok(_) => return self.error("unexpected text after a value"),
+                Err(e) => match self.lines.get(self.pos) {
+                    Some(next) if next.indent > indent || text.starts_with(['[', '{']) => {
+                        text.push(if text.starts_with(['[', '{']) { ' ' } else { '\n' });
+                        text.push_str(&next.text);
+                        self.pos += 1;
+                    }
+                    _ => return self.error(&e),
+                },
+            }
+        }
+    }
+
+    fn block_scalar(&mut self, header: &str, indent: usize) -> String {
+        let folded = header.starts_with('>');
+        let mut lines = Vec::new();
+        while let Some(line) = self.lines.get(self.pos) {
+            if line.indent <= indent && !line.text.is_empty() {
+                break;
+            }
+            lines.push(line);
+            self.pos += 1;
+        }
+        let base = lines.iter().filter(|l| !l.text.is_empty()).map(|l| l.indent).min().unwrap_or(0);
+        let texts = lines
+            .iter()
+            .map(|l| if l.text.is_empty() { String::new() } else { format!("{}{}", " ".repeat(l.indent - base), l.text) })
+            .collect::<Vec<String>>();
+        let mut s = texts.join(if folded { " " } else { "\n" });
+        if header.contains('-') {
+            s = s.trim_end_matches('\n').to_string();
+        } else {
+            s = format!("{}\n", s.trim_end_matches('\n'));
+        }
+        s
+    }
+}
+
+pub fn parse(text: &str) -> Result<Value, String> {
+    let mut lines = Vec::new();
+    let mut in_block_scalar = None;
+    for (i, raw) in text.lines().enumerate() {
+        let indent = raw.len() - raw.trim_start_matches(' ').len();
+        // Comments don't count inside | and > blocks
+        if let Some(block_indent) = in_block_scalar {
+            if raw.trim().is_empty() || indent > block_indent {
+                lines.push(Line { number: i + 1, indent, text: raw.trim().to_string() });
+                continue;
+            }
+            in_block_scalar = None;
+        }
+        let content = strip_comment(&raw[indent..]).trim_end();
+        if content.is_empty() || content.starts_with('%') || content == "---" || content == "..." {
+            continue;
+        }
+        let value = match find_colon(content) {
+            Some(colon) => content[colon + 1..].trim(),
+            None => content.trim_start_matches('-').trim(),
+        };
+        if value.starts_with(['|', '>']) && value.len() <= 2 {
+            in_block_scalar = Some(indent);
+        }
+        lines.push(Line { number: i + 1, indent, text: content.to_string() });
+    }
+    let mut parser = Parser { lines, pos: 0 };
+    let value = parser.block(0)?;
+    if parser.pos < parser.lines.len() {
+        return parser.error("unexpected indentation");
+    }
+    Ok(value)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn s(text: &str) -> Value {
+        Value::Str(text.to_string())
+    }
+
+    #[test]
+    fn parses_nested_mappings_and_sequences() {
+        let value = parse(
+            "%YAML 1.2\n---\nname: Test # comment\nfile_extensions:\n- rs\n- 'r#s'\ncontexts:\n  main:\n    - match: '\\b(fn)\\b'\n      scope: keyword\n    - include: strings\n  empty: []\nflags: {a: 1, b: [x, y]}\n",
+        )
+        .unwrap();
+        assert_eq!(value.get("name"), Some(&s("Test")));
+        assert_eq!(value.get("file_extensions"), Some(&Value::Array(vec![s("rs"), s("r#s")])));
+        let contexts = value.get("contexts").unwrap();
+        let main = contexts.get("main").unwrap().as_array();
+        assert_eq!(main.len(), 2);
+        assert_eq!(main[0].get("match"), Some(&s("\\b(fn)\\b")));
+        assert_eq!(main[0].get("scope"), Some(&s("keyword")));
+        assert_eq!(main[1].get("include"), Some(&s("strings")));
+        assert_eq!(contexts.get("empty"), Some(&Value::Array(vec![])));
+        assert_eq!(
+            value.get("flags"),
+            Some(&Value::Object(vec![
+                ("a".to_string(), Value::Number(1.0)),
+                ("b".to_string(), Value::Array(vec![s("x"), s("y")])),
+            ]))
+        );
+    }
+
+    #[test]
+    fn decodes_quoted_and_block_scalars() {
+        let value = parse(
+            "single: 'it''s # not a comment'\ndouble: \"a\\tb\\n\\x41\\u00e9\\\"\"\nliteral: |\n  one\n    two\nfolded: >-\n  one\n  two\nplain: a: b\nnothing: ~\n",
+        )
+        .unwrap();
+        assert_eq!(value.get("single"), Some(&s("it's # not a comment")));
+        assert_eq!(value.get("double"), Some(&s("a\tb\nAé\"")));
+        assert_eq!(value.get("literal"), Some(&s("one\n  two\n")));
+        assert_eq!(value.get("folded"), Some(&s("one two")));
+        assert_eq!(value.get("plain"), Some(&s("a: b")));
+        assert_eq!(value.get("nothing"), Some(&Value::Null));
+    }
+
+    #[test]
+    fn quoted_strings_and_flow_collections_can_span_lines() {
+        let value = parse("a: 'one\n  two'\nb: [x,\n  y]\n").unwrap();
+        assert_eq!(value.get("a"), Some(&s("one\ntwo")));
+        assert_eq!(value.get("b"), Some(&Value::Array(vec![s("x"), s("y")])));
+    }
+
+    #[test]
+    fn reports_malformed_input() {
+        for text in ["a: 'open", "a: \"open", "a: [1, 2", "a: {b: 1", "a: 1\n  b: 2", "a: 1\nb", "a: \"\\uzz\"", "a: 'x' y"] {
+            assert!(parse(text).is_err(), "{:?} should not parse", text);
+        }
+    }
+}